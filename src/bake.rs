@@ -0,0 +1,360 @@
+//! Baking ambient occlusion, lighting, normals or displacement into a texture, for meshes with
+//! texture coordinates. Lightmaps have a UV set of their own instead, which is either loaded
+//! from another file or generated with a cell for each triangle.
+//!
+//! Every triangle is rasterized in UV space, so each texel whose center it covers learns the
+//! point on the surface it stands for. For ambient occlusion, cosine-distributed rays sample
+//! the hemisphere around the geometric normal from that point, and the texel stores the
+//! fraction that escapes. Lightmaps store the irradiance estimated by `integrator::irradiance`,
+//! which follows the same rays with the path tracer. Normals and displacement are transferred
+//! from a separate, more detailed source mesh (with its own BVH): rays go both ways along the
+//! normal of the target mesh, and the closer hit on the source mesh within the cage distance is
+//! recorded.
+//! Texels that no triangle covers are filled from their neighbors for a few rounds, so that
+//! filtering doesn't pull in the background at UV seams.
+
+use super::{Bake, BakeMap, Config, fail, print_timing};
+use cast::{f32, u32, usize};
+use cgmath::{InnerSpace, Vector2, Vector3, vec2};
+use color::Rgb;
+use film::{self, Colors, Frame};
+use geom::{Index, Ray, index};
+use integrator::{irradiance, offset_origin, secondary_ray, to_world};
+use sampling::{Rng, cosine_hemisphere};
+use scene::Scene;
+use std::f32;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The triangle and barycentric coordinates (u, v, w) of the point each texel stands for.
+type Texel = Option<(Index, f32, f32, f32)>;
+
+pub fn run(scene: &Scene, cfg: &Config, bake: &Bake) {
+    let (width, height) = bake.size;
+    let uvs = match bake.map {
+        BakeMap::Lightmap => lightmap_uvs(scene, cfg, bake),
+        _ => (0..scene.mesh.tris.len()).map(|i| scene.tri_uvs(index(i))).collect(),
+    };
+    let texels = print_timing("rasterizing UVs", || rasterize(&uvs, width, height));
+    let (values, background) = match bake.map {
+        BakeMap::AmbientOcclusion => {
+            let ao = print_timing("baking ambient occlusion",
+                                  || ambient_occlusion(scene, cfg, bake, &texels));
+            (ao, Rgb::grey(1.0))
+        }
+        BakeMap::Lightmap => {
+            let light = print_timing("baking lightmap", || lightmap(scene, cfg, bake, &texels));
+            (light, Rgb::black())
+        }
+        BakeMap::Normal | BakeMap::Displacement => {
+            let source_cfg = Config {
+                input_files: vec![bake.source.clone().expect("BUG: no source mesh to bake from")],
+                part_colors: vec![],
+                ..cfg.clone()
+            };
+            let source = Scene::new(&source_cfg);
+            let diagonal = (scene.bbox().max() - scene.bbox().min()).magnitude();
+            let cage = bake.cage.unwrap_or(0.02 * diagonal);
+            let values = print_timing("baking from the source mesh",
+                                      || transfer(scene, &source, bake.map, cage, &texels));
+            // A flat surface: the unperturbed normal, or no displacement.
+            let background = match bake.map {
+                BakeMap::Normal => Rgb::new(0.5, 0.5, 1.0),
+                _ => Rgb::grey(0.5),
+            };
+            (values, background)
+        }
+    };
+    let values = dilate(values, bake.padding);
+    let mut colors = Frame::new(width, height, background);
+    colors.set_pixels(colors.bounds(), |x, y| values.get(x, y).unwrap_or(background));
+    let image: Box<film::ToBmp> = match bake.map {
+        BakeMap::Lightmap => {
+            Box::new(film::Radiance {
+                         frame: colors,
+                         tonemap: cfg.tonemap,
+                         exposure: cfg.exposure,
+                     })
+        }
+        _ => Box::new(Colors(colors)),
+    };
+    film::save(&*image, &bake.output_file).unwrap_or_else(|e| fail(&e));
+    println!("wrote {}", bake.output_file.display());
+}
+
+/// 2D cross product.
+fn cross(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+/// The texture coordinates of the lightmap for each triangle: those of the mesh given with
+/// `--lightmap-uvs`, or a generated layout, which is written out next to the lightmap so that
+/// the lightmap can be applied with it.
+fn lightmap_uvs(scene: &Scene, cfg: &Config, bake: &Bake) -> Vec<[Vector2<f32>; 3]> {
+    if let Some(ref path) = bake.lightmap_uvs {
+        return load_uvs(scene, cfg, path);
+    }
+    let uvs = print_timing("generating lightmap UVs", || atlas(scene, bake));
+    let obj_path = bake.output_file.with_extension("obj");
+    write_obj(scene, &uvs, &obj_path).unwrap_or_else(|e| fail(&e));
+    println!("wrote {}", obj_path.display());
+    uvs
+}
+
+/// The texture coordinates of the mesh in `path`, which must have the same triangles as the
+/// baked one, in the same order.
+fn load_uvs(scene: &Scene, cfg: &Config, path: &Path) -> Vec<[Vector2<f32>; 3]> {
+    let layout_cfg = Config {
+        input_files: vec![path.to_path_buf()],
+        part_colors: vec![],
+        ..cfg.clone()
+    };
+    let layout = Scene::new(&layout_cfg);
+    let tri_count = scene.mesh.tris.len();
+    let same_tris = layout.mesh.tris.len() == tri_count &&
+                    (0..tri_count).all(|i| {
+                                           layout.mesh.corners(index(i)) ==
+                                           scene.mesh.corners(index(i))
+                                       });
+    if !same_tris {
+        fail(&format!("{} doesn't have the same triangles as the mesh to bake",
+                      path.display()));
+    }
+    (0..tri_count).map(|i| layout.tri_uvs(index(i))).collect()
+}
+
+/// A lightmap layout that puts every triangle into a cell of its own in a grid, scaled to fit
+/// it but otherwise undistorted. The cells leave room for `bake.padding`, so that dilation
+/// doesn't spill from one triangle into another.
+fn atlas(scene: &Scene, bake: &Bake) -> Vec<[Vector2<f32>; 3]> {
+    let tri_count = scene.mesh.tris.len();
+    let mut cols = 1;
+    while cols * cols < tri_count {
+        cols += 1;
+    }
+    let rows = (tri_count + cols - 1) / cols;
+    let (w, h) = (f32(bake.size.0), f32(bake.size.1));
+    let (cell_w, cell_h) = (w / f32(cols), h / f32(rows.max(1)));
+    let margin = f32(bake.padding) + 1.0;
+    let (inner_w, inner_h) = (cell_w - 2.0 * margin, cell_h - 2.0 * margin);
+    if inner_w < 2.0 || inner_h < 2.0 {
+        fail(&format!("{} triangles don't fit into a {}x{} lightmap with --padding {}, pass a \
+                       larger --size or --lightmap-uvs",
+                      tri_count,
+                      bake.size.0,
+                      bake.size.1,
+                      bake.padding));
+    }
+    (0..tri_count)
+        .map(|i| {
+            let (a, b, c) = scene.mesh.corners(index(i));
+            // The triangle in its own plane, with a at the origin and b on the x axis.
+            let len = (b - a).magnitude();
+            let x_axis = (b - a) / len;
+            let along = (c - a).dot(x_axis);
+            let height = (c - a - x_axis * along).magnitude();
+            let flat = [vec2(0.0, 0.0), vec2(len, 0.0), vec2(along, height)];
+            let min_x = along.min(0.0);
+            let scale = (inner_w / (along.max(len) - min_x)).min(inner_h / height);
+            let origin = vec2(f32(i % cols) * cell_w + margin, f32(i / cols) * cell_h + margin);
+            let mut uvs = [vec2(0.0, 0.0); 3];
+            if scale.is_finite() {
+                for (uv, p) in uvs.iter_mut().zip(&flat) {
+                    // Texel coordinates have y pointing down, see `rasterize`.
+                    let texel = origin + (p - vec2(min_x, 0.0)) * scale;
+                    *uv = vec2(texel.x / w, 1.0 - texel.y / h);
+                }
+            }
+            uvs
+        })
+        .collect()
+}
+
+/// Write the mesh as OBJ, with `uvs` as its texture coordinates.
+fn write_obj(scene: &Scene, uvs: &[[Vector2<f32>; 3]], path: &Path) -> Result<(), String> {
+    let error = |e: io::Error| format!("could not write {}: {}", path.display(), e);
+    let mut out = BufWriter::new(File::create(path).map_err(&error)?);
+    for v in &scene.mesh.vertices {
+        writeln!(out, "v {} {} {}", v.x, v.y, v.z).map_err(&error)?;
+    }
+    for uv in uvs.iter().flat_map(|uvs| uvs) {
+        writeln!(out, "vt {} {}", uv.x, uv.y).map_err(&error)?;
+    }
+    for (i, tri) in scene.mesh.tris.iter().enumerate() {
+        let (a, b, c) = (usize(tri.a) + 1, usize(tri.b) + 1, usize(tri.c) + 1);
+        writeln!(out, "f {}/{} {}/{} {}/{}", a, 3 * i + 1, b, 3 * i + 2, c, 3 * i + 3)
+            .map_err(&error)?;
+    }
+    out.flush().map_err(&error)
+}
+
+fn rasterize(uvs: &[[Vector2<f32>; 3]], width: u32, height: u32) -> Frame<Texel> {
+    let mut texels = Frame::new(width, height, None);
+    let (w, h) = (f32(width), f32(height));
+    let mut covered_any = false;
+    // Texel coordinates, with y pointing down like in `Texture::sample`.
+    let texel = |uv: Vector2<f32>| vec2(uv.x * w, (1.0 - uv.y) * h);
+    for (i, uvs) in uvs.iter().enumerate() {
+        let tri_id = index(i);
+        let (a, b, c) = (texel(uvs[0]), texel(uvs[1]), texel(uvs[2]));
+        let area = cross(b - a, c - a);
+        if area == 0.0 {
+            continue;
+        }
+        let min = vec2(a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y));
+        let max = vec2(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y));
+        let x0 = min.x.floor().max(0.0).min(w) as u32;
+        let x1 = max.x.ceil().max(0.0).min(w) as u32;
+        let y0 = min.y.floor().max(0.0).min(h) as u32;
+        let y1 = max.y.ceil().max(0.0).min(h) as u32;
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let p = vec2(f32(x) + 0.5, f32(y) + 0.5);
+                let v = cross(p - a, c - a) / area;
+                let w = cross(b - a, p - a) / area;
+                let u = 1.0 - v - w;
+                if u >= 0.0 && v >= 0.0 && w >= 0.0 {
+                    texels.set(x, y, Some((tri_id, u, v, w)));
+                    covered_any = true;
+                }
+            }
+        }
+    }
+    if !covered_any {
+        fail("the mesh has no texture coordinates to bake into");
+    }
+    texels
+}
+
+fn ambient_occlusion(scene: &Scene,
+                     cfg: &Config,
+                     bake: &Bake,
+                     texels: &Frame<Texel>)
+                     -> Frame<Option<Rgb>> {
+    let mut ao = Frame::new(texels.bounds().w, texels.bounds().h, None);
+    let max_distance = bake.distance.unwrap_or(f32::INFINITY);
+    ao.set_pixels(texels.bounds(), |x, y| {
+        texels.get(x, y).map(|(tri_id, u, v, w)| {
+            let (a, b, c) = scene.mesh.corners(tri_id);
+            let n = scene.mesh.normal(tri_id);
+            let origin = offset_origin(a * u + b * v + c * w, n, cfg.ray_offset);
+            let mut rng = Rng::for_pixel(x, y);
+            let unoccluded = (0..bake.samples)
+                .filter(|_| {
+                    let d = to_world(cosine_hemisphere(rng.next_f32(), rng.next_f32()), n);
+                    !scene.occluded(&secondary_ray(cfg, origin, d), max_distance)
+                })
+                .count();
+            Rgb::grey(f32(u32(unoccluded).unwrap()) / f32(bake.samples))
+        })
+    });
+    ao
+}
+
+/// The light arriving at each texel, divided by pi so that it's the radiance that a white
+/// diffuse surface would reflect there.
+fn lightmap(scene: &Scene,
+            cfg: &Config,
+            bake: &Bake,
+            texels: &Frame<Texel>)
+            -> Frame<Option<Rgb>> {
+    let mut light = Frame::new(texels.bounds().w, texels.bounds().h, None);
+    light.set_pixels(texels.bounds(), |x, y| {
+        let (tri_id, u, v, w) = texels.get(x, y)?;
+        let (a, b, c) = scene.mesh.corners(tri_id);
+        let (p, n) = (a * u + b * v + c * w, scene.mesh.normal(tri_id));
+        let mut rng = Rng::for_pixel(x, y);
+        let mut sum = Rgb::black();
+        for _ in 0..bake.samples {
+            sum += irradiance(scene, cfg, p, n, &mut rng);
+        }
+        Some(sum / (f32(bake.samples) * PI))
+    });
+    light
+}
+
+/// The unit tangent and bitangent of a triangle, i.e., the directions in which the texture
+/// coordinates u and v grow, made orthogonal to the unit normal `n`.
+fn tangent_frame(scene: &Scene, tri_id: Index, n: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let (a, b, c) = scene.mesh.corners(tri_id);
+    let uvs = scene.tri_uvs(tri_id);
+    let (e1, e2) = (b - a, c - a);
+    let (duv1, duv2) = (uvs[1] - uvs[0], uvs[2] - uvs[0]);
+    // Triangles without UV area are never rasterized, so this is finite.
+    let r = 1.0 / cross(duv1, duv2);
+    let tangent = (e1 * duv2.y - e2 * duv1.y) * r;
+    let bitangent = (e2 * duv1.x - e1 * duv2.x) * r;
+    let tangent = (tangent - n * n.dot(tangent)).normalize();
+    let sign = if n.cross(tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+    (tangent, n.cross(tangent) * sign)
+}
+
+/// Find the surface of `source` closest to each texel along the normal of `target`, no
+/// farther than `cage`, and record its normal in the tangent space of `target` (encoded as
+/// 0.5 * n + 0.5, with +v as the green axis) or its signed distance (mapped from -cage..cage to
+/// 0..1).
+fn transfer(target: &Scene,
+            source: &Scene,
+            map: BakeMap,
+            cage: f32,
+            texels: &Frame<Texel>)
+            -> Frame<Option<Rgb>> {
+    let mut values = Frame::new(texels.bounds().w, texels.bounds().h, None);
+    values.set_pixels(texels.bounds(), |x, y| {
+        let (tri_id, u, v, w) = texels.get(x, y)?;
+        let (a, b, c) = target.mesh.corners(tri_id);
+        let n = target.mesh.normal(tri_id);
+        let p = a * u + b * v + c * w;
+        let mut best: Option<(f32, Vector3<f32>)> = None;
+        for &dir in &[n, -n] {
+            let r = Ray { t_max: cage, ..Ray::new(p, dir) };
+            let hit = source.intersect(&r);
+            if hit.is_valid() && best.map_or(true, |(dist, _)| hit.t < dist.abs()) {
+                let dist = if dir == n { hit.t } else { -hit.t };
+                best = Some((dist, source.normal(&r, &hit)));
+            }
+        }
+        let (dist, source_n) = best?;
+        Some(match map {
+                 BakeMap::Normal => {
+                     // The winding of the source mesh may not match, the side facing the same
+                     // way as the target surface is the one that matters.
+                     let source_n = if source_n.dot(n) < 0.0 { -source_n } else { source_n };
+                     let (tangent, bitangent) = tangent_frame(target, tri_id, n);
+                     let encode = |x: f32| 0.5 * x + 0.5;
+                     Rgb::new(encode(source_n.dot(tangent)),
+                              encode(source_n.dot(bitangent)),
+                              encode(source_n.dot(n)))
+                 }
+                 _ => Rgb::grey(0.5 + 0.5 * dist / cage),
+             })
+    });
+    values
+}
+
+/// Fill uncovered texels with the average of their covered neighbors, `rounds` times.
+fn dilate(mut values: Frame<Option<Rgb>>, rounds: u32) -> Frame<Option<Rgb>> {
+    let bounds = values.bounds();
+    for _ in 0..rounds {
+        let mut next = Frame::new(bounds.w, bounds.h, None);
+        next.set_pixels(bounds, |x, y| {
+            if let Some(value) = values.get(x, y) {
+                return Some(value);
+            }
+            let (mut sum, mut count) = (Rgb::black(), 0u32);
+            for ny in y.saturating_sub(1)..(y + 2).min(bounds.h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(bounds.w) {
+                    if let Some(value) = values.get(nx, ny) {
+                        sum += value;
+                        count += 1;
+                    }
+                }
+            }
+            if count > 0 { Some(sum / f32(count)) } else { None }
+        });
+        values = next;
+    }
+    values
+}
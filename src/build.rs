@@ -0,0 +1,389 @@
+//! Parallel construction of BVHs in beevage's tree format.
+//! Once a node is split, its subtrees are built in parallel. Large nodes are also binned in
+//! parallel, in chunks whose bins are merged afterwards. Merging only takes unions of boxes and
+//! sums of counts, so the resulting tree doesn't depend on the number of threads.
+//! Besides binned SAH, there are two simple builders as baselines for judging tree quality.
+
+use beebox::Aabb;
+use beevage::{Axis, Node, Primitive};
+use bvh::surface_area;
+use cast::{f32, usize};
+use cgmath::{Vector3, vec3};
+use rayon;
+use rayon::prelude::*;
+use std::{f32, mem};
+
+/// Nodes with fewer primitives than this are handled sequentially, parallelism doesn't pay for
+/// its overhead there.
+const PARALLEL_THRESHOLD: usize = 4096;
+/// Number of primitives binned by one task in large nodes.
+const BINNING_CHUNK: usize = 1024;
+
+/// How to decide where to split nodes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Builder {
+    /// Minimize the surface area heuristic over a number of candidate splits.
+    Sah,
+    /// Split the centroids in two halves of equal size along the longest axis.
+    Median,
+    /// Split the centroid bounds in the middle of the longest axis.
+    Middle,
+}
+
+pub struct Settings {
+    pub builder: Builder,
+    /// Number of buckets for the SAH.
+    pub bucket_count: usize,
+    /// Cost of a traversal step relative to a triangle test, for the SAH.
+    pub traversal_cost: f32,
+    /// The median and middle builders split until nodes have at most this many primitives.
+    pub max_leaf_size: usize,
+    pub max_depth: usize,
+}
+
+/// The result of a build. `order` is the index of the primitive at each position of the
+/// primitive ranges in the leaves.
+pub struct Built {
+    pub root: Node,
+    pub order: Vec<usize>,
+}
+
+struct PrimRef {
+    index: usize,
+    bb: Aabb,
+    centroid: Vector3<f32>,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bb: Aabb,
+    count: usize,
+}
+
+/// A plane perpendicular to one of the axes, between two of the bins.
+struct Split {
+    axis: usize,
+    centroid_min: f32,
+    /// Converts distances from `centroid_min` to bin indices.
+    scale: f32,
+    /// The first bin on the right side.
+    bin: usize,
+}
+
+pub fn build<P: Primitive + Sync>(settings: &Settings, primitives: &[P]) -> Built {
+    let mut refs: Vec<PrimRef> = primitives.par_iter()
+        .enumerate()
+        .map(|(index, p)| {
+                 let bb = p.bounding_box();
+                 PrimRef {
+                     index,
+                     bb,
+                     centroid: (bb.min() + bb.max()) / 2.0,
+                 }
+             })
+        .collect();
+    let root = build_node(settings, &mut refs, 0, 0);
+    Built {
+        root,
+        order: refs.into_iter().map(|r| r.index).collect(),
+    }
+}
+
+/// Build the subtree for `refs`, which start at position `offset` of the final order.
+fn build_node(settings: &Settings, refs: &mut [PrimRef], offset: usize, depth: usize) -> Node {
+    let bb = bounds(refs);
+    let leaf = Node::Leaf {
+        bb,
+        primitive_range: offset..offset + refs.len(),
+    };
+    if refs.len() <= 1 || depth + 1 >= settings.max_depth {
+        return leaf;
+    }
+    let split = match settings.builder {
+        Builder::Sah => split_sah(settings, refs, &bb),
+        Builder::Median if refs.len() > settings.max_leaf_size => Some(split_median(refs)),
+        Builder::Middle if refs.len() > settings.max_leaf_size => Some(split_middle(refs)),
+        Builder::Median | Builder::Middle => None,
+    };
+    let (axis, mid) = match split {
+        Some(split) => split,
+        None => return leaf,
+    };
+    let parallel = refs.len() >= PARALLEL_THRESHOLD;
+    let (left, right) = refs.split_at_mut(mid);
+    let (left, right) = if parallel {
+        rayon::join(|| build_node(settings, left, offset, depth + 1),
+                    || build_node(settings, right, offset + mid, depth + 1))
+    } else {
+        (build_node(settings, left, offset, depth + 1),
+         build_node(settings, right, offset + mid, depth + 1))
+    };
+    let axis = match axis {
+        0 => Axis::X,
+        1 => Axis::Y,
+        _ => Axis::Z,
+    };
+    Node::Inner {
+        bb,
+        children: Box::new((left, right)),
+        axis,
+    }
+}
+
+/// The split functions reorder `refs` so that the left child's primitives come first, and
+/// return the axis and the number of primitives on the left.
+type SplitResult = (usize, usize);
+
+/// Split according to the SAH, or return `None` if a leaf is cheaper.
+fn split_sah(settings: &Settings, refs: &mut [PrimRef], bb: &Aabb) -> Option<SplitResult> {
+    find_sah_split(settings, refs, bb).map(|split| {
+        (split.axis, partition(refs, |r| split.bin_of(r.centroid) < split.bin))
+    })
+}
+
+fn split_median(refs: &mut [PrimRef]) -> SplitResult {
+    let axis = longest_axis(refs);
+    // A stable sort, so ties don't depend on how the primitives were ordered.
+    refs.sort_by(|a, b| a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap());
+    (axis, refs.len() / 2)
+}
+
+fn split_middle(refs: &mut [PrimRef]) -> SplitResult {
+    let axis = longest_axis(refs);
+    let (min, max) = centroid_bounds(refs);
+    let middle = (min[axis] + max[axis]) / 2.0;
+    let mid = partition(refs, |r| r.centroid[axis] < middle);
+    if mid == 0 || mid == refs.len() {
+        // All centroids are in the same place along this axis.
+        split_median(refs)
+    } else {
+        (axis, mid)
+    }
+}
+
+fn longest_axis(refs: &[PrimRef]) -> usize {
+    let (min, max) = centroid_bounds(refs);
+    let extent = max - min;
+    if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    }
+}
+
+/// The cheapest split according to the SAH, or `None` if a leaf is cheaper.
+fn find_sah_split(settings: &Settings, refs: &[PrimRef], bb: &Aabb) -> Option<Split> {
+    let (centroid_min, centroid_max) = centroid_bounds(refs);
+    let bucket_count = settings.bucket_count;
+    let extent = centroid_max - centroid_min;
+    let splits: Vec<Split> = (0..3)
+        .filter(|&axis| extent[axis] > 0.0)
+        .map(|axis| {
+                 Split {
+                     axis,
+                     centroid_min: centroid_min[axis],
+                     // Shrink a little so the maximum centroid doesn't land in an extra bin.
+                     scale: f32(bucket_count) * (1.0 - 1e-6) / extent[axis],
+                     bin: 0,
+                 }
+             })
+        .collect();
+    if splits.is_empty() {
+        // All centroids coincide, so there's no way to tell the primitives apart.
+        return None;
+    }
+    let bin_all = |refs: &[PrimRef]| {
+        let empty = Bin {
+            bb: Aabb::empty(),
+            count: 0,
+        };
+        let mut bins = vec![vec![empty; bucket_count]; splits.len()];
+        for r in refs {
+            for (split, bins) in splits.iter().zip(&mut bins) {
+                let bin = &mut bins[split.bin_of(r.centroid)];
+                bin.bb = bin.bb.union(r.bb);
+                bin.count += 1;
+            }
+        }
+        bins
+    };
+    let bins = if refs.len() >= PARALLEL_THRESHOLD {
+        refs.par_chunks(BINNING_CHUNK).map(bin_all).reduce_with(merge_bins).unwrap()
+    } else {
+        bin_all(refs)
+    };
+
+    let leaf_cost = f32(refs.len());
+    let area = surface_area(bb);
+    let mut best: Option<(f32, Split)> = None;
+    for (split, bins) in splits.into_iter().zip(bins) {
+        // Sweep from the right to get the right side of every split, then from the left.
+        let mut right = Vec::with_capacity(bucket_count);
+        let (mut right_bb, mut right_count) = (Aabb::empty(), 0);
+        for bin in bins.iter().rev() {
+            right_bb = right_bb.union(bin.bb);
+            right_count += bin.count;
+            right.push((right_bb, right_count));
+        }
+        right.reverse();
+        let (mut left_bb, mut left_count) = (Aabb::empty(), 0);
+        for i in 1..bucket_count {
+            left_bb = left_bb.union(bins[i - 1].bb);
+            left_count += bins[i - 1].count;
+            let (right_bb, right_count) = right[i];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = settings.traversal_cost +
+                       (surface_area(&left_bb) * f32(left_count) +
+                        surface_area(&right_bb) * f32(right_count)) / area;
+            if best.as_ref().map_or(true, |&(best_cost, _)| cost < best_cost) {
+                best = Some((cost, Split { bin: i, ..split }));
+            }
+        }
+    }
+    best.and_then(|(cost, split)| if cost < leaf_cost { Some(split) } else { None })
+}
+
+impl Split {
+    fn bin_of(&self, centroid: Vector3<f32>) -> usize {
+        usize((centroid[self.axis] - self.centroid_min) * self.scale).unwrap_or(0)
+    }
+}
+
+fn merge_bins(mut a: Vec<Vec<Bin>>, b: Vec<Vec<Bin>>) -> Vec<Vec<Bin>> {
+    for (a, b) in a.iter_mut().zip(b) {
+        for (a, b) in a.iter_mut().zip(b) {
+            a.bb = a.bb.union(b.bb);
+            a.count += b.count;
+        }
+    }
+    a
+}
+
+fn bounds(refs: &[PrimRef]) -> Aabb {
+    if refs.len() >= PARALLEL_THRESHOLD {
+        refs.par_iter().map(|r| r.bb).reduce(Aabb::empty, |a, b| a.union(b))
+    } else {
+        refs.iter().fold(Aabb::empty(), |a, r| a.union(r.bb))
+    }
+}
+
+fn centroid_bounds(refs: &[PrimRef]) -> (Vector3<f32>, Vector3<f32>) {
+    let mut min = vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+    let mut max = -min;
+    for r in refs {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(r.centroid[axis]);
+            max[axis] = max[axis].max(r.centroid[axis]);
+        }
+    }
+    (min, max)
+}
+
+/// Move the elements for which `is_left` is true to the front and return how many there are.
+fn partition<F>(refs: &mut [PrimRef], is_left: F) -> usize
+    where F: Fn(&PrimRef) -> bool
+{
+    let mut mid = 0;
+    for i in 0..refs.len() {
+        if is_left(&refs[i]) {
+            refs.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+pub fn node_bbox(node: &Node) -> Aabb {
+    match *node {
+        Node::Leaf { bb, .. } |
+        Node::Inner { bb, .. } => bb,
+    }
+}
+
+/// The expected cost of tracing a random ray through the tree, as in `Bvh::sah_cost`.
+pub fn sah_cost(root: &Node, traversal_cost: f32) -> f32 {
+    fn weighted_cost(node: &Node, traversal_cost: f32) -> f32 {
+        match *node {
+            Node::Leaf { ref bb, ref primitive_range } => {
+                surface_area(bb) * f32(primitive_range.len())
+            }
+            Node::Inner { ref bb, ref children, .. } => {
+                surface_area(bb) * traversal_cost + weighted_cost(&children.0, traversal_cost) +
+                weighted_cost(&children.1, traversal_cost)
+            }
+        }
+    }
+    weighted_cost(root, traversal_cost) / surface_area(&node_bbox(root))
+}
+
+/// Upper limit on the number of passes over the tree in `optimize`.
+const OPTIMIZE_PASSES: usize = 16;
+
+/// Improve the tree with rotations (Kensler 2008): swapping a child with one of the children
+/// of its sibling only changes the box of the sibling, so the SAH cost goes down whenever that
+/// box shrinks. Makes bottom-up passes over the tree until no rotation helps anymore, and
+/// returns the number of rotations.
+pub fn optimize(root: &mut Node) -> usize {
+    let mut total = 0;
+    for _ in 0..OPTIMIZE_PASSES {
+        let rotations = rotate_subtree(root);
+        total += rotations;
+        if rotations == 0 {
+            break;
+        }
+    }
+    total
+}
+
+fn rotate_subtree(node: &mut Node) -> usize {
+    match *node {
+        Node::Leaf { .. } => 0,
+        Node::Inner { ref mut children, .. } => {
+            let rotations = rotate_subtree(&mut children.0) + rotate_subtree(&mut children.1);
+            let (ref mut left, ref mut right) = **children;
+            // Try both directions and apply the better one, if any.
+            let into_right = best_rotation(left, right);
+            let into_left = best_rotation(right, left);
+            match (into_right, into_left) {
+                (Some((gain_r, i)), Some((gain_l, _))) if gain_r >= gain_l => {
+                    rotate(left, right, i)
+                }
+                (_, Some((_, i))) => rotate(right, left, i),
+                (Some((_, i)), None) => rotate(left, right, i),
+                (None, None) => return rotations,
+            }
+            rotations + 1
+        }
+    }
+}
+
+/// The best way to swap `node` with a child of `sibling`: which child, and by how much the
+/// surface area of `sibling` shrinks.
+fn best_rotation(node: &Node, sibling: &Node) -> Option<(f32, usize)> {
+    let (bb, grandchildren) = match *sibling {
+        Node::Inner { ref bb, ref children, .. } => (bb, children),
+        Node::Leaf { .. } => return None,
+    };
+    let area = surface_area(bb);
+    let node_bb = node_bbox(node);
+    // Swapping with the first grandchild leaves the second one in the sibling, and vice versa.
+    let gains = [area - surface_area(&node_bb.union(node_bbox(&grandchildren.1))),
+                 area - surface_area(&node_bb.union(node_bbox(&grandchildren.0)))];
+    let i = if gains[0] >= gains[1] { 0 } else { 1 };
+    if gains[i] > 0.0 { Some((gains[i], i)) } else { None }
+}
+
+fn rotate(node: &mut Node, sibling: &mut Node, grandchild: usize) {
+    // The split axis of the sibling is only a hint for the traversal order, so it's kept.
+    if let Node::Inner { ref mut bb, ref mut children, .. } = *sibling {
+        {
+            let (ref mut first, ref mut second) = **children;
+            mem::swap(node, if grandchild == 0 { first } else { second });
+        }
+        *bb = node_bbox(&children.0).union(node_bbox(&children.1));
+    }
+}
@@ -1,56 +1,269 @@
 use super::{Config, print_timing};
-use arrayvec::ArrayVec;
+use arrayvec::{Array, ArrayVec};
 use beebox::{self, Aabb};
 use beevage::{self, Axis};
-use cast::{u32, usize};
-use geom::{Hit, Ray, Tri, TriSliceExt};
+use build;
+use cast::{f64, u32, usize};
+use cgmath::{InnerSpace, Vector3};
+use geom::{Frustum, Hit, HitFilter, Index, Precision, Ray, Tri, TriBounds, TriMesh, accept_hit,
+           index};
 use rayon::prelude::*;
-use std::u32;
+use std::{f32, mem};
+use std::ops::Range;
 use watertri;
 
 pub struct Bvh {
     nodes: Box<[CompactNode]>,
+    /// The same tree in the compressed layout, which traversal uses instead if it's present.
+    /// The full precision nodes are still needed for refitting and statistics.
+    compressed: Option<Box<[QuantizedNode]>>,
+    /// The parent of each node (the root is its own parent), for `traverse_stackless`.
+    parents: Box<[NodeId]>,
+    /// The number of levels below the root of the deepest leaf.
+    max_depth: usize,
 }
 
-const LEAF_OR_NODE_MASK: u32 = 1 << 31;
+/// How the nodes are laid out in memory for traversal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BvhLayout {
+    /// Full precision boxes, 32 bytes per node.
+    Full,
+    /// Boxes quantized to 8 bits per coordinate relative to the parent's box, 16 bytes per
+    /// node. The boxes are rounded outwards, so they're a bit larger than the full ones.
+    Compressed,
+}
+
+/// How primary rays are traced through the BVH.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Traversal {
+    /// Every ray on its own.
+    Single,
+    /// Coherent rays in packets of up to `PACKET_SIZE`, see `traverse_packet`.
+    Packet,
+    /// Every ray on its own, but only through the nodes that the frustum of its tile can see,
+    /// see `frustum_entries`.
+    Frustum,
+    /// Every ray on its own, without a traversal stack, see `traverse_stackless`.
+    Stackless,
+}
+
+/// Which counter of `TraversalStats` a heatmap shows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HeatCounter {
+    Boxes,
+    Nodes,
+    Leaves,
+    Tris,
+}
+
+/// Gets told what happens while a ray traverses the BVH, to collect statistics about it.
+/// All methods do nothing by default, so that traversing with `NoStats` costs nothing extra.
+pub trait StatsRecorder {
+    /// A node was taken off the traversal stack and its box tested against the ray.
+    fn box_tested(&mut self) {}
+    /// The ray hit the box of the node with index `_node`, so its children or triangles are
+    /// visited next.
+    fn node_visited(&mut self, _node: usize) {}
+    /// The ray reached a leaf and is about to be tested against its `tris` triangles.
+    fn leaf_visited(&mut self, _tris: usize) {}
+    /// Stackless traversal went up from a node to its parent to find the next node.
+    fn backtracked(&mut self) {}
+}
+
+/// Records nothing, for when only the hits matter.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoStats;
+
+impl StatsRecorder for NoStats {}
+
+/// Counts everything `StatsRecorder` gets told about.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TraversalStats {
+    pub boxes_tested: u32,
+    pub nodes_visited: u32,
+    pub leaves_visited: u32,
+    pub tris_tested: u32,
+    pub backtrack_steps: u32,
+}
+
+impl StatsRecorder for TraversalStats {
+    fn box_tested(&mut self) {
+        self.boxes_tested += 1;
+    }
+
+    fn node_visited(&mut self, _node: usize) {
+        self.nodes_visited += 1;
+    }
+
+    fn leaf_visited(&mut self, tris: usize) {
+        self.leaves_visited += 1;
+        self.tris_tested += u32(tris).unwrap();
+    }
+
+    fn backtracked(&mut self) {
+        self.backtrack_steps += 1;
+    }
+}
+
+impl TraversalStats {
+    pub fn get(&self, counter: HeatCounter) -> u32 {
+        match counter {
+            HeatCounter::Boxes => self.boxes_tested,
+            HeatCounter::Nodes => self.nodes_visited,
+            HeatCounter::Leaves => self.leaves_visited,
+            HeatCounter::Tris => self.tris_tested,
+        }
+    }
+}
+
+const LEAF_OR_NODE_MASK: Index = !(Index::max_value() >> 1);
+
+/// Summary of the shape of a BVH, for judging its quality.
+pub struct BvhStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub max_depth: usize,
+    pub min_leaf_size: Index,
+    pub max_leaf_size: Index,
+    /// Number of triangles in all leaves together.
+    pub leaf_tris: Index,
+}
 
 struct CompactNode {
     bb: Aabb,
     /// In leaf nodes, the (absolute) offset of the primitives.
     /// In interior nodes, the (absolute) offset of the second child.
-    offset: u32,
+    offset: Index,
     /// The MSB of this field indicates whether it's a leaf (0) or an interior node (1).
-    /// In leaf nodes, it also contains the number of triangles (< 2^31).
+    /// In leaf nodes, it also contains the number of triangles (at most `MAX_TRIS`).
     /// In interior nodes, the lower bits of this field store the axis.
-    payload: u32,
+    payload: Index,
+}
+
+/// Node of the compressed layout. `offset` and `payload` are the same as in `CompactNode`.
+/// Each coordinate of the box is stored as q in 0..255, which stands for the point q / 255 of
+/// the way from the minimum to the maximum of the parent's (decoded) box along that axis.
+struct QuantizedNode {
+    lo: [u8; 3],
+    hi: [u8; 3],
+    offset: Index,
+    payload: Index,
 }
 
 /// Unpacked representation of a node.
 /// Only used as a temporary, not stored in BVH.
 /// The AABB is omitted since its representation is the same for leaves and interior nodes.
 enum UnpackedNode {
-    Leaf { start: u32, end: u32 },
+    Leaf { start: Index, end: Index },
     Interior { second_child: NodeId, axis: u8 },
 }
 
 impl CompactNode {
     fn unpack(&self) -> UnpackedNode {
-        if self.payload & LEAF_OR_NODE_MASK == 0 {
-            UnpackedNode::Leaf {
-                start: self.offset,
-                end: self.offset + self.payload,
-            }
-        } else {
-            UnpackedNode::Interior {
-                second_child: NodeId(self.offset),
-                axis: self.payload as u8,
-            }
+        unpack(self.offset, self.payload)
+    }
+}
+
+fn unpack(offset: Index, payload: Index) -> UnpackedNode {
+    if payload & LEAF_OR_NODE_MASK == 0 {
+        UnpackedNode::Leaf {
+            start: offset,
+            end: offset + payload,
+        }
+    } else {
+        UnpackedNode::Interior {
+            second_child: NodeId(offset),
+            axis: payload as u8,
+        }
+    }
+}
+
+impl QuantizedNode {
+    fn bbox(&self, parent: &Aabb) -> Aabb {
+        let (min, max) = (parent.min(), parent.max());
+        let mut lo = min;
+        let mut hi = max;
+        for axis in 0..3 {
+            lo[axis] = dequantize(self.lo[axis], min[axis], max[axis]);
+            hi[axis] = dequantize(self.hi[axis], min[axis], max[axis]);
         }
+        Aabb::new([lo, hi].iter().cloned())
+    }
+}
+
+fn dequantize(q: u8, min: f32, max: f32) -> f32 {
+    // Written so that 0 and 255 give exactly `min` and `max`.
+    let s = f32::from(q) / 255.0;
+    min * (1.0 - s) + max * s
+}
+
+/// The smallest range of quantized values whose decoded range contains `lo..hi`, which must be
+/// inside `min..max`.
+fn quantize(lo: f32, hi: f32, min: f32, max: f32) -> (u8, u8) {
+    let scale = if max > min { 255.0 / (max - min) } else { 0.0 };
+    let guess = |x: f32| ((x - min) * scale).max(0.0).min(255.0);
+    // The guesses can be off by one due to rounding, so fix them up with the actual decoding.
+    let mut q_lo = guess(lo).floor() as u8;
+    while q_lo > 0 && dequantize(q_lo, min, max) > lo {
+        q_lo -= 1;
+    }
+    let mut q_hi = guess(hi).ceil() as u8;
+    while q_hi < 255 && dequantize(q_hi, min, max) < hi {
+        q_hi += 1;
+    }
+    (q_lo, q_hi)
+}
+
+/// Read access to the nodes of one of the layouts, so the traversal loops can be shared.
+trait NodeLayout {
+    /// What's needed to decode the box of a node besides the node itself.
+    type Parent: Copy;
+    fn root(&self) -> Self::Parent;
+    /// The box and contents of the node, and what its children need to decode their boxes.
+    fn node(&self, id: NodeId, parent: Self::Parent) -> (Aabb, UnpackedNode, Self::Parent);
+    /// What the children of a node whose decoded box is `bb` need to decode their boxes.
+    /// For the root, `bb` is the full precision box of the root.
+    fn parent_from_box(&self, bb: Aabb) -> Self::Parent;
+}
+
+impl NodeLayout for [CompactNode] {
+    type Parent = ();
+
+    fn root(&self) {}
+
+    fn parent_from_box(&self, _: Aabb) {}
+
+    fn node(&self, id: NodeId, _: ()) -> (Aabb, UnpackedNode, ()) {
+        let node = &self[id.to_index()];
+        (node.bb, node.unpack(), ())
+    }
+}
+
+struct CompressedNodes<'a> {
+    root_bb: Aabb,
+    nodes: &'a [QuantizedNode],
+}
+
+impl<'a> NodeLayout for CompressedNodes<'a> {
+    type Parent = Aabb;
+
+    fn root(&self) -> Aabb {
+        self.root_bb
+    }
+
+    fn parent_from_box(&self, bb: Aabb) -> Aabb {
+        bb
+    }
+
+    fn node(&self, id: NodeId, parent: Aabb) -> (Aabb, UnpackedNode, Aabb) {
+        let node = &self.nodes[id.to_index()];
+        let bb = node.bbox(&parent);
+        (bb, unpack(node.offset, node.payload), bb)
     }
 }
 
 #[derive(Copy,Clone,Debug,PartialEq,Eq)]
-struct NodeId(u32);
+struct NodeId(Index);
 
 impl NodeId {
     fn to_index(&self) -> usize {
@@ -63,26 +276,183 @@ impl NodeId {
 }
 
 impl Bvh {
+    /// Recompute all bounding boxes bottom-up for triangles that moved since the BVH was built.
+    /// The tree topology is kept, so the tree quality degrades as the geometry moves further.
+    pub fn refit(&mut self, mesh: &TriMesh) {
+        // Children always come after their parent, so a reverse sweep visits children first.
+        for i in (0..self.nodes.len()).rev() {
+            let bb = match self.nodes[i].unpack() {
+                UnpackedNode::Leaf { start, end } => mesh.range_bbox(start, end),
+                UnpackedNode::Interior { second_child, .. } => {
+                    let left = &self.nodes[NodeId(index(i)).left_child().to_index()];
+                    let right = &self.nodes[second_child.to_index()];
+                    left.bb.union(right.bb)
+                }
+            };
+            self.nodes[i].bb = bb;
+        }
+        if self.compressed.is_some() {
+            self.set_layout(BvhLayout::Compressed);
+        }
+    }
+
+    pub fn set_layout(&mut self, layout: BvhLayout) {
+        self.compressed = match layout {
+            BvhLayout::Full => None,
+            BvhLayout::Compressed => Some(self.compress()),
+        };
+    }
+
+    /// Quantize all boxes top-down, each relative to the decoded box of its parent (rather
+    /// than the exact one) so that rounding errors can't accumulate.
+    fn compress(&self) -> Box<[QuantizedNode]> {
+        let mut compressed: Vec<_> = self.nodes
+            .iter()
+            .map(|node| {
+                     QuantizedNode {
+                         lo: [0; 3],
+                         hi: [255; 3],
+                         offset: node.offset,
+                         payload: node.payload,
+                     }
+                 })
+            .collect();
+        let mut todo = vec![(NodeId(0), self.nodes[0].bb)];
+        while let Some((id, parent)) = todo.pop() {
+            let node = &self.nodes[id.to_index()];
+            let (bb, q) = (node.bb, &mut compressed[id.to_index()]);
+            for axis in 0..3 {
+                let (lo, hi) = quantize(bb.min()[axis],
+                                        bb.max()[axis],
+                                        parent.min()[axis],
+                                        parent.max()[axis]);
+                q.lo[axis] = lo;
+                q.hi[axis] = hi;
+            }
+            if let UnpackedNode::Interior { second_child, .. } = node.unpack() {
+                let decoded = q.bbox(&parent);
+                todo.push((id.left_child(), decoded));
+                todo.push((second_child, decoded));
+            }
+        }
+        compressed.into_boxed_slice()
+    }
+
+    fn compressed_nodes<'a>(&'a self) -> Option<CompressedNodes<'a>> {
+        self.compressed.as_ref().map(|nodes| {
+                                         CompressedNodes {
+                                             root_bb: self.nodes[0].bb,
+                                             nodes,
+                                         }
+                                     })
+    }
+
+    /// Bytes used by the nodes, in both layouts if the compressed one is used, and their parent
+    /// links.
+    pub fn memory_usage(&self) -> usize {
+        self.nodes.len() * mem::size_of::<CompactNode>() +
+        self.parents.len() * mem::size_of::<NodeId>() +
+        self.compressed.as_ref().map_or(0, |c| c.len() * mem::size_of::<QuantizedNode>())
+    }
+
+    /// The expected cost of tracing a random ray according to the surface area heuristic,
+    /// in units of triangle intersection tests.
+    pub fn sah_cost(&self, traversal_cost: f32) -> f32 {
+        let root_area = surface_area(&self.nodes[0].bb);
+        let mut cost = 0.0;
+        for node in self.nodes.iter() {
+            let node_cost = match node.unpack() {
+                UnpackedNode::Leaf { start, end } => (end - start) as f32,
+                UnpackedNode::Interior { .. } => traversal_cost,
+            };
+            cost += node_cost * surface_area(&node.bb) / root_area;
+        }
+        cost
+    }
+
+    /// The boxes of all nodes at most `max_depth` levels below the root.
+    pub fn boxes(&self, max_depth: usize) -> Vec<Aabb> {
+        let mut boxes = Vec::new();
+        let mut todo = vec![(NodeId(0), 0)];
+        while let Some((id, depth)) = todo.pop() {
+            let node = &self.nodes[id.to_index()];
+            boxes.push(node.bb);
+            if let UnpackedNode::Interior { second_child, .. } = node.unpack() {
+                if depth < max_depth {
+                    todo.push((id.left_child(), depth + 1));
+                    todo.push((second_child, depth + 1));
+                }
+            }
+        }
+        boxes
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            nodes: self.nodes.len(),
+            leaves: 0,
+            max_depth: 0,
+            min_leaf_size: Index::max_value(),
+            max_leaf_size: 0,
+            leaf_tris: 0,
+        };
+        let mut todo = vec![(NodeId(0), 0)];
+        while let Some((id, depth)) = todo.pop() {
+            stats.max_depth = stats.max_depth.max(depth);
+            match self.nodes[id.to_index()].unpack() {
+                UnpackedNode::Leaf { start, end } => {
+                    stats.leaves += 1;
+                    stats.min_leaf_size = stats.min_leaf_size.min(end - start);
+                    stats.max_leaf_size = stats.max_leaf_size.max(end - start);
+                    stats.leaf_tris += end - start;
+                }
+                UnpackedNode::Interior { second_child, .. } => {
+                    todo.push((id.left_child(), depth + 1));
+                    todo.push((second_child, depth + 1));
+                }
+            }
+        }
+        stats
+    }
+
     fn compactify(root: beevage::Node, node_count: usize) -> Bvh {
         let mut nodes = Vec::with_capacity(node_count);
         compactify(&mut nodes, root);
         assert_eq!(nodes.len(),
                    node_count,
                    "Builder reported wrong number of nodes");
-        Bvh { nodes: nodes.into_boxed_slice() }
+        // Children come after their parent, so their parent's depth is known when they're
+        // reached.
+        let mut parents = vec![NodeId(0); nodes.len()];
+        let mut depths = vec![0; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            if let UnpackedNode::Interior { second_child, .. } = node.unpack() {
+                let id = NodeId(index(i));
+                for &child in &[id.left_child(), second_child] {
+                    parents[child.to_index()] = id;
+                    depths[child.to_index()] = depths[i] + 1;
+                }
+            }
+        }
+        Bvh {
+            nodes: nodes.into_boxed_slice(),
+            compressed: None,
+            parents: parents.into_boxed_slice(),
+            max_depth: depths.into_iter().max().unwrap_or(0),
+        }
     }
 }
 
 fn compactify(nodes: &mut Vec<CompactNode>, node: beevage::Node) -> NodeId {
-    let id = NodeId(u32(nodes.len()).unwrap());
-    const INVALID_ID: u32 = u32::MAX;
+    let id = NodeId(index(nodes.len()));
+    const INVALID_ID: Index = !0;
     match node {
         beevage::Node::Leaf { bb, primitive_range } => {
-            let payload = u32(primitive_range.len()).unwrap();
+            let payload = index(primitive_range.len());
             assert!(payload & LEAF_OR_NODE_MASK == 0);
             nodes.push(CompactNode {
                            bb: bb,
-                           offset: u32(primitive_range.start).unwrap(),
+                           offset: index(primitive_range.start),
                            payload: payload,
                        });
         }
@@ -107,57 +477,917 @@ fn compactify(nodes: &mut Vec<CompactNode>, node: beevage::Node) -> NodeId {
     id
 }
 
+pub fn surface_area(bb: &Aabb) -> f32 {
+    let d = bb.max() - bb.min();
+    2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+}
+
+/// The squared distance from `p` to the closest point of `bb`, zero if `p` is inside.
+pub fn box_distance2(bb: &Aabb, p: Vector3<f32>) -> f32 {
+    let (min, max) = (bb.min(), bb.max());
+    let mut d = Vector3::new(0.0, 0.0, 0.0);
+    for axis in 0..3 {
+        d[axis] = (min[axis] - p[axis]).max(p[axis] - max[axis]).max(0.0);
+    }
+    d.magnitude2()
+}
+
+/// The builder keeps trees at most this deep, except that `--bvh-optimize` may make them deeper.
+/// Traversal stacks have room for this many nodes before they need to allocate.
 const MAX_DEPTH: usize = 64;
 
-pub fn construct(tris: &[Tri], cfg: &Config) -> (Bvh, Vec<Tri>) {
-    let msg = format!("building BVH for {} tris", tris.len());
+/// The stack of nodes still to be visited during traversal. It lives on the (call) stack
+/// unless the tree is deeper than `MAX_DEPTH`, in which case the rest spills onto the heap.
+struct TraversalStack<T>
+    where [T; MAX_DEPTH]: Array<Item = T>
+{
+    inline: ArrayVec<[T; MAX_DEPTH]>,
+    spilled: Vec<T>,
+}
+
+impl<T> TraversalStack<T>
+    where [T; MAX_DEPTH]: Array<Item = T>
+{
+    fn new() -> Self {
+        TraversalStack {
+            inline: ArrayVec::new(),
+            spilled: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, x: T) {
+        // Once the inline part is full, it can only shrink after the spilled part is empty.
+        if self.inline.len() < self.inline.capacity() {
+            self.inline.push(x);
+        } else {
+            self.spilled.push(x);
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        self.spilled.pop().or_else(|| self.inline.pop())
+    }
+}
+
+/// Build a BVH for the triangles of `mesh`. The triangles are reordered for the BVH, so this
+/// also returns the reordered triangles and the index in `mesh.tris` of each of them.
+pub fn construct(mesh: &TriMesh, cfg: &Config) -> (Bvh, Vec<Tri>, Vec<usize>) {
+    let msg = format!("building BVH for {} tris", mesh.tris.len());
     print_timing(&msg, move || {
-        let bb = tris.bbox();
-        let config = beevage::Config {
+        let bounds: Vec<TriBounds> = (0..mesh.tris.len())
+            .into_par_iter()
+            .map(|i| TriBounds(mesh.tri_bbox(index(i))))
+            .collect();
+        let settings = build::Settings {
+            builder: cfg.bvh_builder,
             bucket_count: usize(cfg.sah_buckets),
             traversal_cost: cfg.sah_traversal_cost,
+            max_leaf_size: usize(cfg.max_leaf_tris),
             max_depth: MAX_DEPTH,
         };
-        let beevage::Bvh { root, node_count, primitives } = beevage::binned_sah(config, tris, bb);
-        let bvh_tris = primitives.into_par_iter().map(|p| tris[p.index()].clone()).collect();
-        (Bvh::compactify(root, node_count), bvh_tris)
+        let build::Built { root, mut order } = build::build(&settings, &bounds);
+        let policy = LeafPolicy {
+            bounds: &bounds,
+            max_leaf_tris: usize(cfg.max_leaf_tris),
+            traversal_cost: cfg.sah_traversal_cost,
+        };
+        let mut reworked = policy.apply(root, 0, &mut order);
+        if cfg.bvh_optimize {
+            let cost_before = build::sah_cost(&reworked.node, cfg.sah_traversal_cost);
+            let rotations = build::optimize(&mut reworked.node);
+            println!("BVH optimization: {} rotations, SAH cost {:.2} -> {:.2}",
+                     rotations,
+                     cost_before,
+                     build::sah_cost(&reworked.node, cfg.sah_traversal_cost));
+        }
+        let bvh_tris = order.iter().map(|&i| mesh.tris[i]).collect();
+        let mut bvh = Bvh::compactify(reworked.node, reworked.node_count);
+        bvh.set_layout(cfg.bvh_layout);
+        (bvh, bvh_tris, order)
     })
 }
 
+/// Post-processing of the tree built by beevage, whose leaf sizes can't be configured:
+/// leaves with too many triangles are split at the median, and subtrees that are more
+/// expensive (by the SAH) than a single leaf with all of their triangles are collapsed.
+struct LeafPolicy<'a> {
+    bounds: &'a [TriBounds],
+    max_leaf_tris: usize,
+    traversal_cost: f32,
+}
+
+/// A subtree after applying the leaf policy.
+struct Reworked {
+    node: beevage::Node,
+    node_count: usize,
+    /// The SAH cost of the subtree, relative to its own surface area.
+    cost: f32,
+    /// Range of the subtree's triangles in the BVH order.
+    tris: Range<usize>,
+}
+
+impl<'a> LeafPolicy<'a> {
+    /// `order` maps positions in the BVH order to indices into `bounds`. Splitting leaves
+    /// reorders the triangles within them.
+    fn apply(&self, node: beevage::Node, depth: usize, order: &mut [usize]) -> Reworked {
+        match node {
+            beevage::Node::Leaf { bb, primitive_range } => {
+                // Deeper trees work, but traversal has to allocate for them.
+                if primitive_range.len() > self.max_leaf_tris && depth + 1 < MAX_DEPTH {
+                    let split = self.split_leaf(bb, primitive_range, order);
+                    self.apply(split, depth, order)
+                } else {
+                    Reworked {
+                        cost: primitive_range.len() as f32,
+                        tris: primitive_range.clone(),
+                        node: beevage::Node::Leaf { bb, primitive_range },
+                        node_count: 1,
+                    }
+                }
+            }
+            beevage::Node::Inner { bb, children, axis } => {
+                let children = *children; // Workaround for missing box pattern
+                let left = self.apply(children.0, depth + 1, order);
+                let right = self.apply(children.1, depth + 1, order);
+                assert_eq!(left.tris.end, right.tris.start);
+                let tris = left.tris.start..right.tris.end;
+                let cost = self.traversal_cost +
+                           (surface_area(&build::node_bbox(&left.node)) * left.cost +
+                            surface_area(&build::node_bbox(&right.node)) * right.cost) /
+                           surface_area(&bb);
+                if tris.len() <= self.max_leaf_tris && cost >= tris.len() as f32 {
+                    Reworked {
+                        node: beevage::Node::Leaf {
+                            bb,
+                            primitive_range: tris.clone(),
+                        },
+                        node_count: 1,
+                        cost: tris.len() as f32,
+                        tris,
+                    }
+                } else {
+                    Reworked {
+                        node: beevage::Node::Inner {
+                            bb,
+                            children: Box::new((left.node, right.node)),
+                            axis,
+                        },
+                        node_count: 1 + left.node_count + right.node_count,
+                        cost,
+                        tris,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Split a leaf in two halves along the longest axis of its box.
+    fn split_leaf(&self, bb: Aabb, range: Range<usize>, order: &mut [usize]) -> beevage::Node {
+        let extent = bb.max() - bb.min();
+        let (axis, axis_id) = if extent.x >= extent.y && extent.x >= extent.z {
+            (Axis::X, 0)
+        } else if extent.y >= extent.z {
+            (Axis::Y, 1)
+        } else {
+            (Axis::Z, 2)
+        };
+        let centroid = |i: usize| {
+            let tri_bb = &self.bounds[i].0;
+            tri_bb.min()[axis_id] + tri_bb.max()[axis_id]
+        };
+        order[range.clone()].sort_by(|&i, &j| centroid(i).partial_cmp(&centroid(j)).unwrap());
+        let mid = range.start + range.len() / 2;
+        let leaf = |range: Range<usize>| {
+            let mut bb = Aabb::empty();
+            for &i in &order[range.clone()] {
+                bb = bb.union(self.bounds[i].0);
+            }
+            beevage::Node::Leaf {
+                bb,
+                primitive_range: range,
+            }
+        };
+        beevage::Node::Inner {
+            bb,
+            children: Box::new((leaf(range.start..mid), leaf(mid..range.end))),
+            axis,
+        }
+    }
+}
+
+/// Find the closest intersection between `r.t_min` and `r.t_max` that passes `filter`, telling
+/// `stats` about the traversal.
+pub fn traverse<S>(mesh: &TriMesh,
+                   tree: &Bvh,
+                   r: &Ray,
+                   filter: Option<&HitFilter>,
+                   stats: &mut S)
+                   -> Hit
+    where S: StatsRecorder
+{
+    let root = [(NodeId(0), tree.nodes[0].bb)];
+    match tree.compressed_nodes() {
+        Some(nodes) => traverse_nodes(mesh, &nodes, &root, r, filter, stats),
+        None => traverse_nodes(mesh, &*tree.nodes, &root, r, filter, stats),
+    }
+}
+
+/// Nodes to start traversal from instead of the root, see `frustum_entries`.
+/// They're only valid for the BVH they were collected from, in the layout it had then.
+pub struct EntryNodes {
+    /// Each node with the decoded box of its parent (the root's own box for the root).
+    nodes: Vec<(NodeId, Aabb)>,
+}
+
+/// Find the nodes that rays inside of `frustum` may hit, to skip the upper levels of the tree
+/// and everything outside of the frustum when tracing those rays with `traverse_from`.
+/// Nodes that are entirely inside the frustum are entered as a whole, nodes that straddle
+/// its boundary are split further down to the leaves. The nodes are sorted by distance to the
+/// apex, so that primary rays visit them front to back.
+pub fn frustum_entries(tree: &Bvh, frustum: &Frustum) -> EntryNodes {
+    let root_bb = tree.nodes[0].bb;
+    match tree.compressed_nodes() {
+        Some(nodes) => frustum_entries_nodes(&nodes, root_bb, frustum),
+        None => frustum_entries_nodes(&*tree.nodes, root_bb, frustum),
+    }
+}
+
+fn frustum_entries_nodes<L>(nodes: &L, root_bb: Aabb, frustum: &Frustum) -> EntryNodes
+    where L: NodeLayout + ?Sized
+{
+    let mut entries = Vec::new();
+    let mut todo = TraversalStack::new();
+    todo.push((NodeId(0), nodes.root(), root_bb));
+    while let Some((id, parent, parent_bb)) = todo.pop() {
+        let (bb, node, child_parent) = nodes.node(id, parent);
+        if frustum.culls(&bb) {
+            continue;
+        }
+        match node {
+            UnpackedNode::Interior { second_child, .. } if !frustum.contains(&bb) => {
+                todo.push((second_child, child_parent, bb));
+                todo.push((id.left_child(), child_parent, bb));
+            }
+            _ => entries.push((box_distance2(&bb, frustum.apex), id, parent_bb)),
+        }
+    }
+    entries.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    EntryNodes { nodes: entries.into_iter().map(|(_, id, parent_bb)| (id, parent_bb)).collect() }
+}
+
+/// Like `traverse`, but visit only the subtrees below `entries` instead of the whole tree.
+/// The ray must be one of those that the entries were collected for.
+pub fn traverse_from<S>(mesh: &TriMesh,
+                        tree: &Bvh,
+                        entries: &EntryNodes,
+                        r: &Ray,
+                        filter: Option<&HitFilter>,
+                        stats: &mut S)
+                        -> Hit
+    where S: StatsRecorder
+{
+    match tree.compressed_nodes() {
+        Some(nodes) => traverse_nodes(mesh, &nodes, &entries.nodes, r, filter, stats),
+        None => traverse_nodes(mesh, &*tree.nodes, &entries.nodes, r, filter, stats),
+    }
+}
 
-pub fn traverse(tris: &[Tri], tree: &Bvh, r: &Ray) -> Hit {
+fn traverse_nodes<L, S>(mesh: &TriMesh,
+                        nodes: &L,
+                        entries: &[(NodeId, Aabb)],
+                        r: &Ray,
+                        filter: Option<&HitFilter>,
+                        stats: &mut S)
+                        -> Hit
+    where L: NodeLayout + ?Sized,
+          S: StatsRecorder
+{
     // TODO make layout breadth-first and use distance-based traversal
     //      (isect both children, go to nearer one)
-    // TODO then try this:
-    // > Stackless Multi-BVH Traversal for CPU, MIC and GPU Ray Tracing
-    // > Attila T. Áfra and László Szirmay-Kalos
-    // > Computer Graphics Forum (2013)
+    let mut t_max = r.t_max;
+    let r_tri = watertri::RayData::new(r.o, r.d);
+    let r_box = beebox::RayData::new(r.o, r.d);
+    let mut hit = Hit::none();
+
+    let mut todo = TraversalStack::new();
+    for &(entry, parent_bb) in entries {
+        todo.push((entry, nodes.parent_from_box(parent_bb)));
+        while let Some((id, parent)) = todo.pop() {
+            stats.box_tested();
+            let (bb, node, parent) = nodes.node(id, parent);
+            if !box_hit(mesh, &bb, r, &r_box, t_max) {
+                continue;
+            }
+            stats.node_visited(id.to_index());
+            match node {
+                UnpackedNode::Leaf { start, end } => {
+                    stats.leaf_visited(usize(end - start));
+                    mesh.intersect(start, end, r, &r_tri, &mut t_max, filter, &mut hit);
+                }
+                UnpackedNode::Interior { second_child, axis } => {
+                    if r.d[usize(axis)] < 0.0 {
+                        todo.push((id.left_child(), parent));
+                        todo.push((second_child, parent));
+                    } else {
+                        todo.push((second_child, parent));
+                        todo.push((id.left_child(), parent));
+                    }
+                }
+            }
+        }
+    }
+    hit
+}
+
+/// Like `traverse`, but without a traversal stack, following
+/// > Stackless Multi-BVH Traversal for CPU, MIC and GPU Ray Tracing
+/// > Attila T. Áfra and László Szirmay-Kalos
+/// > Computer Graphics Forum (2013)
+/// for binary trees. Instead of pushing the far child, a bit stack records for every level
+/// whether the far child of that level is still to be visited, and the traversal climbs back
+/// up through the parent links to find it. The nodes are visited in the same order as with
+/// `traverse`. Always uses the full precision nodes, since decoding a compressed box needs the
+/// parent's box, which isn't at hand after climbing up. Trees too deep for the bit stack are
+/// traversed with `traverse` instead.
+pub fn traverse_stackless<S>(mesh: &TriMesh,
+                             tree: &Bvh,
+                             r: &Ray,
+                             filter: Option<&HitFilter>,
+                             stats: &mut S)
+                             -> Hit
+    where S: StatsRecorder
+{
+    // Bit i (counting from the least significant one) is set if the far child of the node
+    // i + 1 levels above the current one hasn't been visited yet.
+    let mut bits: u64 = 0;
+    if tree.max_depth > 64 {
+        return traverse(mesh, tree, r, filter, stats);
+    }
+    let mut t_max = r.t_max;
     let r_tri = watertri::RayData::new(r.o, r.d);
     let r_box = beebox::RayData::new(r.o, r.d);
     let mut hit = Hit::none();
+    let mut id = NodeId(0);
+    loop {
+        stats.box_tested();
+        let node = &tree.nodes[id.to_index()];
+        if box_hit(mesh, &node.bb, r, &r_box, t_max) {
+            stats.node_visited(id.to_index());
+            match node.unpack() {
+                UnpackedNode::Leaf { start, end } => {
+                    stats.leaf_visited(usize(end - start));
+                    mesh.intersect(start, end, r, &r_tri, &mut t_max, filter, &mut hit);
+                }
+                UnpackedNode::Interior { second_child, axis } => {
+                    bits = bits << 1 | 1;
+                    id = if r.d[usize(axis)] < 0.0 { second_child } else { id.left_child() };
+                    continue;
+                }
+            }
+        }
+        // Climb up to the closest level whose far child is left, and continue there.
+        while bits & 1 == 0 {
+            if bits == 0 {
+                return hit;
+            }
+            id = tree.parents[id.to_index()];
+            bits >>= 1;
+            stats.backtracked();
+        }
+        bits &= !1;
+        let parent = tree.parents[id.to_index()];
+        id = match tree.nodes[parent.to_index()].unpack() {
+            UnpackedNode::Interior { second_child, .. } if second_child == id => {
+                parent.left_child()
+            }
+            UnpackedNode::Interior { second_child, .. } => second_child,
+            UnpackedNode::Leaf { .. } => unreachable!("BUG: leaf {:?} is a parent", parent),
+        };
+    }
+}
 
-    let mut todo = ArrayVec::<[_; MAX_DEPTH]>::new();
+/// Maximum number of rays that `traverse_packet` handles at once.
+pub const PACKET_SIZE: usize = 16;
+
+/// Like `traverse`, but for up to `PACKET_SIZE` rays at once, which share one traversal stack.
+/// A node is visited if any of the rays hits its box, so this only pays off for coherent rays
+/// (e.g. primary rays of neighboring pixels) that mostly visit the same nodes anyway.
+/// In exchange, every node is fetched once for the whole packet and the box tests for all rays
+/// are simple loops over arrays that the compiler can vectorize.
+/// Each ray has its own entry in `stats`, which is told about the nodes that ray takes part in.
+pub fn traverse_packet<S>(mesh: &TriMesh,
+                          tree: &Bvh,
+                          rays: &[Ray],
+                          filter: Option<&HitFilter>,
+                          stats: &mut [S])
+                          -> Vec<Hit>
+    where S: StatsRecorder
+{
+    match tree.compressed_nodes() {
+        Some(nodes) => traverse_packet_nodes(mesh, &nodes, rays, filter, stats),
+        None => traverse_packet_nodes(mesh, &*tree.nodes, rays, filter, stats),
+    }
+}
+
+fn traverse_packet_nodes<L, S>(mesh: &TriMesh,
+                               nodes: &L,
+                               rays: &[Ray],
+                               filter: Option<&HitFilter>,
+                               stats: &mut [S])
+                               -> Vec<Hit>
+    where L: NodeLayout + ?Sized,
+          S: StatsRecorder
+{
+    assert!(rays.len() <= PACKET_SIZE);
+    assert_eq!(rays.len(), stats.len());
+    let mut packet = PacketData::new(rays);
+    let r_tris: Vec<_> = rays.iter().map(|r| watertri::RayData::new(r.o, r.d)).collect();
+    let mut hits: Vec<_> = rays.iter().map(|_| Hit::none()).collect();
+
+    let mut todo = TraversalStack::new();
+    todo.push((NodeId(0), nodes.root()));
+    while let Some((id, parent)) = todo.pop() {
+        let (bb, node, parent) = nodes.node(id, parent);
+        let active = packet.intersects(&bb);
+        let mut any_active = false;
+        for (i, ray_stats) in stats.iter_mut().enumerate() {
+            // The box is tested against all lanes at once, so every ray pays for it.
+            ray_stats.box_tested();
+            if active[i] {
+                ray_stats.node_visited(id.to_index());
+                any_active = true;
+            }
+        }
+        if !any_active {
+            continue;
+        }
+        match node {
+            UnpackedNode::Leaf { start, end } => {
+                for (i, r) in rays.iter().enumerate() {
+                    if active[i] {
+                        stats[i].leaf_visited(usize(end - start));
+                        mesh.intersect(start,
+                                       end,
+                                       r,
+                                       &r_tris[i],
+                                       &mut packet.t_max[i],
+                                       filter,
+                                       &mut hits[i]);
+                    }
+                }
+            }
+            UnpackedNode::Interior { second_child, axis } => {
+                // The rays are coherent, so any active ray is a good guess for the whole packet.
+                let leader = active.iter().position(|&a| a).unwrap();
+                if rays[leader].d[usize(axis)] < 0.0 {
+                    todo.push((id.left_child(), parent));
+                    todo.push((second_child, parent));
+                } else {
+                    todo.push((second_child, parent));
+                    todo.push((id.left_child(), parent));
+                }
+            }
+        }
+    }
+    hits
+}
+
+/// The rays of a packet in structure-of-arrays layout, for testing them all against a box.
+/// `t_max` shrinks to the closest hit of each ray during traversal. Lanes without a ray have
+/// `t_max` < 0 and never hit anything.
+struct PacketData {
+    origin: [[f32; PACKET_SIZE]; 3],
+    inv_dir: [[f32; PACKET_SIZE]; 3],
+    t_min: [f32; PACKET_SIZE],
+    t_max: [f32; PACKET_SIZE],
+}
+
+impl PacketData {
+    fn new(rays: &[Ray]) -> Self {
+        let mut packet = PacketData {
+            origin: [[0.0; PACKET_SIZE]; 3],
+            inv_dir: [[0.0; PACKET_SIZE]; 3],
+            t_min: [0.0; PACKET_SIZE],
+            t_max: [-1.0; PACKET_SIZE],
+        };
+        for (i, r) in rays.iter().enumerate() {
+            for axis in 0..3 {
+                packet.origin[axis][i] = r.o[axis];
+                packet.inv_dir[axis][i] = 1.0 / r.d[axis];
+            }
+            packet.t_min[i] = r.t_min;
+            packet.t_max[i] = r.t_max;
+        }
+        packet
+    }
+
+    /// Slab test of every lane against `bb`, between the lane's `t_min` and `t_max`.
+    fn intersects(&self, bb: &Aabb) -> [bool; PACKET_SIZE] {
+        let (bb_min, bb_max) = (bb.min(), bb.max());
+        let mut t_enter = self.t_min;
+        // Widen the interval a little so rounding errors can't make rays miss boxes that they
+        // graze, as in "Robust BVH Ray Traversal" (Ize 2013).
+        let mut t_exit = [0.0f32; PACKET_SIZE];
+        for i in 0..PACKET_SIZE {
+            t_exit[i] = self.t_max[i] * (1.0 + 4.0 * f32::EPSILON);
+        }
+        for axis in 0..3 {
+            let (origin, inv_dir) = (&self.origin[axis], &self.inv_dir[axis]);
+            for i in 0..PACKET_SIZE {
+                let t0 = (bb_min[axis] - origin[i]) * inv_dir[i];
+                let t1 = (bb_max[axis] - origin[i]) * inv_dir[i];
+                // f32::max and min ignore the NaN from 0 * inf (origin on a slab boundary and
+                // direction parallel to it), which conservatively counts as inside the slab.
+                t_enter[i] = t_enter[i].max(t0.min(t1));
+                t_exit[i] = t_exit[i].min(t0.max(t1));
+            }
+        }
+        let mut hit = [false; PACKET_SIZE];
+        for i in 0..PACKET_SIZE {
+            hit[i] = t_enter[i] <= t_exit[i];
+        }
+        hit
+    }
+}
+
+/// Find all intersections between `r.t_min` and `r.t_max` that pass `filter`, sorted by t.
+/// If there are more than `capacity` of them, only the closest `capacity` ones are returned.
+pub fn traverse_all(mesh: &TriMesh,
+                    tree: &Bvh,
+                    r: &Ray,
+                    filter: Option<&HitFilter>,
+                    capacity: usize)
+                    -> Vec<Hit> {
+    match tree.compressed_nodes() {
+        Some(nodes) => traverse_all_nodes(mesh, &nodes, r, filter, capacity),
+        None => traverse_all_nodes(mesh, &*tree.nodes, r, filter, capacity),
+    }
+}
+
+fn traverse_all_nodes<L>(mesh: &TriMesh,
+                         nodes: &L,
+                         r: &Ray,
+                         filter: Option<&HitFilter>,
+                         capacity: usize)
+                         -> Vec<Hit>
+    where L: NodeLayout + ?Sized
+{
+    let mut t_max = r.t_max;
+    let r_tri = watertri::RayData::new(r.o, r.d);
+    let r_box = beebox::RayData::new(r.o, r.d);
+    let mut hits: Vec<Hit> = Vec::with_capacity(capacity);
+    if capacity == 0 {
+        return hits;
+    }
+
+    let mut todo = TraversalStack::new();
+    todo.push((NodeId(0), nodes.root()));
+    while let Some((id, parent)) = todo.pop() {
+        let (bb, node, parent) = nodes.node(id, parent);
+        if !box_hit(mesh, &bb, r, &r_box, t_max) {
+            continue;
+        }
+        match node {
+            UnpackedNode::Leaf { start, end } => {
+                for tri_id in start..end {
+                    let isect = match mesh.intersect_tri(tri_id, r, &r_tri) {
+                        Some(isect) => isect,
+                        None => continue,
+                    };
+                    if isect.t <= r.t_min || isect.t >= t_max ||
+                       !accept_hit(filter, tri_id, &isect) {
+                        continue;
+                    }
+                    let pos = hits.iter().position(|hit| hit.t > isect.t).unwrap_or(hits.len());
+                    let mut hit = Hit::none();
+                    hit.replace(tri_id, isect);
+                    hits.insert(pos, hit);
+                    // Once the list is full, anything beyond its farthest hit can be skipped.
+                    if hits.len() > capacity {
+                        hits.pop();
+                    }
+                    if hits.len() == capacity {
+                        t_max = hits[capacity - 1].t;
+                    }
+                }
+            }
+            UnpackedNode::Interior { second_child, axis } => {
+                if r.d[usize(axis)] < 0.0 {
+                    todo.push((id.left_child(), parent));
+                    todo.push((second_child, parent));
+                } else {
+                    todo.push((second_child, parent));
+                    todo.push((id.left_child(), parent));
+                }
+            }
+        }
+    }
+    hits
+}
+
+/// Find the point of the mesh closest to `p`, the triangle it's on and its distance to `p`.
+/// Returns `None` only if there are no triangles at all.
+pub fn closest_point(mesh: &TriMesh,
+                     tree: &Bvh,
+                     p: Vector3<f32>)
+                     -> Option<(Vector3<f32>, Index, f32)> {
+    match tree.compressed_nodes() {
+        Some(nodes) => closest_point_nodes(mesh, &nodes, p),
+        None => closest_point_nodes(mesh, &*tree.nodes, p),
+    }
+}
+
+fn closest_point_nodes<L>(mesh: &TriMesh,
+                          nodes: &L,
+                          p: Vector3<f32>)
+                          -> Option<(Vector3<f32>, Index, f32)>
+    where L: NodeLayout + ?Sized
+{
+    let mut best = None;
+    let mut best_dist2 = f32::INFINITY;
+
+    let mut todo = TraversalStack::new();
+    todo.push((NodeId(0), nodes.root()));
+    while let Some((id, parent)) = todo.pop() {
+        let (bb, node, parent) = nodes.node(id, parent);
+        // Nothing in this subtree can beat the best point so far.
+        if box_distance2(&bb, p) >= best_dist2 {
+            continue;
+        }
+        match node {
+            UnpackedNode::Leaf { start, end } => {
+                for tri_id in start..end {
+                    let q = mesh.closest_point(tri_id, p);
+                    let dist2 = (q - p).magnitude2();
+                    if dist2 < best_dist2 {
+                        best_dist2 = dist2;
+                        best = Some((q, tri_id));
+                    }
+                }
+            }
+            UnpackedNode::Interior { second_child, .. } => {
+                // Visit the nearer child first, it's more likely to shrink the search radius.
+                let first = id.left_child();
+                let first_dist2 = box_distance2(&nodes.node(first, parent).0, p);
+                let second_dist2 = box_distance2(&nodes.node(second_child, parent).0, p);
+                if first_dist2 <= second_dist2 {
+                    todo.push((second_child, parent));
+                    todo.push((first, parent));
+                } else {
+                    todo.push((first, parent));
+                    todo.push((second_child, parent));
+                }
+            }
+        }
+    }
+    best.map(|(q, tri_id)| (q, tri_id, best_dist2.sqrt()))
+}
+
+/// Call `f` with every triangle in a leaf whose box overlaps `bb`. Always uses the full
+/// precision nodes, the query is for diagnostics rather than rendering.
+pub fn overlapping<F>(tree: &Bvh, bb: &Aabb, mut f: F)
+    where F: FnMut(Index)
+{
+    let (min, max) = (bb.min(), bb.max());
+    let mut todo = TraversalStack::new();
+    todo.push(NodeId(0));
+    while let Some(id) = todo.pop() {
+        let node = &tree.nodes[id.to_index()];
+        let (node_min, node_max) = (node.bb.min(), node.bb.max());
+        if (0..3).any(|axis| node_min[axis] > max[axis] || node_max[axis] < min[axis]) {
+            continue;
+        }
+        match node.unpack() {
+            UnpackedNode::Leaf { start, end } => {
+                for tri_id in start..end {
+                    f(tri_id);
+                }
+            }
+            UnpackedNode::Interior { second_child, .. } => {
+                todo.push(second_child);
+                todo.push(id.left_child());
+            }
+        }
+    }
+}
+
+/// Test whether anything that passes `filter` is hit between `r.t_min` and `r.t_max`.
+/// Stops at the first intersection found, which is much cheaper than finding the closest one.
+pub fn occluded(mesh: &TriMesh,
+                tree: &Bvh,
+                r: &Ray,
+                filter: Option<&HitFilter>)
+                -> bool {
+    match tree.compressed_nodes() {
+        Some(nodes) => occluded_nodes(mesh, &nodes, r, filter),
+        None => occluded_nodes(mesh, &*tree.nodes, r, filter),
+    }
+}
+
+fn occluded_nodes<L>(mesh: &TriMesh,
+                     nodes: &L,
+                     r: &Ray,
+                     filter: Option<&HitFilter>)
+                     -> bool
+    where L: NodeLayout + ?Sized
+{
+    let t_max = r.t_max;
+    let r_tri = watertri::RayData::new(r.o, r.d);
+    let r_box = beebox::RayData::new(r.o, r.d);
+
+    let mut todo = TraversalStack::new();
+    todo.push((NodeId(0), nodes.root()));
+    while let Some((id, parent)) = todo.pop() {
+        let (bb, node, parent) = nodes.node(id, parent);
+        if !box_hit(mesh, &bb, r, &r_box, t_max) {
+            continue;
+        }
+        match node {
+            UnpackedNode::Leaf { start, end } => {
+                for tri_id in start..end {
+                    if let Some(isect) = mesh.intersect_tri(tri_id, r, &r_tri) {
+                        if isect.t > r.t_min && isect.t < t_max &&
+                           accept_hit(filter, tri_id, &isect) {
+                            return true;
+                        }
+                    }
+                }
+            }
+            UnpackedNode::Interior { second_child, .. } => {
+                todo.push((second_child, parent));
+                todo.push((id.left_child(), parent));
+            }
+        }
+    }
+    false
+}
+
+/// Same as `traverse`, but prints a log of everything that happens along the way.
+/// Only meant for debugging single rays, it is much too noisy for anything else.
+pub fn traverse_verbose<S>(mesh: &TriMesh,
+                           tree: &Bvh,
+                           r: &Ray,
+                           filter: Option<&HitFilter>,
+                           stats: &mut S)
+                           -> Hit
+    where S: StatsRecorder
+{
+    let mut t_max = r.t_max;
+    let r_tri = watertri::RayData::new(r.o, r.d);
+    let r_box = beebox::RayData::new(r.o, r.d);
+    let mut hit = Hit::none();
+
+    let mut todo = TraversalStack::new();
     todo.push(NodeId(0));
     while let Some(id) = todo.pop() {
-        r.traversal_steps.set(r.traversal_steps.get() + 1);
+        stats.box_tested();
         let node = &tree.nodes[id.to_index()];
-        if !node.bb.intersects(&r_box, 0.0, r.t_max.get()) {
+        let (t_enter, t_exit) = slab_range(&node.bb, r);
+        let is_hit = box_hit(mesh, &node.bb, r, &r_box, t_max);
+        println!("node {:>6}: t-range [{}, {}], t_max {} -> {}",
+                 id.0,
+                 t_enter,
+                 t_exit,
+                 t_max,
+                 if is_hit { "enter" } else { "skip" });
+        if !is_hit {
             continue;
         }
+        stats.node_visited(id.to_index());
         match node.unpack() {
             UnpackedNode::Leaf { start, end } => {
-                tris[usize(start)..usize(end)].intersect(start, r, &r_tri, &mut hit);
+                println!("    leaf with tris {}..{}", start, end);
+                stats.leaf_visited(usize(end - start));
+                for tri_id in start..end {
+                    match mesh.intersect_tri(tri_id, r, &r_tri) {
+                        Some(isect) => {
+                            let closer = isect.t > r.t_min && isect.t < t_max;
+                            let accepted = accept_hit(filter, tri_id, &isect);
+                            println!("    tri {:>8}: t = {}, (u, v, w) = ({}, {}, {}){}",
+                                     tri_id,
+                                     isect.t,
+                                     isect.u,
+                                     isect.v,
+                                     isect.w,
+                                     match (closer, accepted) {
+                                         (true, true) => ", new closest hit",
+                                         (true, false) => ", rejected by filter",
+                                         (false, _) => "",
+                                     });
+                            if closer && accepted {
+                                t_max = isect.t;
+                                hit.replace(tri_id, isect);
+                            }
+                        }
+                        None => println!("    tri {:>8}: miss", tri_id),
+                    }
+                }
             }
             UnpackedNode::Interior { second_child, axis } => {
-                if r.d[usize(axis)] < 0.0 {
-                    todo.push(id.left_child());
-                    todo.push(second_child);
+                let (first, second) = if r.d[usize(axis)] < 0.0 {
+                    (second_child, id.left_child())
                 } else {
-                    todo.push(second_child);
-                    todo.push(id.left_child());
-                }
+                    (id.left_child(), second_child)
+                };
+                println!("    interior split on axis {}, visiting {} before {}",
+                         axis,
+                         first.0,
+                         second.0);
+                todo.push(second);
+                todo.push(first);
             }
         }
     }
     hit
 }
+
+/// Compute the (unclamped) ray parameters at which the ray enters and exits the box.
+fn slab_range(bb: &Aabb, r: &Ray) -> (f32, f32) {
+    let (min, max) = (bb.min(), bb.max());
+    let mut t_enter = f32::NEG_INFINITY;
+    let mut t_exit = f32::INFINITY;
+    for axis in 0..3 {
+        let inv_d = 1.0 / r.d[axis];
+        let t0 = (min[axis] - r.o[axis]) * inv_d;
+        let t1 = (max[axis] - r.o[axis]) * inv_d;
+        t_enter = t_enter.max(t0.min(t1));
+        t_exit = t_exit.min(t0.max(t1));
+    }
+    (t_enter, t_exit)
+}
+
+/// Whether the ray overlaps the box somewhere between `r.t_min` and `t_max`, tested in the
+/// precision selected for the mesh.
+fn box_hit(mesh: &TriMesh, bb: &Aabb, r: &Ray, r_box: &beebox::RayData, t_max: f32) -> bool {
+    match mesh.precision() {
+        Precision::F32 => bb.intersects(r_box, r.t_min, t_max),
+        Precision::F64 => {
+            let (min, max) = (bb.min(), bb.max());
+            let mut t_enter = f64(r.t_min);
+            let mut t_exit = f64(t_max);
+            for axis in 0..3 {
+                let inv_d = 1.0 / f64(r.d[axis]);
+                let t0 = (f64(min[axis]) - f64(r.o[axis])) * inv_d;
+                let t1 = (f64(max[axis]) - f64(r.o[axis])) * inv_d;
+                t_enter = t_enter.max(t0.min(t1));
+                t_exit = t_exit.min(t0.max(t1));
+            }
+            t_enter <= t_exit
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cgmath::vec3;
+
+    /// A mesh of `n` small triangles side by side along the x axis, and a BVH for it that is a
+    /// chain of `n - 1` interior nodes. Each of them has the rest of the chain as its first
+    /// child and triangle `i` as its second one, so that traversing down to the last triangle
+    /// leaves one pending node on the stack for every level.
+    fn chain(n: usize) -> (TriMesh, Bvh) {
+        let mut vertices = Vec::new();
+        let mut tris = Vec::new();
+        for i in 0..n {
+            let x = i as f32;
+            vertices.extend(vec![vec3(x, 0.0, 0.0), vec3(x + 0.5, 0.0, 0.0), vec3(x, 0.5, 0.0)]);
+            tris.push(Tri {
+                          a: index(3 * i),
+                          b: index(3 * i + 1),
+                          c: index(3 * i + 2),
+                      });
+        }
+        let mesh = TriMesh::new(vertices, tris);
+        let leaf = |i: usize| {
+            beevage::Node::Leaf {
+                bb: mesh.tri_bbox(index(i)),
+                primitive_range: i..i + 1,
+            }
+        };
+        let mut node = leaf(n - 1);
+        for i in (0..n - 1).rev() {
+            node = beevage::Node::Inner {
+                bb: mesh.range_bbox(index(i), index(n)),
+                children: Box::new((node, leaf(i))),
+                axis: Axis::X,
+            };
+        }
+        let bvh = Bvh::compactify(node, 2 * n - 1);
+        (mesh, bvh)
+    }
+
+    #[test]
+    fn deeper_than_max_depth() {
+        let n = 3 * MAX_DEPTH;
+        let (mesh, bvh) = chain(n);
+        assert_eq!(bvh.stats().max_depth, n - 1);
+        // Only the last triangle is in the way of this ray.
+        let r = Ray::new(vec3((n - 1) as f32 + 0.1, 0.1, 1.0), vec3(0.0, 0.0, -1.0));
+        let hit = traverse(&mesh, &bvh, &r, None, &mut NoStats);
+        assert!(hit.is_valid());
+        assert_eq!(hit.tri_id, index(n - 1));
+        let hit = traverse_stackless(&mesh, &bvh, &r, None, &mut NoStats);
+        assert_eq!(hit.tri_id, index(n - 1));
+    }
+}
@@ -0,0 +1,528 @@
+use super::Config;
+use beebox::Aabb;
+use cast::{f32, u32, usize};
+use cgmath::{InnerSpace, Vector3, Vector4, vec3};
+use film::Rect;
+use geom::{Frustum, Ray};
+use rayon::prelude::*;
+use sampling::{Rng, concentric_disk};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Pinhole,
+    /// Full 360x180 degree panorama around the eye, longitude along x and latitude along y.
+    Equirectangular,
+    /// Equidistant angular fisheye. The image circle is inscribed in the image and covers
+    /// `angle` radians of field of view, pixels outside of it don't get a ray.
+    Fisheye { angle: f32 },
+}
+
+/// Where within a pixel and on the lens a camera ray starts.
+/// Both are given as points in the unit square.
+#[derive(Copy, Clone, Debug)]
+pub struct CameraSample {
+    pub film: (f32, f32),
+    pub lens: (f32, f32),
+}
+
+impl CameraSample {
+    /// The sample through the center of the pixel and the center of the lens.
+    pub fn center() -> Self {
+        CameraSample {
+            film: (0.5, 0.5),
+            lens: (0.5, 0.5),
+        }
+    }
+
+    pub fn random(rng: &mut Rng) -> Self {
+        CameraSample {
+            film: (rng.next_f32(), rng.next_f32()),
+            lens: (rng.next_f32(), rng.next_f32()),
+        }
+    }
+}
+
+/// The directions of the rays through the pixel centers, in terms of the right, up and forward
+/// axes of the camera. They don't depend on where the camera is or which way it looks, so the
+/// frames of an animation can share them and only rotate them, see `Camera::ray_grid`.
+pub struct RayGrid {
+    /// What the directions do depend on, to tell which cameras can use them.
+    projection: Projection,
+    half_extent: (f32, f32),
+    width: u32,
+    height: u32,
+    /// Row by row, None for pixels that the projection doesn't cover.
+    dirs: Vec<Option<Vector3<f32>>>,
+}
+
+#[derive(Clone)]
+pub struct Camera {
+    projection: Projection,
+    eye: Vector3<f32>,
+    forward: Vector3<f32>,
+    right: Vector3<f32>,
+    up: Vector3<f32>,
+    /// Half the extent of the image plane at distance 1 from the eye, horizontally and vertically.
+    half_extent: (f32, f32),
+    /// The (not normalized) pinhole direction through the top left corner of the image, and how
+    /// it changes from one pixel to the next along x and y.
+    pixel_origin: Vector3<f32>,
+    pixel_dx: Vector3<f32>,
+    pixel_dy: Vector3<f32>,
+    /// Radius of the thin lens, zero for a pinhole.
+    lens_radius: f32,
+    /// Distance from the eye to the plane that's in focus.
+    focus_dist: f32,
+    width: u32,
+    height: u32,
+    /// Primary rays only see what's on the positive side of all of these planes.
+    clip_planes: Vec<Vector4<f32>>,
+    /// Directions for the rays through the pixel centers, if they were computed in advance.
+    ray_grid: Option<Arc<RayGrid>>,
+    /// The row of the image that row 0 of the pixels asked for is, see `rows_from`.
+    first_row: u32,
+}
+
+impl Camera {
+    pub fn new(cfg: &Config, scene_bb: &Aabb) -> Self {
+        let (eye, target) = placement(cfg, scene_bb);
+        Camera::look_at(eye, target, cfg)
+    }
+
+    /// The camera from `Camera::new`, rotated by `angle` radians around the vertical axis
+    /// through the point it looks at.
+    pub fn orbit(cfg: &Config, scene_bb: &Aabb, angle: f32) -> Self {
+        let (eye, target) = placement(cfg, scene_bb);
+        let offset = eye - target;
+        let (sin, cos) = angle.sin_cos();
+        let rotated = vec3(cos * offset.x + sin * offset.z,
+                           offset.y,
+                           -sin * offset.x + cos * offset.z);
+        Camera::look_at(target + rotated, target, cfg)
+    }
+
+    pub fn look_at(eye: Vector3<f32>, target: Vector3<f32>, cfg: &Config) -> Self {
+        Camera::look_at_with_fov(eye, target, cfg.fov, cfg)
+    }
+
+    pub fn look_at_with_fov(eye: Vector3<f32>,
+                            target: Vector3<f32>,
+                            fov: f32,
+                            cfg: &Config)
+                            -> Self {
+        let forward = (target - eye).normalize();
+        // Use +Y as up direction unless we're looking (almost) straight up or down.
+        let world_up = if forward.y.abs() > 0.999 {
+            vec3(0.0, 0.0, -1.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        let right = forward.cross(world_up).normalize();
+        let up = right.cross(forward);
+        let half_extent = half_extent(cfg, fov);
+        Camera {
+            projection: cfg.projection,
+            eye,
+            forward,
+            right,
+            up,
+            half_extent,
+            pixel_origin: forward - half_extent.0 * right + half_extent.1 * up,
+            pixel_dx: right * (2.0 * half_extent.0 / f32(cfg.image_width)),
+            pixel_dy: up * (-2.0 * half_extent.1 / f32(cfg.image_height)),
+            lens_radius: cfg.aperture / 2.0,
+            focus_dist: cfg.focus_dist.unwrap_or((target - eye).magnitude()),
+            width: cfg.image_width,
+            height: cfg.image_height,
+            clip_planes: cfg.clip_planes.clone(),
+            ray_grid: None,
+            first_row: 0,
+        }
+    }
+
+    pub fn eye(&self) -> Vector3<f32> {
+        self.eye
+    }
+
+    /// The camera moved by `offset` along its right axis, still looking the same way, e.g. for
+    /// one eye of a stereo pair.
+    pub fn shifted(&self, offset: f32) -> Camera {
+        Camera { eye: self.eye + offset * self.right, ..self.clone() }
+    }
+
+    /// The same camera, but with pixel rows counted from row `first_row` of its image, for
+    /// rendering a band of rows as if it were an image of its own.
+    pub fn rows_from(&self, first_row: u32) -> Camera {
+        Camera { first_row, ..self.clone() }
+    }
+
+    /// Returns None for pixels that the projection doesn't cover.
+    pub fn primary_ray(&self, x: u32, y: u32, sample: &CameraSample) -> Option<Ray> {
+        let y = y + self.first_row;
+        if let Some(ref grid) = self.ray_grid {
+            if sample.film == (0.5, 0.5) {
+                let dir = grid.dirs[usize(y) * usize(grid.width) + usize(x)];
+                return dir.map(|c| {
+                    let d = c.x * self.right + c.y * self.up + c.z * self.forward;
+                    self.clip(Ray::new(self.eye, d))
+                });
+            }
+        }
+        self.unclipped_ray(x, y, sample).map(|r| self.clip(r))
+    }
+
+    /// The directions of the rays through all pixel centers, for other cameras with the same
+    /// projection, field of view and resolution to use with `with_ray_grid`. None for thin lens
+    /// cameras, whose rays don't all start at the eye.
+    pub fn ray_grid(&self) -> Option<RayGrid> {
+        if self.lens_radius > 0.0 {
+            return None;
+        }
+        let mut dirs = vec![None; usize(self.width) * usize(self.height)];
+        dirs.par_chunks_mut(usize(self.width).max(1))
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = u32(y).unwrap();
+                for (x, dir) in (0..self.width).zip(row) {
+                    let r = self.unclipped_ray(x, y, &CameraSample::center());
+                    *dir = r.map(|r| {
+                                     vec3(r.d.dot(self.right),
+                                          r.d.dot(self.up),
+                                          r.d.dot(self.forward))
+                                 });
+                }
+            });
+        Some(RayGrid {
+                 projection: self.projection,
+                 half_extent: self.half_extent,
+                 width: self.width,
+                 height: self.height,
+                 dirs,
+             })
+    }
+
+    /// Use `grid` for the rays through the pixel centers from now on, instead of computing
+    /// them from scratch, if it was made for a camera like this one. The directions can differ
+    /// from those computed directly in the last bits.
+    pub fn with_ray_grid(mut self, grid: &Arc<RayGrid>) -> Camera {
+        if self.lens_radius == 0.0 && grid.projection == self.projection &&
+           grid.half_extent == self.half_extent &&
+           (grid.width, grid.height) == (self.width, self.height) {
+            self.ray_grid = Some(grid.clone());
+        }
+        self
+    }
+
+    /// The primary rays of the pixels in `tile`, row by row, with `samples[i]` for the i-th
+    /// pixel. For pinhole cameras, the part of the direction that only depends on the row is
+    /// computed once per row (as long as its samples share their vertical film position), and
+    /// each pixel only adds its step along the row. The rays are the same as `primary_ray`'s.
+    pub fn primary_rays(&self, tile: Rect, samples: &[CameraSample]) -> Vec<Option<Ray>> {
+        assert_eq!(samples.len(), usize(tile.w) * usize(tile.h));
+        // The grid is faster still, as it saves normalizing the directions.
+        let pinhole = match self.projection {
+            Projection::Pinhole => self.lens_radius == 0.0 && self.ray_grid.is_none(),
+            _ => false,
+        };
+        let mut rays = Vec::with_capacity(samples.len());
+        let mut samples = samples.iter();
+        for y in tile.y..tile.y + tile.h {
+            let mut row: Option<(f32, Vector3<f32>)> = None;
+            for x in tile.x..tile.x + tile.w {
+                let sample = samples.next().unwrap();
+                if !pinhole {
+                    rays.push(self.primary_ray(x, y, sample));
+                    continue;
+                }
+                let film_y = f32(y + self.first_row) + sample.film.1;
+                let row_dir = match row {
+                    Some((row_y, d)) if row_y == film_y => d,
+                    _ => {
+                        let d = self.pixel_origin + film_y * self.pixel_dy;
+                        row = Some((film_y, d));
+                        d
+                    }
+                };
+                let d = row_dir + (f32(x) + sample.film.0) * self.pixel_dx;
+                rays.push(Some(self.clip(Ray::new(self.eye, d.normalize()))));
+            }
+        }
+        rays
+    }
+
+    fn unclipped_ray(&self, x: u32, y: u32, sample: &CameraSample) -> Option<Ray> {
+        let (film_x, film_y) = (f32(x) + sample.film.0, f32(y) + sample.film.1);
+        let (norm_x, norm_y) = (film_x / f32(self.width), film_y / f32(self.height));
+        let d = match self.projection {
+            Projection::Pinhole => {
+                // Like `primary_rays`, so that both give the same directions.
+                let d = self.pixel_origin + film_y * self.pixel_dy + film_x * self.pixel_dx;
+                let d = d.normalize();
+                if self.lens_radius > 0.0 {
+                    return Some(self.thin_lens_ray(d, sample.lens));
+                }
+                d
+            }
+            Projection::Equirectangular => {
+                // The center of the image looks along the forward direction.
+                let phi = 2.0 * PI * (norm_x - 0.5);
+                let theta = PI * norm_y;
+                let horizontal = phi.cos() * self.forward + phi.sin() * self.right;
+                theta.sin() * horizontal + theta.cos() * self.up
+            }
+            Projection::Fisheye { angle } => {
+                let aspect_ratio = f32(self.width) / f32(self.height);
+                let (cam_x, cam_y) = if aspect_ratio >= 1.0 {
+                    ((2.0 * norm_x - 1.0) * aspect_ratio, 1.0 - 2.0 * norm_y)
+                } else {
+                    (2.0 * norm_x - 1.0, (1.0 - 2.0 * norm_y) / aspect_ratio)
+                };
+                let r = cam_x.hypot(cam_y);
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = r * angle / 2.0;
+                let sideways = if r > 0.0 {
+                    (cam_x / r) * self.right + (cam_y / r) * self.up
+                } else {
+                    self.right
+                };
+                theta.cos() * self.forward + theta.sin() * sideways
+            }
+        };
+        Some(Ray::new(self.eye, d))
+    }
+
+    /// The (not normalized) direction of the pinhole ray through the point of the image at
+    /// `norm_x`, `norm_y`, with the image spanning 0 to 1 in both coordinates.
+    fn pinhole_dir(&self, norm_x: f32, norm_y: f32) -> Vector3<f32> {
+        let cam_x = (2.0 * norm_x - 1.0) * self.half_extent.0;
+        let cam_y = (1.0 - 2.0 * norm_y) * self.half_extent.1;
+        self.forward + cam_x * self.right + cam_y * self.up
+    }
+
+    /// The part of the segment from `a` to `b` that's in front of the camera, in (fractional)
+    /// pixel coordinates. None if the segment is entirely behind the camera, or if the
+    /// projection isn't a pinhole and doesn't map lines to lines. The thin lens is ignored.
+    pub fn project_segment(&self,
+                           a: Vector3<f32>,
+                           b: Vector3<f32>)
+                           -> Option<((f32, f32), (f32, f32))> {
+        if let Projection::Pinhole = self.projection {} else {
+            return None;
+        }
+        // Clip against a plane just in front of the eye, since points behind it don't project
+        // to the image and points on it project to infinity.
+        const NEAR: f32 = 1e-4;
+        let (za, zb) = ((a - self.eye).dot(self.forward), (b - self.eye).dot(self.forward));
+        if za < NEAR && zb < NEAR {
+            return None;
+        }
+        let lerp = |s: f32| a + (b - a) * s;
+        let a = if za < NEAR { lerp((NEAR - za) / (zb - za)) } else { a };
+        let b = if zb < NEAR { lerp((NEAR - za) / (zb - za)) } else { b };
+        let project = |p: Vector3<f32>| {
+            let d = p - self.eye;
+            let z = d.dot(self.forward);
+            let cam_x = d.dot(self.right) / (z * self.half_extent.0);
+            let cam_y = d.dot(self.up) / (z * self.half_extent.1);
+            ((cam_x + 1.0) / 2.0 * f32(self.width),
+             (1.0 - cam_y) / 2.0 * f32(self.height) - f32(self.first_row))
+        };
+        Some((project(a), project(b)))
+    }
+
+    /// A frustum containing all primary rays of the pixels in `window`, or None if the rays
+    /// don't start from a single point or the projection doesn't map lines to lines.
+    pub fn frustum(&self, window: Rect) -> Option<Frustum> {
+        match self.projection {
+            Projection::Pinhole if self.lens_radius == 0.0 => {}
+            _ => return None,
+        }
+        // Widen the window by half a pixel, so that rounding can't push rays at its edges out.
+        let x0 = (f32(window.x) - 0.5) / f32(self.width);
+        let y = window.y + self.first_row;
+        let y0 = (f32(y) - 0.5) / f32(self.height);
+        let x1 = (f32(window.x + window.w) + 0.5) / f32(self.width);
+        let y1 = (f32(y + window.h) + 0.5) / f32(self.height);
+        let corners = [self.pinhole_dir(x0, y0),
+                       self.pinhole_dir(x1, y0),
+                       self.pinhole_dir(x1, y1),
+                       self.pinhole_dir(x0, y1)];
+        let center = self.pinhole_dir((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let mut planes = [Vector4::new(0.0, 0.0, 0.0, 0.0); 4];
+        for (i, plane) in planes.iter_mut().enumerate() {
+            let mut n = corners[i].cross(corners[(i + 1) % 4]);
+            if n.dot(center) < 0.0 {
+                n = -n;
+            }
+            *plane = n.extend(-n.dot(self.eye));
+        }
+        Some(Frustum {
+                 apex: self.eye,
+                 planes,
+             })
+    }
+
+    /// Shrink the interval of `r` to the part on the positive side of every clipping plane.
+    /// If there's no such part, the interval becomes empty and the ray can't hit anything.
+    fn clip(&self, mut r: Ray) -> Ray {
+        for plane in &self.clip_planes {
+            let n = plane.truncate();
+            let dist = n.dot(r.o) + plane.w;
+            let speed = n.dot(r.d);
+            if speed > 0.0 {
+                r.t_min = r.t_min.max(-dist / speed);
+            } else if speed < 0.0 {
+                r.t_max = r.t_max.min(-dist / speed);
+            } else if dist < 0.0 {
+                r.t_max = r.t_min;
+            }
+        }
+        r
+    }
+
+    /// Move the origin of the pinhole ray with direction `d` to a point on the lens, keeping
+    /// the point where it intersects the plane of focus fixed.
+    fn thin_lens_ray(&self, d: Vector3<f32>, lens: (f32, f32)) -> Ray {
+        let focus_point = self.eye + d * (self.focus_dist / d.dot(self.forward));
+        let (lens_x, lens_y) = concentric_disk(lens.0, lens.1);
+        let origin = self.eye + self.lens_radius * (lens_x * self.right + lens_y * self.up);
+        Ray::new(origin, (focus_point - origin).normalize())
+    }
+}
+
+/// Where the camera is and what it looks at, either as configured or chosen automatically.
+pub fn placement(cfg: &Config, scene_bb: &Aabb) -> (Vector3<f32>, Vector3<f32>) {
+    if cfg.autoframe {
+        framing(scene_bb, cfg)
+    } else {
+        (cfg.eye, cfg.look_at)
+    }
+}
+
+/// Place the camera on the +Z side of the box, looking down the -Z axis, such that the
+/// bounding sphere of the box (and hence the entire box) is in view.
+fn framing(bb: &Aabb, cfg: &Config) -> (Vector3<f32>, Vector3<f32>) {
+    let (min, max) = (bb.min(), bb.max());
+    let center = (min + max) / 2.0;
+    let radius = (max - min).magnitude() / 2.0;
+    let (half_w, half_h) = half_extent(cfg, cfg.fov);
+    let half_angle = half_w.min(half_h).atan();
+    (center + vec3(0.0, 0.0, radius / half_angle.sin()), center)
+}
+
+/// Half the extent of the image plane at distance 1 from the eye, horizontally and vertically.
+fn half_extent(cfg: &Config, fov: f32) -> (f32, f32) {
+    let aspect_ratio = f32(cfg.image_width) / f32(cfg.image_height);
+    let half_width = (fov.to_radians() / 2.0).tan();
+    (half_width, half_width / aspect_ratio)
+}
+
+/// A camera placement at a point in time, read from a camera path file.
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe {
+    pub time: f32,
+    pub eye: Vector3<f32>,
+    pub look_at: Vector3<f32>,
+    pub fov: f32,
+}
+
+impl Keyframe {
+    pub fn camera(&self, cfg: &Config) -> Camera {
+        Camera::look_at_with_fov(self.eye, self.look_at, self.fov, cfg)
+    }
+}
+
+/// Read keyframes from a text file with one keyframe per line:
+///
+/// ```text
+/// # time  eye_x eye_y eye_z  look_x look_y look_z  fov
+/// 0.0     0 1 5              0 0 0                 60
+/// 2.5     5 1 0              0 0 0                 45
+/// ```
+///
+/// Empty lines and lines starting with `#` are ignored. Times must be increasing.
+pub fn read_camera_path(path: &Path) -> Result<Vec<Keyframe>, String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut keyframes: Vec<Keyframe> = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}: {}", path.display(), e))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |msg: &str| format!("{}:{}: {}", path.display(), i + 1, msg);
+        let values = line.split_whitespace()
+            .map(|v| v.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| error(&e.to_string()))?;
+        if values.len() != 8 {
+            return Err(error("expected 8 numbers: time, eye (x, y, z), look-at (x, y, z), fov"));
+        }
+        let key = Keyframe {
+            time: values[0],
+            eye: vec3(values[1], values[2], values[3]),
+            look_at: vec3(values[4], values[5], values[6]),
+            fov: values[7],
+        };
+        if keyframes.last().map_or(false, |prev| prev.time >= key.time) {
+            return Err(error("keyframe times must be increasing"));
+        }
+        keyframes.push(key);
+    }
+    if keyframes.is_empty() {
+        return Err(format!("{}: no keyframes", path.display()));
+    }
+    Ok(keyframes)
+}
+
+/// Interpolate the camera placement at `time`, clamping to the first and last keyframe.
+/// Positions and the field of view are interpolated linearly, while the view direction is
+/// interpolated spherically so the camera turns at a constant rate.
+pub fn interpolate(keyframes: &[Keyframe], time: f32) -> Keyframe {
+    let next = match keyframes.iter().position(|k| k.time > time) {
+        Some(0) => return keyframes[0],
+        Some(i) => i,
+        None => return keyframes[keyframes.len() - 1],
+    };
+    let (k0, k1) = (&keyframes[next - 1], &keyframes[next]);
+    let s = (time - k0.time) / (k1.time - k0.time);
+    let eye = k0.eye + (k1.eye - k0.eye) * s;
+    let (offset0, offset1) = (k0.look_at - k0.eye, k1.look_at - k1.eye);
+    let dist = offset0.magnitude() + (offset1.magnitude() - offset0.magnitude()) * s;
+    let dir = slerp(offset0.normalize(), offset1.normalize(), s);
+    Keyframe {
+        time,
+        eye,
+        look_at: eye + dir * dist,
+        fov: k0.fov + (k1.fov - k0.fov) * s,
+    }
+}
+
+/// Spherical linear interpolation between two unit vectors.
+fn slerp(a: Vector3<f32>, b: Vector3<f32>, s: f32) -> Vector3<f32> {
+    let cos_angle = a.dot(b).max(-1.0).min(1.0);
+    let angle = cos_angle.acos();
+    if angle < 1e-4 {
+        return (a + (b - a) * s).normalize();
+    }
+    if angle > PI - 1e-4 {
+        // For opposite directions, the formula below cancels out to nothing, and every way
+        // around is equally short. Turn about the vertical, like panning the camera would.
+        let world_up = if a.y.abs() > 0.999 {
+            vec3(0.0, 0.0, -1.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        let side = a.cross(world_up).normalize();
+        return a * (s * PI).cos() + side * (s * PI).sin();
+    }
+    let sin_angle = angle.sin();
+    a * (((1.0 - s) * angle).sin() / sin_angle) + b * ((s * angle).sin() / sin_angle)
+}
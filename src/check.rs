@@ -0,0 +1,235 @@
+//! Diagnostics for meshes that render with cracks, holes or flickering surfaces.
+//!
+//! Vertices are matched by their exact position, so the checks see the topology of the
+//! surface even if the file duplicates vertices at seams (as STL files always do). Edges shared
+//! by more than two triangles are non-manifold, edges of only one triangle are part of a hole.
+//! Triangles that disagree with their neighbors about the winding order are inverted, and
+//! triangles that cross each other without sharing a vertex are found with the BVH.
+
+use cast::usize;
+use geom::{Index, TriMesh, index, to_f64};
+use rayon::prelude::*;
+use scene::Scene;
+use std::collections::HashMap;
+use std::mem;
+use watertri;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Report {
+    non_manifold_edges: usize,
+    boundary_edges: usize,
+    /// Number of closed loops that the boundary edges form.
+    holes: usize,
+    /// Triangles wound against the majority of their connected surface.
+    inverted_faces: usize,
+    /// Closed surfaces whose normals consistently point inwards.
+    inside_out_shells: usize,
+    /// Pairs of triangles that intersect without sharing a vertex.
+    self_intersections: usize,
+}
+
+impl Report {
+    fn is_clean(&self) -> bool {
+        self.non_manifold_edges == 0 && self.boundary_edges == 0 && self.inverted_faces == 0 &&
+        self.inside_out_shells == 0 && self.self_intersections == 0
+    }
+}
+
+/// Check the triangles of the scene (shapes are ignored), print what was found, and exit with
+/// status 1 if there's any problem.
+pub fn run(scene: &Scene) {
+    let mesh = &scene.mesh;
+    let corners = weld_exact(mesh);
+    let edges = edge_map(&corners);
+    let mut report = Report::default();
+    for faces in edges.values() {
+        match faces.len() {
+            1 => report.boundary_edges += 1,
+            2 => {}
+            _ => report.non_manifold_edges += 1,
+        }
+    }
+    report.holes = count_boundary_loops(&edges);
+    let (inverted_faces, inside_out_shells) = check_orientation(mesh, &corners, &edges);
+    report.inverted_faces = inverted_faces;
+    report.inside_out_shells = inside_out_shells;
+    report.self_intersections = count_self_intersections(scene, &corners);
+
+    println!("triangles: {}", mesh.tris.len());
+    println!("non-manifold edges: {}", report.non_manifold_edges);
+    println!("boundary edges: {} in {} holes", report.boundary_edges, report.holes);
+    println!("inverted faces: {}", report.inverted_faces);
+    println!("closed surfaces wound inside out: {}", report.inside_out_shells);
+    println!("self-intersecting triangle pairs: {}", report.self_intersections);
+    if !report.is_clean() {
+        ::std::process::exit(1);
+    }
+}
+
+/// The corners of each triangle, numbered so that vertices at the same position get the same
+/// number.
+fn weld_exact(mesh: &TriMesh) -> Vec<[usize; 3]> {
+    let mut ids = HashMap::new();
+    let vertex_ids: Vec<usize> = mesh.vertices
+        .iter()
+        .map(|v| {
+                 // Adding zero turns -0.0 into 0.0, so both get the same bits.
+                 let key = ((v.x + 0.0).to_bits(), (v.y + 0.0).to_bits(), (v.z + 0.0).to_bits());
+                 let next_id = ids.len();
+                 *ids.entry(key).or_insert(next_id)
+             })
+        .collect();
+    mesh.tris
+        .iter()
+        .map(|tri| [vertex_ids[usize(tri.a)], vertex_ids[usize(tri.b)], vertex_ids[usize(tri.c)]])
+        .collect()
+}
+
+/// For each undirected edge (smaller vertex first), the triangles that have it, and whether
+/// they go along it from the smaller to the larger vertex.
+fn edge_map(corners: &[[usize; 3]]) -> HashMap<(usize, usize), Vec<(usize, bool)>> {
+    let mut edges: HashMap<(usize, usize), Vec<(usize, bool)>> = HashMap::new();
+    for (i, c) in corners.iter().enumerate() {
+        for k in 0..3 {
+            let (from, to) = (c[k], c[(k + 1) % 3]);
+            let key = if from < to { (from, to) } else { (to, from) };
+            edges.entry(key).or_insert_with(Vec::new).push((i, from < to));
+        }
+    }
+    edges
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+/// The number of connected components of the boundary edges. Each is the rim of one hole,
+/// unless several holes touch at a vertex.
+fn count_boundary_loops(edges: &HashMap<(usize, usize), Vec<(usize, bool)>>) -> usize {
+    let mut vertex_ids = HashMap::new();
+    let mut boundary = Vec::new();
+    for (&(a, b), faces) in edges {
+        if faces.len() == 1 {
+            let next_id = vertex_ids.len();
+            let a = *vertex_ids.entry(a).or_insert(next_id);
+            let next_id = vertex_ids.len();
+            let b = *vertex_ids.entry(b).or_insert(next_id);
+            boundary.push((a, b));
+        }
+    }
+    let mut parents: Vec<usize> = (0..vertex_ids.len()).collect();
+    let mut loops = vertex_ids.len();
+    for (a, b) in boundary {
+        let (a, b) = (find(&mut parents, a), find(&mut parents, b));
+        if a != b {
+            parents[a] = b;
+            loops -= 1;
+        }
+    }
+    loops
+}
+
+/// Walk over each connected surface (through manifold edges), flipping the orientation
+/// whenever two neighbors go along their shared edge in the same direction. Returns the number
+/// of triangles in the minority orientation of their surface, and the number of closed
+/// surfaces whose majority orientation encloses a negative volume.
+fn check_orientation(mesh: &TriMesh,
+                     corners: &[[usize; 3]],
+                     edges: &HashMap<(usize, usize), Vec<(usize, bool)>>)
+                     -> (usize, usize) {
+    let mut neighbors = vec![Vec::new(); corners.len()];
+    let mut closed = vec![true; corners.len()];
+    for faces in edges.values() {
+        if faces.len() == 2 {
+            let ((a, a_fwd), (b, b_fwd)) = (faces[0], faces[1]);
+            neighbors[a].push((b, a_fwd == b_fwd));
+            neighbors[b].push((a, a_fwd == b_fwd));
+        } else {
+            for &(i, _) in faces {
+                closed[i] = false;
+            }
+        }
+    }
+
+    let mut flipped: Vec<Option<bool>> = vec![None; corners.len()];
+    let (mut inverted_faces, mut inside_out_shells) = (0, 0);
+    for seed in 0..corners.len() {
+        if flipped[seed].is_some() {
+            continue;
+        }
+        flipped[seed] = Some(false);
+        let mut todo = vec![seed];
+        let mut shell = Vec::new();
+        while let Some(i) = todo.pop() {
+            shell.push(i);
+            let flip = flipped[i].unwrap();
+            for &(j, inconsistent) in &neighbors[i] {
+                if flipped[j].is_none() {
+                    flipped[j] = Some(flip != inconsistent);
+                    todo.push(j);
+                }
+            }
+        }
+        let num_flipped = shell.iter().filter(|&&i| flipped[i] == Some(true)).count();
+        let majority_flipped = num_flipped * 2 > shell.len();
+        inverted_faces += if majority_flipped { shell.len() - num_flipped } else { num_flipped };
+
+        if shell.iter().all(|&i| closed[i]) {
+            // Six times the signed volume, summing tetrahedra from the origin to each triangle.
+            let volume: f64 = shell.iter()
+                .map(|&i| {
+                    let (a, b, c) = mesh.corners(index(i));
+                    let (a, b, c) = (to_f64(a), to_f64(b), to_f64(c));
+                    let v = a.x * (b.y * c.z - b.z * c.y) - a.y * (b.x * c.z - b.z * c.x) +
+                            a.z * (b.x * c.y - b.y * c.x);
+                    if flipped[i] == Some(majority_flipped) { v } else { -v }
+                })
+                .sum();
+            if volume < 0.0 {
+                inside_out_shells += 1;
+            }
+        }
+    }
+    (inverted_faces, inside_out_shells)
+}
+
+/// Count the pairs of triangles that intersect but share no vertex, finding candidates for
+/// each triangle with the BVH. Coplanar overlaps are not detected.
+fn count_self_intersections(scene: &Scene, corners: &[[usize; 3]]) -> usize {
+    let mesh = &scene.mesh;
+    (0..mesh.tris.len())
+        .into_par_iter()
+        .map(|i| {
+            let tri_id = index(i);
+            let mut count = 0;
+            scene.overlapping_tris(&mesh.tri_bbox(tri_id), |other| {
+                let j = usize(other);
+                let shares_vertex = corners[i].iter().any(|v| corners[j].contains(v));
+                if j > i && !shares_vertex && tris_intersect(mesh, tri_id, other) {
+                    count += 1;
+                }
+            });
+            count
+        })
+        .sum()
+}
+
+/// Whether an edge of either triangle passes through the other one.
+fn tris_intersect(mesh: &TriMesh, first: Index, second: Index) -> bool {
+    let (mut tri, mut other) = (mesh.corners(first), mesh.corners(second));
+    for _ in 0..2 {
+        let (a, b, c) = other;
+        for &(p, q) in &[(tri.0, tri.1), (tri.1, tri.2), (tri.2, tri.0)] {
+            let edge = watertri::RayData::new(p, q - p);
+            if edge.intersect(a, b, c).map_or(false, |isect| isect.t < 1.0) {
+                return true;
+            }
+        }
+        mem::swap(&mut tri, &mut other);
+    }
+    false
+}
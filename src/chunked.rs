@@ -0,0 +1,308 @@
+//! Out-of-core rendering for meshes that don't fit into memory as a `Scene`.
+//!
+//! The OBJ file is read twice. The first pass only keeps the vertex positions, which decide
+//! how space is cut into chunks. The second pass sorts the triangles into one temporary file
+//! per chunk. Then the chunks are loaded one at a time, nearest to the camera first, and all
+//! primary rays are traced against each of them. A BVH is only built for the chunk in memory,
+//! and rays skip every chunk that lies entirely behind the closest hit found so far.
+//!
+//! Materials, textures and secondary rays aren't supported, so this can only render depth
+//! maps, normal maps and images shaded by a light at the camera.
+
+use super::{Config, RenderKind, fail, print_timing};
+use beebox::{self, Aabb};
+use bvh::{self, NoStats};
+use camera::{Camera, CameraSample};
+use cast::{i64, usize};
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use film::{self, Colors, Depthmap, Frame, Normalmap, Rect};
+use geom::{Ray, Tri, TriMesh, index};
+use input;
+use rayon::prelude::*;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Triangles are stored in the chunk files as their three corners, nine `f32`s in total.
+const TRI_BYTES: usize = 9 * 4;
+
+struct Chunk {
+    path: PathBuf,
+    bb: Aabb,
+    tris: usize,
+}
+
+/// The state of one pixel's primary ray. `t` is the distance to the closest hit so far.
+struct PixelRay {
+    x: u32,
+    y: u32,
+    r: Ray,
+    t: f32,
+    normal: Option<Vector3<f32>>,
+}
+
+/// Render the image for `cfg` with the mesh split into (at most) `chunk_count` chunks.
+pub fn render(cfg: &Config, chunk_count: u32) {
+    match cfg.render_kind {
+        RenderKind::Depthmap | RenderKind::Normals | RenderKind::Shaded => {}
+        other => fail(&format!("--chunks can't render {:?} images", other)),
+    }
+    let input = &cfg.input_files[0];
+    let vertices = print_timing("reading vertices", || read_vertices(input));
+    let bb = Aabb::new(vertices.iter().cloned());
+    let bounds = chunk_bounds(&vertices, &bb, usize(chunk_count));
+    let chunks = print_timing("splitting mesh into chunks",
+                              || write_chunks(input, &vertices, &bounds));
+    drop(vertices);
+
+    let camera = Camera::new(cfg, &bb);
+    let window = cfg.crop.unwrap_or(Rect {
+                                        x: 0,
+                                        y: 0,
+                                        w: cfg.image_width,
+                                        h: cfg.image_height,
+                                    });
+    let mut pixels: Vec<PixelRay> = (window.y..window.y + window.h)
+        .flat_map(|y| (window.x..window.x + window.w).map(move |x| (x, y)))
+        .filter_map(|(x, y)| {
+                        camera.primary_ray(x, y, &CameraSample::center()).map(|r| {
+                            PixelRay {
+                                x,
+                                y,
+                                r,
+                                t: r.t_max,
+                                normal: None,
+                            }
+                        })
+                    })
+        .collect();
+
+    let mut order: Vec<&Chunk> = chunks.iter().filter(|chunk| chunk.tris > 0).collect();
+    let eye = camera.eye();
+    let distance2 = |chunk: &Chunk| bvh::box_distance2(&chunk.bb, eye);
+    order.sort_by(|a, b| distance2(a).partial_cmp(&distance2(b)).unwrap());
+    for (i, chunk) in order.iter().enumerate() {
+        let desc = format!("tracing chunk {}/{} ({} tris)", i + 1, order.len(), chunk.tris);
+        print_timing(&desc, || trace_chunk(cfg, chunk, &mut pixels));
+    }
+    for chunk in &chunks {
+        // Leftover temporary files are harmless, so failing to remove them isn't an error.
+        let _ = fs::remove_file(&chunk.path);
+    }
+
+    let output = save(cfg, &pixels);
+    print_timing("saving image",
+                 || film::save(&*output, &cfg.output_file).unwrap_or_else(|e| fail(&e)));
+}
+
+/// Load the chunk, build its BVH and update the closest hit of every ray that reaches it.
+fn trace_chunk(cfg: &Config, chunk: &Chunk, pixels: &mut [PixelRay]) {
+    let mut mesh = read_chunk(chunk);
+    let (bvh, tris, _) = bvh::construct(&mesh, cfg);
+    mesh = TriMesh::new(mesh.vertices, tris);
+    mesh.set_tri_isect(cfg.tri_isect);
+    mesh.set_precision(cfg.precision);
+    mesh.set_cull_backfaces(cfg.cull_backfaces);
+    pixels.par_iter_mut().for_each(|pixel| {
+        let r_box = beebox::RayData::new(pixel.r.o, pixel.r.d);
+        if !chunk.bb.intersects(&r_box, pixel.r.t_min, pixel.t) {
+            return;
+        }
+        let r = Ray { t_max: pixel.t, ..pixel.r };
+        let hit = bvh::traverse(&mesh, &bvh, &r, None, &mut NoStats);
+        if hit.is_valid() {
+            pixel.t = hit.t;
+            pixel.normal = Some(mesh.normal(hit.tri_id));
+        }
+    });
+}
+
+fn save(cfg: &Config, pixels: &[PixelRay]) -> Box<film::ToBmp> {
+    let (w, h) = (cfg.image_width, cfg.image_height);
+    match cfg.render_kind {
+        RenderKind::Depthmap => {
+            let mut frame = Frame::new(w, h, f32::INFINITY);
+            for pixel in pixels.iter().filter(|pixel| pixel.normal.is_some()) {
+                frame.set(pixel.x, pixel.y, pixel.t);
+            }
+            Box::new(Depthmap {
+                         frame,
+                         near: cfg.depth_near,
+                         far: cfg.depth_far,
+                         isoline_interval: cfg.depth_isolines,
+                         background: cfg.background,
+                     })
+        }
+        RenderKind::Normals => {
+            let mut frame = Frame::new(w, h, vec3(0.0, 0.0, 0.0));
+            for pixel in pixels {
+                if let Some(n) = pixel.normal {
+                    // Show the side facing the camera
+                    frame.set(pixel.x, pixel.y, if n.dot(pixel.r.d) > 0.0 { -n } else { n });
+                }
+            }
+            Box::new(Normalmap(frame))
+        }
+        RenderKind::Shaded => {
+            let mut frame = Frame::new(w, h, Rgb::black());
+            for pixel in pixels {
+                if let Some(n) = pixel.normal {
+                    let cos = n.dot(pixel.r.d.normalize()).abs();
+                    frame.set(pixel.x, pixel.y, Rgb::grey(cos));
+                }
+            }
+            Box::new(Colors(frame))
+        }
+        other => panic!("BUG: unhandled render kind {:?}", other),
+    }
+}
+
+fn open(path: &Path) -> Box<BufRead> {
+    input::open(path).unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)))
+}
+
+/// Call `f` with the fields of every non-empty line of the OBJ file.
+fn for_each_obj_line<F>(path: &Path, mut f: F)
+    where F: FnMut(&str, &[&str])
+{
+    for line in open(path).lines() {
+        let line = line.unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)));
+        let line = line.split('#').next().unwrap();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let Some((&keyword, args)) = fields.split_first() {
+            f(keyword, args);
+        }
+    }
+}
+
+fn read_vertices(path: &Path) -> Vec<Vector3<f32>> {
+    let mut vertices = Vec::new();
+    for_each_obj_line(path, |keyword, args| if keyword == "v" {
+        let coord = |i: usize| {
+            args.get(i)
+                .and_then(|s| s.parse().ok())
+                .unwrap_or_else(|| fail(&format!("{}: malformed vertex", path.display())))
+        };
+        vertices.push(vec3(coord(0), coord(1), coord(2)));
+    });
+    vertices
+}
+
+/// Cut the scene into slabs along the longest axis of its bounding box, such that each slab
+/// contains about as many vertices. Returns the axis and the upper boundaries of all but the
+/// last slab.
+fn chunk_bounds(vertices: &[Vector3<f32>], bb: &Aabb, chunk_count: usize) -> (usize, Vec<f32>) {
+    let extent = bb.max() - bb.min();
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        0
+    } else if extent.y >= extent.z {
+        1
+    } else {
+        2
+    };
+    let mut coords: Vec<f32> = vertices.iter()
+        .map(|v| v[axis])
+        .filter(|x| x.is_finite())
+        .collect();
+    coords.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let splits = if coords.is_empty() {
+        Vec::new()
+    } else {
+        (1..chunk_count).map(|i| coords[i * coords.len() / chunk_count]).collect()
+    };
+    (axis, splits)
+}
+
+/// Sort the (triangulated) faces into chunks by their centroids and write each chunk to a
+/// temporary file. Triangles that would mess up the BVH are dropped, like `Scene` does.
+fn write_chunks(path: &Path, vertices: &[Vector3<f32>], bounds: &(usize, Vec<f32>)) -> Vec<Chunk> {
+    let (axis, ref splits) = *bounds;
+    let mut chunks: Vec<Chunk> = (0..splits.len() + 1)
+        .map(|i| {
+                 let file_name = format!("suptracer-{}-chunk{}.bin", process::id(), i);
+                 Chunk {
+                     path: env::temp_dir().join(file_name),
+                     bb: Aabb::empty(),
+                     tris: 0,
+                 }
+             })
+        .collect();
+    let create = |chunk: &Chunk| {
+        let file = File::create(&chunk.path)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", chunk.path.display(), e)));
+        BufWriter::new(file)
+    };
+    let mut writers: Vec<BufWriter<File>> = chunks.iter().map(create).collect();
+    let mut vertices_seen = 0;
+    let mut dropped = 0;
+    for_each_obj_line(path, |keyword, args| match keyword {
+        "v" => vertices_seen += 1,
+        "f" => {
+            let corners: Vec<Vector3<f32>> = args.iter()
+                .map(|arg| vertices[vertex_index(path, arg, vertices_seen)])
+                .collect();
+            for i in 1..corners.len().saturating_sub(1) {
+                let (a, b, c) = (corners[0], corners[i], corners[i + 1]);
+                let finite = [a, b, c].iter().all(|v| v.x.is_finite() && v.y.is_finite() &&
+                                                      v.z.is_finite());
+                if !finite || (b - a).cross(c - a).magnitude2() == 0.0 {
+                    dropped += 1;
+                    continue;
+                }
+                let centroid = (a[axis] + b[axis] + c[axis]) / 3.0;
+                let id = splits.iter().take_while(|&&split| split <= centroid).count();
+                let chunk = &mut chunks[id];
+                chunk.bb = chunk.bb.union(Aabb::new([a, b, c].iter().cloned()));
+                chunk.tris += 1;
+                let coords = [a.x, a.y, a.z, b.x, b.y, b.z, c.x, c.y, c.z];
+                let mut bytes = [0; TRI_BYTES];
+                for (j, x) in coords.iter().enumerate() {
+                    bytes[4 * j..4 * j + 4].copy_from_slice(&x.to_le_bytes());
+                }
+                writers[id].write_all(&bytes)
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", chunk.path.display(), e)));
+            }
+        }
+        _ => {}
+    });
+    for (writer, chunk) in writers.iter_mut().zip(&chunks) {
+        writer.flush().unwrap_or_else(|e| fail(&format!("{}: {}", chunk.path.display(), e)));
+    }
+    if dropped > 0 {
+        println!("warning: dropped {} degenerate triangles or ones with NaN or infinite vertices",
+                 dropped);
+    }
+    chunks
+}
+
+/// The (zero-based) position index of a face corner like `7/3/2` or `-1//4`.
+fn vertex_index(path: &Path, corner: &str, vertices_seen: usize) -> usize {
+    let invalid = || format!("{}: invalid face corner {}", path.display(), corner);
+    let i: i64 = corner.split('/').next().unwrap().parse().unwrap_or_else(|_| fail(&invalid()));
+    let i = if i < 0 { i64(vertices_seen).unwrap() + i } else { i - 1 };
+    usize(i).ok().filter(|&i| i < vertices_seen).unwrap_or_else(|| fail(&invalid()))
+}
+
+fn read_chunk(chunk: &Chunk) -> TriMesh {
+    let mut bytes = Vec::with_capacity(chunk.tris * TRI_BYTES);
+    open(&chunk.path)
+        .read_to_end(&mut bytes)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", chunk.path.display(), e)));
+    let coords: Vec<f32> = bytes.chunks(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    let vertices: Vec<Vector3<f32>> = coords.chunks(3).map(|c| vec3(c[0], c[1], c[2])).collect();
+    let tris = (0..vertices.len() / 3)
+        .map(|i| {
+                 Tri {
+                     a: index(3 * i),
+                     b: index(3 * i + 1),
+                     c: index(3 * i + 2),
+                 }
+             })
+        .collect();
+    TriMesh::new(vertices, tris)
+}
@@ -1,113 +1,2282 @@
-use super::{Config, RenderKind};
-use clap::{Arg, ArgMatches, App};
+use super::{Bake, BakeMap, Config, HeatDiff, RenderKind, Renderer, SelfTest, Stereo, StereoLayout,
+            Sweep};
+use build::Builder;
+use bvh::{BvhLayout, HeatCounter, Traversal};
+use camera::Projection;
+use cast::{f32, i64, u32};
+use cgmath::{InnerSpace, Vector3, Vector4, vec3};
+use clap::{Arg, ArgMatches, ArgSettings, App, AppSettings, Error, ErrorKind, OsValues, Shell,
+           SubCommand, Values};
+use color::Rgb;
+use film::{CountsFormat, Filter, PixelOrder, Rect, Tonemap};
+use geom::{Precision, TriIsect};
+use input::{self, Format};
+use light::Light;
+use material::Material;
+use profile;
 use regex::Regex;
-use std::path::PathBuf;
+use scene::Backend;
+use shape::Shape;
+use std::{env, fmt, process};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use toml;
 
 lazy_static! {
-    static ref IMG_DIM_REGEX: Regex = Regex::new("^([:digit:]+)x([:digit:]+)$").unwrap();
-    static ref POSITIVE_INT_REGEX: Regex = Regex::new("^[:digit:]+$").unwrap();
-    static ref POSITIVE_FLOAT_REGEX: Regex = Regex::new(r"^[:digit:]+\.[:digit:]+$").unwrap();
+    static ref IMG_DIM_REGEX: Regex =
+        Regex::new("^(?:([:digit:]+)x([:digit:]+)|([:digit:]+[pk]))$").unwrap();
+}
+
+/// Named resolutions that `--dim` accepts instead of 'WxH'.
+const DIM_PRESETS: &[(&str, u32, u32)] = &[("480p", 854, 480),
+                                           ("720p", 1280, 720),
+                                           ("1080p", 1920, 1080),
+                                           ("1440p", 2560, 1440),
+                                           ("2k", 2048, 1080),
+                                           ("4k", 3840, 2160),
+                                           ("8k", 7680, 4320)];
+
+/// The width and height of 'WxH' or of a preset such as '1080p'.
+fn parse_img_dim(s: &str) -> Option<(u32, u32)> {
+    let c = IMG_DIM_REGEX.captures(s)?;
+    match c.at(3) {
+        Some(preset) => {
+            DIM_PRESETS
+                .iter()
+                .find(|&&(name, _, _)| name == preset)
+                .map(|&(_, w, h)| (w, h))
+        }
+        None => Some((c[1].parse().ok()?, c[2].parse().ok()?)),
+    }
 }
 
 fn is_img_dim(s: String) -> Result<(), String> {
-    if IMG_DIM_REGEX.is_match(&s) {
+    if parse_img_dim(&s).is_some() {
         Ok(())
     } else {
-        Err("Value must be 'WxH' where W and H are positive integers".to_string())
+        let presets: Vec<&str> = DIM_PRESETS.iter().map(|&(name, _, _)| name).collect();
+        Err(format!("Value must be 'WxH' where W and H are positive integers, or one of {}",
+                    presets.join(", ")))
     }
 }
 
-fn is_positive_int(s: String) -> Result<(), String> {
-    if POSITIVE_INT_REGEX.is_match(&s) {
+/// The number in a percentage such as '50%'.
+fn parse_percentage(s: &str) -> Option<f32> {
+    if !s.ends_with('%') {
+        return None;
+    }
+    match s[..s.len() - 1].parse::<f32>() {
+        Ok(p) if p.is_finite() && p > 0.0 => Some(p),
+        _ => None,
+    }
+}
+
+fn is_percentage(s: String) -> Result<(), String> {
+    if parse_percentage(&s).is_some() {
+        Ok(())
+    } else {
+        Err("Value must be a positive percentage such as 50%".to_string())
+    }
+}
+
+fn is_crop_window(s: String) -> Result<(), String> {
+    if parse_list::<u32>(&s).map(|c| c.len()) == Some(4) {
+        Ok(())
+    } else {
+        Err("Value must be 'x,y,w,h' where x, y, w, h are positive integers".to_string())
+    }
+}
+
+fn is_pixel(s: String) -> Result<(), String> {
+    if parse_list::<u32>(&s).map(|c| c.len()) == Some(2) {
+        Ok(())
+    } else {
+        Err("Value must be 'X,Y' where X and Y are positive integers".to_string())
+    }
+}
+
+fn parse_vec3(s: &str) -> Option<Vector3<f32>> {
+    let components: Vec<f32> = match s.split(',').map(|c| c.trim().parse()).collect() {
+        Ok(components) => components,
+        Err(_) => return None,
+    };
+    if components.len() == 3 {
+        Some(vec3(components[0], components[1], components[2]))
+    } else {
+        None
+    }
+}
+
+fn is_vec3(s: String) -> Result<(), String> {
+    if parse_vec3(&s).is_some() {
         Ok(())
     } else {
-        Err("Value must be a positive integer".to_string())
+        Err("Value must be 'X,Y,Z' where X, Y, Z are numbers".to_string())
+    }
+}
+
+/// A plane 'A,B,C,D', i.e., the points where A*x + B*y + C*z + D = 0.
+fn parse_plane(s: &str) -> Option<Vector4<f32>> {
+    let coefficients: Vec<f32> = match s.split(',').map(|c| c.trim().parse()).collect() {
+        Ok(coefficients) => coefficients,
+        Err(_) => return None,
+    };
+    if coefficients.len() != 4 {
+        return None;
+    }
+    let plane = Vector4::new(coefficients[0], coefficients[1], coefficients[2], coefficients[3]);
+    if plane.truncate().magnitude2() > 0.0 {
+        Some(plane)
+    } else {
+        None
+    }
+}
+
+fn is_plane(s: String) -> Result<(), String> {
+    if parse_plane(&s).is_some() {
+        Ok(())
+    } else {
+        Err("Value must be 'A,B,C,D' where A, B, C, D are numbers and A, B, C aren't all zero"
+                .to_string())
+    }
+}
+
+fn parse_light(s: &str) -> Option<Light> {
+    let parts: Vec<&str> = s.split(':').collect();
+    // Area lights take an optional radiance as the last part.
+    let radiance = |i: usize| match parts.get(i) {
+        Some(l) => l.parse().ok().map(Rgb::grey),
+        None => Some(Rgb::grey(1.0)),
+    };
+    let vec = |i: usize| parts.get(i).and_then(|p| parse_vec3(p));
+    let irradiance = Rgb::grey(1.0);
+    match (parts[0], parts.len()) {
+        ("dir", 2) => {
+            vec(1).and_then(|dir| if dir.magnitude2() > 0.0 {
+                                Some(Light::Directional {
+                                         dir: dir.normalize(),
+                                         irradiance,
+                                     })
+                            } else {
+                                None
+                            })
+        }
+        ("point", 2) => vec(1).map(|pos| Light::Point { pos, irradiance }),
+        ("rect", 4) | ("rect", 5) => {
+            match (vec(1), vec(2), vec(3), radiance(4)) {
+                (Some(corner), Some(edge_u), Some(edge_v), Some(radiance))
+                    if edge_u.cross(edge_v).magnitude2() > 0.0 => {
+                    Some(Light::Rect {
+                             corner,
+                             edge_u,
+                             edge_v,
+                             radiance,
+                         })
+                }
+                _ => None,
+            }
+        }
+        ("disk", 4) | ("disk", 5) => {
+            match (vec(1), vec(2), parts[3].parse::<f32>(), radiance(4)) {
+                (Some(center), Some(normal), Ok(radius), Some(radiance))
+                    if normal.magnitude2() > 0.0 && radius > 0.0 => {
+                    Some(Light::Disk {
+                             center,
+                             normal: normal.normalize(),
+                             radius,
+                             radiance,
+                         })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Index of refraction of `glass` shapes.
+const SHAPE_GLASS_IOR: f32 = 1.5;
+
+fn parse_shape(s: &str) -> Option<(Shape, Material)> {
+    let parts: Vec<&str> = s.split(':').collect();
+    let vec = |i: usize| parts.get(i).and_then(|p| parse_vec3(p));
+    // The material is an optional last part.
+    let material = |i: usize| match parts.get(i).cloned() {
+        None => Some(Material::default()),
+        Some("mirror") => Some(Material::Mirror { reflectance: Rgb::grey(1.0) }),
+        Some("glass") => Some(Material::Glass { ior: SHAPE_GLASS_IOR }),
+        Some(albedo) => {
+            parse_vec3(albedo).map(|c| Material::Diffuse { albedo: Rgb::new(c.x, c.y, c.z) })
+        }
+    };
+    let shape = match (parts[0], parts.len()) {
+        ("sphere", 3) | ("sphere", 4) => {
+            match (vec(1), parts[2].parse::<f32>()) {
+                (Some(center), Ok(radius)) if radius > 0.0 => Shape::Sphere { center, radius },
+                _ => return None,
+            }
+        }
+        ("quad", 4) | ("quad", 5) => {
+            match (vec(1), vec(2), vec(3)) {
+                (Some(corner), Some(edge_u), Some(edge_v))
+                    if edge_u.cross(edge_v).magnitude2() > 0.0 => {
+                    Shape::Quad {
+                        corner,
+                        edge_u,
+                        edge_v,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    let material_part = match shape {
+        Shape::Sphere { .. } => 3,
+        Shape::Quad { .. } => 4,
+    };
+    material(material_part).map(|m| (shape, m))
+}
+
+/// The inverse of `parse_shape`.
+fn format_shape(&(shape, material): &(Shape, Material)) -> String {
+    let vec = |v: Vector3<f32>| format!("{},{},{}", v.x, v.y, v.z);
+    let shape = match shape {
+        Shape::Sphere { center, radius } => format!("sphere:{}:{}", vec(center), radius),
+        Shape::Quad { corner, edge_u, edge_v } => {
+            format!("quad:{}:{}:{}", vec(corner), vec(edge_u), vec(edge_v))
+        }
+    };
+    // Shapes from the command line only have these materials.
+    match material {
+        Material::Mirror { .. } => format!("{}:mirror", shape),
+        Material::Glass { .. } => format!("{}:glass", shape),
+        m => {
+            let albedo = m.albedo();
+            format!("{}:{},{},{}", shape, albedo.r, albedo.g, albedo.b)
+        }
+    }
+}
+
+fn is_shape(s: String) -> Result<(), String> {
+    if parse_shape(&s).is_some() {
+        Ok(())
+    } else {
+        Err("Value must be 'sphere:CENTER:RADIUS[:MATERIAL]' or \
+             'quad:CORNER:EDGE1:EDGE2[:MATERIAL]', where MATERIAL is 'mirror', 'glass' or an \
+             albedo R,G,B"
+                .to_string())
+    }
+}
+
+/// The inverse of `parse_light`.
+fn format_light(light: &Light) -> String {
+    let vec = |v: Vector3<f32>| format!("{},{},{}", v.x, v.y, v.z);
+    // Lights from the command line are always white.
+    match *light {
+        Light::Directional { dir, .. } => format!("dir:{}", vec(dir)),
+        Light::Point { pos, .. } => format!("point:{}", vec(pos)),
+        Light::Rect { corner, edge_u, edge_v, radiance } => {
+            format!("rect:{}:{}:{}:{}", vec(corner), vec(edge_u), vec(edge_v), radiance.r)
+        }
+        Light::Disk { center, normal, radius, radiance } => {
+            format!("disk:{}:{}:{}:{}", vec(center), vec(normal), radius, radiance.r)
+        }
+    }
+}
+
+fn is_light(s: String) -> Result<(), String> {
+    if parse_light(&s).is_some() {
+        Ok(())
+    } else {
+        Err("Value must be 'dir:X,Y,Z' (direction towards the light), 'point:X,Y,Z', \
+             'rect:CORNER:EDGE1:EDGE2[:RADIANCE]', or 'disk:CENTER:NORMAL:RADIUS[:RADIANCE]'"
+                .to_string())
+    }
+}
+
+fn is_float(s: String) -> Result<(), String> {
+    if s.parse::<f32>().map(|x| x.is_finite()).unwrap_or(false) {
+        Ok(())
+    } else {
+        Err("Value must be a number".to_string())
+    }
+}
+
+fn is_positive_int(s: String) -> Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("Value must be a positive integer, at most {}", u32::max_value())),
+    }
+}
+
+/// For numbers of frames and the like, where 0 makes no sense.
+fn is_nonzero_int(s: String) -> Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("Value must be an integer from 1 to {}", u32::max_value())),
     }
 }
 
 fn is_positive_float(s: String) -> Result<(), String> {
-    if POSITIVE_FLOAT_REGEX.is_match(&s) {
+    match s.parse::<f32>() {
+        Ok(x) if x.is_finite() && x >= 0.0 => Ok(()),
+        _ => Err("Value must be a positive number, such as 2, .5 or 1e-3".to_string()),
+    }
+}
+
+fn is_port(s: String) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(()),
+        _ => Err("Value must be a port number between 1 and 65535".to_string()),
+    }
+}
+
+fn is_ip_addr(s: String) -> Result<(), String> {
+    s.parse::<IpAddr>().map(|_| ()).map_err(|_| "Value must be an IP address".to_string())
+}
+
+fn is_fraction(s: String) -> Result<(), String> {
+    match s.parse::<f32>() {
+        Ok(x) if 0.0 < x && x <= 1.0 => Ok(()),
+        _ => Err("Value must be a number greater than 0 and at most 1".to_string()),
+    }
+}
+
+fn parse_list<T: FromStr>(s: &str) -> Option<Vec<T>> {
+    s.split(',').map(|x| x.trim().parse().ok()).collect()
+}
+
+fn is_int_list(s: String) -> Result<(), String> {
+    match parse_list::<u32>(&s) {
+        Some(ref values) if values.iter().all(|&x| x > 0) => Ok(()),
+        _ => Err("Value must be a comma separated list of positive integers".to_string()),
+    }
+}
+
+fn parse_depth_range(s: &str) -> Option<(f32, f32)> {
+    match parse_list::<f32>(s) {
+        Some(ref range) if range.len() == 2 && 0.0 <= range[0] && range[0] < range[1] => {
+            Some((range[0], range[1]))
+        }
+        _ => None,
+    }
+}
+
+fn is_depth_range(s: String) -> Result<(), String> {
+    if parse_depth_range(&s).is_some() {
         Ok(())
     } else {
-        Err("Value must be a positive number of the form 12.34".to_string())
+        Err("Value must be NEAR,FAR with 0 <= NEAR < FAR".to_string())
     }
 }
 
+fn is_float_list(s: String) -> Result<(), String> {
+    match parse_list::<f32>(&s) {
+        Some(ref values) if values.iter().all(|&x| x >= 0.0) => Ok(()),
+        _ => Err("Value must be a comma separated list of non-negative numbers".to_string()),
+    }
+}
+
+/// The options for rendering. The subcommands that render take them, and so does the top level,
+/// where they also apply to `sweep` and `heat-diff` and `suptracer FILE ...` renders like
+/// `suptracer render FILE ...`.
+fn render_args() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("dim")
+             .short("d")
+             .long("dim")
+             .help("the size of the image to render, as WxH or one of 480p, 720p, 1080p, \
+                    1440p, 2k, 4k and 8k")
+             .value_name("DIM")
+             .default_value("1280x720")
+             .validator(is_img_dim),
+         Arg::with_name("scale")
+             .long("scale")
+             .help("Scale the size given by --dim (or the config file) by this percentage, \
+                    e.g. 25% for quick previews of a 4k render")
+             .value_name("PERCENT")
+             .validator(is_percentage),
+         Arg::with_name("buckets")
+             .short("b")
+             .long("buckets")
+             .help("Number of buckets to use in SAH-guided BVH construction")
+             .value_name("N")
+             .default_value("16")
+             .validator(is_positive_int),
+         Arg::with_name("out")
+             .short("o")
+             .long("out")
+             .help("File name for output, a BMP unless it ends in .png, which writes depth \
+                    maps as 16-bit greyscale PNG (near is 0, far and nothing hit are 65535) \
+                    and masks as 1-bit PNG and ID images as 16-bit greyscale PNG (and any \
+                    image as RGBA PNG with --transparent)")
+             .value_name("FILE")
+             .required(false),
+         Arg::with_name("transparent")
+             .long("transparent")
+             .help("Write the image as an RGBA PNG (so --out must end in .png) whose alpha \
+                    is the fraction of the primary rays of each pixel that hit something, \
+                    for compositing over other backgrounds")
+             .conflicts_with_all(&["chunks", "interactive", "overlay-bvh"]),
+         Arg::with_name("stream")
+             .long("stream")
+             .help("Render ROWS rows at a time and append them to the output file (which \
+                    must end in .png, and is always written as RGB) before rendering the \
+                    next ones, so that the full image never has to fit in memory. Depth \
+                    and thickness maps need a fixed --depth-range, heatmaps and layers \
+                    can't be streamed")
+             .value_name("ROWS")
+             .validator(is_positive_int)
+             .conflicts_with_all(&["chunks", "interactive", "watch", "serve", "crop",
+                                   "debug-pixel", "overlay-bvh", "transparent", "stereo",
+                                   "turntable", "camera-path", "spin", "denoise"]),
+         Arg::with_name("weld-epsilon")
+             .long("weld-epsilon")
+             .help("Before building the BVH, merge vertices at most EPS apart and remove \
+                    duplicated triangles")
+             .value_name("EPS")
+             .validator(is_positive_float),
+         Arg::with_name("decimate")
+             .long("decimate")
+             .help("Before building the BVH, simplify the mesh to about RATIO (0 to 1) of its \
+                    triangles by collapsing edges, for quick previews of huge scans")
+             .value_name("RATIO")
+             .validator(is_fraction),
+         Arg::with_name("tessellate")
+             .long("tessellate")
+             .help("Before building the BVH, split every triangle into four at its edge \
+                    midpoints N times, multiplying the triangle count by 4^N, to test how \
+                    things scale without hunting for larger models")
+             .value_name("N")
+             .validator(is_positive_int)
+             .conflicts_with("decimate"),
+         Arg::with_name("sah-tcost")
+             .long("sah-tcost")
+             .help("Relative cost of BVH traversal step compared to triangle intersection")
+             .value_name("COST")
+             .default_value("1.0")
+             .validator(is_positive_float),
+         Arg::with_name("bvh-builder")
+             .long("bvh-builder")
+             .help("How to build the BVH. 'median' and 'middle' (object median and spatial \
+                    middle splits) are fast but make worse trees, for comparison with 'sah'")
+             .default_value("sah")
+             .possible_values(&["sah", "median", "middle"]),
+         Arg::with_name("bvh-optimize")
+             .long("bvh-optimize")
+             .help("Improve the BVH with tree rotations after building it"),
+         Arg::with_name("max-leaf-tris")
+             .long("max-leaf-tris")
+             .help("Split BVH leaves with more triangles than this. Subtrees with at most \
+                    this many triangles are merged into one leaf if that's cheaper according \
+                    to the SAH")
+             .value_name("N")
+             .default_value("16")
+             .validator(is_positive_int),
+         Arg::with_name("traversal")
+             .long("traversal")
+             .help("How to trace primary rays through the BVH. 'packet' traces the rays of \
+                    4x4 pixel tiles together, which is faster for coherent rays. 'frustum' \
+                    first finds the nodes that each 16x16 pixel tile can see and starts \
+                    traversal there (pinhole cameras only). 'stackless' climbs back up the \
+                    tree through parent links instead of using a stack (with `bench`, it's \
+                    compared to 'single'). Secondary rays are always traced one by one from \
+                    the root")
+             .default_value("single")
+             .possible_values(&["single", "packet", "frustum", "stackless"]),
+         Arg::with_name("renderer")
+             .long("renderer")
+             .help("How rendering is organized. 'megakernel' traces and shades each pixel \
+                    in one go. 'wavefront' generates the primary rays of 64x64 pixel tiles \
+                    first, traces them sorted by direction octant and origin, then shades \
+                    all hits (needs --traversal single)")
+             .default_value("megakernel")
+             .possible_values(&["megakernel", "wavefront"]),
+         Arg::with_name("pixel-order")
+             .long("pixel-order")
+             .help("Order in which pixels (or with --traversal packet/frustum, tiles) are \
+                    handed out to the threads tracing primary rays. The space-filling curves \
+                    keep the rays of each thread closer together")
+             .default_value("scanline")
+             .possible_values(&["scanline", "morton", "hilbert"]),
+         Arg::with_name("bvh-layout")
+             .long("bvh-layout")
+             .help("Memory layout of the BVH nodes. 'compressed' quantizes the boxes to 8 \
+                    bits per coordinate, halving the size of the nodes")
+             .default_value("full")
+             .possible_values(&["full", "compressed"]),
+         Arg::with_name("tri-isect")
+             .long("tri-isect")
+             .help("Ray/triangle intersection algorithm. 'woop' precomputes a transform per \
+                    triangle to make each test cheaper, but isn't watertight")
+             .default_value("watertight")
+             .possible_values(&["watertight", "woop"]),
+         Arg::with_name("precision")
+             .long("precision")
+             .help("Precision of ray/box and ray/triangle tests. 'f64' helps to tell whether \
+                    cracks in models far from the origin come from rounding; it always uses \
+                    watertight triangle tests")
+             .default_value("f32")
+             .possible_values(&["f32", "f64"]),
+         Arg::with_name("backend")
+             .long("backend")
+             .help("What finds the closest hits of primary rays and bounces. 'embree' needs \
+                    the 'embree' feature and serves as a reference for the native BVH; it \
+                    ignores cutouts and --cull-backfaces and records no traversal stats")
+             .default_value("native")
+             .possible_values(&["native", "embree"]),
+         Arg::with_name("cull-backfaces")
+             .long("cull-backfaces")
+             .help("Ignore hits on the back side of triangles, including for shadow rays. \
+                    Faster and cleaner for closed shells; inverted normals show up as holes"),
+         Arg::with_name("input")
+             .help("OBJ, PLY, STL or point cloud (.xyz, .pts) files to render, optionally \
+                    compressed (.gz, .zst or a .zip with just the mesh file), or '-' to read \
+                    from stdin. Multiple files are merged into one scene")
+             .value_name("FILE")
+             .multiple(true)
+             .index(1),
+         Arg::with_name("input-format")
+             .long("input-format")
+             .help("Format of all input files [default: by extension, OBJ for stdin]. \
+                    'xyz' is for point clouds in .xyz or .pts files, which are rendered as \
+                    small splats, like PLY files without faces")
+             .possible_values(&["obj", "ply", "stl", "xyz"])
+             .required(false),
+         Arg::with_name("part-color")
+             .long("part-color")
+             .help("Give all surfaces of an input file this albedo, to tell the parts of an \
+                    assembly apart. The first --part-color is for the first input file, \
+                    and so on; files without one keep their materials")
+             .value_name("R,G,B")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(is_vec3)
+             .allow_hyphen_values(true),
+         Arg::with_name("only-group")
+             .long("only-group")
+             .help("Only load the triangles of the OBJ group (g or o) with this name. Can be \
+                    given multiple times to load several groups")
+             .value_name("NAME")
+             .multiple(true)
+             .number_of_values(1),
+         Arg::with_name("exclude-group")
+             .long("exclude-group")
+             .help("Leave out the triangles of the OBJ group (g or o) with this name. Can be \
+                    given multiple times")
+             .value_name("NAME")
+             .multiple(true)
+             .number_of_values(1),
+         Arg::with_name("color-groups")
+             .long("color-groups")
+             .help("Make every surface diffuse with a color that identifies its OBJ group"),
+         Arg::with_name("kind")
+             .short("k")
+             .long("kind")
+             .help("Kind of render to create")
+             .default_value("depth")
+             .possible_values(&["depth", "heat", "normal", "shaded", "path", "uv",
+                                "vertex-color", "layers", "mask", "object-id",
+                                "material-id", "thickness"]),
+         Arg::with_name("heat-counter")
+             .long("heat-counter")
+             .help("What heatmaps count per primary ray: BVH boxes tested, nodes visited \
+                    (boxes hit), leaves visited or triangles tested")
+             .default_value("boxes")
+             .possible_values(&["boxes", "nodes", "leaves", "tris"]),
+         Arg::with_name("raw-counts")
+             .long("raw-counts")
+             .help("Also write the exact per-pixel counts of heat and layers renders next to \
+                    the image (with the extension .csv or .u32), as CSV with a line per row \
+                    or as little-endian u32s in row-major order")
+             .possible_values(&["csv", "bin"])
+             .required(false),
+         Arg::with_name("depth-range")
+             .long("depth-range")
+             .help("Shorthand for --depth-near NEAR --depth-far FAR")
+             .value_name("NEAR,FAR")
+             .required(false)
+             .validator(is_depth_range)
+             .conflicts_with_all(&["depth-near", "depth-far"]),
+         Arg::with_name("depth-near")
+             .long("depth-near")
+             .help("Depth that depth and thickness maps show as nearest, closer ones are \
+                    clamped [default: the closest depth in the image]")
+             .value_name("DIST")
+             .required(false)
+             .validator(is_float),
+         Arg::with_name("depth-far")
+             .long("depth-far")
+             .help("Depth that depth and thickness maps show as farthest, farther ones are \
+                    clamped [default: the farthest depth in the image]")
+             .value_name("DIST")
+             .required(false)
+             .validator(is_float),
+         Arg::with_name("depth-isolines")
+             .long("depth-isolines")
+             .help("Draw red contour lines on depth maps wherever the depth crosses a \
+                    multiple of DIST")
+             .value_name("DIST")
+             .required(false)
+             .validator(is_positive_float),
+         Arg::with_name("overlay-bvh")
+             .long("overlay-bvh")
+             .help("Draw the boxes of the BVH nodes up to DEPTH levels below the root in \
+                    green on top of the image (only with the pinhole projection)")
+             .value_name("DEPTH")
+             .required(false)
+             .validator(is_positive_int)
+             .conflicts_with_all(&["chunks", "interactive"]),
+         Arg::with_name("stereo")
+             .long("stereo")
+             .help("Render a view for each eye, from eyes SEPARATION apart along the \
+                    camera's right axis, e.g. for previews in VR headsets")
+             .value_name("SEPARATION")
+             .validator(is_positive_float)
+             .conflicts_with_all(&["chunks", "interactive", "debug-pixel", "overlay-bvh",
+                                   "transparent"]),
+         Arg::with_name("stereo-layout")
+             .long("stereo-layout")
+             .help("Put the views of both eyes side by side in one image, the left eye's on \
+                    the left, or write them to separate files with '_left' and '_right' \
+                    added to the name")
+             .default_value("side-by-side")
+             .possible_values(&["side-by-side", "separate"]),
+         Arg::with_name("crop")
+             .long("crop")
+             .help("Only render the given sub-rectangle of the image")
+             .value_name("x,y,w,h")
+             .required(false)
+             .validator(is_crop_window),
+         Arg::with_name("debug-pixel")
+             .long("debug-pixel")
+             .help("Trace only this pixel and print a log of the BVH traversal")
+             .value_name("X,Y")
+             .required(false)
+             .validator(is_pixel),
+         Arg::with_name("info")
+             .long("info")
+             .help("Print statistics about the mesh and its BVH instead of rendering")
+             .conflicts_with_all(&["debug-pixel", "interactive", "watch"]),
+         // The `bench` subcommand, from before there was one.
+         Arg::with_name("bench")
+             .long("bench")
+             .hidden(true)
+             .conflicts_with_all(&["info", "debug-pixel", "interactive", "watch"]),
+         Arg::with_name("validate")
+             .long("validate")
+             .help("Check that the primary rays of the first image find the same hits with \
+                    both --bvh-layout options, without rendering")
+             .conflicts_with_all(&["info", "bench", "debug-pixel", "interactive", "watch"]),
+         Arg::with_name("check-determinism")
+             .long("check-determinism")
+             .help("Render the first image on one thread and on all threads and check that \
+                    both are bit-identical, without saving anything")
+             .conflicts_with_all(&["info", "bench", "validate", "debug-pixel", "interactive",
+                                   "watch"]),
+         Arg::with_name("chunks")
+             .long("chunks")
+             .help("Render scenes too large for memory by splitting the mesh into N chunks \
+                    in temporary files and tracing one at a time (only primary rays, so no \
+                    materials or path tracing)")
+             .value_name("N")
+             .required(false)
+             .validator(is_positive_int)
+             .conflicts_with_all(&["info", "bench", "validate", "check-determinism",
+                                   "debug-pixel", "interactive", "watch", "turntable",
+                                   "camera-path", "spin", "shape"]),
+         Arg::with_name("stats-out")
+             .long("stats-out")
+             .help("Write statistics (the memory usage and the coherence of the primary \
+                    rays) to a TOML file")
+             .value_name("FILE"),
+         Arg::with_name("no-autoframe")
+             .long("no-autoframe")
+             .help("Don't position the camera automatically so that it sees the whole scene"),
+         Arg::with_name("eye")
+             .long("eye")
+             .help("Camera position (only used with --no-autoframe)")
+             .value_name("X,Y,Z")
+             .default_value("0,0,0")
+             .validator(is_vec3)
+             .allow_hyphen_values(true),
+         Arg::with_name("look-at")
+             .long("look-at")
+             .help("Point the camera looks at (only used with --no-autoframe)")
+             .value_name("X,Y,Z")
+             .default_value("0,0,-1")
+             .validator(is_vec3)
+             .allow_hyphen_values(true),
+         Arg::with_name("fov")
+             .long("fov")
+             .help("Horizontal field of view of the camera, in degrees")
+             .value_name("DEGREES")
+             .default_value("60.0")
+             .validator(is_positive_float),
+         Arg::with_name("projection")
+             .long("projection")
+             .help("How the camera maps pixels to ray directions")
+             .default_value("pinhole")
+             .possible_values(&["pinhole", "equirect", "fisheye"]),
+         Arg::with_name("fisheye-angle")
+             .long("fisheye-angle")
+             .help("Field of view of the fisheye projection, in degrees")
+             .value_name("DEGREES")
+             .default_value("180.0")
+             .validator(is_positive_float),
+         Arg::with_name("spp")
+             .long("spp")
+             .help("Number of samples per pixel")
+             .value_name("N")
+             .default_value("1")
+             .validator(is_positive_int),
+         Arg::with_name("passes")
+             .long("passes")
+             .help("Render N times with different (jittered) samples and average the images \
+                    as displayed, like the interactive viewer does while the camera rests")
+             .value_name("N")
+             .default_value("1")
+             .validator(is_positive_int),
+         Arg::with_name("aperture")
+             .long("aperture")
+             .help("Diameter of the camera lens, zero for a pinhole camera")
+             .value_name("SIZE")
+             .default_value("0.0")
+             .validator(is_positive_float),
+         Arg::with_name("focus-dist")
+             .long("focus-dist")
+             .help("Distance from the camera to the plane in focus [default: distance to \
+                    the point the camera looks at]")
+             .value_name("DIST")
+             .required(false)
+             .validator(is_positive_float),
+         Arg::with_name("clip")
+             .long("clip")
+             .help("Only show what's on the side of the plane where A*x + B*y + C*z + D >= 0 \
+                    to primary rays, to render a cross-section of the model. Can be given \
+                    multiple times to clip to the intersection of the half-spaces")
+             .value_name("A,B,C,D")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(is_plane)
+             .allow_hyphen_values(true),
+         Arg::with_name("turntable")
+             .long("turntable")
+             .help("Render N frames with the camera orbiting the scene, numbering the \
+                    output files")
+             .value_name("N")
+             .required(false)
+             .validator(is_nonzero_int),
+         Arg::with_name("camera-path")
+             .long("camera-path")
+             .help("Render one frame per time step along the keyframed camera path in FILE")
+             .value_name("FILE")
+             .required(false)
+             .conflicts_with("turntable"),
+         Arg::with_name("fps")
+             .long("fps")
+             .help("Frames per second of camera path animations")
+             .value_name("FPS")
+             .default_value("24.0")
+             .validator(is_positive_float),
+         Arg::with_name("spin")
+             .long("spin")
+             .help("Render N frames of the model spinning around the vertical axis, \
+                    refitting the BVH for each frame")
+             .value_name("N")
+             .required(false)
+             .validator(is_nonzero_int)
+             .conflicts_with_all(&["turntable", "camera-path"]),
+         Arg::with_name("interactive")
+             .long("interactive")
+             .help("Open a window and explore the scene (WASD/QE to move, drag the mouse to \
+                    look around, 1/2/3/4 to switch between depth/heat/normal/shaded)")
+             .conflicts_with_all(&["turntable", "camera-path", "spin", "debug-pixel"]),
+         Arg::with_name("watch")
+             .long("watch")
+             .help("Keep running and render again whenever the input files, their MTL files \
+                    and textures, or the other files the scene is loaded from change")
+             .conflicts_with_all(&["interactive", "debug-pixel"]),
+         Arg::with_name("serve")
+             .long("serve")
+             .help("Serve the image over HTTP while it's rendered, as an MJPEG stream at / \
+                    and as a PNG snapshot at /frame.png, updated after every pass (see \
+                    --passes)")
+             .value_name("PORT")
+             .validator(is_port)
+             .conflicts_with_all(&["interactive", "debug-pixel"]),
+         Arg::with_name("serve-address")
+             .long("serve-address")
+             .help("Address to serve on. The default only lets this machine connect; \
+                    0.0.0.0 lets any machine that can reach it connect")
+             .value_name("ADDR")
+             .default_value("127.0.0.1")
+             .validator(is_ip_addr),
+         Arg::with_name("envmap")
+             .long("envmap")
+             .help("Equirectangular Radiance HDR image lighting the scene from all directions \
+                    [default: uniform white]")
+             .value_name("FILE")
+             .required(false),
+         Arg::with_name("volume")
+             .long("volume")
+             .help("Density grid in Mitsuba's .vol format (one float32 channel) to render \
+                    along with the mesh in shaded and path traced images. Primary rays \
+                    march through it with an emission/absorption model")
+             .value_name("FILE")
+             .required(false),
+         Arg::with_name("volume-absorption")
+             .long("volume-absorption")
+             .help("Fraction of the light absorbed per unit of density and length")
+             .value_name("SIGMA")
+             .default_value("1.0")
+             .validator(is_positive_float),
+         Arg::with_name("volume-emission")
+             .long("volume-emission")
+             .help("Radiance emitted per unit of density and length")
+             .value_name("R,G,B")
+             .default_value("0,0,0")
+             .validator(is_vec3),
+         Arg::with_name("background")
+             .long("background")
+             .help("Color of the pixels where primary rays hit nothing, instead of blue in \
+                    depth maps, black in UV and vertex color images and the environment in \
+                    shaded and path traced ones. Depth maps, UV and vertex color images show \
+                    it as is (in [0, 1]); in shaded and path traced images it's radiance, \
+                    exposed and tone mapped like the rest. Other images, e.g. heatmaps, \
+                    ignore it")
+             .value_name("R,G,B")
+             .validator(is_vec3),
+         Arg::with_name("sky")
+             .long("sky")
+             .help("Light the scene with an analytic daylight sky and sun")
+             .conflicts_with("envmap"),
+         Arg::with_name("sun-elevation")
+             .long("sun-elevation")
+             .help("Angle of the sun above the horizon, in degrees")
+             .value_name("DEGREES")
+             .default_value("45.0")
+             .validator(is_float)
+             .allow_hyphen_values(true),
+         Arg::with_name("sun-azimuth")
+             .long("sun-azimuth")
+             .help("Direction of the sun in degrees, 0 is -Z and 90 is +X")
+             .value_name("DEGREES")
+             .default_value("30.0")
+             .validator(is_float)
+             .allow_hyphen_values(true),
+         Arg::with_name("turbidity")
+             .long("turbidity")
+             .help("Haziness of the sky, from 2.0 (very clear) to 10.0 (hazy)")
+             .value_name("T")
+             .default_value("3.0")
+             .validator(is_positive_float),
+         Arg::with_name("light")
+             .long("light")
+             .help("Add a light, can be given multiple times. Besides directional and point \
+                    lights, there are one-sided rectangular and disk-shaped area lights \
+                    (e.g. 'rect:-1,2,-1:0,0,2:2,0,0:5.0' or 'disk:0,2,0:0,-1,0:0.5'). \
+                    [default for the shaded render: a point light at the camera]")
+             .value_name("KIND:PARAMS")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(is_light)
+             .allow_hyphen_values(true),
+         Arg::with_name("shape")
+             .long("shape")
+             .help("Add a sphere or a (two-sided) quad to the scene, can be given multiple \
+                    times (e.g. 'sphere:0,1,0:0.5:glass' or \
+                    'quad:-1,0,-1:0,0,2:2,0,0:0.8,0.1,0.1'). Without a material, it's diffuse \
+                    with the default albedo")
+             .value_name("KIND:PARAMS")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(is_shape)
+             .allow_hyphen_values(true),
+         Arg::with_name("light-samples")
+             .long("light-samples")
+             .help("Number of shadow rays towards each area light per shading point")
+             .value_name("N")
+             .default_value("16")
+             .validator(is_positive_int),
+         Arg::with_name("tonemap")
+             .long("tonemap")
+             .help("How to map radiance to the displayable range in shaded and path traced \
+                    renders")
+             .default_value("linear")
+             .possible_values(&["linear", "reinhard", "aces"]),
+         Arg::with_name("exposure")
+             .long("exposure")
+             .help("Brighten (or, if negative, darken) the image by this many stops before \
+                    tone mapping")
+             .value_name("STOPS")
+             .default_value("0.0")
+             .validator(is_float)
+             .allow_hyphen_values(true),
+         Arg::with_name("filter")
+             .long("filter")
+             .help("Reconstruction filter for combining the samples of shaded and path traced \
+                    renders into pixels (only used with --spp > 1)")
+             .default_value("box")
+             .possible_values(&["box", "tent", "gaussian", "mitchell"]),
+         Arg::with_name("clamp")
+             .long("clamp")
+             .help("Scale down path traced samples whose brightest channel exceeds this, to \
+                    suppress fireflies at the cost of some energy")
+             .value_name("VALUE")
+             .validator(is_positive_float),
+         Arg::with_name("denoise")
+             .long("denoise")
+             .help("Smooth out the noise of path traced images with an edge-aware filter \
+                    guided by the normals and depth of each pixel"),
+         Arg::with_name("no-mis")
+             .long("no-mis")
+             .help("Only sample lights directly when path tracing, instead of combining that \
+                    with the directions sampled from materials (for comparison renders)"),
+         Arg::with_name("max-depth")
+             .long("max-depth")
+             .help("Maximum number of bounces in path tracing. Most paths end earlier \
+                    because of Russian roulette, see --rr-depth")
+             .value_name("N")
+             .default_value("32")
+             .validator(is_positive_int),
+         Arg::with_name("rr-depth")
+             .long("rr-depth")
+             .help("Number of bounces after which paths are randomly terminated (Russian \
+                    roulette), with a chance that depends on how much light they carry")
+             .value_name("N")
+             .default_value("3")
+             .validator(is_positive_int),
+         Arg::with_name("t-min")
+             .long("t-min")
+             .help("Ignore intersections closer than this to the origin of shadow rays and \
+                    bounces, as a last line of defense against self-intersection")
+             .value_name("T")
+             .default_value("0.0")
+             .validator(is_positive_float),
+         Arg::with_name("ray-offset")
+             .long("ray-offset")
+             .help("Scale the distance by which shadow rays and bounces start off the \
+                    surface (0 disables the offset)")
+             .value_name("SCALE")
+             .default_value("1.0")
+             .validator(is_positive_float)]
+}
+
 pub fn build_app() -> App<'static, 'static> {
     App::new("suptracer")
         .version("0.0.0")
         .author(crate_authors!())
         .about("Approximately the simplest useful path tracer")
-        .arg(Arg::with_name("dimensions")
-                 .short("d")
-                 .long("dim")
-                 .help("the size of the image to render")
-                 .value_name("DIM")
-                 .default_value("1280x720")
-                 .validator(is_img_dim))
-        .arg(Arg::with_name("sah-buckets")
-                 .short("b")
-                 .long("buckets")
-                 .help("Number of buckets to use in SAH-guided BVH construction")
-                 .value_name("N")
-                 .default_value("16")
-                 .validator(is_positive_int))
-        .arg(Arg::with_name("output")
-                 .short("o")
-                 .long("out")
-                 .help("File name for output")
-                 .value_name("FILE")
-                 .required(false))
-        .arg(Arg::with_name("sah-traversal-cost")
-                 .long("sah-tcost")
-                 .help("Relative cost of BVH traversal step compared to triangle intersection")
-                 .value_name("COST")
-                 .default_value("1.0")
-                 .validator(is_positive_float))
-        .arg(Arg::with_name("input")
-                 .help("OBJ file to render")
+        // The subcommands take the input files as their own arguments.
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&render_args())
+        .arg(Arg::with_name("config")
+                 .long("config")
+                 .help("Read settings from a TOML file, whose keys are the long names of the \
+                        options (e.g. 'spp = 64', 'light = [\"point:0,2,0\"]', 'sky = true') \
+                        and 'input'. Options given on the command line take precedence")
                  .value_name("FILE")
-                 .required(true)
-                 .index(1))
-        .arg(Arg::with_name("num-threads")
+                 .global(true))
+        .arg(Arg::with_name("dump-config")
+                 .long("dump-config")
+                 .help("Print the effective settings in the format of --config and exit")
+                 .global(true))
+        .arg(Arg::with_name("threads")
                  .short("j")
+                 .long("threads")
                  .help("Number of threads to use")
                  .value_name("N")
                  .required(false)
-                 .validator(is_positive_int))
-        .arg(Arg::with_name("render-kind")
-                 .short("k")
-                 .long("kind")
-                 .help("Kind of render to create")
-                 .default_value("depth")
-                 .possible_values(&["depth", "heat"]))
+                 .validator(is_positive_int)
+                 .global(true))
+        .arg(Arg::with_name("trace-out")
+                 .long("trace-out")
+                 .help("Record how long loading, building, rendering each tile and saving take \
+                        on each thread, and write it to FILE in the JSON format of \
+                        chrome://tracing")
+                 .value_name("FILE")
+                 .global(true))
+        .arg(Arg::with_name("print-options-json")
+                 .long("print-options-json")
+                 .help("Print the options and subcommands, with their help, values and defaults, \
+                        as JSON and exit, e.g. for frontends that build forms from it")
+                 .global(true))
+        .subcommand(SubCommand::with_name("completions")
+                        .about("Print the completion script of SHELL, e.g. for \
+                                `suptracer completions bash > /etc/bash_completion.d/suptracer`")
+                        .arg(Arg::with_name("shell")
+                                 .value_name("SHELL")
+                                 .required(true)
+                                 .possible_values(&["bash", "zsh", "fish"])
+                                 .index(1)))
+        .subcommand(SubCommand::with_name("render")
+                        .about("Render the input files and save the images, the same as \
+                                leaving out the subcommand")
+                        .args(&render_args()))
+        .subcommand(SubCommand::with_name("bench")
+                        .about("Render the first image with each --tri-isect algorithm and \
+                                compare their Mray/s, without saving anything")
+                        .args(&render_args()))
+        .subcommand(SubCommand::with_name("serve")
+                        .about("Render the input files and serve the images over HTTP while \
+                                they're rendered, like --serve")
+                        .args(&render_args())
+                        .arg(Arg::with_name("port")
+                                 .long("port")
+                                 .help("Port to serve on")
+                                 .value_name("PORT")
+                                 .default_value("8080")
+                                 .validator(is_port)))
+        .subcommand(SubCommand::with_name("sweep")
+                        .about("Build the BVH with every combination of the given SAH \
+                                parameters, render the same view with each and write build \
+                                time, SAH cost and Mray/s to a CSV file")
+                        .arg(Arg::with_name("buckets")
+                                 .long("buckets")
+                                 .help("Numbers of SAH buckets to try")
+                                 .value_name("N,...")
+                                 .default_value("4,8,16,32")
+                                 .validator(is_int_list))
+                        .arg(Arg::with_name("sah-tcost")
+                                 .long("sah-tcost")
+                                 .help("Traversal costs to try")
+                                 .value_name("COST,...")
+                                 .default_value("0.5,1.0,2.0")
+                                 .validator(is_float_list))
+                        .arg(Arg::with_name("csv")
+                                 .long("csv")
+                                 .help("File to write the results to")
+                                 .value_name("FILE")
+                                 .default_value("sweep.csv")))
+        .subcommand(SubCommand::with_name("heat-diff")
+                        .alias("diff")
+                        .about("Render the heatmap with the BVH built as configured, then with \
+                                the BVH options given here, and save the difference: blue \
+                                where the second BVH needs fewer steps, red where it needs more")
+                        .arg(Arg::with_name("bvh-builder")
+                                 .long("bvh-builder")
+                                 .help("Builder for the second BVH")
+                                 .possible_values(&["sah", "median", "middle"]))
+                        .arg(Arg::with_name("bvh-layout")
+                                 .long("bvh-layout")
+                                 .help("Node layout of the second BVH")
+                                 .possible_values(&["full", "compressed"]))
+                        .arg(Arg::with_name("buckets")
+                                 .long("buckets")
+                                 .help("SAH buckets for the second BVH")
+                                 .value_name("N")
+                                 .validator(is_positive_int))
+                        .arg(Arg::with_name("sah-tcost")
+                                 .long("sah-tcost")
+                                 .help("SAH traversal cost for the second BVH")
+                                 .value_name("COST")
+                                 .validator(is_positive_float))
+                        .arg(Arg::with_name("max-leaf-tris")
+                                 .long("max-leaf-tris")
+                                 .help("Leaf size limit for the second BVH")
+                                 .value_name("N")
+                                 .validator(is_positive_int)))
+        .subcommand(SubCommand::with_name("check")
+                        .about("Report non-manifold edges, holes, inverted faces and \
+                                self-intersections of the mesh instead of rendering it. Exits \
+                                with status 1 if anything is found")
+                        .arg(Arg::with_name("input")
+                                 .help("The mesh files to check, like the main input")
+                                 .value_name("FILE")
+                                 .required(true)
+                                 .multiple(true)
+                                 .index(1)))
+        .subcommand(SubCommand::with_name("selftest")
+                        .about("Render small meshes with every traversal, renderer and BVH \
+                                builder and compare them against reference images. Exits with \
+                                status 1 if any differs")
+                        .arg(Arg::with_name("dir")
+                                 .long("dir")
+                                 .help("Directory with the meshes and reference images")
+                                 .value_name("DIR")
+                                 .default_value("tests/golden"))
+                        .arg(Arg::with_name("bless")
+                                 .long("bless")
+                                 .help("Render new reference images instead of comparing")))
+        .subcommand(SubCommand::with_name("bake")
+                        .about("Bake the ambient occlusion of the mesh, or the normals or \
+                                displacement of a more detailed source mesh, into a texture \
+                                laid out by its texture coordinates, instead of rendering it")
+                        .arg(Arg::with_name("input")
+                                 .help("The mesh files to bake, like the main input")
+                                 .value_name("FILE")
+                                 .required(true)
+                                 .multiple(true)
+                                 .index(1))
+                        .arg(Arg::with_name("map")
+                                 .long("map")
+                                 .help("What to bake. 'lightmap' writes the irradiance over pi \
+                                        from the path tracer, with the --tonemap and --exposure \
+                                        given before 'bake', laid out by --lightmap-uvs. \
+                                        'normal' writes tangent-space normals with +v as green, \
+                                        'displacement' the signed distance along the normal, \
+                                        from -cage as black to +cage as white")
+                                 .default_value("ao")
+                                 .possible_values(&["ao", "lightmap", "normal", "displacement"]))
+                        .arg(Arg::with_name("source")
+                                 .long("source")
+                                 .help("The detailed mesh to take normals or displacement from")
+                                 .value_name("FILE"))
+                        .arg(Arg::with_name("lightmap-uvs")
+                                 .long("lightmap-uvs")
+                                 .help("A mesh with the same triangles whose texture \
+                                        coordinates lay out the lightmap. Without it, every \
+                                        triangle gets a cell of its own and the layout is \
+                                        written next to the lightmap as OBJ")
+                                 .value_name("FILE"))
+                        .arg(Arg::with_name("cage")
+                                 .long("cage")
+                                 .help("How far from the baked surface to look for the source \
+                                        mesh, in both directions (default: 2% of the diagonal \
+                                        of the bounding box)")
+                                 .value_name("DIST")
+                                 .validator(is_positive_float))
+                        .arg(Arg::with_name("size")
+                                 .long("size")
+                                 .help("Resolution of the texture")
+                                 .value_name("WxH")
+                                 .default_value("1024x1024")
+                                 .validator(is_img_dim))
+                        .arg(Arg::with_name("samples")
+                                 .long("samples")
+                                 .help("Occlusion rays or light paths per texel")
+                                 .value_name("N")
+                                 .default_value("64")
+                                 .validator(is_positive_int))
+                        .arg(Arg::with_name("distance")
+                                 .long("distance")
+                                 .help("Only count geometry closer than this as occluding \
+                                        (default: no limit)")
+                                 .value_name("DIST")
+                                 .validator(is_positive_float))
+                        .arg(Arg::with_name("padding")
+                                 .long("padding")
+                                 .help("Fill this many texels around the covered parts of the \
+                                        texture, so filtering doesn't bleed in the background \
+                                        at UV seams")
+                                 .value_name("TEXELS")
+                                 .default_value("2")
+                                 .validator(is_positive_int))
+                        .arg(Arg::with_name("out")
+                                 .short("o")
+                                 .long("out")
+                                 .help("File to write the texture to (default: the input file \
+                                        with e.g. '_ao.bmp' instead of its extension)")
+                                 .value_name("FILE")))
+}
+
+/// Parse the command line, filling in anything it doesn't set from the `--config` file.
+/// Handles `--dump-config` by printing the resulting configuration and exiting.
+pub fn parse_args() -> Config {
+    let cli_matches = build_app().get_matches();
+    if let Some(m) = cli_matches.subcommand_matches("completions") {
+        let shell = match m.value_of("shell") {
+            Some("bash") => Shell::Bash,
+            Some("zsh") => Shell::Zsh,
+            Some("fish") => Shell::Fish,
+            other => panic!("BUG: unhandled shell {:?}", other),
+        };
+        build_app().gen_completions_to("suptracer", shell, &mut io::stdout());
+        process::exit(0);
+    }
+    if Options::new(&cli_matches).is_present("print-options-json") {
+        println!("{}", options_json(&build_app(), ""));
+        process::exit(0);
+    }
+    let matches = match Options::new(&cli_matches).value_of_os("config") {
+        Some(path) => {
+            let file_args = read_config_file(Path::new(path), &Options::new(&cli_matches))
+                .unwrap_or_else(|msg| Error::with_description(&msg, ErrorKind::Io).exit());
+            // The file's arguments go first, so they can't swallow the command line's.
+            let mut args: Vec<OsString> = env::args_os().collect();
+            let cli_args = args.split_off(1);
+            args.extend(file_args);
+            args.extend(cli_args);
+            build_app().get_matches_from(args)
+        }
+        None => cli_matches,
+    };
+    let cfg = parse_matches(&matches);
+    if Options::new(&matches).is_present("dump-config") {
+        print!("{}", dump_config(&cfg));
+        process::exit(0);
+    }
+    cfg
+}
+
+/// What `options_json` says about an argument.
+struct OptionInfo<'a> {
+    name: &'a str,
+    long: Option<&'a str>,
+    short: Option<char>,
+    help: Option<&'a str>,
+    takes_value: bool,
+    value_name: Option<&'a str>,
+    default: Option<&'a OsStr>,
+    possible_values: &'a [&'a str],
+    multiple: bool,
+    required: bool,
+    /// Whether subcommands take it as well.
+    global: bool,
+    /// Position of positional arguments, starting at 1.
+    index: Option<u64>,
+}
+
+impl<'a> OptionInfo<'a> {
+    fn to_json(&self, indent: &str) -> String {
+        let string = |s: &str| format!("\"{}\"", profile::escape(s));
+        let optional = |s: Option<String>| s.unwrap_or_else(|| "null".to_string());
+        let values: Vec<String> = self.possible_values.iter().map(|v| string(v)).collect();
+        let fields = [("name", string(self.name)),
+                      ("long", optional(self.long.map(&string))),
+                      ("short", optional(self.short.map(|c| string(&c.to_string())))),
+                      ("help", optional(self.help.map(&string))),
+                      ("takes_value", self.takes_value.to_string()),
+                      ("value_name", optional(self.value_name.map(&string))),
+                      ("default", optional(self.default.map(|d| string(&d.to_string_lossy())))),
+                      ("possible_values", format!("[{}]", values.join(", "))),
+                      ("multiple", self.multiple.to_string()),
+                      ("required", self.required.to_string()),
+                      ("global", self.global.to_string()),
+                      ("index", optional(self.index.map(|i| i.to_string())))];
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|&(key, ref value)| format!("{}    \"{}\": {}", indent, key, value))
+            .collect();
+        format!("{}  {{\n{}\n{}  }}", indent, fields.join(",\n"), indent)
+    }
+}
+
+/// The arguments and subcommands of `app` as JSON, for `--print-options-json`. clap has no
+/// public way to list them, so this reads the fields it keeps them in, which are public but
+/// hidden from its documentation (hence the exact version of clap in Cargo.toml).
+fn options_json(app: &App, indent: &str) -> String {
+    let p = &app.p;
+    let mut options = Vec::new();
+    for pos in p.positionals.values().filter(|p| !p.b.settings.is_set(ArgSettings::Hidden)) {
+        options.push(OptionInfo {
+                         name: pos.b.name,
+                         long: None,
+                         short: None,
+                         help: pos.b.help,
+                         takes_value: true,
+                         value_name: pos.v.val_names.as_ref().and_then(|n| n.values().next())
+                             .cloned(),
+                         default: pos.v.default_val,
+                         possible_values: pos.v.possible_vals.as_ref().map_or(&[], |v| &v[..]),
+                         multiple: pos.b.settings.is_set(ArgSettings::Multiple),
+                         required: pos.b.settings.is_set(ArgSettings::Required),
+                         global: pos.b.settings.is_set(ArgSettings::Global),
+                         index: Some(pos.index),
+                     });
+    }
+    for opt in p.opts.iter().filter(|o| !o.b.settings.is_set(ArgSettings::Hidden)) {
+        options.push(OptionInfo {
+                         name: opt.b.name,
+                         long: opt.s.long,
+                         short: opt.s.short,
+                         help: opt.b.help,
+                         takes_value: true,
+                         value_name: opt.v.val_names.as_ref().and_then(|n| n.values().next())
+                             .cloned(),
+                         default: opt.v.default_val,
+                         possible_values: opt.v.possible_vals.as_ref().map_or(&[], |v| &v[..]),
+                         multiple: opt.b.settings.is_set(ArgSettings::Multiple),
+                         required: opt.b.settings.is_set(ArgSettings::Required),
+                         global: opt.b.settings.is_set(ArgSettings::Global),
+                         index: None,
+                     });
+    }
+    for flag in p.flags.iter().filter(|f| !f.b.settings.is_set(ArgSettings::Hidden)) {
+        options.push(OptionInfo {
+                         name: flag.b.name,
+                         long: flag.s.long,
+                         short: flag.s.short,
+                         help: flag.b.help,
+                         takes_value: false,
+                         value_name: None,
+                         default: None,
+                         possible_values: &[],
+                         multiple: flag.b.settings.is_set(ArgSettings::Multiple),
+                         required: false,
+                         global: flag.b.settings.is_set(ArgSettings::Global),
+                         index: None,
+                     });
+    }
+    // Positionals first, then the rest alphabetically, as in the help.
+    options.sort_by_key(|o| (o.index.is_none(), o.index, o.long));
+    let inner = format!("{}    ", indent);
+    let options: Vec<String> = options.iter().map(|o| o.to_json(&inner)).collect();
+    let subcommands: Vec<String> = p.subcommands
+        .iter()
+        .map(|sc| format!("{}{}", inner, options_json(sc, &inner)))
+        .collect();
+    let about = p.meta
+        .about
+        .map_or("null".to_string(), |a| format!("\"{}\"", profile::escape(a)));
+    format!("{{\n{i}  \"name\": \"{}\",\n{i}  \"about\": {},\n{i}  \"options\": [\n{}\n{i}  ],\n\
+             {i}  \"subcommands\": [\n{}\n{i}  ]\n{i}}}",
+            profile::escape(&p.meta.name),
+            about,
+            options.join(",\n"),
+            subcommands.join(",\n"),
+            i = indent)
+}
+
+/// The configuration for the given command line, without looking at config files.
+pub fn config_from_args<I, T>(args: I) -> Config
+    where I: IntoIterator<Item = T>,
+          T: Into<OsString> + Clone
+{
+    parse_matches(&build_app().get_matches_from(args))
+}
+
+/// Turn the settings in a TOML config file into command line arguments, skipping those that
+/// were already given on the command line.
+fn read_config_file(path: &Path, cli_matches: &Options) -> Result<Vec<OsString>, String> {
+    let error = |e: &fmt::Display| format!("{}: {}", path.display(), e);
+    let mut text = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| error(&e))?;
+    let table = match text.parse::<toml::Value>().map_err(|e| error(&e))? {
+        toml::Value::Table(table) => table,
+        _ => return Err(error(&"expected a table")),
+    };
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if cli_matches.occurrences_of(&key) > 0 {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(x) => x.to_string(),
+                toml::Value::Boolean(true) => {
+                    args.push(OsString::from(format!("--{}", key)));
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                _ => return Err(error(&format!("unsupported value for '{}'", key))),
+            };
+            if key != "input" {
+                args.push(OsString::from(format!("--{}", key)));
+            }
+            args.push(OsString::from(value));
+        }
+    }
+    Ok(args)
+}
+
+/// Write out `cfg` as a config file that reproduces it.
+fn dump_config(cfg: &Config) -> String {
+    use toml::Value;
+    // Go through strings to avoid printing the rounding errors of f32 -> f64 conversion.
+    let float = |x: f32| Value::Float(x.to_string().parse().unwrap());
+    let int = |x: u32| Value::Integer(i64(x));
+    let string = |s: String| Value::String(s);
+    let path = |p: &Path| Value::String(p.display().to_string());
+    let vec = |v: Vector3<f32>| string(format!("{},{},{}", v.x, v.y, v.z));
+    let mut t = toml::value::Table::new();
+    let mut set = |key: &str, value: Value| { t.insert(key.to_string(), value); };
+    set("input", Value::Array(cfg.input_files.iter().map(|p| path(p)).collect()));
+    if let Some(format) = cfg.input_format {
+        let format = match format {
+            Format::Obj => "obj",
+            Format::Ply => "ply",
+            Format::Stl => "stl",
+            Format::Xyz => "xyz",
+        };
+        set("input-format", string(format.to_string()));
+    }
+    let color = |c: &Rgb| string(format!("{},{},{}", c.r, c.g, c.b));
+    set("part-color", Value::Array(cfg.part_colors.iter().map(color).collect()));
+    let names = |names: &[String]| Value::Array(names.iter().cloned().map(string).collect());
+    set("only-group", names(&cfg.only_groups));
+    set("exclude-group", names(&cfg.exclude_groups));
+    set("color-groups", Value::Boolean(cfg.color_groups));
+    set("out", path(&cfg.output_file));
+    set("dim", string(format!("{}x{}", cfg.image_width, cfg.image_height)));
+    set("buckets", int(cfg.sah_buckets));
+    if let Some(epsilon) = cfg.weld_epsilon {
+        set("weld-epsilon", float(epsilon));
+    }
+    if let Some(ref trace_out) = cfg.trace_out {
+        set("trace-out", path(trace_out));
+    }
+    if let Some(port) = cfg.serve {
+        set("serve", int(u32::from(port)));
+    }
+    set("serve-address", string(cfg.serve_address.to_string()));
+    if let Some(levels) = cfg.tessellate {
+        set("tessellate", int(levels));
+    }
+    if let Some(ratio) = cfg.decimate {
+        set("decimate", float(ratio));
+    }
+    if let Some(stereo) = cfg.stereo {
+        set("stereo", float(stereo.separation));
+        let layout = match stereo.layout {
+            StereoLayout::SideBySide => "side-by-side",
+            StereoLayout::Separate => "separate",
+        };
+        set("stereo-layout", string(layout.to_string()));
+    }
+    if let Some(rows) = cfg.stream {
+        set("stream", int(rows));
+    }
+    set("sah-tcost", float(cfg.sah_traversal_cost));
+    let bvh_builder = match cfg.bvh_builder {
+        Builder::Sah => "sah",
+        Builder::Median => "median",
+        Builder::Middle => "middle",
+    };
+    set("bvh-builder", string(bvh_builder.to_string()));
+    set("bvh-optimize", Value::Boolean(cfg.bvh_optimize));
+    set("max-leaf-tris", int(cfg.max_leaf_tris));
+    let traversal = match cfg.traversal {
+        Traversal::Single => "single",
+        Traversal::Packet => "packet",
+        Traversal::Frustum => "frustum",
+        Traversal::Stackless => "stackless",
+    };
+    set("traversal", string(traversal.to_string()));
+    let pixel_order = match cfg.pixel_order {
+        PixelOrder::Scanline => "scanline",
+        PixelOrder::Morton => "morton",
+        PixelOrder::Hilbert => "hilbert",
+    };
+    set("pixel-order", string(pixel_order.to_string()));
+    let renderer = match cfg.renderer {
+        Renderer::Megakernel => "megakernel",
+        Renderer::Wavefront => "wavefront",
+    };
+    set("renderer", string(renderer.to_string()));
+    let bvh_layout = match cfg.bvh_layout {
+        BvhLayout::Full => "full",
+        BvhLayout::Compressed => "compressed",
+    };
+    set("bvh-layout", string(bvh_layout.to_string()));
+    let tri_isect = match cfg.tri_isect {
+        TriIsect::Watertight => "watertight",
+        TriIsect::Woop => "woop",
+    };
+    set("tri-isect", string(tri_isect.to_string()));
+    let precision = match cfg.precision {
+        Precision::F32 => "f32",
+        Precision::F64 => "f64",
+    };
+    set("precision", string(precision.to_string()));
+    set("cull-backfaces", Value::Boolean(cfg.cull_backfaces));
+    let backend = match cfg.backend {
+        Backend::Native => "native",
+        Backend::Embree => "embree",
+    };
+    set("backend", string(backend.to_string()));
+    if let Some(n) = cfg.num_threads {
+        set("threads", int(n));
+    }
+    let kind = match cfg.render_kind {
+        RenderKind::Depthmap => "depth",
+        RenderKind::Heatmap => "heat",
+        RenderKind::Normals => "normal",
+        RenderKind::Shaded => "shaded",
+        RenderKind::PathTraced => "path",
+        RenderKind::Uv => "uv",
+        RenderKind::VertexColor => "vertex-color",
+        RenderKind::Layers => "layers",
+        RenderKind::Mask => "mask",
+        RenderKind::ObjectId => "object-id",
+        RenderKind::MaterialId => "material-id",
+        RenderKind::Thickness => "thickness",
+    };
+    set("kind", string(kind.to_string()));
+    let heat_counter = match cfg.heat_counter {
+        HeatCounter::Boxes => "boxes",
+        HeatCounter::Nodes => "nodes",
+        HeatCounter::Leaves => "leaves",
+        HeatCounter::Tris => "tris",
+    };
+    set("heat-counter", string(heat_counter.to_string()));
+    if let Some(format) = cfg.counts_format {
+        let format = match format {
+            CountsFormat::Csv => "csv",
+            CountsFormat::Binary => "bin",
+        };
+        set("raw-counts", string(format.to_string()));
+    }
+    if let Some(near) = cfg.depth_near {
+        set("depth-near", float(near));
+    }
+    if let Some(far) = cfg.depth_far {
+        set("depth-far", float(far));
+    }
+    if let Some(interval) = cfg.depth_isolines {
+        set("depth-isolines", float(interval));
+    }
+    if let Some(depth) = cfg.overlay_bvh {
+        set("overlay-bvh", int(depth));
+    }
+    if let Some(c) = cfg.crop {
+        set("crop", string(format!("{},{},{},{}", c.x, c.y, c.w, c.h)));
+    }
+    if let Some((x, y)) = cfg.debug_pixel {
+        set("debug-pixel", string(format!("{},{}", x, y)));
+    }
+    set("info", Value::Boolean(cfg.info));
+    set("bench", Value::Boolean(cfg.bench));
+    set("validate", Value::Boolean(cfg.validate));
+    set("check-determinism", Value::Boolean(cfg.check_determinism));
+    if let Some(n) = cfg.chunks {
+        set("chunks", int(n));
+    }
+    if let Some(ref p) = cfg.stats_out {
+        set("stats-out", path(p));
+    }
+    set("no-autoframe", Value::Boolean(!cfg.autoframe));
+    set("eye", vec(cfg.eye));
+    set("look-at", vec(cfg.look_at));
+    set("fov", float(cfg.fov));
+    let projection = match cfg.projection {
+        Projection::Pinhole => "pinhole",
+        Projection::Equirectangular => "equirect",
+        Projection::Fisheye { angle } => {
+            set("fisheye-angle", float(angle.to_degrees()));
+            "fisheye"
+        }
+    };
+    set("projection", string(projection.to_string()));
+    set("spp", int(cfg.spp));
+    set("passes", int(cfg.passes));
+    set("aperture", float(cfg.aperture));
+    if let Some(dist) = cfg.focus_dist {
+        set("focus-dist", float(dist));
+    }
+    let plane = |p: &Vector4<f32>| string(format!("{},{},{},{}", p.x, p.y, p.z, p.w));
+    set("clip", Value::Array(cfg.clip_planes.iter().map(plane).collect()));
+    if let Some(n) = cfg.turntable {
+        set("turntable", int(n));
+    }
+    if let Some(ref p) = cfg.camera_path {
+        set("camera-path", path(p));
+    }
+    set("fps", float(cfg.fps));
+    if let Some(n) = cfg.spin {
+        set("spin", int(n));
+    }
+    set("interactive", Value::Boolean(cfg.interactive));
+    set("watch", Value::Boolean(cfg.watch));
+    if let Some(ref p) = cfg.envmap {
+        set("envmap", path(p));
+    }
+    if let Some(ref p) = cfg.volume {
+        set("volume", path(p));
+    }
+    set("volume-absorption", float(cfg.volume_absorption));
+    set("volume-emission", color(&cfg.volume_emission));
+    set("sky", Value::Boolean(cfg.sky));
+    if let Some(ref c) = cfg.background {
+        set("background", color(c));
+    }
+    set("sun-elevation", float(cfg.sun_elevation));
+    set("sun-azimuth", float(cfg.sun_azimuth));
+    set("turbidity", float(cfg.turbidity));
+    set("light",
+        Value::Array(cfg.lights.iter().map(|l| string(format_light(l))).collect()));
+    set("shape",
+        Value::Array(cfg.shapes.iter().map(|s| string(format_shape(s))).collect()));
+    set("light-samples", int(cfg.light_samples));
+    let tonemap = match cfg.tonemap {
+        Tonemap::Linear => "linear",
+        Tonemap::Reinhard => "reinhard",
+        Tonemap::Aces => "aces",
+    };
+    set("tonemap", string(tonemap.to_string()));
+    set("exposure", float(cfg.exposure));
+    let filter = match cfg.filter {
+        Filter::Box => "box",
+        Filter::Tent => "tent",
+        Filter::Gaussian => "gaussian",
+        Filter::Mitchell => "mitchell",
+    };
+    set("filter", string(filter.to_string()));
+    if let Some(clamp) = cfg.clamp {
+        set("clamp", float(clamp));
+    }
+    set("denoise", Value::Boolean(cfg.denoise));
+    set("transparent", Value::Boolean(cfg.transparent));
+    set("no-mis", Value::Boolean(!cfg.mis));
+    set("max-depth", int(cfg.max_depth));
+    set("rr-depth", int(cfg.rr_depth));
+    set("t-min", float(cfg.ray_t_min));
+    set("ray-offset", float(cfg.ray_offset));
+    Value::Table(t).to_string()
+}
+
+fn parse_builder(value: Option<&str>) -> Builder {
+    match value {
+        Some("sah") => Builder::Sah,
+        Some("median") => Builder::Median,
+        Some("middle") => Builder::Middle,
+        other => panic!("BUG: unhandled BVH builder {:?}", other),
+    }
+}
+
+fn parse_layout(value: Option<&str>) -> BvhLayout {
+    match value {
+        Some("full") => BvhLayout::Full,
+        Some("compressed") => BvhLayout::Compressed,
+        other => panic!("BUG: unhandled BVH layout {:?}", other),
+    }
 }
 
-pub fn parse_matches(matches: ArgMatches) -> Config {
-    fn parse_arg<T: FromStr>(matches: &ArgMatches, key: &str) -> Option<T> {
+/// The subcommands that take `render_args`.
+const RENDER_SUBCOMMANDS: &[&str] = &["render", "bench", "serve"];
+
+/// The `global` arguments, which can be given before or after any subcommand.
+const GLOBAL_ARGS: &[&str] = &["config",
+                               "dump-config",
+                               "threads",
+                               "trace-out",
+                               "print-options-json"];
+
+/// The options of a command line, for reading the options of rendering from wherever they were
+/// given: after a subcommand that renders, or before the subcommand (or without one).
+struct Options<'a> {
+    top: &'a ArgMatches<'a>,
+    sub: Option<&'a ArgMatches<'a>>,
+}
+
+impl<'a> Options<'a> {
+    fn new(top: &'a ArgMatches<'a>) -> Self {
+        let sub = match top.subcommand() {
+            (name, sub) if RENDER_SUBCOMMANDS.contains(&name) => sub,
+            _ => None,
+        };
+        Options { top, sub }
+    }
+
+    /// Just the options in `matches`, e.g. of a subcommand with options of its own.
+    fn only(matches: &'a ArgMatches<'a>) -> Self {
+        Options {
+            top: matches,
+            sub: None,
+        }
+    }
+
+    /// The matches that have `key`: the subcommand's if it was given there or before neither,
+    /// so that its default applies, otherwise those before the subcommand.
+    fn source(&self, key: &str) -> &'a ArgMatches<'a> {
+        if GLOBAL_ARGS.contains(&key) {
+            return self.global_source(key);
+        }
+        match self.sub {
+            Some(sub) if sub.occurrences_of(key) > 0 || self.top.occurrences_of(key) == 0 => sub,
+            _ => self.top,
+        }
+    }
+
+    /// The innermost matches where the global `key` was given, whatever the subcommands are.
+    /// Older versions of clap don't pass the values of global arguments up or down at all.
+    fn global_source(&self, key: &str) -> &'a ArgMatches<'a> {
+        let mut source = self.top;
+        let mut matches = self.top;
+        while let (_, Some(sub)) = matches.subcommand() {
+            if sub.occurrences_of(key) > 0 {
+                source = sub;
+            }
+            matches = sub;
+        }
+        source
+    }
+
+    fn is_present(&self, key: &str) -> bool {
+        self.source(key).is_present(key)
+    }
+
+    fn occurrences_of(&self, key: &str) -> u64 {
+        self.source(key).occurrences_of(key)
+    }
+
+    fn value_of(&self, key: &str) -> Option<&'a str> {
+        self.source(key).value_of(key)
+    }
+
+    fn value_of_os(&self, key: &str) -> Option<&'a OsStr> {
+        self.source(key).value_of_os(key)
+    }
+
+    fn values_of(&self, key: &str) -> Option<Values<'a>> {
+        self.source(key).values_of(key)
+    }
+
+    fn values_of_os(&self, key: &str) -> Option<OsValues<'a>> {
+        self.source(key).values_of_os(key)
+    }
+
+    fn subcommand_matches(&self, name: &str) -> Option<&'a ArgMatches<'a>> {
+        self.top.subcommand_matches(name)
+    }
+
+    fn subcommand_name(&self) -> Option<&'a str> {
+        self.top.subcommand_name()
+    }
+}
+
+fn parse_matches(matches: &ArgMatches) -> Config {
+    fn parse_arg<T: FromStr>(matches: &Options, key: &str) -> Option<T> {
         matches.value_of(key).and_then(|s| s.parse().ok())
     }
 
-    let input_file = matches.value_of_os("input").map(PathBuf::from).unwrap();
-    let output_file = matches.value_of_os("output")
+    let matches = &Options::new(matches);
+
+    let check = matches.subcommand_matches("check");
+    let bake = matches.subcommand_matches("bake");
+    let input_values = check.or(bake)
+        .and_then(|m| m.values_of_os("input"))
+        .or_else(|| matches.values_of_os("input"));
+    let selftest = matches.subcommand_matches("selftest");
+    let input_files: Vec<PathBuf> = match input_values {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        // The self test brings its own meshes.
+        None if selftest.is_some() => Vec::new(),
+        None => {
+            Error::with_description("No input file given, neither on the command line nor in \
+                                     the config file",
+                                    ErrorKind::MissingRequiredArgument)
+                    .exit()
+        }
+    };
+    let input_format = match matches.value_of("input-format") {
+        Some("obj") => Some(Format::Obj),
+        Some("ply") => Some(Format::Ply),
+        Some("stl") => Some(Format::Stl),
+        Some("xyz") => Some(Format::Xyz),
+        None => None,
+        other => panic!("BUG: unhandled input format {:?}", other),
+    };
+    let stdin_inputs = input_files.iter().filter(|path| input::is_stdin(path)).count();
+    if stdin_inputs > 1 {
+        Error::with_description("stdin ('-') can only be read once", ErrorKind::ValueValidation)
+                .exit();
+    }
+    if stdin_inputs > 0 {
+        for &arg in &["watch", "chunks"] {
+            if matches.is_present(arg) {
+                let msg = format!("--{} needs an input file, it can't read from stdin", arg);
+                Error::with_description(&msg, ErrorKind::ArgumentConflict).exit();
+            }
+        }
+    }
+    if matches.is_present("chunks") {
+        let first = &input_files[0];
+        if input_files.len() > 1 {
+            Error::with_description("--chunks can only split a single input file",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+        if input_format.unwrap_or_else(|| Format::from_extension(first)) != Format::Obj {
+            Error::with_description("--chunks can only split OBJ files",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+    }
+    let part_colors: Vec<Rgb> = matches.values_of("part-color")
+        .map(|values| {
+                 values.map(|s| parse_vec3(s).unwrap())
+                     .map(|c| Rgb::new(c.x, c.y, c.z))
+                     .collect()
+             })
+        .unwrap_or_default();
+    if part_colors.len() > input_files.len() {
+        let msg = format!("{} part colors given for {} input files",
+                          part_colors.len(),
+                          input_files.len());
+        Error::with_description(&msg, ErrorKind::TooManyValues).exit();
+    }
+    let output_file = matches.value_of_os("out")
         .map(PathBuf::from)
-        .unwrap_or(input_file.with_extension("bmp"));
+        .unwrap_or_else(|| match input_files.first() {
+                            Some(first) if !input::is_stdin(first) => first.with_extension("bmp"),
+                            // Also without input files, for the self test, which writes none.
+                            _ => PathBuf::from("out.bmp"),
+                        });
+    let bake = bake.map(|m| {
+        let m = &Options::only(m);
+        let map = match m.value_of("map") {
+            Some("ao") => BakeMap::AmbientOcclusion,
+            Some("lightmap") => BakeMap::Lightmap,
+            Some("normal") => BakeMap::Normal,
+            Some("displacement") => BakeMap::Displacement,
+            other => panic!("BUG: unhandled bake map {:?}", other),
+        };
+        let needs_source = map == BakeMap::Normal || map == BakeMap::Displacement;
+        if needs_source != m.is_present("source") {
+            Error::with_description("bake --source is needed for --map normal and \
+                                     --map displacement, and only for those",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+        if m.is_present("lightmap-uvs") && map != BakeMap::Lightmap {
+            Error::with_description("bake --lightmap-uvs only works with --map lightmap",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+        if parse_arg::<u32>(m, "samples") == Some(0) {
+            Error::with_description("bake --samples must be at least 1",
+                                    ErrorKind::ValueValidation)
+                    .exit();
+        }
+        let size = parse_img_dim(m.value_of("size").unwrap()).unwrap();
+        let suffix = match map {
+            BakeMap::AmbientOcclusion => "_ao.bmp",
+            BakeMap::Lightmap => "_lightmap.bmp",
+            BakeMap::Normal => "_normal.bmp",
+            BakeMap::Displacement => "_displacement.bmp",
+        };
+        let output_file = m.value_of_os("out")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| if input::is_stdin(&input_files[0]) {
+                                PathBuf::from(&suffix[1..])
+                            } else {
+                                let mut name = input_files[0].file_stem().unwrap().to_os_string();
+                                name.push(suffix);
+                                input_files[0].with_file_name(name)
+                            });
+        Bake {
+            map,
+            source: m.value_of_os("source").map(PathBuf::from),
+            cage: parse_arg(m, "cage"),
+            size,
+            samples: parse_arg(m, "samples").unwrap(),
+            distance: parse_arg(m, "distance"),
+            padding: parse_arg(m, "padding").unwrap(),
+            lightmap_uvs: m.value_of_os("lightmap-uvs").map(PathBuf::from),
+            output_file,
+        }
+    });
+    let png_output = output_file.extension().and_then(|e| e.to_str()).map(str::to_lowercase) ==
+                     Some("png".to_string());
+    let png_kind = match matches.value_of("kind") {
+        Some("depth") | Some("mask") | Some("object-id") | Some("material-id") |
+        Some("thickness") => true,
+        _ => false,
+    };
+    let transparent = matches.is_present("transparent");
+    if transparent && !png_output {
+        Error::with_description("--transparent writes PNG, so --out must end in .png",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
+    let stream = matches.is_present("stream");
+    if stream && !png_output {
+        Error::with_description("--stream writes PNG, so --out must end in .png",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
+    if png_output && !png_kind && !transparent && !stream {
+        Error::with_description("Only depth and thickness maps, masks and ID images can be \
+                                 written as PNG",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
+    if matches.value_of("renderer") == Some("wavefront") &&
+       matches.value_of("traversal") != Some("single") {
+        Error::with_description("--renderer wavefront traces rays one by one, it needs \
+                                 --traversal single",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
+    if matches.value_of("kind") == Some("thickness") && matches.is_present("cull-backfaces") {
+        Error::with_description("--kind thickness measures where rays leave through back faces, \
+                                 which --cull-backfaces ignores",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
+    // Not a conflict clap checks, since --trace-out is global and not all subcommands watch.
+    if matches.is_present("trace-out") && matches.is_present("watch") {
+        Error::with_description("--trace-out writes the trace when done, which --watch never is",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
+    let bench = matches.subcommand_name() == Some("bench") || matches.is_present("bench");
+    if matches.value_of("precision") == Some("f64") &&
+       (matches.value_of("tri-isect") == Some("woop") || bench) {
+        Error::with_description("--precision f64 always uses watertight triangle tests, it can't \
+                                 be combined with --tri-isect woop or `bench`",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
+    if matches.is_present("overlay-bvh") {
+        if png_output {
+            Error::with_description("Images with a BVH overlay can't be written as PNG",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+        if matches.value_of("projection") != Some("pinhole") {
+            Error::with_description("--overlay-bvh needs --projection pinhole",
+                                    ErrorKind::ArgumentConflict)
+                    .exit();
+        }
+    }
+    let depth_range = matches.value_of("depth-range").map(|s| parse_depth_range(s).unwrap());
+    let counted = match matches.value_of("kind") {
+        Some("heat") | Some("layers") => true,
+        _ => false,
+    };
+    if matches.is_present("raw-counts") && !counted {
+        Error::with_description("--raw-counts needs --kind heat or --kind layers",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
+    // Every band of rows would be scaled to its own range otherwise.
+    let fixed_depth_range = depth_range.is_some() ||
+                            matches.is_present("depth-near") && matches.is_present("depth-far");
+    let normalized = match matches.value_of("kind") {
+        Some("depth") | Some("thickness") => !fixed_depth_range,
+        _ => counted,
+    };
+    if stream && normalized {
+        Error::with_description("--stream can't render heatmaps or layers, nor depth and \
+                                 thickness maps without --depth-range",
+                                ErrorKind::ArgumentConflict)
+                .exit();
+    }
 
-    let dim = matches.value_of("dimensions").unwrap();
-    let dim_captures = IMG_DIM_REGEX.captures(dim).unwrap();
+    let dim = matches.value_of("dim").unwrap();
+    let (mut image_width, mut image_height) = parse_img_dim(dim).unwrap();
+    if let Some(scale) = matches.value_of("scale") {
+        let percent = parse_percentage(scale).unwrap();
+        let scaled = |n: u32| {
+            u32((f32(n) * percent / 100.0).round()).unwrap_or(u32::max_value()).max(1)
+        };
+        image_width = scaled(image_width);
+        image_height = scaled(image_height);
+    }
+    let crop = matches.value_of("crop").map(|s| {
+        let c = parse_list::<u32>(s).unwrap();
+        let window = Rect {
+            x: c[0],
+            y: c[1],
+            w: c[2],
+            h: c[3],
+        };
+        if window.w == 0 || window.h == 0 {
+            let msg = format!("Crop window {} is empty, its width and height must be positive",
+                              s);
+            Error::with_description(&msg, ErrorKind::ValueValidation).exit();
+        }
+        let fits = |start: u32, len: u32, max| {
+            start.checked_add(len).map_or(false, |end| end <= max)
+        };
+        if !fits(window.x, window.w, image_width) || !fits(window.y, window.h, image_height) {
+            let msg = format!("Crop window {} exceeds the image dimensions {}", s, dim);
+            Error::with_description(&msg, ErrorKind::ValueValidation).exit();
+        }
+        window
+    });
+    let debug_pixel = matches.value_of("debug-pixel").map(|s| {
+        let c = parse_list::<u32>(s).unwrap();
+        let (x, y) = (c[0], c[1]);
+        if x >= image_width || y >= image_height {
+            let msg = format!("Pixel {} is outside the image dimensions {}", s, dim);
+            Error::with_description(&msg, ErrorKind::ValueValidation).exit();
+        }
+        (x, y)
+    });
+    let spp = parse_arg(matches, "spp").unwrap();
+    if spp == 0 {
+        Error::with_description("At least one sample per pixel is needed",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
+    let passes = parse_arg(matches, "passes").unwrap();
+    if passes == 0 {
+        Error::with_description("At least one pass is needed", ErrorKind::ValueValidation).exit();
+    }
+    let max_leaf_tris = parse_arg(matches, "max-leaf-tris").unwrap();
+    if max_leaf_tris == 0 {
+        Error::with_description("BVH leaves must be allowed to hold at least one triangle",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
+    let light_samples = parse_arg(matches, "light-samples").unwrap();
+    if light_samples == 0 {
+        Error::with_description("At least one shadow ray per area light is needed",
+                                ErrorKind::ValueValidation)
+                .exit();
+    }
     Config {
-        input_file,
+        input_files,
+        input_format,
+        part_colors,
+        only_groups: matches.values_of("only-group")
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default(),
+        exclude_groups: matches.values_of("exclude-group")
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default(),
+        color_groups: matches.is_present("color-groups"),
         output_file,
-        image_width: dim_captures[1].parse().unwrap(),
-        image_height: dim_captures[2].parse().unwrap(),
-        sah_buckets: parse_arg(&matches, "sah-buckets").unwrap(),
-        sah_traversal_cost: parse_arg(&matches, "sah-traversal-cost").unwrap(),
-        num_threads: parse_arg(&matches, "num-threads"),
-        render_kind: match matches.value_of("render-kind") {
+        image_width,
+        image_height,
+        sah_buckets: parse_arg(matches, "buckets").unwrap(),
+        sah_traversal_cost: parse_arg(matches, "sah-tcost").unwrap(),
+        bvh_builder: parse_builder(matches.value_of("bvh-builder")),
+        bvh_optimize: matches.is_present("bvh-optimize"),
+        max_leaf_tris,
+        traversal: match matches.value_of("traversal") {
+            Some("single") => Traversal::Single,
+            Some("packet") => Traversal::Packet,
+            Some("frustum") => Traversal::Frustum,
+            Some("stackless") => Traversal::Stackless,
+            other => panic!("BUG: unhandled traversal {:?}", other),
+        },
+        pixel_order: match matches.value_of("pixel-order") {
+            Some("scanline") => PixelOrder::Scanline,
+            Some("morton") => PixelOrder::Morton,
+            Some("hilbert") => PixelOrder::Hilbert,
+            other => panic!("BUG: unhandled pixel order {:?}", other),
+        },
+        renderer: match matches.value_of("renderer") {
+            Some("megakernel") => Renderer::Megakernel,
+            Some("wavefront") => Renderer::Wavefront,
+            other => panic!("BUG: unhandled renderer {:?}", other),
+        },
+        bvh_layout: parse_layout(matches.value_of("bvh-layout")),
+        tri_isect: match matches.value_of("tri-isect") {
+            Some("watertight") => TriIsect::Watertight,
+            Some("woop") => TriIsect::Woop,
+            other => panic!("BUG: unhandled triangle intersection {:?}", other),
+        },
+        precision: match matches.value_of("precision") {
+            Some("f32") => Precision::F32,
+            Some("f64") => Precision::F64,
+            other => panic!("BUG: unhandled precision {:?}", other),
+        },
+        cull_backfaces: matches.is_present("cull-backfaces"),
+        backend: match matches.value_of("backend") {
+            Some("native") => Backend::Native,
+            Some("embree") => Backend::Embree,
+            other => panic!("BUG: unhandled backend {:?}", other),
+        },
+        num_threads: parse_arg(matches, "threads"),
+        render_kind: match matches.value_of("kind") {
             Some("depth") => RenderKind::Depthmap,
             Some("heat") => RenderKind::Heatmap,
-            other => panic!("BUG: unhandled render-kind {:?}", other),
+            Some("normal") => RenderKind::Normals,
+            Some("shaded") => RenderKind::Shaded,
+            Some("path") => RenderKind::PathTraced,
+            Some("uv") => RenderKind::Uv,
+            Some("vertex-color") => RenderKind::VertexColor,
+            Some("layers") => RenderKind::Layers,
+            Some("mask") => RenderKind::Mask,
+            Some("object-id") => RenderKind::ObjectId,
+            Some("material-id") => RenderKind::MaterialId,
+            Some("thickness") => RenderKind::Thickness,
+            other => panic!("BUG: unhandled kind {:?}", other),
+        },
+        heat_counter: match matches.value_of("heat-counter") {
+            Some("boxes") => HeatCounter::Boxes,
+            Some("nodes") => HeatCounter::Nodes,
+            Some("leaves") => HeatCounter::Leaves,
+            Some("tris") => HeatCounter::Tris,
+            other => panic!("BUG: unhandled heat counter {:?}", other),
         },
+        counts_format: match matches.value_of("raw-counts") {
+            Some("csv") => Some(CountsFormat::Csv),
+            Some("bin") => Some(CountsFormat::Binary),
+            None => None,
+            other => panic!("BUG: unhandled raw counts format {:?}", other),
+        },
+        depth_near: depth_range.map(|(near, _)| near).or(parse_arg(matches, "depth-near")),
+        depth_far: depth_range.map(|(_, far)| far).or(parse_arg(matches, "depth-far")),
+        depth_isolines: parse_arg(matches, "depth-isolines"),
+        overlay_bvh: parse_arg(matches, "overlay-bvh"),
+        crop,
+        debug_pixel,
+        autoframe: !matches.is_present("no-autoframe"),
+        eye: parse_vec3(matches.value_of("eye").unwrap()).unwrap(),
+        look_at: parse_vec3(matches.value_of("look-at").unwrap()).unwrap(),
+        fov: parse_arg(matches, "fov").unwrap(),
+        projection: match matches.value_of("projection") {
+            Some("pinhole") => Projection::Pinhole,
+            Some("equirect") => Projection::Equirectangular,
+            Some("fisheye") => {
+                let angle: f32 = parse_arg(matches, "fisheye-angle").unwrap();
+                Projection::Fisheye { angle: angle.to_radians() }
+            }
+            other => panic!("BUG: unhandled projection {:?}", other),
+        },
+        spp,
+        passes,
+        pass: 0,
+        first_row: 0,
+        aperture: parse_arg(matches, "aperture").unwrap(),
+        focus_dist: parse_arg(matches, "focus-dist"),
+        clip_planes: matches.values_of("clip")
+            .map(|values| values.map(|s| parse_plane(s).unwrap()).collect())
+            .unwrap_or_default(),
+        turntable: parse_arg(matches, "turntable"),
+        camera_path: matches.value_of_os("camera-path").map(PathBuf::from),
+        fps: parse_arg(matches, "fps").unwrap(),
+        spin: parse_arg(matches, "spin"),
+        interactive: matches.is_present("interactive"),
+        watch: matches.is_present("watch"),
+        envmap: matches.value_of_os("envmap").map(PathBuf::from),
+        volume: matches.value_of_os("volume").map(PathBuf::from),
+        volume_absorption: parse_arg(matches, "volume-absorption").unwrap(),
+        volume_emission: {
+            let c = parse_vec3(matches.value_of("volume-emission").unwrap()).unwrap();
+            Rgb::new(c.x, c.y, c.z)
+        },
+        max_depth: parse_arg(matches, "max-depth").unwrap(),
+        rr_depth: parse_arg(matches, "rr-depth").unwrap(),
+        ray_t_min: parse_arg(matches, "t-min").unwrap(),
+        ray_offset: parse_arg(matches, "ray-offset").unwrap(),
+        sky: matches.is_present("sky"),
+        background: matches.value_of("background").map(|s| {
+            let c = parse_vec3(s).unwrap();
+            Rgb::new(c.x, c.y, c.z)
+        }),
+        sun_elevation: parse_arg(matches, "sun-elevation").unwrap(),
+        sun_azimuth: parse_arg(matches, "sun-azimuth").unwrap(),
+        turbidity: parse_arg(matches, "turbidity").unwrap(),
+        shapes: matches.values_of("shape")
+            .map(|values| values.map(|s| parse_shape(s).unwrap()).collect())
+            .unwrap_or_default(),
+        lights: matches.values_of("light")
+            .map(|values| values.map(|s| parse_light(s).unwrap()).collect())
+            .unwrap_or_default(),
+        light_samples,
+        tonemap: match matches.value_of("tonemap") {
+            Some("linear") => Tonemap::Linear,
+            Some("reinhard") => Tonemap::Reinhard,
+            Some("aces") => Tonemap::Aces,
+            other => panic!("BUG: unhandled tonemap {:?}", other),
+        },
+        exposure: parse_arg(matches, "exposure").unwrap(),
+        clamp: parse_arg(matches, "clamp"),
+        denoise: matches.is_present("denoise"),
+        transparent,
+        mis: !matches.is_present("no-mis"),
+        info: matches.is_present("info"),
+        bench,
+        validate: matches.is_present("validate"),
+        check_determinism: matches.is_present("check-determinism"),
+        check: check.is_some(),
+        selftest: selftest.map(|m| {
+            SelfTest {
+                dir: PathBuf::from(m.value_of_os("dir").unwrap()),
+                bless: m.is_present("bless"),
+            }
+        }),
+        bake,
+        chunks: parse_arg(matches, "chunks"),
+        stats_out: matches.value_of_os("stats-out").map(PathBuf::from),
+        heat_diff: matches.subcommand_matches("heat-diff").map(|m| {
+            let m = &Options::only(m);
+            HeatDiff {
+                builder: m.value_of("bvh-builder").map(|s| parse_builder(Some(s))),
+                layout: m.value_of("bvh-layout").map(|s| parse_layout(Some(s))),
+                buckets: parse_arg(m, "buckets"),
+                traversal_cost: parse_arg(m, "sah-tcost"),
+                max_leaf_tris: match parse_arg(m, "max-leaf-tris") {
+                    Some(0) => {
+                        Error::with_description("BVH leaves must be allowed to hold at least \
+                                                 one triangle",
+                                                ErrorKind::ValueValidation)
+                                .exit()
+                    }
+                    n => n,
+                },
+            }
+        }),
+        sweep: matches.subcommand_matches("sweep").map(|m| {
+            Sweep {
+                buckets: parse_list(m.value_of("buckets").unwrap()).unwrap(),
+                traversal_costs: parse_list(m.value_of("sah-tcost").unwrap()).unwrap(),
+                csv_file: PathBuf::from(m.value_of_os("csv").unwrap()),
+            }
+        }),
+        weld_epsilon: parse_arg(matches, "weld-epsilon"),
+        decimate: parse_arg(matches, "decimate"),
+        stream: parse_arg(matches, "stream"),
+        stereo: parse_arg(matches, "stereo").map(|separation| {
+            Stereo {
+                separation,
+                layout: match matches.value_of("stereo-layout") {
+                    Some("side-by-side") => StereoLayout::SideBySide,
+                    Some("separate") => StereoLayout::Separate,
+                    other => panic!("BUG: unhandled stereo layout {:?}", other),
+                },
+            }
+        }),
+        tessellate: parse_arg(matches, "tessellate"),
+        serve: parse_arg(matches, "serve").or_else(|| {
+            matches.subcommand_matches("serve").and_then(|m| parse_arg(&Options::only(m), "port"))
+        }),
+        serve_address: parse_arg(matches, "serve-address").unwrap(),
+        trace_out: matches.value_of_os("trace-out").map(PathBuf::from),
+        filter: match matches.value_of("filter") {
+            Some("box") => Filter::Box,
+            Some("tent") => Filter::Tent,
+            Some("gaussian") => Filter::Gaussian,
+            Some("mitchell") => Filter::Mitchell,
+            other => panic!("BUG: unhandled filter {:?}", other),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{self, Value};
+
+    #[test]
+    fn global_options_after_any_subcommand() {
+        let cfg = config_from_args(vec!["suptracer", "mesh.obj", "sweep", "--threads", "4"]);
+        assert_eq!(cfg.num_threads, Some(4));
+        let cfg = config_from_args(vec!["suptracer", "--threads", "3", "mesh.obj", "sweep"]);
+        assert_eq!(cfg.num_threads, Some(3));
+        let cfg = config_from_args(vec!["suptracer", "check", "mesh.obj", "--threads", "2"]);
+        assert_eq!(cfg.num_threads, Some(2));
+    }
+
+    #[test]
+    fn options_json_describes_the_options() {
+        let json: Value = serde_json::from_str(&options_json(&build_app(), "")).unwrap();
+        assert_eq!(json["name"], "suptracer");
+        let options = json["options"].as_array().unwrap();
+        let option = |name: &str| options.iter().find(|o| o["name"] == name).cloned();
+
+        let input = option("input").unwrap();
+        assert_eq!(input["index"], 1);
+        assert_eq!(input["multiple"], true);
+        let builder = option("bvh-builder").unwrap();
+        assert_eq!(builder["long"], "bvh-builder");
+        assert_eq!(builder["takes_value"], true);
+        assert_eq!(builder["default"], "sah");
+        assert_eq!(builder["possible_values"].to_string(), r#"["sah","median","middle"]"#);
+        assert_eq!(option("threads").unwrap()["global"], true);
+        assert_eq!(option("dump-config").unwrap()["takes_value"], false);
+        assert!(option("bench").is_none(), "hidden options are left out");
+
+        let subcommands = json["subcommands"].as_array().unwrap();
+        let sweep = subcommands.iter().find(|sc| sc["name"] == "sweep").unwrap();
+        assert!(sweep["options"].as_array().unwrap().iter().any(|o| o["name"] == "csv"));
     }
 }
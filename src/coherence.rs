@@ -0,0 +1,134 @@
+//! How coherent the primary rays are, for `--stats-out`, so that changes to the pixel order,
+//! the tiling or packet traversal can be judged by more than the time they take.
+//!
+//! The rays through the pixel centers are traced one by one, recording the nodes each visits,
+//! and compared within small tiles of the image: how much the number of traversal steps varies,
+//! and how many rays start out through the same nodes as another ray of the tile. Packets are
+//! traced as well, to see how many of their lanes do useful work.
+
+use super::{Config, PACKET_TILE, tiles};
+use bvh::{StatsRecorder, TraversalStats};
+use camera::{Camera, CameraSample};
+use cast::{f64, i64, u64, usize};
+use film::{PixelOrder, Rect};
+use geom::Ray;
+use rayon::prelude::*;
+use scene::Scene;
+use std::collections::HashMap;
+use toml::Value;
+use toml::value::Table;
+
+/// Side length of the square tiles whose rays are compared with each other.
+const TILE: u32 = 8;
+/// How many of the first nodes two rays must have in common to share a prefix.
+const PREFIX_NODES: usize = 8;
+
+/// The nodes a ray visited, in order.
+#[derive(Default)]
+struct NodePath(Vec<usize>);
+
+impl StatsRecorder for NodePath {
+    fn node_visited(&mut self, node: usize) {
+        self.0.push(node);
+    }
+}
+
+/// Sums over tiles, to be divided at the end.
+#[derive(Default)]
+struct Totals {
+    rays: usize,
+    /// The variance of the number of nodes visited, summed over the tiles with two rays or more.
+    step_variance: f64,
+    tiles: usize,
+    /// Rays whose first `PREFIX_NODES` nodes are also the first ones of another ray in the tile.
+    shared_prefix: usize,
+    /// Box tests and box hits of all lanes in packet traversal.
+    lane_box_tests: u64,
+    lane_box_hits: u64,
+}
+
+impl Totals {
+    fn add(self, other: Totals) -> Totals {
+        Totals {
+            rays: self.rays + other.rays,
+            step_variance: self.step_variance + other.step_variance,
+            tiles: self.tiles + other.tiles,
+            shared_prefix: self.shared_prefix + other.shared_prefix,
+            lane_box_tests: self.lane_box_tests + other.lane_box_tests,
+            lane_box_hits: self.lane_box_hits + other.lane_box_hits,
+        }
+    }
+}
+
+/// The coherence of the primary rays of `camera` in the image (or the crop window), as a table
+/// for the stats file.
+pub fn stats(scene: &Scene, cfg: &Config, camera: &Camera) -> Table {
+    let window = cfg.crop.unwrap_or(Rect {
+                                        x: 0,
+                                        y: 0,
+                                        w: cfg.image_width,
+                                        h: cfg.image_height,
+                                    });
+    let totals = tiles(window, TILE, PixelOrder::Scanline)
+        .par_iter()
+        .map(|tile| tile_totals(scene, camera, tile))
+        .reduce(Totals::default, Totals::add);
+    let ratio = |a: f64, b: f64| if b > 0.0 { a / b } else { 0.0 };
+    let mut table = Table::new();
+    let mut set = |key: &str, value: Value| {
+        table.insert(key.to_string(), value);
+    };
+    set("rays", Value::Integer(i64(totals.rays).unwrap_or(i64::max_value())));
+    set("tile_size", Value::Integer(i64::from(TILE)));
+    set("mean_tile_step_variance",
+        Value::Float(ratio(totals.step_variance, f64(totals.tiles))));
+    set("prefix_nodes", Value::Integer(i64(PREFIX_NODES).unwrap()));
+    set("shared_prefix_fraction",
+        Value::Float(ratio(f64(totals.shared_prefix), f64(totals.rays))));
+    set("packet_size", Value::Integer(i64::from(PACKET_TILE * PACKET_TILE)));
+    set("packet_simd_utilization",
+        Value::Float(ratio(f64(totals.lane_box_hits), f64(totals.lane_box_tests))));
+    table
+}
+
+/// The center rays of the pixels in `tile` that the camera covers.
+fn primary_rays(camera: &Camera, tile: &Rect) -> Vec<Ray> {
+    let samples = vec![CameraSample::center(); usize(tile.w) * usize(tile.h)];
+    camera.primary_rays(*tile, &samples).into_iter().filter_map(|r| r).collect()
+}
+
+fn tile_totals(scene: &Scene, camera: &Camera, tile: &Rect) -> Totals {
+    let paths: Vec<Vec<usize>> = primary_rays(camera, tile)
+        .iter()
+        .map(|r| {
+                 let mut path = NodePath::default();
+                 scene.intersect_recorded(r, &mut path);
+                 path.0
+             })
+        .collect();
+    let mut totals = Totals { rays: paths.len(), ..Totals::default() };
+    if paths.len() >= 2 {
+        let steps: Vec<f64> = paths.iter().map(|path| f64(path.len())).collect();
+        let mean = steps.iter().sum::<f64>() / f64(steps.len());
+        totals.step_variance = steps.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() /
+                               f64(steps.len());
+        totals.tiles = 1;
+    }
+    let mut prefixes: HashMap<&[usize], usize> = HashMap::new();
+    for path in &paths {
+        *prefixes.entry(&path[..path.len().min(PREFIX_NODES)]).or_insert(0) += 1;
+    }
+    totals.shared_prefix = prefixes.values().filter(|&&count| count >= 2).sum();
+
+    // Every lane tests every box the packet visits, but only those whose ray hits it need to.
+    for packet in tiles(*tile, PACKET_TILE, PixelOrder::Scanline) {
+        let rays = primary_rays(camera, &packet);
+        let mut stats = vec![TraversalStats::default(); rays.len()];
+        scene.intersect_packet(&rays, &mut stats);
+        for s in &stats {
+            totals.lane_box_tests += u64(s.boxes_tested);
+            totals.lane_box_hits += u64(s.nodes_visited);
+        }
+    }
+    totals
+}
@@ -0,0 +1,94 @@
+use cast::f32;
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign};
+
+/// Linear RGB radiance (or reflectance, or anything else with three color channels).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rgb {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+}
+
+impl Rgb {
+    pub fn new(r: f32, g: f32, b: f32) -> Self {
+        Rgb { r, g, b }
+    }
+
+    pub fn grey(v: f32) -> Self {
+        Rgb::new(v, v, v)
+    }
+
+    pub fn black() -> Self {
+        Rgb::grey(0.0)
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.r == 0.0 && self.g == 0.0 && self.b == 0.0
+    }
+
+    pub fn max_component(&self) -> f32 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    /// The color with the given hue (in turns), saturation and value, all in [0, 1].
+    pub fn from_hsv(h: f32, s: f32, v: f32) -> Self {
+        let channel = |n: f32| {
+            let k = (n + h * 6.0) % 6.0;
+            v - v * s * k.min(4.0 - k).min(1.0).max(0.0)
+        };
+        Rgb::new(channel(5.0), channel(3.0), channel(1.0))
+    }
+
+    /// A color for the index `id` of something, e.g. a group. Consecutive indices get very
+    /// different hues.
+    pub fn distinct(id: u32) -> Self {
+        // Steps of the golden ratio spread the hues evenly no matter how many there are.
+        let hue = (f32(id) * 0.618_034).fract();
+        Rgb::from_hsv(hue, 0.6, 0.9)
+    }
+
+    /// Relative luminance according to Rec. 709.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+}
+
+impl Add for Rgb {
+    type Output = Rgb;
+    fn add(self, other: Rgb) -> Rgb {
+        Rgb::new(self.r + other.r, self.g + other.g, self.b + other.b)
+    }
+}
+
+impl AddAssign for Rgb {
+    fn add_assign(&mut self, other: Rgb) {
+        *self = *self + other;
+    }
+}
+
+impl Mul for Rgb {
+    type Output = Rgb;
+    fn mul(self, other: Rgb) -> Rgb {
+        Rgb::new(self.r * other.r, self.g * other.g, self.b * other.b)
+    }
+}
+
+impl MulAssign for Rgb {
+    fn mul_assign(&mut self, other: Rgb) {
+        *self = *self * other;
+    }
+}
+
+impl Mul<f32> for Rgb {
+    type Output = Rgb;
+    fn mul(self, s: f32) -> Rgb {
+        Rgb::new(self.r * s, self.g * s, self.b * s)
+    }
+}
+
+impl Div<f32> for Rgb {
+    type Output = Rgb;
+    fn div(self, s: f32) -> Rgb {
+        Rgb::new(self.r / s, self.g / s, self.b / s)
+    }
+}
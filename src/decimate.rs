@@ -0,0 +1,383 @@
+//! Simplifying a mesh by collapsing edges, choosing the cheapest collapse by the quadric error
+//! metric of Garland and Heckbert.
+//!
+//! Each vertex carries a quadric, the sum of the squared distances to the planes of the
+//! triangles around it in the original mesh. Collapsing an edge merges the quadrics of its
+//! vertices and moves the remaining vertex to the position that minimizes the merged one. Edges
+//! on the boundary of the surface also get planes perpendicular to their triangle, so that holes
+//! and open borders keep their shape. Collapses that would flip a triangle or pinch the surface
+//! into a non-manifold are skipped.
+
+use cast::{f64, usize};
+use cgmath::{InnerSpace, Matrix3, SquareMatrix, Vector3, vec3};
+use geom::{TriMesh, index, to_f64};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::AddAssign;
+
+/// How much more the planes along boundary edges count than those of triangles.
+const BOUNDARY_WEIGHT: f64 = 10.0;
+
+pub struct Report {
+    pub tris_before: usize,
+    pub tris_after: usize,
+    /// The largest and average square root of the quadric error of the collapses, which is
+    /// about the distance the surface moved.
+    pub max_error: f64,
+    pub mean_error: f64,
+}
+
+/// A symmetric 4x4 matrix, stored as its upper triangle row by row.
+#[derive(Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// The squared distance to the plane through `p` with unit normal `n`, times `weight`.
+    fn plane(n: Vector3<f64>, p: Vector3<f64>, weight: f64) -> Quadric {
+        let (a, b, c, d) = (n.x, n.y, n.z, -n.dot(p));
+        let mut q = [a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d];
+        for x in &mut q {
+            *x *= weight;
+        }
+        Quadric(q)
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x + q[4] * y * y +
+        2.0 * q[5] * y * z + 2.0 * q[6] * y + q[7] * z * z + 2.0 * q[8] * z + q[9]
+    }
+
+    /// The point where the error is smallest, if there's a single one.
+    fn minimum(&self) -> Option<Vector3<f64>> {
+        let q = &self.0;
+        let a = Matrix3::new(q[0], q[1], q[2], q[1], q[4], q[5], q[2], q[5], q[7]);
+        if a.determinant().abs() < 1e-10 {
+            return None;
+        }
+        a.invert().map(|inv| inv * -vec3(q[3], q[6], q[8]))
+    }
+}
+
+impl AddAssign for Quadric {
+    fn add_assign(&mut self, other: Quadric) {
+        for (x, y) in self.0.iter_mut().zip(&other.0) {
+            *x += *y;
+        }
+    }
+}
+
+/// Collapsing the edge between two vertices, valid as long as neither changed since.
+struct Collapse {
+    cost: f64,
+    target: Vector3<f64>,
+    from: usize,
+    to: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Collapse) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Collapse) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    // Reversed, so that the heap pops the cheapest collapse first.
+    fn cmp(&self, other: &Collapse) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+struct Decimator {
+    positions: Vec<Vector3<f64>>,
+    quadrics: Vec<Quadric>,
+    corners: Vec<[usize; 3]>,
+    removed: Vec<bool>,
+    /// The triangles around each vertex, including some that were removed since.
+    vertex_tris: Vec<Vec<usize>>,
+    alive: Vec<bool>,
+    versions: Vec<u32>,
+    heap: BinaryHeap<Collapse>,
+}
+
+/// Collapse edges until at most `ratio` of the triangles are left or no edge can be collapsed.
+/// Vertices are moved and triangles renumbered in place; the triangles that collapsed are marked
+/// with false in the returned vector but not removed, so the caller can drop their attributes as
+/// well. Vertices that are no longer used stay in the mesh.
+///
+/// Only vertices shared between triangles are considered connected, so the mesh should be
+/// welded first.
+pub fn decimate(mesh: &mut TriMesh, ratio: f32) -> (Vec<bool>, Report) {
+    let tris_before = mesh.tris.len();
+    let target = (f64(ratio) * f64(tris_before)).ceil() as usize;
+    let mut d = Decimator::new(mesh);
+    let mut live = tris_before;
+    let mut errors = Vec::new();
+    while live > target {
+        let collapse = match d.heap.pop() {
+            Some(collapse) => collapse,
+            None => break,
+        };
+        let (from, to) = (collapse.from, collapse.to);
+        if !d.alive[from] || !d.alive[to] ||
+           collapse.versions != (d.versions[from], d.versions[to]) {
+            continue;
+        }
+        if !d.is_manifold_collapse(from, to) || d.flips(from, to, collapse.target) {
+            continue;
+        }
+        live -= d.collapse(from, to, collapse.target);
+        errors.push(collapse.cost.max(0.0).sqrt());
+    }
+
+    for (v, p) in mesh.vertices.iter_mut().zip(&d.positions) {
+        *v = vec3(p.x as f32, p.y as f32, p.z as f32);
+    }
+    for (tri, c) in mesh.tris.iter_mut().zip(&d.corners) {
+        tri.a = index(c[0]);
+        tri.b = index(c[1]);
+        tri.c = index(c[2]);
+    }
+    let keep = d.removed.iter().map(|&removed| !removed).collect();
+    let report = Report {
+        tris_before,
+        tris_after: live,
+        max_error: errors.iter().cloned().fold(0.0, f64::max),
+        mean_error: if errors.is_empty() {
+            0.0
+        } else {
+            errors.iter().sum::<f64>() / f64(errors.len())
+        },
+    };
+    (keep, report)
+}
+
+impl Decimator {
+    fn new(mesh: &TriMesh) -> Decimator {
+        let positions: Vec<_> = mesh.vertices.iter().map(|&v| to_f64(v)).collect();
+        let corners: Vec<[usize; 3]> = mesh.tris
+            .iter()
+            .map(|tri| [usize(tri.a), usize(tri.b), usize(tri.c)])
+            .collect();
+        let mut quadrics = vec![Quadric::default(); positions.len()];
+        let mut vertex_tris = vec![Vec::new(); positions.len()];
+        // For each undirected edge, the number of triangles that have it and one of them.
+        let mut edges: HashMap<(usize, usize), (u32, usize)> = HashMap::new();
+        for (i, c) in corners.iter().enumerate() {
+            let n = tri_normal(&positions, c);
+            for k in 0..3 {
+                vertex_tris[c[k]].push(i);
+                if let Some(n) = n {
+                    quadrics[c[k]] += Quadric::plane(n, positions[c[k]], 1.0);
+                }
+                let (a, b) = (c[k], c[(k + 1) % 3]);
+                let key = if a < b { (a, b) } else { (b, a) };
+                edges.entry(key).or_insert((0, i)).0 += 1;
+            }
+        }
+        for (&(a, b), &(count, tri)) in &edges {
+            if count != 1 {
+                continue;
+            }
+            if let Some(n) = tri_normal(&positions, &corners[tri]) {
+                let (pa, pb) = (positions[a], positions[b]);
+                let side = (pb - pa).cross(n);
+                if side.magnitude2() > 0.0 {
+                    let plane = Quadric::plane(side.normalize(), pa, BOUNDARY_WEIGHT);
+                    quadrics[a] += plane;
+                    quadrics[b] += plane;
+                }
+            }
+        }
+
+        let num_vertices = positions.len();
+        let mut d = Decimator {
+            positions,
+            quadrics,
+            removed: vec![false; corners.len()],
+            corners,
+            vertex_tris,
+            alive: vec![true; num_vertices],
+            versions: vec![0; num_vertices],
+            heap: BinaryHeap::new(),
+        };
+        for &(a, b) in edges.keys() {
+            d.push_collapse(a, b);
+        }
+        d
+    }
+
+    /// Queue the collapse of `from` into `to` at the best position.
+    fn push_collapse(&mut self, from: usize, to: usize) {
+        let mut q = self.quadrics[from];
+        q += self.quadrics[to];
+        let (a, b) = (self.positions[from], self.positions[to]);
+        let mut candidates = vec![a, b, (a + b) * 0.5];
+        candidates.extend(q.minimum());
+        let (cost, target) = candidates.into_iter()
+            .map(|p| (q.error(p), p))
+            .fold((f64::INFINITY, b), |best, c| if c.0 < best.0 { c } else { best });
+        self.heap.push(Collapse {
+                           cost,
+                           target,
+                           from,
+                           to,
+                           versions: (self.versions[from], self.versions[to]),
+                       });
+    }
+
+    fn live_tris(&self, v: usize) -> Vec<usize> {
+        self.vertex_tris[v].iter().cloned().filter(|&t| !self.removed[t]).collect()
+    }
+
+    fn neighbors(&self, v: usize) -> HashSet<usize> {
+        self.live_tris(v)
+            .into_iter()
+            .flat_map(|t| self.corners[t].to_vec())
+            .filter(|&u| u != v)
+            .collect()
+    }
+
+    /// The link condition: the vertices adjacent to both ends of the edge must be exactly the
+    /// opposite corners of the triangles that share it, or the collapse glues surfaces together.
+    fn is_manifold_collapse(&self, from: usize, to: usize) -> bool {
+        let shared = self.live_tris(from)
+            .into_iter()
+            .filter(|&t| self.corners[t].contains(&to))
+            .count();
+        let common = self.neighbors(from).intersection(&self.neighbors(to)).count();
+        shared > 0 && shared <= 2 && common == shared
+    }
+
+    /// Whether moving both vertices to `target` turns any remaining triangle around.
+    fn flips(&self, from: usize, to: usize, target: Vector3<f64>) -> bool {
+        self.live_tris(from).into_iter().chain(self.live_tris(to)).any(|t| {
+            let c = self.corners[t];
+            if c.contains(&from) && c.contains(&to) {
+                return false;
+            }
+            let before = tri_normal(&self.positions, &c);
+            let mut moved = [self.positions[c[0]], self.positions[c[1]], self.positions[c[2]]];
+            for k in 0..3 {
+                if c[k] == from || c[k] == to {
+                    moved[k] = target;
+                }
+            }
+            let after = (moved[1] - moved[0]).cross(moved[2] - moved[0]);
+            match before {
+                Some(n) => after.dot(n) <= 0.0,
+                None => false,
+            }
+        })
+    }
+
+    /// Merge `from` into `to`, returning the number of triangles that disappeared.
+    fn collapse(&mut self, from: usize, to: usize, target: Vector3<f64>) -> usize {
+        let mut gone = 0;
+        let from_tris = ::std::mem::replace(&mut self.vertex_tris[from], Vec::new());
+        for t in from_tris {
+            if self.removed[t] {
+                continue;
+            }
+            if self.corners[t].contains(&to) {
+                self.removed[t] = true;
+                gone += 1;
+            } else {
+                for corner in &mut self.corners[t] {
+                    if *corner == from {
+                        *corner = to;
+                    }
+                }
+                self.vertex_tris[to].push(t);
+            }
+        }
+        let q = self.quadrics[from];
+        self.quadrics[to] += q;
+        self.positions[to] = target;
+        self.alive[from] = false;
+        self.versions[to] += 1;
+        let removed = &self.removed;
+        self.vertex_tris[to].retain(|&t| !removed[t]);
+        // Only the collapses of the edges at `to` cost something else now. The new version of
+        // `to` marks those already in the heap as stale, so they're skipped when popped.
+        for u in self.neighbors(to) {
+            self.push_collapse(u, to);
+        }
+        gone
+    }
+}
+
+/// The unit normal of a triangle, or None if it has no area.
+fn tri_normal(positions: &[Vector3<f64>], c: &[usize; 3]) -> Option<Vector3<f64>> {
+    let (a, b, e) = (positions[c[0]], positions[c[1]], positions[c[2]]);
+    let n = (b - a).cross(e - a);
+    if n.magnitude2() > 0.0 { Some(n.normalize()) } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geom::Tri;
+
+    /// A flat grid of `n` by `n` squares, each split into two triangles.
+    fn grid(n: usize) -> TriMesh {
+        let mut vertices = Vec::new();
+        for y in 0..n + 1 {
+            for x in 0..n + 1 {
+                vertices.push(vec3(x as f32, y as f32, 0.0));
+            }
+        }
+        let mut tris = Vec::new();
+        let v = |x: usize, y: usize| index(y * (n + 1) + x);
+        for y in 0..n {
+            for x in 0..n {
+                tris.push(Tri {
+                              a: v(x, y),
+                              b: v(x + 1, y),
+                              c: v(x + 1, y + 1),
+                          });
+                tris.push(Tri {
+                              a: v(x, y),
+                              b: v(x + 1, y + 1),
+                              c: v(x, y + 1),
+                          });
+            }
+        }
+        TriMesh::new(vertices, tris)
+    }
+
+    #[test]
+    fn flat_grid_down_to_target() {
+        let mut mesh = grid(8);
+        let (keep, report) = decimate(&mut mesh, 0.25);
+        assert_eq!(report.tris_before, 128);
+        assert_eq!(keep.iter().filter(|&&k| k).count(), report.tris_after);
+        // Collapses remove one or two triangles, so the target may be undershot by one.
+        assert!(report.tris_after == 32 || report.tris_after == 31,
+                "{} triangles left",
+                report.tris_after);
+        // Nothing moves out of the plane or past the border, so the error stays zero.
+        assert_eq!(report.max_error, 0.0);
+        let kept = mesh.tris.iter().zip(&keep).filter(|&(_, &k)| k);
+        let (mut min, mut max) = (vec3(8.0f32, 8.0, 0.0), vec3(0.0f32, 0.0, 0.0));
+        for (tri, _) in kept {
+            for &i in &[tri.a, tri.b, tri.c] {
+                let p = mesh.vertices[usize(i)];
+                assert_eq!(p.z, 0.0);
+                min = vec3(min.x.min(p.x), min.y.min(p.y), 0.0);
+                max = vec3(max.x.max(p.x), max.y.max(p.y), 0.0);
+            }
+        }
+        assert_eq!((min, max), (vec3(0.0, 0.0, 0.0), vec3(8.0, 8.0, 0.0)));
+    }
+}
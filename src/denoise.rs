@@ -0,0 +1,109 @@
+//! Edge-avoiding à-trous wavelet filtering (Dammertz et al. 2010) for noisy path traced images.
+//! Each pass blurs with a 5x5 B3 spline kernel whose taps are spread further apart every time,
+//! and neighbors only contribute if their color, normal and depth are similar enough.
+
+use cast::{f32, i64};
+use cgmath::{InnerSpace, Vector3};
+use color::Rgb;
+use film::{Frame, Rect};
+use std::f32;
+
+/// Number of filter passes. The kernel spans 4 * 2^i + 1 pixels in pass i.
+const PASSES: u32 = 5;
+/// B3 spline coefficients. The 2D kernel is their outer product.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// How quickly the weight falls off with the (tone mapped) color difference. Halved every pass,
+/// since the image gets smoother and the remaining differences are more likely to be edges.
+const COLOR_SIGMA: f32 = 0.5;
+/// Exponent applied to the cosine between normals.
+const NORMAL_POWER: i32 = 64;
+/// How quickly the weight falls off with the depth difference, relative to the depth.
+const DEPTH_SIGMA: f32 = 0.05;
+
+/// Denoise the pixels of `color` inside `window`, using `normals` and `depth` (as produced by
+/// the normals and depth render kinds) to avoid blurring across geometric edges.
+pub fn denoise(color: &Frame<Rgb>,
+               normals: &Frame<Vector3<f32>>,
+               depth: &Frame<f32>,
+               window: Rect)
+               -> Frame<Rgb> {
+    let bounds = color.bounds();
+    let mut current = Frame::new(bounds.w, bounds.h, Rgb::black());
+    current.set_pixels(window, |x, y| color.get(x, y));
+    for pass in 0..PASSES {
+        let mut next = Frame::new(bounds.w, bounds.h, Rgb::black());
+        next.set_pixels(window,
+                        |x, y| filter_pixel(&current, normals, depth, window, pass, x, y));
+        current = next;
+    }
+    current
+}
+
+fn filter_pixel(color: &Frame<Rgb>,
+                normals: &Frame<Vector3<f32>>,
+                depth: &Frame<f32>,
+                window: Rect,
+                pass: u32,
+                x: u32,
+                y: u32)
+                -> Rgb {
+    let step = 1i64 << pass;
+    let color_sigma = COLOR_SIGMA / f32(step);
+    let (c_p, n_p, z_p) = (color.get(x, y), normals.get(x, y), depth.get(x, y));
+    let compressed_p = compress(c_p);
+    let mut sum = Rgb::black();
+    let mut total_weight = 0.0;
+    for (i, &kx) in KERNEL.iter().enumerate() {
+        for (j, &ky) in KERNEL.iter().enumerate() {
+            let qx = i64(x) + (i64(i).unwrap() - 2) * step;
+            let qy = i64(y) + (i64(j).unwrap() - 2) * step;
+            if qx < i64(window.x) || qx >= i64(window.x + window.w) || qy < i64(window.y) ||
+               qy >= i64(window.y + window.h) {
+                continue;
+            }
+            let (qx, qy) = (qx as u32, qy as u32);
+            let c_q = color.get(qx, qy);
+            let w_depth = depth_weight(z_p, depth.get(qx, qy));
+            if w_depth == 0.0 {
+                continue;
+            }
+            let w_normal = normal_weight(n_p, normals.get(qx, qy));
+            let compressed_q = compress(c_q);
+            let (dr, dg, db) = (compressed_q.r - compressed_p.r,
+                                compressed_q.g - compressed_p.g,
+                                compressed_q.b - compressed_p.b);
+            let dist2 = dr * dr + dg * dg + db * db;
+            let w_color = (-dist2 / (color_sigma * color_sigma)).exp();
+            let w = kx * ky * w_color * w_normal * w_depth;
+            sum += c_q * w;
+            total_weight += w;
+        }
+    }
+    // The center tap always has weight > 0 unless the pixel itself is NaN.
+    if total_weight > 0.0 { sum / total_weight } else { c_p }
+}
+
+/// Squeeze radiance into [0, 1) so that a few very bright samples don't dominate the color
+/// differences.
+fn compress(c: Rgb) -> Rgb {
+    c / (1.0 + c.luminance())
+}
+
+fn normal_weight(n_p: Vector3<f32>, n_q: Vector3<f32>) -> f32 {
+    let zero = Vector3::new(0.0, 0.0, 0.0);
+    if n_p == zero || n_q == zero {
+        // At least one of the pixels saw only the background, which the depth handles.
+        1.0
+    } else {
+        n_p.dot(n_q).max(0.0).powi(NORMAL_POWER)
+    }
+}
+
+fn depth_weight(z_p: f32, z_q: f32) -> f32 {
+    match (z_p == f32::INFINITY, z_q == f32::INFINITY) {
+        (true, true) => 1.0,
+        (false, false) => (-(z_p - z_q).abs() / (DEPTH_SIGMA * z_p.max(1e-3))).exp(),
+        _ => 0.0,
+    }
+}
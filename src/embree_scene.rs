@@ -0,0 +1,142 @@
+//! Intersection through Embree, as a state-of-the-art reference to compare the native BVH
+//! against. Only built with the `embree` feature.
+
+use super::fail;
+use embree::sys;
+use geom::{Hit, HitFilter, Index, Ray, TriMesh};
+use std::{mem, ptr, slice};
+use watertri::Intersection;
+
+/// `RTC_INVALID_GEOMETRY_ID`, the `geomID` of rays that didn't hit anything.
+const INVALID_ID: u32 = !0;
+
+/// An Embree device and a committed scene with one triangle mesh, a copy of a `TriMesh`.
+/// Triangle IDs are the same as in the `TriMesh` it was created from.
+pub struct EmbreeScene {
+    device: sys::RTCDevice,
+    scene: sys::RTCScene,
+}
+
+// Committed Embree scenes can be traced from any number of threads at once.
+unsafe impl Send for EmbreeScene {}
+unsafe impl Sync for EmbreeScene {}
+
+impl EmbreeScene {
+    pub fn new(mesh: &TriMesh) -> Self {
+        // Embree's indices are 32 bits even with the `large-scenes` feature.
+        assert!(mesh.vertices.len() <= u32::max_value() as usize,
+                "too many vertices for Embree");
+        unsafe {
+            let device = sys::rtcNewDevice(ptr::null());
+            if device.is_null() {
+                fail("couldn't create an Embree device");
+            }
+            let scene = sys::rtcNewScene(device);
+            let geometry = sys::rtcNewGeometry(device, sys::RTCGeometryType::TRIANGLE);
+            let vertices = sys::rtcSetNewGeometryBuffer(geometry,
+                                                        sys::RTCBufferType::VERTEX,
+                                                        0,
+                                                        sys::RTCFormat::FLOAT3,
+                                                        mem::size_of::<[f32; 3]>(),
+                                                        mesh.vertices.len());
+            let vertices = slice::from_raw_parts_mut(vertices as *mut [f32; 3],
+                                                     mesh.vertices.len());
+            for (dst, v) in vertices.iter_mut().zip(&mesh.vertices) {
+                *dst = [v.x, v.y, v.z];
+            }
+            let tris = sys::rtcSetNewGeometryBuffer(geometry,
+                                                    sys::RTCBufferType::INDEX,
+                                                    0,
+                                                    sys::RTCFormat::UINT3,
+                                                    mem::size_of::<[u32; 3]>(),
+                                                    mesh.tris.len());
+            let tris = slice::from_raw_parts_mut(tris as *mut [u32; 3], mesh.tris.len());
+            for (dst, tri) in tris.iter_mut().zip(&mesh.tris) {
+                *dst = [tri.a as u32, tri.b as u32, tri.c as u32];
+            }
+            sys::rtcCommitGeometry(geometry);
+            sys::rtcAttachGeometry(scene, geometry);
+            sys::rtcReleaseGeometry(geometry);
+            sys::rtcCommitScene(scene);
+            EmbreeScene { device, scene }
+        }
+    }
+
+    /// The closest hit between `r.t_min` and `r.t_max`.
+    pub fn intersect(&self, r: &Ray) -> Hit {
+        let mut context = sys::RTCIntersectContext {
+            flags: sys::RTCIntersectContextFlags::INCOHERENT,
+            filter: None,
+            instID: [INVALID_ID],
+        };
+        let mut ray_hit = sys::RTCRayHit {
+            ray: sys::RTCRay {
+                org_x: r.o.x,
+                org_y: r.o.y,
+                org_z: r.o.z,
+                tnear: r.t_min,
+                dir_x: r.d.x,
+                dir_y: r.d.y,
+                dir_z: r.d.z,
+                time: 0.0,
+                tfar: r.t_max,
+                mask: !0,
+                id: 0,
+                flags: 0,
+            },
+            hit: sys::RTCHit {
+                Ng_x: 0.0,
+                Ng_y: 0.0,
+                Ng_z: 0.0,
+                u: 0.0,
+                v: 0.0,
+                primID: INVALID_ID,
+                geomID: INVALID_ID,
+                instID: [INVALID_ID],
+            },
+        };
+        unsafe {
+            sys::rtcIntersect1(self.scene, &mut context, &mut ray_hit);
+        }
+        if ray_hit.hit.geomID == INVALID_ID {
+            return Hit::none();
+        }
+        // Embree's u and v are the weights of the second and third vertex.
+        let (u, v) = (ray_hit.hit.u, ray_hit.hit.v);
+        Hit {
+            tri_id: Index::from(ray_hit.hit.primID),
+            t: ray_hit.ray.tfar,
+            u: 1.0 - u - v,
+            v: u,
+            w: v,
+        }
+    }
+
+    /// Like `intersect`, but skip hits that don't pass `filter`.
+    pub fn intersect_filtered(&self, r: &Ray, filter: &HitFilter) -> Hit {
+        let mut r = *r;
+        loop {
+            let hit = self.intersect(&r);
+            let i = Intersection {
+                t: hit.t,
+                u: hit.u,
+                v: hit.v,
+                w: hit.w,
+            };
+            if !hit.is_valid() || filter(hit.tri_id, &i) {
+                return hit;
+            }
+            // Embree's range includes `tnear`, so continue just past the rejected hit.
+            r.t_min = f32::from_bits(hit.t.max(0.0).to_bits() + 1);
+        }
+    }
+}
+
+impl Drop for EmbreeScene {
+    fn drop(&mut self) {
+        unsafe {
+            sys::rtcReleaseScene(self.scene);
+            sys::rtcReleaseDevice(self.device);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use super::Config;
+use cast::{f32, usize};
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use sampling::{Distribution2D, uniform_sphere};
+use sky::Sky;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+/// The light arriving from infinitely far away, i.e., what rays that miss all geometry see.
+pub enum Environment {
+    Constant(Rgb),
+    Map(EnvMap),
+    Sky(Sky),
+}
+
+/// An equirectangular environment map, oriented like the equirectangular camera:
+/// +Y is up and the center of the image is in the -Z direction.
+pub struct EnvMap {
+    width: usize,
+    height: usize,
+    texels: Vec<Rgb>,
+    /// For importance sampling texels proportionally to their contribution to the lighting.
+    distribution: Distribution2D,
+}
+
+impl Environment {
+    pub fn radiance(&self, d: Vector3<f32>) -> Rgb {
+        match *self {
+            Environment::Constant(c) => c,
+            Environment::Map(ref map) => map.radiance(d),
+            Environment::Sky(ref sky) => sky.radiance(d),
+        }
+    }
+
+    /// The sun, if the environment has one that should be treated as a directional light.
+    pub fn sun(&self) -> Option<(Vector3<f32>, Rgb)> {
+        match *self {
+            Environment::Sky(ref sky) => Some(sky.sun()),
+            _ => None,
+        }
+    }
+
+    /// Sample an incoming direction, returning it with its radiance and pdf (w.r.t. solid angle).
+    pub fn sample(&self, u: f32, v: f32) -> (Vector3<f32>, Rgb, f32) {
+        match *self {
+            Environment::Constant(c) => (uniform_sphere(u, v), c, 1.0 / (4.0 * PI)),
+            Environment::Map(ref map) => map.sample(u, v),
+            Environment::Sky(ref sky) => {
+                let d = uniform_sphere(u, v);
+                (d, sky.radiance(d), 1.0 / (4.0 * PI))
+            }
+        }
+    }
+
+    /// The pdf of `sample` returning the direction `d`.
+    pub fn pdf(&self, d: Vector3<f32>) -> f32 {
+        match *self {
+            Environment::Constant(_) | Environment::Sky(_) => 1.0 / (4.0 * PI),
+            Environment::Map(ref map) => map.pdf(d),
+        }
+    }
+}
+
+impl EnvMap {
+    pub fn new(width: usize, height: usize, texels: Vec<Rgb>) -> Self {
+        assert_eq!(texels.len(), width * height);
+        // Texels near the poles cover less solid angle, weigh them by sin(theta) to account
+        // for that. Also make sure even black texels can be sampled.
+        let func: Vec<f32> = texels.iter()
+            .enumerate()
+            .map(|(i, t)| {
+                     let theta = PI * (f32(i / width) + 0.5) / f32(height);
+                     (t.luminance() + 1e-3) * theta.sin()
+                 })
+            .collect();
+        let distribution = Distribution2D::new(&func, width, height);
+        EnvMap {
+            width,
+            height,
+            texels,
+            distribution,
+        }
+    }
+
+    fn radiance(&self, d: Vector3<f32>) -> Rgb {
+        let (u, v) = dir_to_uv(d);
+        let x = usize(u * f32(self.width)).unwrap_or(0).min(self.width - 1);
+        let y = usize(v * f32(self.height))
+            .unwrap_or(0)
+            .min(self.height - 1);
+        self.texels[y * self.width + x]
+    }
+
+    fn sample(&self, u: f32, v: f32) -> (Vector3<f32>, Rgb, f32) {
+        let ((x, y), pdf_uv) = self.distribution.sample(u, v);
+        let d = uv_to_dir(x, y);
+        let sin_theta = (PI * y).sin();
+        let pdf = if sin_theta > 0.0 {
+            pdf_uv / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        };
+        (d, self.radiance(d), pdf)
+    }
+
+    fn pdf(&self, d: Vector3<f32>) -> f32 {
+        let (u, v) = dir_to_uv(d);
+        let sin_theta = (PI * v).sin();
+        if sin_theta > 0.0 {
+            self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        }
+    }
+}
+
+fn dir_to_uv(d: Vector3<f32>) -> (f32, f32) {
+    let d = d.normalize();
+    let phi = d.x.atan2(-d.z);
+    let theta = d.y.max(-1.0).min(1.0).acos();
+    (phi / (2.0 * PI) + 0.5, theta / PI)
+}
+
+fn uv_to_dir(u: f32, v: f32) -> Vector3<f32> {
+    let phi = 2.0 * PI * (u - 0.5);
+    let theta = PI * v;
+    let sin_theta = theta.sin();
+    vec3(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos())
+}
+
+/// Read an image in the Radiance RGBE (.hdr) format.
+pub fn read_hdr(path: &Path) -> io::Result<EnvMap> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file"));
+    }
+    // The header ends with an empty line.
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("unexpected end of header"));
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+        if trimmed.starts_with("FORMAT=") && trimmed != "FORMAT=32-bit_rle_rgbe" {
+            return Err(invalid("only the RGBE pixel format is supported"));
+        }
+    }
+    line.clear();
+    reader.read_line(&mut line)?;
+    let dims: Vec<&str> = line.split_whitespace().collect();
+    let (height, width) = match (dims.get(0), dims.get(1), dims.get(2), dims.get(3)) {
+        (Some(&"-Y"), Some(h), Some(&"+X"), Some(w)) => {
+            match (h.parse::<usize>(), w.parse::<usize>()) {
+                (Ok(h), Ok(w)) if h > 0 && w > 0 => (h, w),
+                _ => return Err(invalid("invalid image dimensions")),
+            }
+        }
+        _ => return Err(invalid("only the standard -Y H +X W orientation is supported")),
+    };
+
+    let mut texels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        read_scanline(&mut reader, &mut scanline)?;
+        texels.extend(scanline.iter().map(|&rgbe| rgbe_to_rgb(rgbe)));
+    }
+    Ok(EnvMap::new(width, height, texels))
+}
+
+fn read_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    reader.read_exact(&mut first)?;
+    let is_rle = first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+    if !is_rle || width < 8 || width > 0x7fff {
+        // Flat scanline.
+        scanline[0] = first;
+        for px in &mut scanline[1..] {
+            reader.read_exact(px)?;
+        }
+        return Ok(());
+    }
+    if (usize(first[2]) << 8 | usize(first[3])) != width {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "scanline width mismatch"));
+    }
+    // Each of the four channels is run-length encoded separately.
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count)?;
+            let (count, is_run) = if count[0] > 128 {
+                (usize(count[0] - 128), true)
+            } else {
+                (usize(count[0]), false)
+            };
+            if count == 0 || x + count > width {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad scanline data"));
+            }
+            if is_run {
+                let mut value = [0u8; 1];
+                reader.read_exact(&mut value)?;
+                for px in &mut scanline[x..x + count] {
+                    px[channel] = value[0];
+                }
+            } else {
+                for px in &mut scanline[x..x + count] {
+                    let mut value = [0u8; 1];
+                    reader.read_exact(&mut value)?;
+                    px[channel] = value[0];
+                }
+            }
+            x += count;
+        }
+    }
+    Ok(())
+}
+
+fn rgbe_to_rgb(rgbe: [u8; 4]) -> Rgb {
+    if rgbe[3] == 0 {
+        return Rgb::black();
+    }
+    let scale = 2f32.powi(i32::from(rgbe[3]) - 136);
+    Rgb::new(f32(rgbe[0]) * scale,
+             f32(rgbe[1]) * scale,
+             f32(rgbe[2]) * scale)
+}
+
+/// Load the environment map or set up the sky model given in the configuration, if any.
+pub fn load(cfg: &Config) -> io::Result<Environment> {
+    if let Some(ref path) = cfg.envmap {
+        read_hdr(path).map(Environment::Map)
+    } else if cfg.sky {
+        let sky = Sky::new(cfg.sun_elevation.to_radians(),
+                           cfg.sun_azimuth.to_radians(),
+                           cfg.turbidity);
+        Ok(Environment::Sky(sky))
+    } else {
+        Ok(Environment::Constant(Rgb::grey(1.0)))
+    }
+}
+
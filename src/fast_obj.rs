@@ -0,0 +1,231 @@
+//! A parallel loader for OBJ files that contain nothing but geometry, which is what huge
+//! scans usually are. The file is memory-mapped and cut into chunks at line boundaries, the
+//! chunks are parsed in parallel and their vertices and faces are stitched together at the
+//! end. Files with anything else (materials, texture coordinates, ...) are left to obj-rs.
+//! Both `o` and `g` statements start a group, and vertices may have a color (`v x y z r g b`).
+
+use cast::{i64, u32, usize};
+use cgmath::{Vector3, vec3};
+use color::Rgb;
+use input::{Geometry, NO_VERTEX_COLOR};
+use memmap::Mmap;
+use rayon;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+
+/// The file is cut into about this many chunks per thread, so that threads that get chunks
+/// with cheap lines don't run out of work early.
+const CHUNKS_PER_THREAD: usize = 8;
+
+/// The contents of one chunk of the file.
+#[derive(Default)]
+struct Chunk {
+    vertices: Vec<Vector3<f32>>,
+    /// The colors of `vertices`, empty if none of them has one.
+    colors: Vec<Rgb>,
+    /// The position indices of the corners of all faces, as written in the file.
+    corners: Vec<i64>,
+    /// For each face, where its corners start in `corners` and how many vertices precede it in
+    /// this chunk, which relative (negative) indices are based on.
+    faces: Vec<(usize, usize)>,
+    /// The groups started in this chunk, with the number of faces in the chunk before each.
+    group_starts: Vec<(usize, String)>,
+}
+
+/// Read the OBJ file at `path`, or return None if it uses anything besides vertex positions,
+/// faces, normals (which are ignored), objects, groups and smoothing groups.
+pub fn read(path: &Path) -> Result<Option<Geometry>, String> {
+    let error = |e: String| format!("{}: {}", path.display(), e);
+    let file = File::open(path).map_err(|e| error(e.to_string()))?;
+    if file.metadata().map_err(|e| error(e.to_string()))?.len() == 0 {
+        return Ok(None);
+    }
+    let map = unsafe { Mmap::map(&file) }.map_err(|e| error(e.to_string()))?;
+    let ranges = split_lines(&map, rayon::current_num_threads() * CHUNKS_PER_THREAD);
+    let parsed: Vec<Result<Option<Chunk>, String>> =
+        ranges.par_iter().map(|range| parse_chunk(&map[range.clone()])).collect();
+    let mut chunks = Vec::with_capacity(parsed.len());
+    for chunk in parsed {
+        match chunk.map_err(&error)? {
+            Some(chunk) => chunks.push(chunk),
+            None => return Ok(None),
+        }
+    }
+
+    // Number of vertices in all chunks before each chunk.
+    let mut vertices_before = Vec::with_capacity(chunks.len());
+    let mut vertex_count = 0;
+    for chunk in &chunks {
+        vertices_before.push(vertex_count);
+        vertex_count += chunk.vertices.len();
+    }
+    let (groups, face_groups) = assign_groups(&chunks);
+    let tris: Vec<Result<Vec<([usize; 3], u32)>, String>> = chunks.par_iter()
+        .zip(&vertices_before)
+        .zip(&face_groups)
+        .map(|((chunk, &before), groups)| triangulate(chunk, before, vertex_count, groups))
+        .collect();
+    let mut all_tris = Vec::new();
+    let mut tri_groups = Vec::new();
+    for chunk_tris in tris {
+        for (tri, group) in chunk_tris.map_err(&error)? {
+            all_tris.push(tri);
+            tri_groups.push(group);
+        }
+    }
+    let has_colors = chunks.iter().any(|chunk| !chunk.colors.is_empty());
+    let mut vertices = Vec::with_capacity(vertex_count);
+    let mut colors = Vec::with_capacity(if has_colors { vertex_count } else { 0 });
+    for chunk in chunks {
+        if has_colors {
+            colors.extend(chunk.colors);
+            colors.resize(vertices.len() + chunk.vertices.len(), NO_VERTEX_COLOR);
+        }
+        vertices.extend(chunk.vertices);
+    }
+    Ok(Some(Geometry {
+                vertices,
+                tris: all_tris,
+                groups,
+                tri_groups,
+                colors,
+            }))
+}
+
+/// The names of all groups in the order they first appear, and the index of the group of each
+/// face in each chunk. Faces before the first group statement are in the group `default`, like
+/// obj-rs does it, and groups with the same name are the same group.
+fn assign_groups(chunks: &[Chunk]) -> (Vec<String>, Vec<Vec<u32>>) {
+    let mut groups = Vec::new();
+    let mut ids = HashMap::new();
+    let mut group_id = |name: &str| {
+        *ids.entry(name.to_string()).or_insert_with(|| {
+            groups.push(name.to_string());
+            u32(groups.len() - 1).unwrap()
+        })
+    };
+    let mut current = group_id("default");
+    let mut face_groups = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let mut chunk_groups = Vec::with_capacity(chunk.faces.len());
+        let mut starts = chunk.group_starts.iter().peekable();
+        for face in 0..chunk.faces.len() {
+            while starts.peek().map_or(false, |&&(before, _)| before <= face) {
+                current = group_id(&starts.next().unwrap().1);
+            }
+            chunk_groups.push(current);
+        }
+        // Groups started after the last face of the chunk continue in the next one.
+        for &(_, ref name) in starts {
+            current = group_id(name);
+        }
+        face_groups.push(chunk_groups);
+    }
+    (groups, face_groups)
+}
+
+/// Cut `bytes` into about `count` ranges of similar length that end after a line break
+/// (or at the end).
+fn split_lines(bytes: &[u8], count: usize) -> Vec<Range<usize>> {
+    let target_len = bytes.len() / count + 1;
+    let mut ranges = Vec::with_capacity(count);
+    let mut start = 0;
+    while start < bytes.len() {
+        let mut end = (start + target_len).min(bytes.len());
+        end += bytes[end..].iter().position(|&b| b == b'\n').map_or(0, |i| i + 1);
+        ranges.push(start..end.min(bytes.len()));
+        start = end;
+    }
+    ranges
+}
+
+/// Parse the lines in `bytes`, or return None if there's anything the loader doesn't support.
+fn parse_chunk(bytes: &[u8]) -> Result<Option<Chunk>, String> {
+    let text = match str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return Ok(None),
+    };
+    let mut chunk = Chunk::default();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap();
+        if line.ends_with('\\') {
+            // Continued on the next line, which may be in another chunk.
+            return Ok(None);
+        }
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("v") => {
+                let values = fields.map(str::parse)
+                    .collect::<Result<Vec<f32>, _>>()
+                    .map_err(|_| format!("malformed vertex '{}'", line))?;
+                // Four values are x, y, z and w, which doesn't matter for triangles.
+                match values.len() {
+                    3 | 4 => {}
+                    6 => {
+                        chunk.colors.resize(chunk.vertices.len(), NO_VERTEX_COLOR);
+                        chunk.colors.push(Rgb::new(values[3], values[4], values[5]));
+                    }
+                    _ => return Err(format!("malformed vertex '{}'", line)),
+                }
+                chunk.vertices.push(vec3(values[0], values[1], values[2]));
+            }
+            Some("f") => {
+                chunk.faces.push((chunk.corners.len(), chunk.vertices.len()));
+                for corner in fields {
+                    let mut parts = corner.split('/');
+                    let position = parts.next().unwrap();
+                    if parts.next().map_or(false, |tex_coord| !tex_coord.is_empty()) {
+                        return Ok(None);
+                    }
+                    let i = position.parse().map_err(|_| format!("malformed face '{}'", line))?;
+                    chunk.corners.push(i);
+                }
+            }
+            Some("o") | Some("g") => {
+                let name = fields.collect::<Vec<&str>>().join(" ");
+                let name = if name.is_empty() { "default".to_string() } else { name };
+                chunk.group_starts.push((chunk.faces.len(), name));
+            }
+            Some("vn") | Some("s") | None => {}
+            Some(_) => return Ok(None),
+        }
+    }
+    Ok(Some(chunk))
+}
+
+/// Resolve the indices of the faces in `chunk`, which is preceded by `before` vertices in the
+/// whole file, and triangulate them as fans. Each triangle comes with the group of its face.
+fn triangulate(chunk: &Chunk,
+               before: usize,
+               vertex_count: usize,
+               face_groups: &[u32])
+               -> Result<Vec<([usize; 3], u32)>, String> {
+    let mut tris = Vec::with_capacity(chunk.faces.len());
+    for (i, &(start, seen)) in chunk.faces.iter().enumerate() {
+        let end = chunk.faces.get(i + 1).map_or(chunk.corners.len(), |next| next.0);
+        let resolve = |index: i64| {
+            let resolved = if index < 0 {
+                i64(before + seen).unwrap() + index
+            } else {
+                index - 1
+            };
+            usize(resolved)
+                .ok()
+                .filter(|&i| i < vertex_count)
+                .ok_or_else(|| format!("invalid vertex index {}", index))
+        };
+        let corners = &chunk.corners[start..end];
+        if corners.len() < 3 {
+            continue;
+        }
+        let first = resolve(corners[0])?;
+        for pair in corners[1..].windows(2) {
+            tris.push(([first, resolve(pair[0])?, resolve(pair[1])?], face_groups[i]));
+        }
+    }
+    Ok(tris)
+}
@@ -1,9 +1,111 @@
 use bmp;
-use cast::{usize, u32, u8};
-use itertools::{Itertools, MinMaxResult};
+use cast::{f32, f64, i64, usize, u16, u32, u8};
+use cgmath::Vector3;
+use color::Rgb;
+use image::ColorType;
+use image::png::PNGEncoder;
+use itertools::Itertools;
 use ordered_float::NotNaN;
 use rayon::prelude::*;
-use std::{f32, iter, slice};
+use std::{f32, fmt, iter, mem, slice};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Add, Mul, Range};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes in all frame buffers that currently exist.
+static FRAME_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The most bytes there ever were in frame buffers at the same time.
+static PEAK_FRAME_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn peak_frame_bytes() -> usize {
+    PEAK_FRAME_BYTES.load(Ordering::SeqCst)
+}
+
+fn buffer_bytes<T>(buffer: &[T]) -> usize {
+    buffer.len() * mem::size_of::<T>()
+}
+
+/// An axis-aligned rectangle of pixels, given by its top left corner and its size.
+#[derive(Copy, Clone, Debug)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        self.x <= x && x < self.x + self.w && self.y <= y && y < self.y + self.h
+    }
+}
+
+/// The order in which pixels (or tiles of pixels) are traced. Rayon hands out contiguous runs
+/// of this order to each thread, so orders along space-filling curves make the rays traced by
+/// one thread more coherent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelOrder {
+    /// Row by row, left to right.
+    Scanline,
+    /// Along the Z-order curve.
+    Morton,
+    Hilbert,
+}
+
+impl PixelOrder {
+    /// All points of a `w` by `h` grid, in this order.
+    pub fn points(self, w: u32, h: u32) -> Vec<(u32, u32)> {
+        let mut points: Vec<(u32, u32)> =
+            (0..h).flat_map(|y| (0..w).map(move |x| (x, y))).collect();
+        // The curves are defined on square grids with power of two sides, so take the points
+        // in the order in which such a grid containing ours visits them.
+        let side = w.max(h).next_power_of_two();
+        match self {
+            PixelOrder::Scanline => {}
+            PixelOrder::Morton => points.sort_by_key(|&(x, y)| morton_index(x, y)),
+            PixelOrder::Hilbert => points.sort_by_key(|&(x, y)| hilbert_index(side, x, y)),
+        }
+        points
+    }
+}
+
+/// Position of (x, y) along the Z-order curve: the bits of x and y, interleaved.
+fn morton_index(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = u64::from(v);
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        (v | (v << 1)) & 0x5555_5555_5555_5555
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+/// Position of (x, y) along the Hilbert curve through a `side` by `side` grid, where `side`
+/// is a power of two.
+fn hilbert_index(side: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = u32::from(x & s != 0);
+        let ry = u32::from(y & s != 0);
+        d += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+        // Rotate the quadrant so that the curve through it starts and ends in the right place.
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
 
 pub struct Frame<T> {
     width: u32,
@@ -11,63 +113,260 @@ pub struct Frame<T> {
     buffer: Vec<T>,
 }
 
+impl<T> Drop for Frame<T> {
+    fn drop(&mut self) {
+        FRAME_BYTES.fetch_sub(buffer_bytes(&self.buffer), Ordering::SeqCst);
+    }
+}
+
 impl<T: Sync + Send + Copy> Frame<T> {
     pub fn new(width: u32, height: u32, value: T) -> Self {
+        Frame::from_buffer(width, height, vec![value; usize(width) * usize(height)])
+    }
+
+    fn from_buffer(width: u32, height: u32, buffer: Vec<T>) -> Self {
+        let live = FRAME_BYTES.fetch_add(buffer_bytes(&buffer), Ordering::SeqCst) +
+                   buffer_bytes(&buffer);
+        let mut peak = PEAK_FRAME_BYTES.load(Ordering::SeqCst);
+        while live > peak {
+            peak = match PEAK_FRAME_BYTES.compare_exchange(peak,
+                                                           live,
+                                                           Ordering::SeqCst,
+                                                           Ordering::SeqCst) {
+                Ok(_) => live,
+                Err(current) => current,
+            };
+        }
         Frame {
             width,
             height,
-            buffer: vec![value; usize(width) * usize(height)],
+            buffer,
         }
     }
 
     pub fn for_each_pixel<F>(&self, mut f: F)
         where F: FnMut(u32, u32, T)
     {
-        for (i, px) in self.pixel_values().enumerate() {
-            // TODO why height and not width?
-            // TODO iterate differently to avoid the divmod
-            let x = u32(i).unwrap() / self.height;
-            let y = u32(i).unwrap() % self.height;
-            f(x, y, px)
+        for (y, row) in self.rows() {
+            for (x, &px) in (0..self.width).zip(row) {
+                f(x, y, px)
+            }
+        }
+    }
+
+    /// The rows of pixels from top to bottom, each with its y coordinate.
+    pub fn rows<'a>(&'a self) -> iter::Zip<Range<u32>, slice::Chunks<'a, T>> {
+        (0..self.height).zip(self.buffer.chunks(self.row_len()))
+    }
+
+    /// Like `rows`, but the pixels can be changed.
+    pub fn rows_mut<'a>(&'a mut self) -> iter::Zip<Range<u32>, slice::ChunksMut<'a, T>> {
+        let row_len = self.row_len();
+        (0..self.height).zip(self.buffer.chunks_mut(row_len))
+    }
+
+    /// The width, but at least 1 so that even empty frames can be split into rows.
+    fn row_len(&self) -> usize {
+        usize(self.width).max(1)
+    }
+
+    /// A frame of the same size with `f` applied to every pixel, in parallel.
+    pub fn map<U, F>(&self, f: F) -> Frame<U>
+        where F: Sync + Fn(T) -> U,
+              U: Sync + Send + Copy
+    {
+        let buffer = self.buffer.par_iter().map(|&px| f(px)).collect();
+        Frame::from_buffer(self.width, self.height, buffer)
+    }
+
+    /// Fold the pixels in parallel: each thread folds some of them into a fresh `identity()`
+    /// with `fold`, and the results are combined with `reduce`. The pixels come in no
+    /// particular order.
+    pub fn par_fold<A, I, F, R>(&self, identity: I, fold: F, reduce: R) -> A
+        where I: Sync + Send + Fn() -> A,
+              F: Sync + Send + Fn(A, T) -> A,
+              R: Sync + Send + Fn(A, A) -> A,
+              A: Send
+    {
+        self.buffer.par_iter().cloned().fold(&identity, fold).reduce(&identity, reduce)
+    }
+
+    /// The smallest and largest `key` of any pixel, ignoring those for which it's None.
+    /// None if it's None for all of them.
+    pub fn par_minmax<K, F>(&self, key: F) -> Option<(K, K)>
+        where F: Sync + Send + Fn(T) -> Option<K>,
+              K: Send + Ord + Copy
+    {
+        let merge = |a: Option<(K, K)>, b: Option<(K, K)>| match (a, b) {
+            (Some((min_a, max_a)), Some((min_b, max_b))) => {
+                Some((min_a.min(min_b), max_a.max(max_b)))
+            }
+            (a, None) => a,
+            (None, b) => b,
+        };
+        self.par_fold(|| None, |acc, px| merge(acc, key(px).map(|k| (k, k))), merge)
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            w: self.width,
+            h: self.height,
         }
     }
 
-    pub fn set_pixels<F>(&mut self, f: F)
+    /// Compute the value of every pixel inside `window` with `f`.
+    /// All other pixels keep their current value.
+    pub fn set_pixels<F>(&mut self, window: Rect, f: F)
         where F: Send + Sync + Fn(u32, u32) -> T
     {
-        // TODO why height and not width?
-        let height = self.height;
-        self.buffer[..]
-            .par_iter_mut()
+        let (width, row_len) = (self.width, self.row_len());
+        self.buffer
+            .par_chunks_mut(row_len)
             .enumerate()
-            // TODO iterate differently to avoid the divmod
-            .for_each(move |(i, px)| {
-                let x = u32(i).unwrap() / height;
-                let y = u32(i).unwrap() % height;
-                *px = f(x, y);
+            .for_each(move |(y, row)| {
+                let y = u32(y).unwrap();
+                if y < window.y || y >= window.y + window.h {
+                    return;
+                }
+                for (x, px) in (0..width).zip(row) {
+                    if window.contains(x, y) {
+                        *px = f(x, y);
+                    }
+                }
             });
     }
 
-    fn pixel_values(&self) -> iter::Cloned<slice::Iter<T>>
-        where T: Copy
-    {
-        self.buffer.iter().cloned()
+    pub fn get(&self, x: u32, y: u32) -> T {
+        self.buffer[self.index(x, y)]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, value: T) {
+        let i = self.index(x, y);
+        self.buffer[i] = value;
+    }
+
+    /// Pixels are stored row by row.
+    fn index(&self, x: u32, y: u32) -> usize {
+        usize(y) * usize(self.width) + usize(x)
     }
 
+    /// Convert the pixels with `f`, straight into the image, row by row.
     fn to_bmp<F>(&self, f: F) -> bmp::Image
         where F: Fn(T) -> bmp::Pixel
     {
         let mut img = bmp::Image::new(self.width, self.height);
-        self.for_each_pixel(|x, y, px| { img.set_pixel(x, y, f(px)); });
+        for (y, row) in (0..self.height).zip(self.buffer.chunks(self.row_len())) {
+            for (x, &value) in (0..self.width).zip(row) {
+                img.set_pixel(x, y, f(value));
+            }
+        }
         img
     }
 }
 
+/// A reconstruction filter, for weighting samples by their distance from the pixel center.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    /// The Mitchell-Netravali filter with B = C = 1/3.
+    Mitchell,
+}
+
+impl Filter {
+    /// Samples further away than this (in pixels, along either axis) have zero weight.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    /// The weight of a sample at offset (dx, dy) from the pixel center.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, d: f32) -> f32 {
+        let (x, r) = (d.abs(), self.radius());
+        if x >= r {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - x,
+            Filter::Gaussian => {
+                // Shifted down so that it reaches zero at the radius
+                let falloff = 2.0;
+                (-falloff * x * x).exp() - (-falloff * r * r).exp()
+            }
+            Filter::Mitchell => {
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                let w = if x < 1.0 {
+                    (12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x +
+                    (6.0 - 2.0 * b)
+                } else {
+                    (-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x +
+                    (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+                };
+                w / 6.0
+            }
+        }
+    }
+}
+
+impl<T> Frame<(T, f32)>
+    where T: Copy + Send + Sync + Add<Output = T> + Mul<f32, Output = T>
+{
+    /// Add a sample taken at the film position (x, y), where pixel (i, j) covers
+    /// [i, i + 1) × [j, j + 1), to the weighted sums of all pixels within the radius of `filter`.
+    pub fn add_sample(&mut self, filter: Filter, x: f32, y: f32, value: T) {
+        // The pixel centers are at half-integer positions.
+        let (x, y) = (x - 0.5, y - 0.5);
+        let r = filter.radius();
+        let (w, h) = (i64(self.width), i64(self.height));
+        let clamp_x = |v: f32| i64(v).unwrap_or(0).max(0).min(w - 1);
+        let clamp_y = |v: f32| i64(v).unwrap_or(0).max(0).min(h - 1);
+        let (x0, x1) = (clamp_x((x - r).ceil()), clamp_x((x + r).floor()));
+        let (y0, y1) = (clamp_y((y - r).ceil()), clamp_y((y + r).floor()));
+        for px in x0..x1 + 1 {
+            for py in y0..y1 + 1 {
+                let w = filter.weight(f32(px) - x, f32(py) - y);
+                if w != 0.0 {
+                    let i = self.index(u32(px).unwrap(), u32(py).unwrap());
+                    let (sum, weight) = self.buffer[i];
+                    self.buffer[i] = (sum + value * w, weight + w);
+                }
+            }
+        }
+    }
+
+    /// Normalize the weighted sums. Pixels outside of `window` or without any samples (or a
+    /// total weight of zero, which filters with negative lobes can cause) become `background`.
+    pub fn resolve(&self, window: Rect, background: T) -> Frame<T> {
+        let mut frame = Frame::new(self.width, self.height, background);
+        frame.set_pixels(window, |x, y| {
+            let (sum, weight) = self.buffer[self.index(x, y)];
+            if weight != 0.0 { sum * (1.0 / weight) } else { background }
+        });
+        frame
+    }
+}
+
 /// Compute the linear interpolation coefficient for producing x from x0 and x1, i.e.,
 /// the scalar t \in [0, 1] such that x = (1 - t) * x0 + t * x1
 /// Panics if this is not possible, i.e., x is not between x0 and x1.
+/// If x0 == x1, every t works and 0 is returned.
 fn inv_lerp<T: Copy + Into<f64> + PartialOrd>(x: T, x0: T, x1: T) -> f64 {
     assert!(x0 <= x && x <= x1);
+    if x0 == x1 {
+        return 0.0;
+    }
     let t = (x.into() - x0.into()) / (x1.into() - x0.into());
     debug_assert!(0.0 <= t && t <= 1.0);
     t
@@ -75,37 +374,412 @@ fn inv_lerp<T: Copy + Into<f64> + PartialOrd>(x: T, x0: T, x1: T) -> f64 {
 
 pub trait ToBmp {
     fn to_bmp(&self) -> bmp::Image;
+
+    /// The exact bits of every pixel, for checking that two renders are identical.
+    fn exact_bits(&self) -> Frame<[u32; 3]>;
+
+    /// A greyscale version of the image for writing as PNG, with the bit depth of its
+    /// samples: 16 for renders whose values are too precise for the 8 bits per channel of
+    /// BMPs, 1 for black and white ones.
+    fn to_grey_png(&self) -> Option<(Frame<u16>, u8)> {
+        None
+    }
+
+    /// The exact counts that the image visualizes, if it's a heatmap.
+    fn counts(&self) -> Option<&Frame<u32>> {
+        None
+    }
+
+    /// A JSON document explaining the values in the image, if it's an ID image.
+    fn legend(&self) -> Option<String> {
+        None
+    }
+
+    /// The linear values behind the image, if it's tone mapped radiance.
+    fn radiance(&self) -> Option<&Radiance> {
+        None
+    }
+}
+
+/// Pixel values that can be compared bit for bit, see `ToBmp::exact_bits`.
+pub trait ExactBits {
+    /// The bits of the value, padded with zeros.
+    fn exact_bits(self) -> [u32; 3];
+}
+
+impl ExactBits for f32 {
+    fn exact_bits(self) -> [u32; 3] {
+        [self.to_bits(), 0, 0]
+    }
 }
 
-pub struct Depthmap(pub Frame<f32>);
+impl ExactBits for u32 {
+    fn exact_bits(self) -> [u32; 3] {
+        [self, 0, 0]
+    }
+}
+
+impl ExactBits for i64 {
+    fn exact_bits(self) -> [u32; 3] {
+        let bits = self as u64;
+        [bits as u32, (bits >> 32) as u32, 0]
+    }
+}
+
+impl ExactBits for bool {
+    fn exact_bits(self) -> [u32; 3] {
+        [u32::from(self), 0, 0]
+    }
+}
+
+impl ExactBits for Rgb {
+    fn exact_bits(self) -> [u32; 3] {
+        [self.r.to_bits(), self.g.to_bits(), self.b.to_bits()]
+    }
+}
+
+impl ExactBits for Vector3<f32> {
+    fn exact_bits(self) -> [u32; 3] {
+        [self.x.to_bits(), self.y.to_bits(), self.z.to_bits()]
+    }
+}
+
+impl<T: Sync + Send + Copy + ExactBits> Frame<T> {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.map(ExactBits::exact_bits)
+    }
+}
+
+/// File formats for the raw counts of heatmaps.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CountsFormat {
+    /// One line per row of pixels, with comma-separated counts.
+    Csv,
+    /// Little-endian `u32`s, row by row, without any header.
+    Binary,
+}
+
+impl CountsFormat {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            CountsFormat::Csv => "csv",
+            CountsFormat::Binary => "u32",
+        }
+    }
+}
+
+/// Write the counts of a heatmap to `path` in the given format.
+pub fn save_counts(counts: &Frame<u32>, format: CountsFormat, path: &Path) -> Result<(), String> {
+    let error = |e: io::Error| format!("could not write {}: {}", path.display(), e);
+    let mut out = BufWriter::new(File::create(path).map_err(&error)?);
+    for (_, row) in counts.rows() {
+        let row = row.iter();
+        match format {
+            CountsFormat::Csv => writeln!(out, "{}", row.map(|c| c.to_string()).join(",")),
+            CountsFormat::Binary => row.map(|c| out.write_all(&c.to_le_bytes())).collect(),
+        }.map_err(&error)?;
+    }
+    out.flush().map_err(&error)
+}
+
+/// Write `image` to `path`, as a greyscale PNG (see `ToBmp::to_grey_png`) if the extension
+/// is `.png` and as a BMP otherwise.
+pub fn save(image: &ToBmp, path: &Path) -> Result<(), String> {
+    let error = |e: &fmt::Display| format!("could not write {}: {}", path.display(), e);
+    let is_png = path.extension().and_then(OsStr::to_str).map(str::to_lowercase) ==
+                 Some("png".to_string());
+    if !is_png {
+        return image.to_bmp().save(path).map_err(|e| error(&e));
+    }
+    let only_some = "only depth and thickness maps, masks and ID images can be written as PNG";
+    let (frame, bits) = image.to_grey_png().ok_or_else(|| error(&only_some))?;
+    // PNG stores samples row by row, 16-bit ones big-endian and 1-bit ones packed into bytes
+    // starting at the most significant bit, with every row starting at a new byte.
+    let row_bytes = match bits {
+        16 => usize(frame.width) * 2,
+        1 => (usize(frame.width) + 7) / 8,
+        _ => panic!("BUG: unsupported PNG bit depth {}", bits),
+    };
+    let mut bytes = vec![0; row_bytes * usize(frame.height)];
+    if row_bytes > 0 {
+        bytes.par_chunks_mut(row_bytes)
+            .zip(frame.buffer.par_chunks(frame.row_len()))
+            .for_each(|(out, row)| for (x, &value) in row.iter().enumerate() {
+                          if bits == 16 {
+                              out[2 * x..2 * x + 2].copy_from_slice(&value.to_be_bytes());
+                          } else if value != 0 {
+                              out[x / 8] |= 0x80 >> (x % 8);
+                          }
+                      });
+    }
+    let file = File::create(path).map_err(|e| error(&e))?;
+    PNGEncoder::new(BufWriter::new(file))
+        .encode(&bytes, frame.width, frame.height, ColorType::Gray(bits))
+        .map_err(|e| error(&e))
+}
+
+/// Write `image` to `path` as an RGBA PNG whose (straight, not premultiplied) alpha is `coverage`,
+/// clamped to [0, 1].
+pub fn save_rgba(image: &ToBmp, coverage: &Frame<f32>, path: &Path) -> Result<(), String> {
+    let error = |e: &fmt::Display| format!("could not write {}: {}", path.display(), e);
+    let img = image.to_bmp();
+    let (width, height) = (img.get_width(), img.get_height());
+    assert_eq!((width, height), (coverage.width, coverage.height));
+    let mut bytes = vec![0; 4 * usize(width) * usize(height)];
+    if width > 0 {
+        bytes.par_chunks_mut(4 * usize(width))
+            .zip(coverage.buffer.par_chunks(coverage.row_len()))
+            .enumerate()
+            .for_each(|(y, (out, row))| {
+                let y = u32(y).unwrap();
+                for ((x, rgba), &alpha) in (0..width).zip(out.chunks_mut(4)).zip(row) {
+                    let px = img.get_pixel(x, y);
+                    rgba.copy_from_slice(&[px.r, px.g, px.b, clamped_to_u8(alpha)]);
+                }
+            });
+    }
+    let file = File::create(path).map_err(|e| error(&e))?;
+    PNGEncoder::new(BufWriter::new(file))
+        .encode(&bytes, width, height, ColorType::RGBA(8))
+        .map_err(|e| error(&e))
+}
+
+pub struct Depthmap {
+    pub frame: Frame<f32>,
+    /// The depths that are shown as white and black, each None to use the closest or farthest
+    /// depth in the frame.
+    pub near: Option<f32>,
+    pub far: Option<f32>,
+    /// Draw contour lines where the depth crosses a multiple of this.
+    pub isoline_interval: Option<f32>,
+    /// The color of pixels where nothing was hit, blue if None.
+    pub background: Option<Rgb>,
+}
 pub struct Heatmap(pub Frame<u32>);
 
+/// The difference of two heatmaps, later minus earlier.
+pub struct HeatDifference(pub Frame<i64>);
+/// Unit normals, or the zero vector where nothing was hit.
+pub struct Normalmap(pub Frame<Vector3<f32>>);
+/// Linear radiance values, tone mapped and sRGB encoded for display.
+pub struct Radiance {
+    pub frame: Frame<Rgb>,
+    pub tonemap: Tonemap,
+    /// Scale the radiance by 2^exposure before tone mapping.
+    pub exposure: f32,
+}
+/// Colors that are already meant for display, written out as-is (clamped to [0, 1]).
+pub struct Colors(pub Frame<Rgb>);
+/// Another image with lines drawn on top, e.g. the edges of BVH nodes.
+pub struct Overlay {
+    pub image: Box<ToBmp>,
+    /// Start and end of each line in pixel coordinates, possibly outside of the image.
+    pub lines: Vec<((f32, f32), (f32, f32))>,
+}
+/// Two images of the same size next to each other, e.g. the views of the left and right eye.
+pub struct SideBySide(pub Box<ToBmp>, pub Box<ToBmp>);
+/// Whether anything was hit in each pixel, white where it was and black elsewhere.
+pub struct Mask(pub Frame<bool>);
+/// The object or material in each pixel, as an ID that is 0 for the background and an index
+/// into `names` plus one otherwise. Each ID has its own color from a fixed palette.
+pub struct IdMap {
+    pub frame: Frame<u32>,
+    pub names: Vec<String>,
+}
+
+/// How to squeeze radiance values into the [0, 1] range of the display.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tonemap {
+    /// Clip everything above 1.
+    Linear,
+    /// Reinhard et al.'s L / (1 + L) curve, applied to luminance to preserve hue.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl Tonemap {
+    fn apply(&self, c: Rgb) -> Rgb {
+        match *self {
+            Tonemap::Linear => c,
+            Tonemap::Reinhard => c / (1.0 + c.luminance()),
+            Tonemap::Aces => {
+                let curve = |x: f32| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+                Rgb::new(curve(c.r), curve(c.g), curve(c.b))
+            }
+        }
+    }
+}
+
+fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn clamped_to_u8(x: f32) -> u8 {
+    u8((x.max(0.0).min(1.0) * 255.0).round()).unwrap()
+}
+
+/// A color meant for display as a pixel, clamped to [0, 1] without any tone mapping.
+fn display_pixel(c: Rgb) -> bmp::Pixel {
+    bmp::Pixel {
+        r: clamped_to_u8(c.r),
+        g: clamped_to_u8(c.g),
+        b: clamped_to_u8(c.b),
+    }
+}
+
+/// The sum of several images of the same view, to refine a render progressively with passes
+/// that use different samples. Radiance is summed before tone mapping, other images as displayed.
+pub struct Accumulator {
+    sum: Frame<Rgb>,
+    passes: u32,
+    /// The tone mapping and exposure of the passes, if they are radiance.
+    display: Option<(Tonemap, f32)>,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        Accumulator {
+            sum: Frame::new(width, height, Rgb::black()),
+            passes: 0,
+            display: None,
+        }
+    }
+
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    /// Start over, e.g. because the camera moved.
+    pub fn reset(&mut self) {
+        let bounds = self.sum.bounds();
+        self.sum.set_pixels(bounds, |_, _| Rgb::black());
+        self.passes = 0;
+        self.display = None;
+    }
+
+    pub fn add(&mut self, image: &ToBmp) {
+        if let Some(radiance) = image.radiance() {
+            for (y, row) in self.sum.rows_mut() {
+                for (x, sum) in (0..).zip(row) {
+                    *sum = *sum + radiance.frame.get(x, y);
+                }
+            }
+            self.display = Some((radiance.tonemap, radiance.exposure));
+        } else {
+            let image = image.to_bmp();
+            for (y, row) in self.sum.rows_mut() {
+                for (x, sum) in (0..).zip(row) {
+                    let bmp::Pixel { r, g, b } = image.get_pixel(x, y);
+                    *sum = *sum + Rgb::new(f32(r), f32(g), f32(b)) / 255.0;
+                }
+            }
+        }
+        self.passes += 1;
+    }
+
+    pub fn average(&self) -> Box<ToBmp> {
+        let scale = 1.0 / f32(self.passes.max(1));
+        let frame = self.sum.map(|c| c * scale);
+        match self.display {
+            Some((tonemap, exposure)) => {
+                Box::new(Radiance {
+                             frame,
+                             tonemap,
+                             exposure,
+                         })
+            }
+            None => Box::new(Colors(frame)),
+        }
+    }
+}
+
+impl Depthmap {
+    /// Where the depth `depth` lies between near (0) and far (1), clamped to that range.
+    fn mapping(&self) -> Box<Fn(f32) -> f64 + Sync> {
+        let hit_depth = |x| if x == f32::INFINITY { None } else { Some(NotNaN::new(x).unwrap()) };
+        let (min, max) = match self.frame.par_minmax(hit_depth) {
+            Some((min, max)) => (min.into_inner(), max.into_inner()),
+            // Nothing was hit, so the min and max don't matter.
+            None => (0.0, 0.0),
+        };
+        let near = self.near.unwrap_or(min);
+        // Without this, a far limit below the closest depth would put far in front of near.
+        let far = self.far.unwrap_or(max).max(near);
+        Box::new(move |depth| inv_lerp(depth.max(near).min(far), near, far))
+    }
+
+    /// Whether the pixel is on a contour line, i.e., a neighbor to the right or below is in
+    /// another interval of depths.
+    fn on_isoline(&self, interval: f32, x: u32, y: u32) -> bool {
+        let band = |x, y| {
+            let depth = self.frame.get(x, y);
+            if depth == f32::INFINITY { None } else { Some((depth / interval).floor()) }
+        };
+        let here = band(x, y);
+        let differs = |x, y| here.is_some() && band(x, y).map_or(false, |b| Some(b) != here);
+        (x + 1 < self.frame.width && differs(x + 1, y)) ||
+        (y + 1 < self.frame.height && differs(x, y + 1))
+    }
+}
+
 impl ToBmp for Depthmap {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.frame.exact_bits()
+    }
+
     fn to_bmp(&self) -> bmp::Image {
-        let frame = &self.0;
-        let (min_depth, max_depth) = match frame.pixel_values()
-                  .filter(|&x| x != f32::INFINITY)
-                  .minmax_by_key(|&x| NotNaN::new(x).unwrap()) {
-            MinMaxResult::MinMax(min, max) => (min, max),
-            _ => panic!("frame empty or not a single pixel"),
-        };
-        frame.to_bmp(|depth| if depth == f32::INFINITY {
-                         bmp::consts::BLUE
-                     } else {
-                         let intensity = inv_lerp(depth, min_depth, max_depth);
-                         let s = u8(((1.0 - intensity) * 255.0).round()).unwrap();
-                         bmp::Pixel { r: s, g: s, b: s }
-                     })
+        let mapping = self.mapping();
+        let background = self.background.map_or(bmp::consts::BLUE, display_pixel);
+        let mut img = self.frame.to_bmp(|depth| if depth == f32::INFINITY {
+                                             background
+                                         } else {
+                                             let s = 1.0 - mapping(depth);
+                                             let s = u8((s * 255.0).round()).unwrap();
+                                             bmp::Pixel { r: s, g: s, b: s }
+                                         });
+        if let Some(interval) = self.isoline_interval {
+            let red = bmp::Pixel { r: 255, g: 0, b: 0 };
+            for y in 0..self.frame.height {
+                for x in 0..self.frame.width {
+                    if self.on_isoline(interval, x, y) {
+                        img.set_pixel(x, y, red);
+                    }
+                }
+            }
+        }
+        img
+    }
+
+    /// Depths from near (0) to far (65535), unlike the BMP where near is white. Pixels where
+    /// nothing was hit are 65535 as well.
+    fn to_grey_png(&self) -> Option<(Frame<u16>, u8)> {
+        let mapping = self.mapping();
+        let grey = self.frame.map(|depth| if depth == f32::INFINITY {
+                                      u16::max_value()
+                                  } else {
+                                      u16(mapping(depth) * 65535.0).unwrap()
+                                  });
+        Some((grey, 16))
     }
 }
 
 impl ToBmp for Heatmap {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.0.exact_bits()
+    }
+
+    fn counts(&self) -> Option<&Frame<u32>> {
+        Some(&self.0)
+    }
+
     fn to_bmp(&self) -> bmp::Image {
         let frame = &self.0;
-        let (min_heat, max_heat) = match frame.pixel_values().minmax() {
-            MinMaxResult::MinMax(min, max) => (min, max),
-            _ => panic!("frame empty or a single pixel"),
-        };
+        let (min_heat, max_heat) = frame.par_minmax(Some).expect("frame empty");
         frame.to_bmp(|heat| {
                          let intensity = inv_lerp(heat, min_heat, max_heat);
                          let s = u8((intensity * 255.0).round()).unwrap();
@@ -113,3 +787,248 @@ impl ToBmp for Heatmap {
                      })
     }
 }
+
+impl ToBmp for HeatDifference {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.0.exact_bits()
+    }
+
+    /// Blue where fewer steps were needed, red where more were, both scaled by the largest
+    /// change either way so that their intensities can be compared.
+    fn to_bmp(&self) -> bmp::Image {
+        let frame = &self.0;
+        let max_change = frame.par_fold(|| 1, |max, d| max.max(d.abs()), i64::max);
+        frame.to_bmp(|d| {
+                         let s = u8((f64(d.abs()) / f64(max_change) * 255.0).round()).unwrap();
+                         if d < 0 {
+                             bmp::Pixel { r: 0, g: 0, b: s }
+                         } else {
+                             bmp::Pixel { r: s, g: 0, b: 0 }
+                         }
+                     })
+    }
+}
+
+impl ToBmp for Mask {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.0.exact_bits()
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        self.0.to_bmp(|hit| if hit { bmp::consts::WHITE } else { bmp::consts::BLACK })
+    }
+
+    fn to_grey_png(&self) -> Option<(Frame<u16>, u8)> {
+        Some((self.0.map(u16::from), 1))
+    }
+}
+
+/// `left` and `right`, which have the same size, next to each other in one frame.
+fn side_by_side<T>(left: &Frame<T>, right: &Frame<T>) -> Frame<T>
+    where T: Sync + Send + Copy
+{
+    let (w, h) = (left.width, left.height);
+    assert_eq!((w, h), (right.width, right.height));
+    let mut buffer = Vec::with_capacity(2 * left.buffer.len());
+    for ((_, l), (_, r)) in left.rows().zip(right.rows()) {
+        buffer.extend_from_slice(l);
+        buffer.extend_from_slice(r);
+    }
+    Frame::from_buffer(2 * w, h, buffer)
+}
+
+impl ToBmp for SideBySide {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        side_by_side(&self.0.exact_bits(), &self.1.exact_bits())
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        let (left, right) = (self.0.to_bmp(), self.1.to_bmp());
+        let (w, h) = (left.get_width(), left.get_height());
+        let mut img = bmp::Image::new(2 * w, h);
+        for y in 0..h {
+            for x in 0..w {
+                img.set_pixel(x, y, left.get_pixel(x, y));
+                img.set_pixel(w + x, y, right.get_pixel(x, y));
+            }
+        }
+        img
+    }
+
+    fn to_grey_png(&self) -> Option<(Frame<u16>, u8)> {
+        let ((left, bits), (right, _)) = (self.0.to_grey_png()?, self.1.to_grey_png()?);
+        Some((side_by_side(&left, &right), bits))
+    }
+
+    fn legend(&self) -> Option<String> {
+        self.0.legend()
+    }
+}
+
+/// Bright green, which hardly appears in any of the renders.
+const OVERLAY_COLOR: bmp::Pixel = bmp::Pixel { r: 0, g: 255, b: 0 };
+
+impl ToBmp for Overlay {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.image.exact_bits()
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        let mut img = self.image.to_bmp();
+        for &(a, b) in &self.lines {
+            draw_line(&mut img, a, b, OVERLAY_COLOR);
+        }
+        img
+    }
+
+    fn counts(&self) -> Option<&Frame<u32>> {
+        self.image.counts()
+    }
+
+    fn legend(&self) -> Option<String> {
+        self.image.legend()
+    }
+}
+
+/// Set the pixels along the line from `a` to `b`, clipped to the image first so that lines
+/// reaching far outside of it are cheap.
+fn draw_line(img: &mut bmp::Image, a: (f32, f32), b: (f32, f32), color: bmp::Pixel) {
+    let (w, h) = (f32(img.get_width()), f32(img.get_height()));
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    // Liang-Barsky: shrink the parameter range [s0, s1] to the part inside each boundary.
+    let (mut s0, mut s1) = (0.0f32, 1.0f32);
+    for &(p, q) in &[(-dx, a.0), (dx, w - a.0), (-dy, a.1), (dy, h - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return;
+            }
+        } else if p < 0.0 {
+            s0 = s0.max(q / p);
+        } else {
+            s1 = s1.min(q / p);
+        }
+    }
+    if s0 > s1 || !s0.is_finite() || !s1.is_finite() {
+        return;
+    }
+    let steps = (dx.abs().max(dy.abs()) * (s1 - s0)).ceil().max(1.0);
+    let mut i = 0.0;
+    while i <= steps {
+        let s = s0 + (s1 - s0) * i / steps;
+        let (x, y) = ((a.0 + dx * s).floor(), (a.1 + dy * s).floor());
+        if x >= 0.0 && x < w && y >= 0.0 && y < h {
+            img.set_pixel(x as u32, y as u32, color);
+        }
+        i += 1.0;
+    }
+}
+
+impl IdMap {
+    fn color(id: u32) -> bmp::Pixel {
+        if id == 0 {
+            return bmp::consts::BLACK;
+        }
+        let c = Rgb::distinct(id - 1);
+        bmp::Pixel {
+            r: clamped_to_u8(c.r),
+            g: clamped_to_u8(c.g),
+            b: clamped_to_u8(c.b),
+        }
+    }
+}
+
+impl ToBmp for IdMap {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.frame.exact_bits()
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        self.frame.to_bmp(IdMap::color)
+    }
+
+    fn to_grey_png(&self) -> Option<(Frame<u16>, u8)> {
+        // There are never anywhere close to 65535 objects or materials in practice.
+        Some((self.frame.map(|id| u16(id).unwrap_or(u16::max_value())), 16))
+    }
+
+    fn legend(&self) -> Option<String> {
+        let mut entries = vec![json_entry(0, "background", IdMap::color(0))];
+        for (i, name) in self.names.iter().enumerate() {
+            let id = u32(i + 1).unwrap();
+            entries.push(json_entry(id, name, IdMap::color(id)));
+        }
+        Some(format!("{{\n  \"ids\": [\n{}\n  ]\n}}\n", entries.join(",\n")))
+    }
+}
+
+/// One ID of an `IdMap` legend, with the color used for it in BMPs.
+fn json_entry(id: u32, name: &str, color: bmp::Pixel) -> String {
+    let mut quoted = String::from("\"");
+    for c in name.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    format!("    {{ \"id\": {}, \"name\": {}, \"color\": [{}, {}, {}] }}",
+            id,
+            quoted,
+            color.r,
+            color.g,
+            color.b)
+}
+
+impl ToBmp for Normalmap {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.0.exact_bits()
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        let to_u8 = |x: f32| u8(((x * 0.5 + 0.5) * 255.0).round()).unwrap();
+        self.0.to_bmp(|n| if n == Vector3::new(0.0, 0.0, 0.0) {
+                          bmp::consts::BLACK
+                      } else {
+                          bmp::Pixel {
+                              r: to_u8(n.x),
+                              g: to_u8(n.y),
+                              b: to_u8(n.z),
+                          }
+                      })
+    }
+}
+
+impl ToBmp for Radiance {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.frame.exact_bits()
+    }
+
+    fn radiance(&self) -> Option<&Radiance> {
+        Some(self)
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        let scale = 2f32.powf(self.exposure);
+        let to_u8 = |x: f32| clamped_to_u8(linear_to_srgb(x.max(0.0).min(1.0)));
+        self.frame.to_bmp(|c| {
+                              let c = self.tonemap.apply(c * scale);
+                              bmp::Pixel {
+                                  r: to_u8(c.r),
+                                  g: to_u8(c.g),
+                                  b: to_u8(c.b),
+                              }
+                          })
+    }
+}
+
+impl ToBmp for Colors {
+    fn exact_bits(&self) -> Frame<[u32; 3]> {
+        self.0.exact_bits()
+    }
+
+    fn to_bmp(&self) -> bmp::Image {
+        self.0.to_bmp(display_pixel)
+    }
+}
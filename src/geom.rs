@@ -1,36 +1,369 @@
 use beebox::Aabb;
 use beevage;
-use cast::u32;
-use cgmath::Vector3;
-use std::{f32, u32};
-use std::cell::Cell;
+use cast::{f64, usize};
+use cgmath::{InnerSpace, Vector3, Vector4};
+use rayon::prelude::*;
+use std::{f32, mem};
 use watertri;
 
-#[derive(Clone, Debug)]
+/// Index of a triangle or vertex. The default of 32 bits caps scenes at `MAX_TRIS` triangles,
+/// the `large-scenes` feature lifts that limit at the cost of larger triangles and BVH nodes.
+#[cfg(not(feature = "large-scenes"))]
+pub type Index = u32;
+#[cfg(feature = "large-scenes")]
+pub type Index = u64;
+
+/// The most triangles (and vertices) a mesh can have. BVH leaves store their triangle count
+/// next to a flag bit, so this is one bit less than what fits in an `Index`.
+pub const MAX_TRIS: usize = (Index::max_value() >> 1) as usize;
+
+/// Convert a triangle, vertex or BVH node number to an `Index`. Meshes are checked against
+/// `MAX_TRIS` when they're loaded, so this failing is a bug.
+pub fn index(i: usize) -> Index {
+    assert!(i as u64 <= u64::from(Index::max_value()), "BUG: index {} out of range", i);
+    i as Index
+}
+
+/// A triangle, given by the indices of its corners in the vertex buffer of a `TriMesh`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Tri {
-    pub a: Vector3<f32>,
-    pub b: Vector3<f32>,
-    pub c: Vector3<f32>,
+    pub a: Index,
+    pub b: Index,
+    pub c: Index,
+}
+
+/// Algorithms for intersecting rays with triangles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriIsect {
+    /// Watertight ray/triangle intersection (Woop, Benthin, Wald 2013). No gaps between
+    /// neighboring triangles, but a lot of work per ray and triangle.
+    Watertight,
+    /// Transform the ray into a space where the triangle is the unit triangle (Woop 2004).
+    /// Cheaper per test, but needs a precomputed transform per triangle and can let rays slip
+    /// through shared edges.
+    Woop,
+}
+
+/// The floating point precision of ray/box and ray/triangle tests. The scene is stored in
+/// single precision either way, but models far from the origin can lose enough precision
+/// when rays are transformed relative to the triangles to show cracks or miss surfaces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    /// Do the tests in double precision. Triangles are always intersected with the watertight
+    /// algorithm then, and packet traversal still tests boxes in single precision.
+    F64,
+}
+
+impl Default for Precision {
+    fn default() -> Precision {
+        Precision::F32
+    }
+}
+
+/// The affine transform that maps a triangle to the unit triangle (0,0,0), (1,0,0), (0,1,0)
+/// and its normal to (0,0,1). Each row transforms homogeneous coordinates to one of x, y, z.
+#[derive(Copy, Clone, Debug)]
+pub struct WoopTri {
+    rows: [Vector4<f32>; 3],
 }
 
-impl Tri {
+impl WoopTri {
+    pub fn new(a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> WoopTri {
+        let (e1, e2) = (b - a, c - a);
+        let n = e1.cross(e2);
+        // The rows of the inverse of the matrix with columns e1, e2, n, by the adjugate. Its
+        // determinant is |n|^2, which is only zero for degenerate triangles (dropped when
+        // loading), unlike `Matrix3::invert`, which gives up on tiny but valid triangles.
+        let det = n.magnitude2();
+        let inv = [e2.cross(n) / det, n.cross(e1) / det, n / det];
+        let row = |i: usize| inv[i].extend(-inv[i].dot(a));
+        WoopTri { rows: [row(0), row(1), row(2)] }
+    }
+
+    pub fn intersect(&self, o: Vector3<f32>, d: Vector3<f32>) -> Option<watertri::Intersection> {
+        let (o, d) = (o.extend(1.0), d.extend(0.0));
+        let t = -self.rows[2].dot(o) / self.rows[2].dot(d);
+        // Also rejects rays parallel to the triangle, where t is infinite or NaN.
+        if !(t > 0.0 && t < f32::INFINITY) {
+            return None;
+        }
+        let v = self.rows[0].dot(o) + t * self.rows[0].dot(d);
+        if v < 0.0 || v > 1.0 {
+            return None;
+        }
+        let w = self.rows[1].dot(o) + t * self.rows[1].dot(d);
+        if w < 0.0 || v + w > 1.0 {
+            return None;
+        }
+        Some(watertri::Intersection {
+                 t,
+                 u: 1.0 - v - w,
+                 v,
+                 w,
+             })
+    }
+}
+
+/// Triangles sharing a vertex buffer. Triangles are identified by their index in `tris`.
+#[derive(Clone, Debug, Default)]
+pub struct TriMesh {
+    pub vertices: Vec<Vector3<f32>>,
+    pub tris: Vec<Tri>,
+    /// The per-triangle transforms if `TriIsect::Woop` is used, in the order of `tris`.
+    woop_tris: Option<Vec<WoopTri>>,
+    /// Whether hits on the back side of a triangle (w.r.t. the winding order) are ignored.
+    cull_backfaces: bool,
+    precision: Precision,
+}
+
+impl TriMesh {
+    /// A mesh that uses watertight intersection.
+    pub fn new(vertices: Vec<Vector3<f32>>, tris: Vec<Tri>) -> TriMesh {
+        TriMesh {
+            vertices,
+            tris,
+            woop_tris: None,
+            cull_backfaces: false,
+            precision: Precision::F32,
+        }
+    }
+
+    /// Bytes used by the vertices, the triangles and the precomputed data for intersection.
+    pub fn memory_usage(&self) -> usize {
+        self.vertices.capacity() * mem::size_of::<Vector3<f32>>() +
+        self.tris.capacity() * mem::size_of::<Tri>() +
+        self.woop_tris.as_ref().map_or(0, |w| w.capacity() * mem::size_of::<WoopTri>())
+    }
+
+    pub fn tri_isect(&self) -> TriIsect {
+        if self.woop_tris.is_some() {
+            TriIsect::Woop
+        } else {
+            TriIsect::Watertight
+        }
+    }
+
+    /// Switch to another intersection algorithm. This must be called again (with the same
+    /// algorithm) after changing the vertices or triangles, to update precomputed data.
+    pub fn set_tri_isect(&mut self, isect: TriIsect) {
+        self.woop_tris = match isect {
+            TriIsect::Watertight => None,
+            TriIsect::Woop => {
+                let woop_tris = (0..self.tris.len())
+                    .into_par_iter()
+                    .map(|i| {
+                             let (a, b, c) = self.corners(index(i));
+                             WoopTri::new(a, b, c)
+                         })
+                    .collect();
+                Some(woop_tris)
+            }
+        };
+    }
+
+    /// Ignore hits where the ray enters a triangle from behind, i.e., where the geometric
+    /// normal points away from the ray origin. Only sensible for closed, consistently wound
+    /// meshes, but it makes inverted normals show up as holes.
+    pub fn set_cull_backfaces(&mut self, cull: bool) {
+        self.cull_backfaces = cull;
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.precision = precision;
+    }
+
+    /// Intersect a ray with one triangle, using whichever algorithm was selected.
+    pub fn intersect_tri(&self,
+                         tri_id: Index,
+                         ray: &Ray,
+                         ray_data: &watertri::RayData)
+                         -> Option<watertri::Intersection> {
+        if self.cull_backfaces {
+            let (a, b, c) = self.corners(tri_id);
+            if (b - a).cross(c - a).dot(ray.d) >= 0.0 {
+                return None;
+            }
+        }
+        if self.precision == Precision::F64 {
+            let (a, b, c) = self.corners(tri_id);
+            return intersect_watertight_f64(ray.o, ray.d, a, b, c);
+        }
+        match self.woop_tris {
+            Some(ref woop_tris) => woop_tris[usize(tri_id)].intersect(ray.o, ray.d),
+            None => {
+                let (a, b, c) = self.corners(tri_id);
+                ray_data.intersect(a, b, c)
+            }
+        }
+    }
+
+    pub fn corners(&self, tri_id: Index) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        let tri = &self.tris[usize(tri_id)];
+        (self.vertices[usize(tri.a)], self.vertices[usize(tri.b)], self.vertices[usize(tri.c)])
+    }
+
+    pub fn tri_bbox(&self, tri_id: Index) -> Aabb {
+        let (a, b, c) = self.corners(tri_id);
+        Aabb::new([a, b, c].iter().cloned())
+    }
+
+    /// The unit geometric normal, oriented according to the winding order.
+    pub fn normal(&self, tri_id: Index) -> Vector3<f32> {
+        let (a, b, c) = self.corners(tri_id);
+        (b - a).cross(c - a).normalize()
+    }
+
+    /// The point of the triangle closest to `p`, following Ericson's "Real-Time Collision
+    /// Detection" (section 5.1.5): find the Voronoi region of the triangle that `p` is in,
+    /// then project `p` onto that vertex, edge or face.
+    pub fn closest_point(&self, tri_id: Index, p: Vector3<f32>) -> Vector3<f32> {
+        let (a, b, c) = self.corners(tri_id);
+        let (ab, ac) = (b - a, c - a);
+        let (d1, d2) = (ab.dot(p - a), ac.dot(p - a));
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return a;
+        }
+        let (d3, d4) = (ab.dot(p - b), ac.dot(p - b));
+        if d3 >= 0.0 && d4 <= d3 {
+            return b;
+        }
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            return a + ab * (d1 / (d1 - d3));
+        }
+        let (d5, d6) = (ab.dot(p - c), ac.dot(p - c));
+        if d6 >= 0.0 && d5 <= d6 {
+            return c;
+        }
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            return a + ac * (d2 / (d2 - d6));
+        }
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+            return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+        }
+        let denom = va + vb + vc;
+        a + ab * (vb / denom) + ac * (vc / denom)
+    }
+
+    /// The bounding box of all triangles. Vertices that no triangle uses don't count.
     pub fn bbox(&self) -> Aabb {
-        Aabb::new([self.a, self.b, self.c].iter().cloned())
+        self.range_bbox(0, index(self.tris.len()))
+    }
+
+    /// The bounding box of the triangles `start..end`.
+    pub fn range_bbox(&self, start: Index, end: Index) -> Aabb {
+        let mut res = Aabb::empty();
+        for tri_id in start..end {
+            res = res.union(self.tri_bbox(tri_id));
+        }
+        res
+    }
+
+    /// Intersect the ray with the triangles `start..end`, updating `hit` and `t_max` when an
+    /// intersection is between `ray.t_min` and `t_max` and passes `filter`.
+    pub fn intersect(&self,
+                     start: Index,
+                     end: Index,
+                     ray: &Ray,
+                     ray_data: &watertri::RayData,
+                     t_max: &mut f32,
+                     filter: Option<&HitFilter>,
+                     hit: &mut Hit) {
+        for tri_id in start..end {
+            if let Some(intersection) = self.intersect_tri(tri_id, ray, ray_data) {
+                if intersection.t > ray.t_min && intersection.t < *t_max &&
+                   accept_hit(filter, tri_id, &intersection) {
+                    *t_max = intersection.t;
+                    hit.replace(tri_id, intersection);
+                }
+            }
+        }
+    }
+}
+
+pub fn to_f64(v: Vector3<f32>) -> Vector3<f64> {
+    Vector3::new(f64(v.x), f64(v.y), f64(v.z))
+}
+
+/// The watertight ray/triangle test of Woop, Benthin and Wald (2013), like
+/// `watertri::RayData::intersect` but in double precision. The corners are moved relative to
+/// the ray origin before anything else, which is where single precision suffers the most for
+/// triangles far from the origin.
+fn intersect_watertight_f64(o: Vector3<f32>,
+                            d: Vector3<f32>,
+                            a: Vector3<f32>,
+                            b: Vector3<f32>,
+                            c: Vector3<f32>)
+                            -> Option<watertri::Intersection> {
+    let (o, d) = (to_f64(o), to_f64(d));
+    // Permute the axes so that z is the dominant axis of the ray direction, keeping the
+    // winding order intact.
+    let kz = if d.x.abs() > d.y.abs() {
+        if d.x.abs() > d.z.abs() { 0 } else { 2 }
+    } else if d.y.abs() > d.z.abs() {
+        1
+    } else {
+        2
+    };
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if d[kz] < 0.0 {
+        mem::swap(&mut kx, &mut ky);
+    }
+    // Shear the corners so that the ray points along +z from the origin.
+    let (sx, sy, sz) = (d[kx] / d[kz], d[ky] / d[kz], 1.0 / d[kz]);
+    let (a, b, c) = (to_f64(a) - o, to_f64(b) - o, to_f64(c) - o);
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+    // Scaled barycentric coordinates, all with the same sign if the ray passes through the
+    // triangle (or exactly through an edge).
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    if t <= 0.0 || t.is_nan() {
+        return None;
     }
+    Some(watertri::Intersection {
+             t: t as f32,
+             u: (u / det) as f32,
+             v: (v / det) as f32,
+             w: (w / det) as f32,
+         })
 }
 
-impl beevage::Primitive for Tri {
+/// What the BVH builder sees of a triangle.
+pub struct TriBounds(pub Aabb);
+
+impl beevage::Primitive for TriBounds {
     fn bounding_box(&self) -> Aabb {
-        self.bbox()
+        self.0
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub o: Vector3<f32>,
     pub d: Vector3<f32>,
-    pub t_max: Cell<f32>,
-    pub traversal_steps: Cell<u32>,
+    /// Intersections at t <= `t_min` don't count, to keep secondary rays from hitting the
+    /// surface they start on.
+    pub t_min: f32,
+    /// Intersections at t >= `t_max` don't count either, e.g. to clip the ray to a region.
+    pub t_max: f32,
 }
 
 impl Ray {
@@ -38,16 +371,49 @@ impl Ray {
         Ray {
             o: origin,
             d: direction,
-            t_max: Cell::new(f32::INFINITY),
-            traversal_steps: Cell::new(0),
+            t_min: 0.0,
+            t_max: f32::INFINITY,
         }
     }
 }
 
-const INVALID_ID: u32 = u32::MAX;
+/// The region that a bundle of rays from a common origin can reach, bounded by planes through
+/// the origin. A point `p` is inside if `n.dot(p) + w >= 0` for every plane `(n, w)`.
+#[derive(Copy, Clone, Debug)]
+pub struct Frustum {
+    pub apex: Vector3<f32>,
+    pub planes: [Vector4<f32>; 4],
+}
+
+impl Frustum {
+    /// Whether `bb` is entirely outside of the frustum. Boxes that straddle a corner of the
+    /// frustum may be reported as not culled although they're outside, which is harmless.
+    pub fn culls(&self, bb: &Aabb) -> bool {
+        self.planes.iter().any(|plane| corner_distance(bb, plane, true) < 0.0)
+    }
+
+    /// Whether `bb` is entirely inside of the frustum.
+    pub fn contains(&self, bb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| corner_distance(bb, plane, false) >= 0.0)
+    }
+}
+
+/// The signed distance (scaled by the length of the normal) to `plane` of the corner of `bb`
+/// furthest along the normal if `positive`, otherwise of the corner furthest against it.
+fn corner_distance(bb: &Aabb, plane: &Vector4<f32>, positive: bool) -> f32 {
+    let (min, max) = (bb.min(), bb.max());
+    let mut dist = plane.w;
+    for axis in 0..3 {
+        let take_max = (plane[axis] >= 0.0) == positive;
+        dist += plane[axis] * if take_max { max[axis] } else { min[axis] };
+    }
+    dist
+}
+
+const INVALID_ID: Index = !0;
 
 pub struct Hit {
-    pub tri_id: u32,
+    pub tri_id: Index,
     pub t: f32,
     pub u: f32,
     pub v: f32,
@@ -79,7 +445,7 @@ impl Hit {
         }
     }
 
-    pub fn replace(&mut self, tri_id: u32, i: watertri::Intersection) {
+    pub fn replace(&mut self, tri_id: Index, i: watertri::Intersection) {
         self.tri_id = tri_id;
         self.t = i.t;
         self.u = i.u;
@@ -88,28 +454,12 @@ impl Hit {
     }
 }
 
-pub trait TriSliceExt {
-    fn bbox(&self) -> Aabb;
-    fn intersect(&self, offset: u32, ray: &Ray, ray_data: &watertri::RayData, hit: &mut Hit);
-}
-
-impl TriSliceExt for [Tri] {
-    fn intersect(&self, offset: u32, ray: &Ray, ray_data: &watertri::RayData, hit: &mut Hit) {
-        for (i, tri) in self.iter().enumerate() {
-            if let Some(intersection) = ray_data.intersect(tri.a, tri.b, tri.c) {
-                if intersection.t < ray.t_max.get() {
-                    ray.t_max.set(intersection.t);
-                    hit.replace(offset + u32(i).unwrap(), intersection);
-                }
-            }
-        }
-    }
+/// Decides whether an intersection with the triangle with the given index counts, e.g. to let
+/// rays pass through the transparent parts of alpha-masked textures. It's asked before the hit
+/// shortens the ray, so rejected hits don't hide anything behind them.
+pub type HitFilter<'a> = Fn(Index, &watertri::Intersection) -> bool + 'a;
 
-    fn bbox(&self) -> Aabb {
-        let mut res = Aabb::empty();
-        for tri in self {
-            res = res.union(tri.bbox());
-        }
-        res
-    }
+/// Whether an intersection passes the filter, if there is one.
+pub fn accept_hit(filter: Option<&HitFilter>, tri_id: Index, i: &watertri::Intersection) -> bool {
+    filter.map_or(true, |f| f(tri_id, i))
 }
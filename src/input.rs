@@ -0,0 +1,112 @@
+//! Reading input files, which may be compressed or come from stdin. The compression format is
+//! chosen by the extension: `.gz`, `.zst`, or a `.zip` archive with a single entry.
+//! Everything is decompressed while it's being read, so even huge files never exist
+//! uncompressed on disk or in memory.
+
+use cgmath::Vector3;
+use color::Rgb;
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+use zstd;
+
+/// The mesh file formats that can be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Obj,
+    Ply,
+    Stl,
+    /// Point clouds in `.xyz` or `.pts` text files.
+    Xyz,
+}
+
+impl Format {
+    /// The format that the extension of `path` indicates (ignoring compression), OBJ if it's
+    /// none of the others.
+    pub fn from_extension(path: &Path) -> Format {
+        let path = if is_compressed(path) {
+            path.file_stem().map_or(path, Path::new)
+        } else {
+            path
+        };
+        match path.extension().and_then(OsStr::to_str).map(|e| e.to_lowercase()) {
+            Some(ref e) if e == "ply" => Format::Ply,
+            Some(ref e) if e == "stl" => Format::Stl,
+            Some(ref e) if e == "xyz" || e == "pts" => Format::Xyz,
+            _ => Format::Obj,
+        }
+    }
+}
+
+/// Bare triangles, as read by the loaders that don't go through obj-rs.
+pub struct Geometry {
+    pub vertices: Vec<Vector3<f32>>,
+    /// The faces, triangulated as fans, as indices into `vertices`.
+    pub tris: Vec<[usize; 3]>,
+    /// The names of the groups the file has, empty if the format has no groups.
+    pub groups: Vec<String>,
+    /// Index into `groups` for each triangle, empty if `groups` is.
+    pub tri_groups: Vec<u32>,
+    /// The color of each vertex as stored in the file (usually sRGB), empty if it has none.
+    pub colors: Vec<Rgb>,
+}
+
+/// The color of vertices without one in files where others have one.
+pub const NO_VERTEX_COLOR: Rgb = Rgb {
+    r: 1.0,
+    g: 1.0,
+    b: 1.0,
+};
+
+/// Whether `path` is `-`, which stands for stdin.
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Whether `open` decompresses the file at `path`.
+pub fn is_compressed(path: &Path) -> bool {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("gz") | Some("zst") | Some("zip") => true,
+        _ => false,
+    }
+}
+
+/// Open `path` (or stdin, for `-`) for buffered reading of its (decompressed) contents.
+pub fn open(path: &Path) -> io::Result<Box<BufRead>> {
+    if is_stdin(path) {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(path)?;
+    let read: Box<Read> = match path.extension().and_then(OsStr::to_str) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        Some("zst") => Box::new(zstd::stream::read::Decoder::new(file)?),
+        Some("zip") => zip_entry(file)?,
+        _ => Box::new(file),
+    };
+    Ok(Box::new(BufReader::new(read)))
+}
+
+/// The only file in the zip archive. The archive is only used to find where the compressed
+/// data starts, so that it can be decompressed without borrowing the archive.
+fn zip_entry(file: File) -> io::Result<Box<Read>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut archive = ZipArchive::new(file).map_err(|e| invalid(e.to_string()))?;
+    if archive.len() != 1 {
+        return Err(invalid(format!("zip archive must contain one file, not {}", archive.len())));
+    }
+    let (compression, start, size) = {
+        let entry = archive.by_index_raw(0).map_err(|e| invalid(e.to_string()))?;
+        (entry.compression(), entry.data_start(), entry.compressed_size())
+    };
+    let mut file = archive.into_inner();
+    file.seek(SeekFrom::Start(start))?;
+    let data = file.take(size);
+    match compression {
+        CompressionMethod::Stored => Ok(Box::new(data)),
+        CompressionMethod::Deflated => Ok(Box::new(DeflateDecoder::new(data))),
+        other => Err(invalid(format!("unsupported zip compression {}", other))),
+    }
+}
@@ -0,0 +1,443 @@
+use super::Config;
+use cast::{f32, f64, usize, u32};
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use geom::{Hit, Ray};
+use light::Light;
+use sampling::{Rng, cosine_hemisphere};
+use scene::Scene;
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What an image shows: a value for each primary ray, computed from what it hit, and how the
+/// values of the samples of a pixel are combined. The render loop (tiles, packets, sampling the
+/// camera) is the same for all of them, so a new kind of image only needs a new integrator.
+/// Integrators that transport light trace further rays from the hit themselves, as
+/// `path_trace` does. suptracer is only a binary, so this is for its own image kinds, not an
+/// API for other crates.
+pub trait Integrator: Sync {
+    type Value: Copy + Send + Sync;
+
+    /// The value of pixels that no primary ray goes through, e.g. the corners of a fisheye.
+    fn background(&self) -> Self::Value;
+
+    /// The value of the primary ray `r`, which hit `hit` (or nothing, if it isn't valid).
+    fn shade(&self, hit: Hit, r: Ray, rng: &mut Rng) -> Self::Value;
+
+    /// Combine the values of the samples of one pixel.
+    fn average(&self, samples: &[Self::Value]) -> Self::Value;
+}
+
+/// What primary rays that hit nothing show in shaded and path traced images. Rays that bounce
+/// off a surface always see the environment, so `--background` doesn't change the lighting.
+/// Unlike in depth maps and other images that aren't tone mapped, the color is radiance.
+#[derive(Copy, Clone, Debug)]
+pub enum MissShader {
+    /// The same radiance in every direction.
+    Color(Rgb),
+    /// The environment map or sky model, or black if there's neither.
+    Environment,
+}
+
+impl MissShader {
+    pub fn new(cfg: &Config) -> Self {
+        cfg.background.map_or(MissShader::Environment, MissShader::Color)
+    }
+
+    pub fn radiance(&self, scene: &Scene, r: &Ray) -> Rgb {
+        match *self {
+            MissShader::Color(c) => c,
+            MissShader::Environment => scene.env.radiance(r.d),
+        }
+    }
+}
+
+/// An integrator made of closures, for images that don't need a type of their own.
+pub struct Shader<T, F, A> {
+    background: T,
+    shade: F,
+    average: A,
+}
+
+impl<T, F, A> Shader<T, F, A>
+    where F: Sync + Fn(Hit, Ray, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    pub fn new(background: T, shade: F, average: A) -> Self {
+        Shader {
+            background,
+            shade,
+            average,
+        }
+    }
+}
+
+impl<T, F, A> Integrator for Shader<T, F, A>
+    where F: Sync + Fn(Hit, Ray, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    type Value = T;
+
+    fn background(&self) -> T {
+        self.background
+    }
+
+    fn shade(&self, hit: Hit, r: Ray, rng: &mut Rng) -> T {
+        (self.shade)(hit, r, rng)
+    }
+
+    fn average(&self, samples: &[T]) -> T {
+        (self.average)(samples)
+    }
+}
+
+/// Estimate the radiance arriving along the primary ray `r`, which hit `hit`, with a path
+/// tracer. Direct lighting from the environment and the lights is estimated by sampling them
+/// at every non-specular vertex (next event estimation). With `cfg.mis`, the directions
+/// sampled from the BSDF to continue the path also pick up light they happen to hit, and both
+/// estimates are combined with multiple importance sampling (balance heuristic). Otherwise,
+/// only paths leaving the camera or a specular surface pick up light that way.
+///
+/// After `cfg.rr_depth` bounces, paths are terminated with Russian roulette, so `cfg.max_depth`
+/// is only a safety net. Also returns the number of surfaces the path hit. If `r` hit nothing,
+/// it sees `miss`.
+pub fn path_trace(scene: &Scene,
+                  cfg: &Config,
+                  miss: MissShader,
+                  hit: Hit,
+                  r: Ray,
+                  rng: &mut Rng)
+                  -> (Rgb, u32) {
+    if !hit.is_valid() {
+        return (miss.radiance(scene, &r), 0);
+    }
+    let mut radiance = Rgb::black();
+    let mut throughput = Rgb::grey(1.0);
+    let mut vertices = 0;
+    let (mut hit, mut r) = (hit, r);
+    for depth in 0..cfg.max_depth {
+        vertices += 1;
+        let p = r.o + r.d * hit.t;
+        let mut n = scene.normal(&r, &hit);
+        let entering = n.dot(r.d) < 0.0;
+        if !entering {
+            n = -n;
+        }
+        let material = scene.material(&hit);
+
+        if !material.is_specular() {
+            let origin = offset_origin(p, n, cfg.ray_offset);
+            let wo = -r.d;
+            let bsdf = |wi| material.eval(wo, wi, n);
+            let bsdf_pdf = |wi| material.pdf(wo, wi, n);
+            let bsdf_pdf = if cfg.mis { Some(&bsdf_pdf as &Fn(Vector3<f32>) -> f32) } else { None };
+            radiance += throughput * env_light(scene, cfg, origin, n, &bsdf, bsdf_pdf, rng);
+            for light in &scene.lights {
+                radiance += throughput *
+                            direct_light(scene, cfg, light, origin, n, &bsdf, bsdf_pdf, rng);
+            }
+        }
+
+        if depth + 1 == cfg.max_depth {
+            break;
+        }
+        // Continue the path.
+        let scatter = material.sample(r.d, n, entering, rng);
+        throughput *= scatter.weight;
+        if depth + 1 >= cfg.rr_depth {
+            // Russian roulette: continue with a probability proportional to the throughput,
+            // and make up for the terminated paths by boosting the surviving ones.
+            let survival = throughput.max_component().min(1.0);
+            if rng.next_f32() >= survival {
+                break;
+            }
+            throughput = throughput / survival;
+        }
+        // Refracted rays continue on the other side of the surface.
+        let side = if scatter.wi.dot(n) > 0.0 { n } else { -n };
+        r = secondary_ray(cfg, offset_origin(p, side, cfg.ray_offset), scatter.wi);
+        // A flat surface can't be hit again by a ray leaving it, no matter what rounding
+        // errors in the offset origin say.
+        let last_tri = hit.tri_id;
+        hit = if scene.is_flat(last_tri) {
+            scene.intersect_filtered(&r, &|tri_id, _| tri_id != last_tri)
+        } else {
+            scene.intersect(&r)
+        };
+        if material.is_specular() || cfg.mis {
+            radiance += throughput * emitted(scene, cfg, &r, &hit, scatter.pdf);
+        }
+        if !hit.is_valid() {
+            break;
+        }
+    }
+    (radiance, vertices)
+}
+
+/// Estimate the irradiance at the point `p` of a surface with unit normal `n`, for baking it
+/// into a lightmap. Direct light from the lights and the environment is sampled like at path
+/// vertices (without MIS), indirect light by path tracing from a cosine-distributed direction.
+pub fn irradiance(scene: &Scene,
+                  cfg: &Config,
+                  p: Vector3<f32>,
+                  n: Vector3<f32>,
+                  rng: &mut Rng)
+                  -> Rgb {
+    let origin = offset_origin(p, n, cfg.ray_offset);
+    // With a BSDF of one, the estimates are just incoming radiance times cosine.
+    let unit = |_| Rgb::grey(1.0);
+    let mut irradiance = env_light(scene, cfg, origin, n, &unit, None, rng);
+    for light in &scene.lights {
+        irradiance += direct_light(scene, cfg, light, origin, n, &unit, None, rng);
+    }
+    let wi = to_world(cosine_hemisphere(rng.next_f32(), rng.next_f32()), n);
+    let r = secondary_ray(cfg, origin, wi);
+    let hit = scene.intersect(&r);
+    // Misses are already covered by sampling the environment. The cosine and pdf cancel out
+    // but for a factor of pi.
+    if hit.is_valid() {
+        irradiance += path_trace(scene, cfg, MissShader::Environment, hit, r, rng).0 * PI;
+    }
+    irradiance
+}
+
+/// The light the ray `r`, sampled from a BSDF with pdf `bsdf_pdf`, picks up from the lights in
+/// front of `hit` and from the environment if it missed, weighted for combining it with next
+/// event estimation. A pdf of zero stands for a specular bounce, which next event estimation
+/// can't handle, so everything counts in full.
+fn emitted(scene: &Scene, cfg: &Config, r: &Ray, hit: &Hit, bsdf_pdf: f32) -> Rgb {
+    let weight = |light_pdf: f32| if bsdf_pdf == 0.0 {
+        1.0
+    } else {
+        balance_heuristic(bsdf_pdf, light_pdf)
+    };
+    let mut radiance = Rgb::black();
+    for light in &scene.lights {
+        if let Some((dist, emitted, light_pdf)) = light.intersect(r.o, r.d) {
+            if !hit.is_valid() || dist < hit.t {
+                let samples = f32(cfg.light_samples);
+                radiance += emitted * weight(samples * light_pdf);
+            }
+        }
+    }
+    if !hit.is_valid() {
+        radiance += scene.env.radiance(r.d) * weight(scene.env.pdf(r.d));
+    }
+    radiance
+}
+
+/// The weight of a sample taken with pdf `pdf`, when another strategy could have produced the
+/// same sample with pdf `other_pdf`. For strategies taking several samples, the pdfs need to be
+/// multiplied with the number of samples.
+fn balance_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if pdf + other_pdf > 0.0 { pdf / (pdf + other_pdf) } else { 0.0 }
+}
+
+/// Estimate the radiance that the environment contributes by reflecting off the surface at
+/// `origin` with normal `n`, from a single sample. If `bsdf_pdf` is given, the sample is
+/// weighted for combining it with BSDF sampling.
+fn env_light(scene: &Scene,
+             cfg: &Config,
+             origin: Vector3<f32>,
+             n: Vector3<f32>,
+             bsdf: &Fn(Vector3<f32>) -> Rgb,
+             bsdf_pdf: Option<&Fn(Vector3<f32>) -> f32>,
+             rng: &mut Rng)
+             -> Rgb {
+    let (wi, env_radiance, pdf) = scene.env.sample(rng.next_f32(), rng.next_f32());
+    let cos = wi.dot(n);
+    if cos <= 0.0 || pdf <= 0.0 || env_radiance.is_black() {
+        return Rgb::black();
+    }
+    let weight = bsdf_pdf.map_or(1.0, |bsdf_pdf| balance_heuristic(pdf, bsdf_pdf(wi)));
+    let shadow_ray = secondary_ray(cfg, origin, wi);
+    if weight > 0.0 && !scene.occluded(&shadow_ray, f32::INFINITY) {
+        bsdf(wi) * env_radiance * (cos * weight / pdf)
+    } else {
+        Rgb::black()
+    }
+}
+
+/// Shade the primary hit `hit` of the ray `r` with N·L diffuse shading from `lights`, casting
+/// shadow rays towards each of them. If `r` hit nothing, it sees `miss`.
+pub fn shade(scene: &Scene,
+             cfg: &Config,
+             lights: &[Light],
+             miss: MissShader,
+             hit: Hit,
+             r: Ray,
+             rng: &mut Rng)
+             -> Rgb {
+    if !hit.is_valid() {
+        return miss.radiance(scene, &r);
+    }
+    let p = r.o + r.d * hit.t;
+    let mut n = scene.normal(&r, &hit);
+    if n.dot(r.d) > 0.0 {
+        n = -n;
+    }
+    let origin = offset_origin(p, n, cfg.ray_offset);
+    // Plain N·L shading, without the 1/pi of a physically based diffuse BRDF.
+    let albedo = scene.material(&hit).albedo();
+    let bsdf = |_| albedo;
+    let mut radiance = Rgb::black();
+    for light in lights {
+        radiance += direct_light(scene, cfg, light, origin, n, &bsdf, None, rng);
+    }
+    radiance
+}
+
+/// Estimate the radiance that `light` contributes by reflecting off the surface at `origin`
+/// with normal `n`, where `bsdf` gives the BSDF for light arriving from a given direction.
+/// Area lights are sampled with `cfg.light_samples` shadow rays. If `bsdf_pdf` is given, the
+/// samples are weighted for combining them with BSDF sampling.
+fn direct_light(scene: &Scene,
+                cfg: &Config,
+                light: &Light,
+                origin: Vector3<f32>,
+                n: Vector3<f32>,
+                bsdf: &Fn(Vector3<f32>) -> Rgb,
+                bsdf_pdf: Option<&Fn(Vector3<f32>) -> f32>,
+                rng: &mut Rng)
+                -> Rgb {
+    let samples = if light.is_delta() { 1 } else { cfg.light_samples };
+    let mut radiance = Rgb::black();
+    for _ in 0..samples {
+        let (wi, dist, e, pdf) = light.sample(origin, rng.next_f32(), rng.next_f32());
+        let cos = wi.dot(n);
+        if cos <= 0.0 || e.is_black() {
+            continue;
+        }
+        // Delta lights can't be hit by BSDF samples, so they keep their full weight.
+        let weight = match bsdf_pdf {
+            Some(bsdf_pdf) if !light.is_delta() => {
+                balance_heuristic(f32(samples) * pdf, bsdf_pdf(wi))
+            }
+            _ => 1.0,
+        };
+        let shadow_ray = secondary_ray(cfg, origin, wi);
+        // Stop a little short of the light, in case it sits right on top of some geometry.
+        if !scene.occluded(&shadow_ray, dist * (1.0 - 1e-3)) {
+            radiance += bsdf(wi) * e * (cos * weight);
+        }
+    }
+    radiance / f32(samples)
+}
+
+/// Statistics about the radiance samples computed for an image, and clamping of outliers.
+pub struct SampleStats {
+    count: AtomicUsize,
+    clamped: AtomicUsize,
+    non_finite: AtomicUsize,
+    path_vertices: AtomicUsize,
+    /// Bit pattern of the largest luminance seen (before clamping). For non-negative floats,
+    /// comparing the bit patterns as integers gives the same order as comparing the floats.
+    max_luminance_bits: AtomicUsize,
+}
+
+impl SampleStats {
+    pub fn new() -> Self {
+        SampleStats {
+            count: AtomicUsize::new(0),
+            clamped: AtomicUsize::new(0),
+            non_finite: AtomicUsize::new(0),
+            path_vertices: AtomicUsize::new(0),
+            max_luminance_bits: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record a sample and return it, scaled down if its brightest channel exceeds `clamp`.
+    /// Samples that are NaN or infinite are replaced with black.
+    pub fn record(&self, radiance: Rgb, clamp: Option<f32>) -> Rgb {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let max = radiance.max_component();
+        if !max.is_finite() || !radiance.luminance().is_finite() {
+            self.non_finite.fetch_add(1, Ordering::Relaxed);
+            return Rgb::black();
+        }
+        let bits = usize(radiance.luminance().max(0.0).to_bits());
+        let mut current = self.max_luminance_bits.load(Ordering::Relaxed);
+        while bits > current {
+            match self.max_luminance_bits
+                      .compare_exchange_weak(current, bits, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        match clamp {
+            Some(limit) if max > limit => {
+                self.clamped.fetch_add(1, Ordering::Relaxed);
+                radiance * (limit / max)
+            }
+            _ => radiance,
+        }
+    }
+
+    /// Record the number of surfaces a path hit, for the average path length.
+    pub fn record_path_length(&self, vertices: u32) {
+        self.path_vertices.fetch_add(usize(vertices), Ordering::Relaxed);
+    }
+
+    pub fn print(&self) {
+        let count = self.count.load(Ordering::Relaxed);
+        let percent = |n: usize| if count == 0 { 0.0 } else { 100.0 * f64(n) / f64(count) };
+        let clamped = self.clamped.load(Ordering::Relaxed);
+        let non_finite = self.non_finite.load(Ordering::Relaxed);
+        let max_bits = u32(self.max_luminance_bits.load(Ordering::Relaxed)).unwrap();
+        println!("{} samples, {} clamped ({:.3}%), {} NaN/infinite ({:.3}%), max luminance {}",
+                 count,
+                 clamped,
+                 percent(clamped),
+                 non_finite,
+                 percent(non_finite),
+                 f32::from_bits(max_bits));
+        let path_vertices = self.path_vertices.load(Ordering::Relaxed);
+        if path_vertices > 0 {
+            println!("average path length: {:.2} bounces",
+                     f64(path_vertices) / f64(count));
+        }
+    }
+}
+
+/// Move a point slightly off the surface along the unit normal `n`, so rays starting there
+/// don't hit the same surface. This follows Wächter and Binder, "A Fast and Robust Method for
+/// Avoiding Self-Intersection" (Ray Tracing Gems, 2019): each coordinate moves by a number of
+/// ULPs, so the offset grows with the rounding error of the hit point. Close to zero, where
+/// ULPs are tiny, it moves by a small fixed distance instead. `scale` multiplies the offset.
+pub fn offset_origin(p: Vector3<f32>, n: Vector3<f32>, scale: f32) -> Vector3<f32> {
+    const ORIGIN: f32 = 1.0 / 32.0;
+    const FLOAT_SCALE: f32 = 1.0 / 65536.0;
+    const INT_SCALE: f32 = 256.0;
+    let mut res = p;
+    for axis in 0..3 {
+        let (x, offset) = (p[axis], n[axis] * scale);
+        res[axis] = if x.abs() < ORIGIN {
+            x + FLOAT_SCALE * offset
+        } else {
+            let ulps = (INT_SCALE * offset) as i32;
+            let ulps = if x < 0.0 { ulps.wrapping_neg() } else { ulps };
+            // Only absurd scales overflow, but that mustn't panic in debug builds.
+            f32::from_bits((x.to_bits() as i32).wrapping_add(ulps) as u32)
+        };
+    }
+    res
+}
+
+/// A ray leaving a surface at `origin` (already moved off it with `offset_origin`).
+pub fn secondary_ray(cfg: &Config, origin: Vector3<f32>, d: Vector3<f32>) -> Ray {
+    Ray { t_min: cfg.ray_t_min, ..Ray::new(origin, d) }
+}
+
+/// Transform a direction given relative to +Z into the frame where +Z is the unit vector `n`.
+pub fn to_world(v: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    // Building an orthonormal basis from a unit vector (Duff et al. 2017)
+    let sign = 1f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    let t = vec3(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let s = vec3(b, sign + n.y * n.y * a, -n.y);
+    t * v.x + s * v.y + n * v.z
+}
@@ -0,0 +1,133 @@
+use super::{Config, RenderKind, fail, renderer};
+use bmp;
+use camera::{self, Camera};
+use cast::{usize, u32};
+use cgmath::{InnerSpace, Vector3, vec3};
+use film::{Accumulator, ToBmp};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window, WindowOptions};
+use scene::Scene;
+use std::time::Instant;
+
+/// While the camera moves, render at 1/PREVIEW_SCALE of the resolution in each dimension.
+const PREVIEW_SCALE: u32 = 4;
+/// Radians per pixel of mouse movement.
+const MOUSE_SENSITIVITY: f32 = 0.005;
+/// While the camera rests, passes with jittered samples are averaged until there are this many.
+const MAX_PASSES: u32 = 64;
+
+/// A free-flying camera, described by position and viewing angles.
+struct View {
+    eye: Vector3<f32>,
+    yaw: f32,
+    pitch: f32,
+}
+
+impl View {
+    fn forward(&self) -> Vector3<f32> {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        vec3(sin_yaw * cos_pitch, sin_pitch, -cos_yaw * cos_pitch)
+    }
+
+    fn camera(&self, cfg: &Config) -> Camera {
+        Camera::look_at(self.eye, self.eye + self.forward(), cfg)
+    }
+}
+
+pub fn run(scene: &Scene, cfg: &Config) {
+    let (width, height) = (usize(cfg.image_width), usize(cfg.image_height));
+    let mut window = Window::new("suptracer", width, height, WindowOptions::default())
+        .unwrap_or_else(|e| fail(&format!("could not open window: {}", e)));
+    let mut preview_cfg = cfg.clone();
+    preview_cfg.image_width = (cfg.image_width / PREVIEW_SCALE).max(1);
+    preview_cfg.image_height = (cfg.image_height / PREVIEW_SCALE).max(1);
+    preview_cfg.crop = None;
+    let mut full_cfg = cfg.clone();
+    full_cfg.crop = None;
+
+    let (eye, target) = camera::placement(cfg, scene.bbox());
+    let dir = (target - eye).normalize();
+    let mut view = View {
+        eye,
+        yaw: dir.x.atan2(-dir.z),
+        pitch: dir.y.asin(),
+    };
+    let scene_size = (scene.bbox().max() - scene.bbox().min()).magnitude();
+    let move_step = scene_size * 0.01;
+
+    let mut kind = cfg.render_kind;
+    let mut buffer = vec![0u32; width * height];
+    let mut last_mouse: Option<(f32, f32)> = None;
+    // Starts over whenever the view changes.
+    let mut accumulator = Accumulator::new(cfg.image_width, cfg.image_height);
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let mut moved = false;
+        let forward = view.forward();
+        let right = forward.cross(vec3(0.0, 1.0, 0.0)).normalize();
+        let movements = [(Key::W, forward),
+                         (Key::S, -forward),
+                         (Key::D, right),
+                         (Key::A, -right),
+                         (Key::E, vec3(0.0, 1.0, 0.0)),
+                         (Key::Q, vec3(0.0, -1.0, 0.0))];
+        for &(key, dir) in &movements {
+            if window.is_key_down(key) {
+                view.eye += dir * move_step;
+                moved = true;
+            }
+        }
+        let kinds = [(Key::Key1, RenderKind::Depthmap),
+                     (Key::Key2, RenderKind::Heatmap),
+                     (Key::Key3, RenderKind::Normals),
+                     (Key::Key4, RenderKind::Shaded)];
+        for &(key, new_kind) in &kinds {
+            if window.is_key_pressed(key, KeyRepeat::No) && kind != new_kind {
+                kind = new_kind;
+                accumulator.reset();
+            }
+        }
+        let mouse = window.get_mouse_pos(MouseMode::Pass);
+        if window.get_mouse_down(MouseButton::Left) {
+            if let (Some((x0, y0)), Some((x1, y1))) = (last_mouse, mouse) {
+                if (x0, y0) != (x1, y1) {
+                    view.yaw += (x1 - x0) * MOUSE_SENSITIVITY;
+                    view.pitch = (view.pitch - (y1 - y0) * MOUSE_SENSITIVITY)
+                        .max(-1.5)
+                        .min(1.5);
+                    moved = true;
+                }
+            }
+        }
+        last_mouse = mouse;
+
+        if moved {
+            let preview = renderer(kind)(scene, &preview_cfg, &view.camera(&preview_cfg));
+            draw(&*preview, &mut buffer, width, height);
+            accumulator.reset();
+        } else if accumulator.passes() < MAX_PASSES {
+            let start = Instant::now();
+            full_cfg.pass = accumulator.passes();
+            accumulator.add(&*renderer(kind)(scene, &full_cfg, &view.camera(&full_cfg)));
+            draw(&*accumulator.average(), &mut buffer, width, height);
+            let t = start.elapsed();
+            let ms = t.as_secs() * 1000 + u64::from(t.subsec_nanos() / 1_000_000);
+            window.set_title(&format!("suptracer (pass {}, {} ms)", accumulator.passes(), ms));
+        }
+        window.update_with_buffer(&buffer, width, height)
+            .unwrap_or_else(|e| fail(&format!("could not update window: {}", e)));
+    }
+}
+
+/// Scale the image up to fill the whole window.
+fn draw(image: &ToBmp, buffer: &mut [u32], width: usize, height: usize) {
+    let img = image.to_bmp();
+    let (img_width, img_height) = (usize(img.get_width()), usize(img.get_height()));
+    for y in 0..height {
+        for x in 0..width {
+            let src_x = u32(x * img_width / width).unwrap();
+            let src_y = u32(y * img_height / height).unwrap();
+            let bmp::Pixel { r, g, b } = img.get_pixel(src_x, src_y);
+            buffer[y * width + x] = (u32(r) << 16) | (u32(g) << 8) | u32(b);
+        }
+    }
+}
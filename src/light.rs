@@ -0,0 +1,141 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use integrator::to_world;
+use sampling::concentric_disk;
+use std::f32;
+use std::f32::consts::PI;
+
+/// A light source. Lights are not part of the geometry: camera rays never see them and they
+/// don't cast shadows, but rays that bounced off a surface can hit area lights (see
+/// `intersect`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Light {
+    /// Infinitely far away in the direction `dir` (a unit vector), like the sun.
+    Directional { dir: Vector3<f32>, irradiance: Rgb },
+    /// Shining in all directions from `pos`. Its light doesn't fall off with distance, so that
+    /// the same light works for scenes of any scale.
+    Point { pos: Vector3<f32>, irradiance: Rgb },
+    /// The parallelogram spanned by `edge_u` and `edge_v` starting at `corner`, emitting
+    /// `radiance` from the side `edge_u × edge_v` points to.
+    Rect {
+        corner: Vector3<f32>,
+        edge_u: Vector3<f32>,
+        edge_v: Vector3<f32>,
+        radiance: Rgb,
+    },
+    /// A disk emitting `radiance` from the side the unit vector `normal` points to.
+    Disk {
+        center: Vector3<f32>,
+        normal: Vector3<f32>,
+        radius: f32,
+        radiance: Rgb,
+    },
+}
+
+impl Light {
+    /// Whether the light is a single point or direction, so that sampling it more than once
+    /// is pointless and its shadows are hard.
+    pub fn is_delta(&self) -> bool {
+        match *self {
+            Light::Directional { .. } | Light::Point { .. } => true,
+            Light::Rect { .. } | Light::Disk { .. } => false,
+        }
+    }
+
+    /// Pick a point on the light (using `u` and `v` in [0, 1)) and return the unit direction
+    /// from `p` towards it, the distance to it, an estimate of the irradiance the light
+    /// contributes to a surface at `p` that is facing that direction head-on, and the pdf
+    /// (w.r.t. solid angle) of the direction. The pdf is zero for delta lights.
+    pub fn sample(&self, p: Vector3<f32>, u: f32, v: f32) -> (Vector3<f32>, f32, Rgb, f32) {
+        match *self {
+            Light::Directional { dir, irradiance } => (dir, f32::INFINITY, irradiance, 0.0),
+            Light::Point { pos, irradiance } => {
+                let to_light = pos - p;
+                let dist = to_light.magnitude();
+                (to_light / dist, dist, irradiance, 0.0)
+            }
+            Light::Rect { corner, edge_u, edge_v, radiance } => {
+                let q = corner + edge_u * u + edge_v * v;
+                let n = edge_u.cross(edge_v);
+                let area = n.magnitude();
+                sample_area(p, q, n / area, area, radiance)
+            }
+            Light::Disk { center, normal, radius, radiance } => {
+                let (x, y) = concentric_disk(u, v);
+                let q = center + to_world(vec3(x * radius, y * radius, 0.0), normal);
+                sample_area(p, q, normal, PI * radius * radius, radiance)
+            }
+        }
+    }
+
+    /// Find where the ray from `o` in the unit direction `d` hits the front of the light.
+    /// Returns the distance, the emitted radiance, and the pdf of `sample` picking `d` from
+    /// `o`. Delta lights can't be hit.
+    pub fn intersect(&self, o: Vector3<f32>, d: Vector3<f32>) -> Option<(f32, Rgb, f32)> {
+        match *self {
+            Light::Directional { .. } | Light::Point { .. } => None,
+            Light::Rect { corner, edge_u, edge_v, radiance } => {
+                let n = edge_u.cross(edge_v);
+                let t = plane_distance(o, d, corner, n)?;
+                let q = o + d * t - corner;
+                // Solve q = a * edge_u + b * edge_v.
+                let n2 = n.magnitude2();
+                let a = q.cross(edge_v).dot(n) / n2;
+                let b = edge_u.cross(q).dot(n) / n2;
+                if a < 0.0 || a > 1.0 || b < 0.0 || b > 1.0 {
+                    return None;
+                }
+                let area = n2.sqrt();
+                Some((t, radiance, area_pdf(d, t, n / area, area)))
+            }
+            Light::Disk { center, normal, radius, radiance } => {
+                let t = plane_distance(o, d, center, normal)?;
+                if (o + d * t - center).magnitude2() > radius * radius {
+                    return None;
+                }
+                Some((t, radiance, area_pdf(d, t, normal, PI * radius * radius)))
+            }
+        }
+    }
+}
+
+/// The distance along the ray to the plane through `q` with normal `n`, if the ray hits the
+/// side `n` points to.
+fn plane_distance(o: Vector3<f32>,
+                  d: Vector3<f32>,
+                  q: Vector3<f32>,
+                  n: Vector3<f32>)
+                  -> Option<f32> {
+    let cos = d.dot(n);
+    if cos >= 0.0 {
+        return None;
+    }
+    let t = (q - o).dot(n) / cos;
+    if t > 0.0 { Some(t) } else { None }
+}
+
+/// Convert the pdf of picking a point uniformly on a light with the given area and unit normal
+/// `n`, at distance `dist` in direction `d`, to solid angle.
+fn area_pdf(d: Vector3<f32>, dist: f32, n: Vector3<f32>, area: f32) -> f32 {
+    let cos_light = -d.dot(n);
+    if cos_light > 0.0 { dist * dist / (cos_light * area) } else { 0.0 }
+}
+
+/// Convert the contribution of the point `q`, picked uniformly on a light with the given area
+/// and normal `n`, from radiance per area to irradiance at `p`.
+fn sample_area(p: Vector3<f32>,
+               q: Vector3<f32>,
+               n: Vector3<f32>,
+               area: f32,
+               radiance: Rgb)
+               -> (Vector3<f32>, f32, Rgb, f32) {
+    let to_light = q - p;
+    let dist = to_light.magnitude();
+    let wi = to_light / dist;
+    let pdf = area_pdf(wi, dist, n, area);
+    if pdf == 0.0 {
+        // Looking at the back of the light.
+        return (wi, dist, Rgb::black(), 0.0);
+    }
+    (wi, dist, radiance / pdf, pdf)
+}
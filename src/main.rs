@@ -7,114 +7,1754 @@ extern crate cgmath;
 extern crate clap;
 extern crate cast;
 extern crate elapsed;
+#[cfg(feature = "embree")]
+extern crate embree;
+extern crate flate2;
+extern crate image;
 #[macro_use]
 extern crate lazy_static;
+extern crate memmap;
 extern crate itertools;
+extern crate minifb;
+extern crate notify;
 extern crate obj;
 extern crate ordered_float;
 extern crate rayon;
 extern crate regex;
+#[cfg(test)]
+extern crate serde_json;
+extern crate toml;
 extern crate watertri;
+extern crate zip;
+extern crate zstd;
 
-use cast::{usize, u32, f32, f64};
-use cgmath::{InnerSpace, vec3};
-use film::{Frame, Depthmap, Heatmap};
-use geom::{Hit, Ray};
-use scene::Scene;
+use beebox::Aabb;
+use build::Builder;
+use bvh::{BvhLayout, HeatCounter, NoStats, StatsRecorder, Traversal, TraversalStats};
+use camera::{Camera, CameraSample, Projection};
+use cast::{usize, u32, u64, i64, f32, f64};
+use cgmath::{InnerSpace, Vector3, Vector4, vec3};
+use color::Rgb;
+use film::{Accumulator, Frame, Colors, Depthmap, Filter, HeatDifference, Heatmap, IdMap, Mask,
+           Normalmap, PixelOrder, Radiance, Rect, SideBySide, Tonemap};
+use geom::{Hit, Index, Precision, Ray, TriIsect};
+use integrator::{Integrator, MissShader, Shader};
+use light::Light;
+use material::Material;
+use rayon::prelude::*;
+use sampling::Rng;
+use scene::{Backend, IdKind, Scene};
+use shape::Shape;
 use std::f32;
-use std::path::PathBuf;
+use std::f32::consts::PI;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
+mod bake;
+mod build;
 mod bvh;
+mod camera;
+mod check;
+mod chunked;
 mod cli;
+mod coherence;
+mod color;
+mod decimate;
+mod denoise;
+#[cfg(feature = "embree")]
+mod embree_scene;
+mod envmap;
+mod fast_obj;
 mod film;
 mod geom;
+mod input;
+mod integrator;
+mod interactive;
+mod light;
+mod material;
+mod ply;
+mod png_stream;
+mod points;
+mod profile;
+mod sampling;
 mod scene;
+mod selftest;
+mod serve;
+mod shape;
+mod sky;
+mod stl;
+mod texture;
+mod volume;
+mod watch;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum RenderKind {
     Depthmap,
     Heatmap,
+    Normals,
+    Shaded,
+    PathTraced,
+    Uv,
+    VertexColor,
+    Layers,
+    Mask,
+    ObjectId,
+    MaterialId,
+    Thickness,
 }
 
+/// How the work of rendering an image is organized.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// Each pixel is traced and shaded from start to finish by one closure.
+    Megakernel,
+    /// The primary rays of a whole tile are generated first, then traced as one stream sorted
+    /// for coherence, then shaded together.
+    Wavefront,
+}
+
+/// Settings of the `sweep` subcommand, which renders the same view with BVHs built using every
+/// combination of the SAH parameters.
+#[derive(Clone)]
+struct Sweep {
+    buckets: Vec<u32>,
+    traversal_costs: Vec<f32>,
+    csv_file: PathBuf,
+}
+
+/// Settings of the `selftest` subcommand, which compares renders of bundled meshes against
+/// reference images.
+#[derive(Clone)]
+struct SelfTest {
+    /// Where the meshes and reference images are.
+    dir: PathBuf,
+    /// Write new reference images instead of comparing.
+    bless: bool,
+}
+
+/// Settings of the `heat-diff` subcommand: how the BVH of the second heatmap is built
+/// differently from the first one. None keeps the main setting.
+#[derive(Clone)]
+struct HeatDiff {
+    builder: Option<Builder>,
+    layout: Option<BvhLayout>,
+    buckets: Option<u32>,
+    traversal_cost: Option<f32>,
+    max_leaf_tris: Option<u32>,
+}
+
+impl HeatDiff {
+    /// The configuration for the second heatmap.
+    fn apply(&self, cfg: &Config) -> Config {
+        Config {
+            bvh_builder: self.builder.unwrap_or(cfg.bvh_builder),
+            bvh_layout: self.layout.unwrap_or(cfg.bvh_layout),
+            sah_buckets: self.buckets.unwrap_or(cfg.sah_buckets),
+            sah_traversal_cost: self.traversal_cost.unwrap_or(cfg.sah_traversal_cost),
+            max_leaf_tris: self.max_leaf_tris.unwrap_or(cfg.max_leaf_tris),
+            ..cfg.clone()
+        }
+    }
+}
+
+/// How `--stereo` writes the views of the two eyes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum StereoLayout {
+    /// One image twice as wide, the left eye's view on the left.
+    SideBySide,
+    /// Two images, with `_left` and `_right` added to the file name.
+    Separate,
+}
+
+/// Render a view for each eye, from eyes `separation` apart along the camera's right axis.
+#[derive(Copy, Clone, Debug)]
+struct Stereo {
+    separation: f32,
+    layout: StereoLayout,
+}
+
+/// What the `bake` subcommand writes into the texture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum BakeMap {
+    AmbientOcclusion,
+    /// The light arriving at the surface, from the path tracer.
+    Lightmap,
+    /// The normals of the source mesh in the tangent space of the baked mesh.
+    Normal,
+    /// The distance from the baked mesh to the source mesh along its normals.
+    Displacement,
+}
+
+/// Settings of the `bake` subcommand, which writes the ambient occlusion of the mesh, or
+/// details of a source mesh, into a texture laid out by its texture coordinates.
+#[derive(Clone)]
+struct Bake {
+    map: BakeMap,
+    /// The detailed mesh to take normals or displacement from.
+    source: Option<PathBuf>,
+    /// How far from the baked surface to look for the source mesh, None for 2% of the
+    /// diagonal of the scene's bounding box.
+    cage: Option<f32>,
+    size: (u32, u32),
+    samples: u32,
+    /// How far away geometry can still occlude, None for no limit.
+    distance: Option<f32>,
+    /// How many texels uncovered texels next to covered ones are filled in.
+    padding: u32,
+    /// The mesh whose texture coordinates lay out the lightmap, None to generate them.
+    lightmap_uvs: Option<PathBuf>,
+    output_file: PathBuf,
+}
+
+#[derive(Clone)]
 pub struct Config {
-    input_file: PathBuf,
+    /// The meshes to load, all into one scene.
+    input_files: Vec<PathBuf>,
+    /// None to pick the format of each input file by its extension.
+    input_format: Option<input::Format>,
+    /// Albedo for all surfaces of the first `part_colors.len()` input files.
+    part_colors: Vec<Rgb>,
+    /// If not empty, only the OBJ groups with these names are loaded.
+    only_groups: Vec<String>,
+    exclude_groups: Vec<String>,
+    color_groups: bool,
     output_file: PathBuf,
     image_width: u32,
     image_height: u32,
     sah_buckets: u32,
     sah_traversal_cost: f32,
+    bvh_builder: Builder,
+    bvh_optimize: bool,
+    max_leaf_tris: u32,
+    traversal: Traversal,
+    pixel_order: PixelOrder,
+    renderer: Renderer,
+    bvh_layout: BvhLayout,
+    tri_isect: TriIsect,
+    precision: Precision,
+    cull_backfaces: bool,
+    backend: Backend,
     num_threads: Option<u32>,
     render_kind: RenderKind,
+    heat_counter: HeatCounter,
+    crop: Option<Rect>,
+    debug_pixel: Option<(u32, u32)>,
+    autoframe: bool,
+    eye: Vector3<f32>,
+    look_at: Vector3<f32>,
+    fov: f32,
+    projection: Projection,
+    spp: u32,
+    /// Number of passes with different samples whose images are averaged.
+    passes: u32,
+    /// Which pass of a progressive render this is. Only the first one traces the pixel
+    /// centers when there's one sample per pixel.
+    pass: u32,
+    /// Which row of the full image the first row rendered is, when rendering it band by band.
+    first_row: u32,
+    aperture: f32,
+    focus_dist: Option<f32>,
+    clip_planes: Vec<Vector4<f32>>,
+    turntable: Option<u32>,
+    camera_path: Option<PathBuf>,
+    fps: f32,
+    spin: Option<u32>,
+    interactive: bool,
+    watch: bool,
+    envmap: Option<PathBuf>,
+    /// A density grid to render along with the mesh, see `volume`.
+    volume: Option<PathBuf>,
+    volume_absorption: f32,
+    volume_emission: Rgb,
+    max_depth: u32,
+    rr_depth: u32,
+    ray_t_min: f32,
+    ray_offset: f32,
+    sky: bool,
+    /// What primary rays that miss everything show instead of the usual background of the
+    /// kind of image, see `MissShader`.
+    background: Option<Rgb>,
+    sun_elevation: f32,
+    sun_azimuth: f32,
+    turbidity: f32,
+    /// Also write the raw counts of heatmaps next to the image, in this format.
+    counts_format: Option<film::CountsFormat>,
+    /// The depths that depth maps show as near and far, instead of the closest and farthest.
+    depth_near: Option<f32>,
+    depth_far: Option<f32>,
+    /// Spacing of the contour lines drawn on depth maps, if any.
+    depth_isolines: Option<f32>,
+    /// Draw the boxes of the BVH nodes up to this depth on top of the image.
+    overlay_bvh: Option<u32>,
+    lights: Vec<Light>,
+    shapes: Vec<(Shape, Material)>,
+    light_samples: u32,
+    tonemap: Tonemap,
+    exposure: f32,
+    filter: Filter,
+    clamp: Option<f32>,
+    denoise: bool,
+    /// Write RGBA PNGs with the coverage of each pixel as alpha.
+    transparent: bool,
+    mis: bool,
+    info: bool,
+    bench: bool,
+    validate: bool,
+    check_determinism: bool,
+    /// Run the `check` subcommand, diagnosing the mesh instead of rendering.
+    check: bool,
+    bake: Option<Bake>,
+    selftest: Option<SelfTest>,
+    stats_out: Option<PathBuf>,
+    chunks: Option<u32>,
+    sweep: Option<Sweep>,
+    heat_diff: Option<HeatDiff>,
+    weld_epsilon: Option<f32>,
+    /// Where to write the recorded spans for chrome://tracing.
+    trace_out: Option<PathBuf>,
+    /// Port to serve the images on over HTTP while they're rendered.
+    serve: Option<u16>,
+    /// Where `serve` listens, the loopback address unless asked otherwise.
+    serve_address: IpAddr,
+    /// Split every triangle into four this many times before building the BVH.
+    tessellate: Option<u32>,
+    /// Simplify the mesh to this fraction of its triangles before building the BVH.
+    decimate: Option<f32>,
+    stereo: Option<Stereo>,
+    /// Render and write the image this many rows at a time, see `render_streamed`.
+    stream: Option<u32>,
 }
 
-fn primary_ray(x: u32, y: u32, cfg: &Config) -> Ray {
-    let norm_x = (f32(x) + 0.5) / f32(cfg.image_width);
-    let norm_y = (f32(y) + 0.5) / f32(cfg.image_height);
-    let aspect_ratio = f32(cfg.image_width) / f32(cfg.image_height);
-    let cam_x = aspect_ratio * (norm_x - 0.5);
-    let cam_y = aspect_ratio * (0.5 - norm_y);
-    let d = vec3(cam_x, cam_y, -1.0).normalize();
-    Ray::new(vec3(0.0, 0.0, 0.0), d)
+/// Render an image by tracing `cfg.spp` primary rays per pixel and combining the values
+/// `integrator` computes for each of them.
+fn render<I>(scene: &Scene, cfg: &Config, camera: &Camera, integrator: &I) -> film::Frame<I::Value>
+    where I: Integrator
+{
+    render_recorded(scene,
+                    cfg,
+                    camera,
+                    integrator.background(),
+                    |hit, r, _: NoStats, rng| integrator.shade(hit, r, rng),
+                    |samples| integrator.average(samples))
 }
 
-fn render<T, F>(scene: &Scene, cfg: &Config, background: T, shader: F) -> film::Frame<T>
-    where F: Sync + Fn(Hit, Ray) -> T,
+/// Like `render`, but `shader` also gets what a fresh `S` recorded while the primary ray
+/// traversed the BVH.
+///
+/// With `--transparent`, the fraction of the primary rays of each pixel that hit something is
+/// recorded with `Scene::add_coverage`, and only those rays make up the pixel's value, so that
+/// the coverage is a straight (not premultiplied) alpha for it.
+fn render_recorded<S, T, F, A>(scene: &Scene,
+                               cfg: &Config,
+                               camera: &Camera,
+                               background: T,
+                               shader: F,
+                               average: A)
+                               -> film::Frame<T>
+    where S: StatsRecorder + Default,
+          F: Sync + Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    if !cfg.transparent {
+        return render_frame(scene, cfg, camera, background, shader, average);
+    }
+    let frame = render_frame(scene,
+                             cfg,
+                             camera,
+                             (background, 0.0),
+                             |hit: Hit, r, stats, rng: &mut Rng| {
+                                 let covered = if hit.is_valid() { 1.0 } else { 0.0 };
+                                 (shader(hit, r, stats, rng), covered)
+                             },
+                             |samples: &[(T, f32)]| {
+                                 let hits: Vec<T> = samples.iter()
+                                     .filter(|&&(_, covered)| covered > 0.0)
+                                     .map(|&(value, _)| value)
+                                     .collect();
+                                 let coverage = f32(hits.len()) / f32(samples.len());
+                                 if hits.is_empty() {
+                                     // Still show what's there (e.g. the sky), with alpha 0.
+                                     let misses: Vec<T> = samples.iter().map(|s| s.0).collect();
+                                     (average(&misses), coverage)
+                                 } else {
+                                     (average(&hits), coverage)
+                                 }
+                             });
+    scene.add_coverage(frame.map(|(_, coverage)| coverage));
+    frame.map(|(value, _)| value)
+}
+
+fn render_frame<S, T, F, A>(scene: &Scene,
+                            cfg: &Config,
+                            camera: &Camera,
+                            background: T,
+                            shader: F,
+                            average: A)
+                            -> film::Frame<T>
+    where S: StatsRecorder + Default,
+          F: Sync + Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
           T: Copy + Send + Sync
 {
     let mut frame = Frame::new(cfg.image_width, cfg.image_height, background);
-    frame.set_pixels(|x, y| {
-                         let r = primary_ray(x, y, cfg);
-                         let hit = scene.intersect(&r);
-                         shader(hit, r)
-                     });
+    let window = cfg.crop.unwrap_or(frame.bounds());
+    if cfg.renderer == Renderer::Wavefront {
+        render_wavefront(scene, cfg, camera, background, &shader, &average, &mut frame, window);
+        return frame;
+    }
+    match cfg.traversal {
+        Traversal::Single | Traversal::Stackless => {
+            let stackless = cfg.traversal == Traversal::Stackless;
+            let intersect = |r: &Ray, stats: &mut S| if stackless {
+                scene.intersect_stackless(r, stats)
+            } else {
+                scene.intersect_recorded(r, stats)
+            };
+            let pixels: Vec<(u32, u32)> = cfg.pixel_order
+                .points(window.w, window.h)
+                .into_iter()
+                .map(|(x, y)| (window.x + x, window.y + y))
+                .collect();
+            // Chunks of pixels rather than single ones are the unit of work, so that they can
+            // show up in profiles.
+            let chunks: Vec<Vec<T>> = pixels.par_chunks(PIXEL_CHUNK)
+                .map(|chunk| {
+                    profile::span("pixels", || {
+                        chunk.iter()
+                            .map(|&(x, y)| {
+                                render_pixel(cfg, camera, background, &intersect, &shader,
+                                             &average, x, y)
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            let values = chunks.into_iter().flat_map(|chunk| chunk);
+            for (&(x, y), value) in pixels.iter().zip(values) {
+                frame.set(x, y, value);
+            }
+        }
+        Traversal::Packet => {
+            render_packets(scene, cfg, camera, background, &shader, &average, &mut frame, window)
+        }
+        Traversal::Frustum => {
+            render_frustums(scene, cfg, camera, background, &shader, &average, &mut frame, window)
+        }
+    }
+    frame
+}
+
+/// Number of consecutive pixels (in the pixel order) rendered together with single ray
+/// traversal.
+const PIXEL_CHUNK: usize = 64;
+
+/// Trace the primary rays of one pixel with `intersect` and combine their values.
+fn render_pixel<S, T, I, F, A>(cfg: &Config,
+                               camera: &Camera,
+                               background: T,
+                               intersect: &I,
+                               shader: &F,
+                               average: &A,
+                               x: u32,
+                               y: u32)
+                               -> T
+    where S: StatsRecorder + Default,
+          I: Fn(&Ray, &mut S) -> Hit,
+          F: Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Fn(&[T]) -> T,
+          T: Copy
+{
+    let trace = |sample: &CameraSample, rng: &mut Rng| match camera.primary_ray(x, y, sample) {
+        Some(r) => {
+            let mut stats = S::default();
+            let hit = intersect(&r, &mut stats);
+            shader(hit, r, stats, rng)
+        }
+        None => background,
+    };
+    let mut rng = Rng::for_pixel_pass(x, cfg.first_row + y, cfg.pass);
+    if cfg.spp == 1 {
+        trace(&camera_sample(cfg, &mut rng), &mut rng)
+    } else {
+        let samples: Vec<T> = (0..cfg.spp)
+            .map(|_| {
+                let sample = camera_sample(cfg, &mut rng);
+                trace(&sample, &mut rng)
+            })
+            .collect();
+        average(&samples)
+    }
+}
+
+fn camera_sample(cfg: &Config, rng: &mut Rng) -> CameraSample {
+    if cfg.spp == 1 && cfg.pass == 0 {
+        // Stick to the pixel center for reproducibility, but still sample the lens. Later
+        // passes of a progressive render jitter, so that averaging them antialiases.
+        CameraSample { film: (0.5, 0.5), ..CameraSample::random(rng) }
+    } else {
+        CameraSample::random(rng)
+    }
+}
+
+/// Side length of the square tiles of pixels whose primary rays are traced as one packet.
+const PACKET_TILE: u32 = 4;
+
+/// Split `window` into square tiles of side length `size`, smaller at the right and bottom
+/// edges if needed, in the given order.
+fn tiles(window: Rect, size: u32, order: PixelOrder) -> Vec<Rect> {
+    let grid = ((window.w + size - 1) / size, (window.h + size - 1) / size);
+    order.points(grid.0, grid.1)
+        .into_iter()
+        .map(|(i, j)| {
+                 let (x, y) = (window.x + i * size, window.y + j * size);
+                 Rect {
+                     x,
+                     y,
+                     w: size.min(window.x + window.w - x),
+                     h: size.min(window.y + window.h - y),
+                 }
+             })
+        .collect()
+}
+
+/// The part of `render` for `--traversal packet`: the primary rays for the same sample of all
+/// pixels in a small tile are traced together, everything after that ray by ray.
+/// Each pixel uses its random numbers in the same order as with single ray traversal, so the
+/// images are identical.
+fn render_packets<S, T, F, A>(scene: &Scene,
+                              cfg: &Config,
+                              camera: &Camera,
+                              background: T,
+                              shader: &F,
+                              average: &A,
+                              frame: &mut Frame<T>,
+                              window: Rect)
+    where S: StatsRecorder + Default,
+          F: Sync + Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    let render_tile = |tile: &Rect| {
+        let pixels: Vec<(u32, u32)> = (tile.y..tile.y + tile.h)
+            .flat_map(|y| (tile.x..tile.x + tile.w).map(move |x| (x, y)))
+            .collect();
+        let mut rngs: Vec<Rng> = pixels.iter()
+            .map(|&(x, y)| Rng::for_pixel_pass(x, cfg.first_row + y, cfg.pass))
+            .collect();
+        let mut samples = vec![Vec::with_capacity(usize(cfg.spp)); pixels.len()];
+        for _ in 0..cfg.spp {
+            let camera_samples: Vec<_> =
+                rngs.iter_mut().map(|rng| camera_sample(cfg, rng)).collect();
+            // Pixels that the camera doesn't cover get no lane in the packet.
+            let (lanes, rays): (Vec<usize>, Vec<Ray>) = camera.primary_rays(*tile, &camera_samples)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, r)| r.map(|r| (i, r)))
+                .unzip();
+            let mut stats: Vec<S> = rays.iter().map(|_| S::default()).collect();
+            let hits = scene.intersect_packet(&rays, &mut stats);
+            let mut values = vec![background; pixels.len()];
+            for (((i, r), hit), stats) in lanes.into_iter().zip(rays).zip(hits).zip(stats) {
+                values[i] = shader(hit, r, stats, &mut rngs[i]);
+            }
+            for (pixel_samples, value) in samples.iter_mut().zip(values) {
+                pixel_samples.push(value);
+            }
+        }
+        pixels.into_iter()
+            .zip(samples)
+            .map(|(pixel, pixel_samples)| if cfg.spp == 1 {
+                     (pixel, pixel_samples[0])
+                 } else {
+                     (pixel, average(&pixel_samples))
+                 })
+            .collect::<Vec<_>>()
+    };
+    let tiles: Vec<_> = tiles(window, PACKET_TILE, cfg.pixel_order)
+        .par_iter()
+        .map(|tile| profile::span("tile", || render_tile(tile)))
+        .collect();
+    for ((x, y), value) in tiles.into_iter().flat_map(|t| t) {
+        frame.set(x, y, value);
+    }
+}
+
+/// Side length of the square tiles of pixels that share one frustum for `render_frustums`.
+const FRUSTUM_TILE: u32 = 16;
+
+/// The part of `render` for `--traversal frustum`: for each small tile, collect the BVH nodes
+/// that its frustum can see, then trace the primary rays of its pixels one by one, starting
+/// from those nodes instead of the root. Falls back to single ray traversal for cameras
+/// whose rays don't fit in a frustum.
+fn render_frustums<S, T, F, A>(scene: &Scene,
+                               cfg: &Config,
+                               camera: &Camera,
+                               background: T,
+                               shader: &F,
+                               average: &A,
+                               frame: &mut Frame<T>,
+                               window: Rect)
+    where S: StatsRecorder + Default,
+          F: Sync + Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    let render_tile = |tile: &Rect| {
+        let entries = camera.frustum(*tile).map(|frustum| scene.visible_nodes(&frustum));
+        let intersect = |r: &Ray, stats: &mut S| match entries {
+            Some(ref entries) => scene.intersect_from(entries, r, stats),
+            None => scene.intersect_recorded(r, stats),
+        };
+        (tile.y..tile.y + tile.h)
+            .flat_map(|y| (tile.x..tile.x + tile.w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                     let value =
+                         render_pixel(cfg, camera, background, &intersect, shader, average, x, y);
+                     ((x, y), value)
+                 })
+            .collect::<Vec<_>>()
+    };
+    let tiles: Vec<_> = tiles(window, FRUSTUM_TILE, cfg.pixel_order)
+        .par_iter()
+        .map(|tile| profile::span("tile", || render_tile(tile)))
+        .collect();
+    for ((x, y), value) in tiles.into_iter().flat_map(|t| t) {
+        frame.set(x, y, value);
+    }
+}
+
+/// Side length of the square tiles of pixels whose primary rays form one stream for
+/// `render_wavefront`.
+const WAVEFRONT_TILE: u32 = 64;
+
+/// The order in which `render_wavefront` traces a stream of rays: by the octant of the
+/// direction, then by the cell of a 1024^3 grid over `bb` that contains the origin, along the
+/// Z-order curve. Rays with the same key tend to visit the same BVH nodes.
+fn stream_key(r: &Ray, bb: &Aabb) -> u64 {
+    let octant = u64::from(r.d.x < 0.0) | u64::from(r.d.y < 0.0) << 1 |
+                 u64::from(r.d.z < 0.0) << 2;
+    let (min, max) = (bb.min(), bb.max());
+    let mut cell = 0;
+    for axis in 0..3 {
+        let extent = max[axis] - min[axis];
+        let s = if extent > 0.0 { (r.o[axis] - min[axis]) / extent } else { 0.0 };
+        let c = (s.max(0.0).min(1.0) * 1023.0) as u64;
+        // Spread the 10 bits of c out to every third bit.
+        for bit in 0..10 {
+            cell |= ((c >> bit) & 1) << (3 * bit + axis);
+        }
+    }
+    octant << 30 | cell
+}
+
+/// The alternative to the other `render` variants for `--renderer wavefront`: for each tile,
+/// all primary rays for one sample of every pixel are generated in one pass, sorted by
+/// `stream_key` and traced one after another, and only then are the hits shaded.
+/// Each pixel still uses its random numbers in the same order, so the images are identical.
+fn render_wavefront<S, T, F, A>(scene: &Scene,
+                                cfg: &Config,
+                                camera: &Camera,
+                                background: T,
+                                shader: &F,
+                                average: &A,
+                                frame: &mut Frame<T>,
+                                window: Rect)
+    where S: StatsRecorder + Default,
+          F: Sync + Fn(Hit, Ray, S, &mut Rng) -> T,
+          A: Sync + Fn(&[T]) -> T,
+          T: Copy + Send + Sync
+{
+    let render_tile = |tile: &Rect| {
+        let pixels: Vec<(u32, u32)> = (tile.y..tile.y + tile.h)
+            .flat_map(|y| (tile.x..tile.x + tile.w).map(move |x| (x, y)))
+            .collect();
+        let mut rngs: Vec<Rng> = pixels.iter()
+            .map(|&(x, y)| Rng::for_pixel_pass(x, cfg.first_row + y, cfg.pass))
+            .collect();
+        let mut samples = vec![Vec::with_capacity(usize(cfg.spp)); pixels.len()];
+        for _ in 0..cfg.spp {
+            let camera_samples: Vec<_> =
+                rngs.iter_mut().map(|rng| camera_sample(cfg, rng)).collect();
+            let mut stream: Vec<(u64, usize, Ray)> = camera.primary_rays(*tile, &camera_samples)
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, r)| r.map(|r| (stream_key(&r, scene.bbox()), i, r)))
+                .collect();
+            stream.sort_by_key(|&(key, _, _)| key);
+            let hits: Vec<(usize, Ray, Hit, S)> = stream.into_iter()
+                .map(|(_, i, r)| {
+                         let mut stats = S::default();
+                         let hit = scene.intersect_recorded(&r, &mut stats);
+                         (i, r, hit, stats)
+                     })
+                .collect();
+            let mut values = vec![background; pixels.len()];
+            for (i, r, hit, stats) in hits {
+                values[i] = shader(hit, r, stats, &mut rngs[i]);
+            }
+            for (pixel_samples, value) in samples.iter_mut().zip(values) {
+                pixel_samples.push(value);
+            }
+        }
+        pixels.into_iter()
+            .zip(samples)
+            .map(|(pixel, pixel_samples)| if cfg.spp == 1 {
+                     (pixel, pixel_samples[0])
+                 } else {
+                     (pixel, average(&pixel_samples))
+                 })
+            .collect::<Vec<_>>()
+    };
+    let tiles: Vec<_> = tiles(window, WAVEFRONT_TILE, cfg.pixel_order)
+        .par_iter()
+        .map(|tile| profile::span("tile", || render_tile(tile)))
+        .collect();
+    for ((x, y), value) in tiles.into_iter().flat_map(|t| t) {
+        frame.set(x, y, value);
+    }
+}
+
+/// Number of rows traced in parallel before their samples are added to the frame.
+const FILTER_BAND_HEIGHT: u32 = 16;
+
+/// Like `render`, but combine the samples of each pixel with `cfg.filter` instead of averaging
+/// them, which also lets samples contribute to neighboring pixels.
+fn render_filtered<F>(scene: &Scene, cfg: &Config, camera: &Camera, shader: F) -> Frame<Rgb>
+    where F: Sync + Fn(Hit, Ray, &mut Rng) -> Rgb
+{
+    if cfg.spp == 1 {
+        return render(scene, cfg, camera, &Shader::new(Rgb::black(), shader, average_radiance));
+    }
+    let mut sums = Frame::new(cfg.image_width, cfg.image_height, (Rgb::black(), 0.0));
+    // As in `render_recorded`, transparent images leave out the samples that miss.
+    let mut coverage_sums = if cfg.transparent {
+        Some(Frame::new(cfg.image_width, cfg.image_height, (0.0, 0.0)))
+    } else {
+        None
+    };
+    let window = cfg.crop.unwrap_or(sums.bounds());
+    let trace_pixel = |&(x, y): &(u32, u32)| {
+        let mut rng = Rng::for_pixel_pass(x, cfg.first_row + y, cfg.pass);
+        (0..cfg.spp)
+            .map(|_| {
+                let sample = CameraSample::random(&mut rng);
+                let (value, covered) = match camera.primary_ray(x, y, &sample) {
+                    Some(r) => {
+                        let hit = scene.intersect(&r);
+                        let covered = hit.is_valid();
+                        (shader(hit, r, &mut rng), covered)
+                    }
+                    None => (Rgb::black(), false),
+                };
+                (f32(x) + sample.film.0, f32(y) + sample.film.1, value, covered)
+            })
+            .collect::<Vec<_>>()
+    };
+    // Tracing is parallel, but adding samples touches neighboring pixels, so it's sequential.
+    let mut band_start = window.y;
+    while band_start < window.y + window.h {
+        let band_end = (band_start + FILTER_BAND_HEIGHT).min(window.y + window.h);
+        let pixels: Vec<(u32, u32)> = (band_start..band_end)
+            .flat_map(|y| (window.x..window.x + window.w).map(move |x| (x, y)))
+            .collect();
+        let samples: Vec<Vec<_>> = pixels.par_iter().map(&trace_pixel).collect();
+        for (x, y, value, covered) in samples.into_iter().flat_map(|s| s) {
+            match coverage_sums {
+                Some(ref mut coverage_sums) => {
+                    coverage_sums.add_sample(cfg.filter, x, y, if covered { 1.0 } else { 0.0 });
+                    if covered {
+                        sums.add_sample(cfg.filter, x, y, value);
+                    }
+                }
+                None => sums.add_sample(cfg.filter, x, y, value),
+            }
+        }
+        band_start = band_end;
+    }
+    if let Some(coverage_sums) = coverage_sums {
+        scene.add_coverage(coverage_sums.resolve(window, 0.0));
+    }
+    sums.resolve(window, Rgb::black())
+}
+
+fn average_depth(samples: &[f32]) -> f32 {
+    let hits = samples.iter().filter(|&&t| t != f32::INFINITY);
+    let (sum, count) = hits.fold((0.0, 0), |(sum, count), &t| (sum + t, count + 1));
+    if count == 0 { f32::INFINITY } else { sum / f32(count) }
+}
+
+fn average_heat(samples: &[u32]) -> u32 {
+    let sum: u64 = samples.iter().map(|&steps| u64(steps)).sum();
+    u32((sum + u64(samples.len()) / 2) / u64(samples.len())).unwrap()
+}
+
+/// The distance to the first hit in each pixel, or infinity where nothing was hit.
+fn render_depth(scene: &Scene, cfg: &Config, camera: &Camera) -> Frame<f32> {
+    let depth = Shader::new(f32::INFINITY,
+                            |hit, _, _| if hit.is_valid() { hit.t } else { f32::INFINITY },
+                            average_depth);
+    render(scene, cfg, camera, &depth)
+}
+
+fn render_depthmap(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    Box::new(Depthmap {
+                 frame: render_depth(scene, cfg, camera),
+                 near: cfg.depth_near,
+                 far: cfg.depth_far,
+                 isoline_interval: cfg.depth_isolines,
+                 background: cfg.background,
+             })
+}
+
+fn render_heatmap(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    Box::new(Heatmap(render_heat(scene, cfg, camera)))
+}
+
+/// The value of `cfg.heat_counter` for each pixel, also printing its average.
+fn render_heat(scene: &Scene, cfg: &Config, camera: &Camera) -> Frame<u32> {
+    let frame = render_recorded(scene,
+                                cfg,
+                                camera,
+                                0,
+                                |_, _, stats: TraversalStats, _| stats.get(cfg.heat_counter),
+                                average_heat);
+    let window = cfg.crop.unwrap_or(frame.bounds());
+    let mut total = 0;
+    frame.for_each_pixel(|x, y, count| if window.contains(x, y) {
+                             total += u64(count);
+                         });
+    let counter = match cfg.heat_counter {
+        HeatCounter::Boxes => "boxes tested",
+        HeatCounter::Nodes => "nodes visited",
+        HeatCounter::Leaves => "leaves visited",
+        HeatCounter::Tris => "triangles tested",
+    };
+    println!("{:.2} {} per pixel on average",
+             f64(total) / (f64(window.w) * f64(window.h)),
+             counter);
     frame
 }
 
-fn render_depthmap(scene: &Scene, cfg: &Config) -> Box<film::ToBmp> {
-    let frame = render(scene,
-                       cfg,
-                       f32::INFINITY,
-                       |hit, _| if hit.is_valid() { hit.t } else { f32::INFINITY });
-    Box::new(Depthmap(frame))
+/// The unit normal of the first hit in each pixel, facing the camera, or the zero vector where
+/// nothing was hit.
+fn render_normals(scene: &Scene, cfg: &Config, camera: &Camera) -> Frame<Vector3<f32>> {
+    let background = vec3(0.0, 0.0, 0.0);
+    let normals = Shader::new(background,
+                              |hit, r, _| if hit.is_valid() {
+                                  let n = scene.normal(&r, &hit);
+                                  // Show the side facing the camera
+                                  if n.dot(r.d) > 0.0 { -n } else { n }
+                              } else {
+                                  background
+                              },
+                              |samples| {
+                                  let sum = samples.iter().fold(background, |acc, &n| acc + n);
+                                  if sum == background { sum } else { sum.normalize() }
+                              });
+    render(scene, cfg, camera, &normals)
+}
+
+fn render_normalmap(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    Box::new(Normalmap(render_normals(scene, cfg, camera)))
+}
+
+fn average_radiance(samples: &[Rgb]) -> Rgb {
+    let sum = samples.iter().fold(Rgb::black(), |acc, &c| acc + c);
+    sum / f32(samples.len())
+}
+
+/// What the camera sees along the primary ray `r` through the scene's volume, if it has one,
+/// when `radiance` arrives from the surface at distance `t` (infinity for the background).
+fn through_volume(scene: &Scene, r: &Ray, t: f32, radiance: Rgb) -> Rgb {
+    match scene.volume {
+        Some(ref volume) => volume.composite(r, t, radiance),
+        None => radiance,
+    }
+}
+
+fn render_shaded(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let mut lights = scene.lights.clone();
+    if lights.is_empty() {
+        // Light the scene from the camera, so that everything visible is lit.
+        lights.push(Light::Point {
+                        pos: camera.eye(),
+                        irradiance: Rgb::grey(1.0),
+                    });
+    }
+    let miss = MissShader::new(cfg);
+    let frame = render_filtered(scene, cfg, camera, |hit, r, rng| {
+        let t = if hit.is_valid() { hit.t } else { f32::INFINITY };
+        let radiance = integrator::shade(scene, cfg, &lights, miss, hit, r, rng);
+        through_volume(scene, &r, t, radiance)
+    });
+    Box::new(Radiance {
+                 frame,
+                 tonemap: cfg.tonemap,
+                 exposure: cfg.exposure,
+             })
 }
 
-fn render_heatmap(scene: &Scene, cfg: &Config) -> Box<film::ToBmp> {
-    let frame = render(scene, cfg, 0, |_, r| r.traversal_steps.get());
+fn render_path_traced(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let stats = integrator::SampleStats::new();
+    let miss = MissShader::new(cfg);
+    let mut frame = render_filtered(scene, cfg, camera, |hit, r, rng| {
+        let t = if hit.is_valid() { hit.t } else { f32::INFINITY };
+        let (radiance, path_length) = integrator::path_trace(scene, cfg, miss, hit, r, rng);
+        stats.record_path_length(path_length);
+        through_volume(scene, &r, t, stats.record(radiance, cfg.clamp))
+    });
+    stats.print();
+    if cfg.denoise {
+        // The guides aren't part of the image, so their coverage mustn't be recorded.
+        let guide_cfg = Config { transparent: false, ..cfg.clone() };
+        let (normals, depth) = print_timing("rendering denoiser guides", || {
+            (render_normals(scene, &guide_cfg, camera), render_depth(scene, &guide_cfg, camera))
+        });
+        let window = cfg.crop.unwrap_or(frame.bounds());
+        frame = print_timing("denoising",
+                             || denoise::denoise(&frame, &normals, &depth, window));
+    }
+    Box::new(Radiance {
+                 frame,
+                 tonemap: cfg.tonemap,
+                 exposure: cfg.exposure,
+             })
+}
+
+/// Show the texture coordinates (wrapped into [0, 1]) as red and green.
+fn render_uv(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let background = cfg.background.unwrap_or(Rgb::black());
+    let uv = Shader::new(background,
+                         |hit, _, _| if hit.is_valid() {
+                             let uv = scene.uv(&hit);
+                             Rgb::new(uv.x - uv.x.floor(), uv.y - uv.y.floor(), 0.0)
+                         } else {
+                             background
+                         },
+                         average_radiance);
+    let frame = render(scene, cfg, camera, &uv);
+    Box::new(Colors(frame))
+}
+
+/// Show the vertex colors of the input (e.g. what a scanner captured) without any lighting.
+fn render_vertex_colors(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let background = cfg.background.unwrap_or(Rgb::black());
+    let colors = Shader::new(background,
+                             |hit, _, _| if hit.is_valid() {
+                                 scene.vertex_color(&hit)
+                             } else {
+                                 background
+                             },
+                             average_radiance);
+    let frame = render(scene, cfg, camera, &colors);
+    Box::new(Colors(frame))
+}
+
+/// Mark the pixels where any primary ray hits something, e.g. to measure the projected area
+/// of the model.
+fn render_mask(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let mask = Shader::new(false,
+                           |hit, _, _| hit.is_valid(),
+                           |samples| samples.iter().any(|&hit| hit));
+    let frame = render(scene, cfg, camera, &mask);
+    Box::new(Mask(frame))
+}
+
+/// The ID that most samples of a pixel have, the smallest one if there's a tie, so that pixels
+/// on the edge of an object don't get an unrelated ID.
+fn majority_id(samples: &[u32]) -> u32 {
+    let mut ids = samples.to_vec();
+    ids.sort();
+    let (mut best, mut best_count) = (0, 0);
+    let mut i = 0;
+    while i < ids.len() {
+        let count = ids[i..].iter().take_while(|&&id| id == ids[i]).count();
+        if count > best_count {
+            best = ids[i];
+            best_count = count;
+        }
+        i += count;
+    }
+    best
+}
+
+/// Label each pixel with an integer identifying the object or material in it, for
+/// segmentation. The legend lists what each integer stands for.
+fn render_ids(scene: &Scene, cfg: &Config, camera: &Camera, kind: IdKind) -> Box<film::ToBmp> {
+    let ids = Shader::new(0,
+                          |hit, _, _| if hit.is_valid() { scene.id(&hit, kind) } else { 0 },
+                          majority_id);
+    let frame = render(scene, cfg, camera, &ids);
+    Box::new(IdMap {
+                 frame,
+                 names: scene.id_names(kind),
+             })
+}
+
+fn render_object_ids(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    render_ids(scene, cfg, camera, IdKind::Object)
+}
+
+fn render_material_ids(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    render_ids(scene, cfg, camera, IdKind::Material)
+}
+
+/// Surfaces beyond this many along a ray aren't counted by `render_layers`.
+const MAX_LAYERS: usize = 255;
+
+/// Count how many surfaces each primary ray passes through, e.g. to spot stacked transparent
+/// surfaces or (on closed meshes) how often the ray enters and leaves the object.
+fn render_layers(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let layers = Shader::new(0,
+                             |_, r, _| u32(scene.intersect_all(&r, MAX_LAYERS).len()).unwrap(),
+                             average_heat);
+    let frame = render(scene, cfg, camera, &layers);
     Box::new(Heatmap(frame))
 }
 
+/// The wall thickness at the first hit in each pixel: how far a ray travels from there along
+/// the inward normal before it leaves through the other side. Infinity where nothing was hit
+/// or the ray escapes through a hole. Shown like a depth map, so thin walls come out white.
+fn render_thickness(scene: &Scene, cfg: &Config, camera: &Camera) -> Box<film::ToBmp> {
+    let thickness = |hit: Hit, r: Ray, _: &mut Rng| {
+        if !hit.is_valid() {
+            return f32::INFINITY;
+        }
+        let n = scene.normal(&r, &hit);
+        let inward = if n.dot(r.d) > 0.0 { n } else { -n };
+        let origin = integrator::offset_origin(r.o + r.d * hit.t, inward, cfg.ray_offset);
+        let inner = integrator::secondary_ray(cfg, origin, inward);
+        // The exit is the first hit past the entry. Like bounces in `integrator::path_trace`,
+        // flat surfaces can't be hit again, so a second hit on the entry triangle is skipped.
+        let (entry, flat) = (hit.tri_id, scene.is_flat(hit.tri_id));
+        scene.intersect_all(&inner, 2)
+            .into_iter()
+            .find(|exit| !flat || exit.tri_id != entry)
+            .map_or(f32::INFINITY, |exit| exit.t)
+    };
+    let frame = render(scene, cfg, camera, &Shader::new(f32::INFINITY, thickness, average_depth));
+    Box::new(Depthmap {
+                 frame,
+                 near: cfg.depth_near,
+                 far: cfg.depth_far,
+                 isoline_interval: cfg.depth_isolines,
+                 background: cfg.background,
+             })
+}
+
+fn renderer(kind: RenderKind) -> fn(&Scene, &Config, &Camera) -> Box<film::ToBmp> {
+    match kind {
+        RenderKind::Depthmap => render_depthmap,
+        RenderKind::Heatmap => render_heatmap,
+        RenderKind::Normals => render_normalmap,
+        RenderKind::Shaded => render_shaded,
+        RenderKind::PathTraced => render_path_traced,
+        RenderKind::Uv => render_uv,
+        RenderKind::VertexColor => render_vertex_colors,
+        RenderKind::Layers => render_layers,
+        RenderKind::Mask => render_mask,
+        RenderKind::ObjectId => render_object_ids,
+        RenderKind::MaterialId => render_material_ids,
+        RenderKind::Thickness => render_thickness,
+    }
+}
+
+fn debug_pixel(scene: &Scene, camera: &Camera, x: u32, y: u32) {
+    let r = match camera.primary_ray(x, y, &CameraSample::center()) {
+        Some(r) => r,
+        None => {
+            println!("pixel ({}, {}) is not covered by the camera projection", x, y);
+            return;
+        }
+    };
+    println!("tracing pixel ({}, {}): origin {:?}, direction {:?}", x, y, r.o, r.d);
+    let mut stats = TraversalStats::default();
+    let hit = scene.intersect_verbose(&r, &mut stats);
+    if hit.is_valid() {
+        println!("hit tri {} at t = {}, (u, v, w) = ({}, {}, {})",
+                 hit.tri_id,
+                 hit.t,
+                 hit.u,
+                 hit.v,
+                 hit.w);
+    } else {
+        println!("no hit");
+    }
+    if let Some((p, tri_id, dist)) = scene.closest_point(r.o) {
+        println!("closest point to the ray origin: {:?} on tri {}, at distance {}",
+                 p,
+                 tri_id,
+                 dist);
+    }
+    println!("{} boxes tested, {} nodes visited, {} leaves visited, {} triangles tested",
+             stats.boxes_tested,
+             stats.nodes_visited,
+             stats.leaves_visited,
+             stats.tris_tested);
+}
+
 fn main() {
-    let cfg = cli::parse_matches(cli::build_app().get_matches());
+    let cfg = cli::parse_args();
     if let Some(num_threads) = cfg.num_threads {
         let rayon_cfg = rayon::Configuration::new().num_threads(usize(num_threads));
         rayon::initialize(rayon_cfg).unwrap();
     }
+    if cfg.trace_out.is_some() {
+        profile::enable();
+    }
+    run(&cfg);
+    if let Some(ref path) = cfg.trace_out {
+        profile::write_chrome_trace(path)
+            .unwrap_or_else(|e| fail(&format!("could not write {}: {}", path.display(), e)));
+        println!("wrote trace to {}", path.display());
+    }
+}
 
-    let scene = Scene::new(&cfg);
-    let render: fn(_, _) -> _ = match cfg.render_kind {
-        RenderKind::Depthmap => render_depthmap,
-        RenderKind::Heatmap => render_heatmap,
+/// Do whatever the configuration asks for.
+fn run(cfg: &Config) {
+    if let Some(ref test) = cfg.selftest {
+        selftest::run(test);
+        return;
+    }
+    if let Some(chunks) = cfg.chunks {
+        chunked::render(cfg, chunks);
+        return;
+    }
+    let mut scene = Scene::new(cfg);
+    if cfg.check {
+        check::run(&scene);
+        return;
+    }
+    if let Some(ref bake) = cfg.bake {
+        bake::run(&scene, cfg, bake);
+        return;
+    }
+    if cfg.info {
+        scene.print_info(cfg);
+        report_stats(&scene, cfg);
+        return;
+    }
+    if cfg.bench {
+        bench(&mut scene, cfg);
+        return;
+    }
+    if cfg.validate {
+        validate(&mut scene, cfg);
+        return;
+    }
+    if cfg.check_determinism {
+        check_determinism(&scene, cfg);
+        return;
+    }
+    if let Some(ref diff) = cfg.heat_diff {
+        heat_diff(&mut scene, cfg, diff);
+        return;
+    }
+    if let Some(ref sweep) = cfg.sweep {
+        run_sweep(&mut scene, cfg, sweep);
+        return;
+    }
+    if let Some((x, y)) = cfg.debug_pixel {
+        debug_pixel(&scene, &Camera::new(cfg, scene.bbox()), x, y);
+        return;
+    }
+    if cfg.interactive {
+        interactive::run(&scene, cfg);
+        return;
+    }
+    if let Some(rows) = cfg.stream {
+        render_streamed(&scene, cfg, rows);
+        report_stats(&scene, cfg);
+        return;
+    }
+    let preview = cfg.serve.map(|port| {
+        serve::start(cfg.serve_address, port)
+            .unwrap_or_else(|e| fail(&format!("could not serve on port {}: {}", port, e)))
+    });
+    render_shots(&mut scene, cfg, preview.as_ref()).unwrap_or_else(|e| fail(&e));
+    report_stats(&scene, cfg);
+    if cfg.watch {
+        let mut watched = cfg.input_files.clone();
+        watched.extend(cfg.camera_path.iter().cloned());
+        watched.extend(cfg.envmap.iter().cloned());
+        watched.extend(cfg.volume.iter().cloned());
+        watched.extend(scene.material_files().iter().cloned());
+        watch::on_change(&watched, || {
+            print_timing("reloading and rendering", || {
+                // The files may only be partly written, so errors only skip this change.
+                let result = Scene::load(cfg)
+                    .and_then(|mut scene| render_shots(&mut scene, cfg, preview.as_ref()));
+                if let Err(e) = result {
+                    eprintln!("error: {}", e);
+                }
+            });
+        });
+    }
+    if let Some(preview) = preview {
+        preview.wait();
+    }
+}
+
+/// Render and save all images the configuration asks for, showing them on `preview` as well.
+/// Only fails if the camera path can't be read.
+fn render_shots(scene: &mut Scene,
+                cfg: &Config,
+                preview: Option<&serve::Preview>)
+                -> Result<(), String> {
+    let render = renderer(cfg.render_kind);
+    let mut shots = plan_shots(cfg, scene)?;
+    // Separate stereo images make two shots of each frame, left eye first.
+    let mut shots_per_frame = 1;
+    if let Some(Stereo { separation, layout: StereoLayout::Separate }) = cfg.stereo {
+        shots_per_frame = 2;
+        shots = shots.into_iter()
+            .flat_map(|(camera, file)| {
+                let (left, right) = stereo_pair(&camera, separation);
+                vec![(left, suffixed_file_name(&file, "left")),
+                     (right, suffixed_file_name(&file, "right"))]
+            })
+            .collect();
+    }
+    // Spinning always starts from the original geometry, to avoid accumulating errors.
+    let rest_pose = if cfg.spin.is_some() { scene.mesh.vertices.clone() } else { Vec::new() };
+    let rest_center = (scene.bbox().min() + scene.bbox().max()) / 2.0;
+    let multiple_frames = shots.len() > 1;
+    // The frames of an animation only differ in where the camera is and which way it looks,
+    // so the directions of their rays relative to the camera only need to be computed once.
+    let ray_grid = if multiple_frames {
+        print_timing("precomputing ray directions", || shots[0].0.ray_grid()).map(Arc::new)
+    } else {
+        None
     };
-    let (frame, t) = measure_and_print_time("rendering", || render(&scene, &cfg));
-    let output_file = cfg.output_file.display().to_string();
-    print_timing("creating BMP",
-                 move || frame.to_bmp().save(&output_file).unwrap());
-    let rays_tested = scene.rays_tested();
-    let seconds = f64(t.as_secs()) + f64(t.subsec_nanos()) / 1e9;
+    let mut t = Duration::new(0, 0);
+    for (i, (camera, output_file)) in shots.into_iter().enumerate() {
+        let camera = match ray_grid {
+            Some(ref grid) => camera.with_ray_grid(grid),
+            None => camera,
+        };
+        let first_eye = i % shots_per_frame == 0;
+        if let Some(n) = cfg.spin {
+            if first_eye {
+                let angle = 2.0 * PI * f32(i / shots_per_frame) / f32(n);
+                scene.spin(&rest_pose, rest_center, angle);
+                print_timing("refitting BVH", || scene.refit());
+                let (rebuilt, _, _) = bvh::construct(&scene.mesh, cfg);
+                println!("SAH cost: {:.2} refitted vs. {:.2} rebuilt",
+                         scene.sah_cost(cfg),
+                         rebuilt.sah_cost(cfg.sah_traversal_cost));
+            }
+        }
+        // The preview shows the left eye of stereo images instead of flickering between both.
+        let preview = if first_eye { preview } else { None };
+        let rays_before = scene.rays_tested();
+        let desc = format!("rendering {}", output_file.display());
+        let (mut frame, frame_t) = measure_and_print_time(&desc, || match cfg.stereo {
+            Some(Stereo { separation, layout: StereoLayout::SideBySide }) => {
+                let (left, right) = stereo_pair(&camera, separation);
+                Box::new(SideBySide(render_passes(render, scene, cfg, &left, preview),
+                                    render_passes(render, scene, cfg, &right, None)))
+            }
+            _ => render_passes(render, scene, cfg, &camera, preview),
+        });
+        if let Some(depth) = cfg.overlay_bvh {
+            frame = overlay_bvh(scene, &camera, depth, frame);
+        }
+        t += frame_t;
+        if multiple_frames {
+            print_ray_stats(scene.rays_tested() - rays_before, frame_t);
+        }
+        if cfg.transparent {
+            let coverage = scene.take_coverage().expect("BUG: no coverage recorded");
+            print_timing("saving image", || {
+                film::save_rgba(&*frame, &coverage, &output_file).unwrap_or_else(|e| fail(&e))
+            });
+        } else {
+            print_timing("saving image",
+                         || film::save(&*frame, &output_file).unwrap_or_else(|e| fail(&e)));
+        }
+        if let (Some(format), Some(counts)) = (cfg.counts_format, frame.counts()) {
+            let counts_file = output_file.with_extension(format.extension());
+            print_timing(&format!("saving counts to {}", counts_file.display()),
+                         || film::save_counts(counts, format, &counts_file))
+                    .unwrap_or_else(|e| fail(&e));
+        }
+        if let Some(legend) = frame.legend() {
+            let legend_file = output_file.with_extension("json");
+            print_timing(&format!("saving legend to {}", legend_file.display()),
+                         || fs::write(&legend_file, legend))
+                    .unwrap_or_else(|e| {
+                                        fail(&format!("could not write {}: {}",
+                                                      legend_file.display(),
+                                                      e))
+                                    });
+        }
+    }
+    print_ray_stats(scene.rays_tested(), t);
+    Ok(())
+}
+
+/// Render the image `band_rows` rows at a time and append each band to the PNG file before
+/// rendering the next, so that only one band is held in memory however large the image is.
+fn render_streamed(scene: &Scene, cfg: &Config, band_rows: u32) {
+    let path = &cfg.output_file;
+    let desc = format!("rendering and writing {}", path.display());
+    let (result, t) = measure_and_print_time(&desc, || {
+        let file = BufWriter::new(File::create(path)?);
+        let mut png = png_stream::Writer::new(file, cfg.image_width, cfg.image_height)?;
+        let render = renderer(cfg.render_kind);
+        let camera = Camera::new(cfg, scene.bbox());
+        let mut first_row = 0;
+        while first_row < cfg.image_height {
+            let rows = band_rows.min(cfg.image_height - first_row);
+            // With several samples per pixel, samples also count for the rows next to theirs.
+            // So the band is rendered with the rows its filter reaches on either side, and
+            // only its own rows are written, lest there be a seam between bands.
+            let margin = if cfg.spp > 1 { u32(cfg.filter.radius().ceil()).unwrap() } else { 0 };
+            let top = first_row.saturating_sub(margin);
+            let bottom = (first_row + rows + margin).min(cfg.image_height);
+            let band_cfg = Config {
+                image_height: bottom - top,
+                first_row: top,
+                ..cfg.clone()
+            };
+            let band = render_passes(render, scene, &band_cfg, &camera.rows_from(top), None);
+            let image = band.to_bmp();
+            let mut rgb = Vec::with_capacity(3 * usize(cfg.image_width) * usize(rows));
+            for y in first_row - top..first_row - top + rows {
+                for x in 0..cfg.image_width {
+                    let p = image.get_pixel(x, y);
+                    rgb.extend_from_slice(&[p.r, p.g, p.b]);
+                }
+            }
+            png.write_rows(&rgb)?;
+            first_row += rows;
+        }
+        png.finish().map(|_| ())
+    });
+    result.unwrap_or_else(|e| fail(&format!("could not write {}: {}", path.display(), e)));
+    print_ray_stats(scene.rays_tested(), t);
+}
+
+/// Render `cfg.passes` times with different samples and average the images.
+/// The average so far is published to `preview` after every pass.
+fn render_passes(render: fn(&Scene, &Config, &Camera) -> Box<film::ToBmp>,
+                 scene: &Scene,
+                 cfg: &Config,
+                 camera: &Camera,
+                 preview: Option<&serve::Preview>)
+                 -> Box<film::ToBmp> {
+    if cfg.passes == 1 {
+        let image = render(scene, cfg, camera);
+        if let Some(preview) = preview {
+            preview.publish(&*image);
+        }
+        return image;
+    }
+    let mut accumulator = Accumulator::new(cfg.image_width, cfg.image_height);
+    for pass in 0..cfg.passes {
+        accumulator.add(&*render(scene, &Config { pass, ..cfg.clone() }, camera));
+        if let Some(preview) = preview {
+            preview.publish(&*accumulator.average());
+        }
+    }
+    accumulator.average()
+}
+
+/// Draw the edges of the BVH nodes at most `depth` levels below the root on top of `image`, to
+/// see how the tree partitions the scene.
+fn overlay_bvh(scene: &Scene,
+               camera: &Camera,
+               depth: u32,
+               image: Box<film::ToBmp>)
+               -> Box<film::ToBmp> {
+    let mut lines = Vec::new();
+    for bb in scene.bvh_boxes(usize(depth)) {
+        let (lo, hi) = (bb.min(), bb.max());
+        // Bit i of the corner index selects the minimum or maximum along axis i.
+        let corner = |i: usize| {
+            vec3(if i & 1 == 0 { lo.x } else { hi.x },
+                 if i & 2 == 0 { lo.y } else { hi.y },
+                 if i & 4 == 0 { lo.z } else { hi.z })
+        };
+        for i in 0..8 {
+            for bit in &[1, 2, 4] {
+                if i & bit == 0 {
+                    lines.extend(camera.project_segment(corner(i), corner(i | bit)));
+                }
+            }
+        }
+    }
+    Box::new(film::Overlay { image, lines })
+}
+
+/// Decide which camera(s) to render the scene with and where to write the images.
+fn plan_shots(cfg: &Config, scene: &Scene) -> Result<Vec<(Camera, PathBuf)>, String> {
+    let shots = if let Some(ref path) = cfg.camera_path {
+        let keyframes = camera::read_camera_path(path)?;
+        let start = keyframes[0].time;
+        let duration = keyframes[keyframes.len() - 1].time - start;
+        let frames = u32((duration * cfg.fps).floor()).unwrap() + 1;
+        (0..frames)
+            .map(|i| {
+                     let key = camera::interpolate(&keyframes, start + f32(i) / cfg.fps);
+                     (key.camera(cfg), numbered_file_name(&cfg.output_file, i))
+                 })
+            .collect()
+    } else if let Some(n) = cfg.turntable {
+        (0..n)
+            .map(|i| {
+                     let angle = 2.0 * PI * f32(i) / f32(n);
+                     let camera = Camera::orbit(cfg, scene.bbox(), angle);
+                     (camera, numbered_file_name(&cfg.output_file, i))
+                 })
+            .collect()
+    } else if let Some(n) = cfg.spin {
+        (0..n)
+            .map(|i| (Camera::new(cfg, scene.bbox()), numbered_file_name(&cfg.output_file, i)))
+            .collect()
+    } else {
+        vec![(Camera::new(cfg, scene.bbox()), cfg.output_file.clone())]
+    };
+    Ok(shots)
+}
+
+/// Render the first shot with each triangle intersection algorithm and compare their speed.
+/// Nothing is saved, the images are only rendered for timing.
+fn bench(scene: &mut Scene, cfg: &Config) {
+    let render = renderer(cfg.render_kind);
+    let (camera, _) = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e)).swap_remove(0);
+    let mut mrays_per_sec = Vec::new();
+    for &isect in &[TriIsect::Watertight, TriIsect::Woop] {
+        scene.set_tri_isect(isect);
+        let rays_before = scene.rays_tested();
+        let desc = format!("rendering with {:?} traversal and {:?} intersection",
+                           cfg.traversal,
+                           isect);
+        let (_, t) = measure_and_print_time(&desc, || render(scene, cfg, &camera));
+        let rays = scene.rays_tested() - rays_before;
+        print_ray_stats(rays, t);
+        mrays_per_sec.push(f64(rays) / 1e6 / seconds(t));
+    }
+    println!("Woop vs. watertight: {:+.1}% Mray/s",
+             (mrays_per_sec[1] / mrays_per_sec[0] - 1.0) * 100.0);
+    if cfg.traversal == Traversal::Stackless {
+        compare_stackless(scene, cfg, &camera);
+    }
+}
+
+/// Trace the primary rays through the pixel centers of `camera` with and without a traversal
+/// stack, and print how the number of traversal steps and the speed differ.
+fn compare_stackless(scene: &Scene, cfg: &Config, camera: &Camera) {
+    let window = cfg.crop.unwrap_or(Rect {
+                                        x: 0,
+                                        y: 0,
+                                        w: cfg.image_width,
+                                        h: cfg.image_height,
+                                    });
+    let rays: Vec<Ray> = (window.y..window.y + window.h)
+        .flat_map(|y| (window.x..window.x + window.w).map(move |x| (x, y)))
+        .filter_map(|(x, y)| camera.primary_ray(x, y, &CameraSample::center()))
+        .collect();
+    if rays.is_empty() {
+        return;
+    }
+    let trace = |stackless: bool| {
+        rays.par_iter()
+            .map(|r| {
+                     let mut stats = TraversalStats::default();
+                     let hit = if stackless {
+                         scene.intersect_stackless(r, &mut stats)
+                     } else {
+                         scene.intersect_recorded(r, &mut stats)
+                     };
+                     (hit.tri_id, stats)
+                 })
+            .collect::<Vec<_>>()
+    };
+    let mut results = Vec::new();
+    for &stackless in &[false, true] {
+        let desc = if stackless { "stackless traversal" } else { "stack-based traversal" };
+        let (traced, t) = measure_and_print_time(&format!("tracing with {}", desc),
+                                                 || trace(stackless));
+        // Every node taken off the stack or reached by climbing up counts as a step.
+        let per_ray = |count: &Fn(&TraversalStats) -> u32| {
+            let sum: u64 = traced.iter().map(|&(_, ref s)| u64(count(s))).sum();
+            f64(sum) / f64(u64(traced.len()))
+        };
+        let backtrack_steps = per_ray(&|s| s.backtrack_steps);
+        let steps_per_ray = per_ray(&|s| s.boxes_tested) + backtrack_steps;
+        println!("{}: {:.2} steps per ray ({:.2} back up), {:.2} Mray/s",
+                 desc,
+                 steps_per_ray,
+                 backtrack_steps,
+                 f64(u64(rays.len())) / 1e6 / seconds(t));
+        results.push((traced, steps_per_ray, seconds(t)));
+    }
+    let mismatches = results[0].0
+        .iter()
+        .zip(&results[1].0)
+        .filter(|&(a, b)| a.0 != b.0)
+        .count();
+    println!("stackless vs. stack-based: {:+.1}% steps, {:+.1}% time, {} of {} rays hit a \
+              different triangle",
+             (results[1].1 / results[0].1 - 1.0) * 100.0,
+             (results[1].2 / results[0].2 - 1.0) * 100.0,
+             mismatches,
+             rays.len());
+}
+
+/// Rebuild the BVH for every combination of the sweep's SAH parameters, render the first shot
+/// with each, and write build time, SAH cost and Mray/s to a CSV file.
+fn run_sweep(scene: &mut Scene, cfg: &Config, sweep: &Sweep) {
+    let render = renderer(cfg.render_kind);
+    let (camera, _) = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e)).swap_remove(0);
+    let mut csv = String::from("buckets,sah_tcost,build_seconds,sah_cost,mrays_per_second\n");
+    for &buckets in &sweep.buckets {
+        for &traversal_cost in &sweep.traversal_costs {
+            let cfg = Config {
+                sah_buckets: buckets,
+                sah_traversal_cost: traversal_cost,
+                ..cfg.clone()
+            };
+            let desc = format!("rebuilding BVH with {} buckets and traversal cost {}",
+                               buckets,
+                               traversal_cost);
+            let (_, build_t) = measure_and_print_time(&desc, || scene.rebuild_bvh(&cfg));
+            let rays_before = scene.rays_tested();
+            let (_, t) = measure_and_print_time("rendering", || render(scene, &cfg, &camera));
+            let rays = scene.rays_tested() - rays_before;
+            print_ray_stats(rays, t);
+            csv.push_str(&format!("{},{},{},{},{}\n",
+                                  buckets,
+                                  traversal_cost,
+                                  seconds(build_t),
+                                  scene.sah_cost(&cfg),
+                                  f64(rays) / 1e6 / seconds(t)));
+        }
+    }
+    let mut file = File::create(&sweep.csv_file)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", sweep.csv_file.display(), e)));
+    file.write_all(csv.as_bytes()).unwrap_or_else(|e| fail(&e.to_string()));
+    println!("wrote {}", sweep.csv_file.display());
+}
+
+/// Render the heatmap of the first shot with the BVH built as configured and again with the
+/// parameters of the `heat-diff` subcommand, and save the difference.
+fn heat_diff(scene: &mut Scene, cfg: &Config, diff: &HeatDiff) {
+    let (camera, _) = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e)).swap_remove(0);
+    let before = print_timing("rendering heatmap", || render_heat(scene, cfg, &camera));
+    let other = diff.apply(cfg);
+    print_timing("rebuilding BVH", || scene.rebuild_bvh(&other));
+    let after = print_timing("rendering heatmap again", || render_heat(scene, &other, &camera));
+    let mut difference = Frame::new(cfg.image_width, cfg.image_height, 0);
+    difference.set_pixels(difference.bounds(),
+                          |x, y| i64(after.get(x, y)) - i64(before.get(x, y)));
+    let (mut fewer, mut more) = (0, 0);
+    difference.for_each_pixel(|_, _, d| if d < 0 {
+                                  fewer += 1;
+                              } else if d > 0 {
+                                  more += 1;
+                              });
+    println!("{} pixels need fewer steps, {} need more", fewer, more);
+    print_timing("saving image", || {
+        film::save(&HeatDifference(difference), &cfg.output_file).unwrap_or_else(|e| fail(&e))
+    });
+}
+
+/// Check that the primary rays of the first shot hit the same triangles at the same distance
+/// (and are occluded the same way) with the compressed BVH layout as with the full one, and
+/// exit with an error if not.
+fn validate(scene: &mut Scene, cfg: &Config) {
+    let (camera, _) = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e)).swap_remove(0);
+    let window = cfg.crop.unwrap_or(Rect {
+                                        x: 0,
+                                        y: 0,
+                                        w: cfg.image_width,
+                                        h: cfg.image_height,
+                                    });
+    let (pixels, rays): (Vec<(u32, u32)>, Vec<Ray>) = (window.y..window.y + window.h)
+        .flat_map(|y| (window.x..window.x + window.w).map(move |x| (x, y)))
+        .filter_map(|(x, y)| {
+                        camera.primary_ray(x, y, &CameraSample::center()).map(|r| ((x, y), r))
+                    })
+        .unzip();
+    let t_max = vec![f32::INFINITY; rays.len()];
+    let trace_all = |scene: &Scene| -> Vec<(Index, u32, bool)> {
+        let hits = scene.intersect_batch(&rays);
+        let occluded = scene.occluded_batch(&rays, &t_max);
+        hits.iter()
+            .zip(occluded)
+            .map(|(hit, occluded)| (hit.tri_id, hit.t.to_bits(), occluded))
+            .collect()
+    };
+    let layouts = [BvhLayout::Full, BvhLayout::Compressed];
+    let hits: Vec<_> = layouts.iter()
+        .map(|&layout| {
+                 scene.set_bvh_layout(layout);
+                 print_timing(&format!("tracing with {:?} BVH layout", layout),
+                              || trace_all(scene))
+             })
+        .collect();
+    let mismatches: Vec<_> = pixels.iter()
+        .zip(hits[0].iter().zip(&hits[1]))
+        .filter(|&(_, (full, compressed))| full != compressed)
+        .map(|(pixel, _)| pixel)
+        .collect();
+    if mismatches.is_empty() {
+        println!("all {} primary rays found the same hits with both BVH layouts",
+                 pixels.len());
+    } else {
+        for &&(x, y) in mismatches.iter().take(10) {
+            println!("pixel ({}, {}) differs, see --debug-pixel {},{}", x, y, x, y);
+        }
+        println!("{} of {} primary rays found different hits with the compressed BVH layout",
+                 mismatches.len(),
+                 pixels.len());
+        std::process::exit(1);
+    }
+}
+
+/// Render the first shot on a single thread and then on all threads, and exit with an error
+/// unless both images are identical down to the last bit. Anything that depends on how the
+/// work is split between threads (e.g. the order of floating point additions or which random
+/// numbers a pixel gets) shows up as a difference.
+fn check_determinism(scene: &Scene, cfg: &Config) {
+    let render = renderer(cfg.render_kind);
+    let (camera, _) = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e)).swap_remove(0);
+    let single_thread = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(1))
+        .unwrap_or_else(|e| fail(&format!("could not start a thread pool: {}", e)));
+    let first = print_timing("rendering on one thread", || {
+        single_thread.install(|| render(scene, cfg, &camera).exact_bits())
+    });
+    let second = print_timing("rendering on all threads",
+                              || render(scene, cfg, &camera).exact_bits());
+    let bounds = first.bounds();
+    let mismatches: Vec<(u32, u32)> = (bounds.y..bounds.y + bounds.h)
+        .flat_map(|y| (bounds.x..bounds.x + bounds.w).map(move |x| (x, y)))
+        .filter(|&(x, y)| first.get(x, y) != second.get(x, y))
+        .collect();
+    if mismatches.is_empty() {
+        println!("both renders are bit-identical");
+    } else {
+        for &(x, y) in mismatches.iter().take(10) {
+            println!("pixel ({}, {}) differs, see --debug-pixel {},{}", x, y, x, y);
+        }
+        println!("{} of {} pixels differ between the renders",
+                 mismatches.len(),
+                 bounds.w * bounds.h);
+        std::process::exit(1);
+    }
+}
+
+/// Report the memory usage, and write it to `cfg.stats_out`, if given, along with the
+/// coherence of the primary rays.
+fn report_stats(scene: &Scene, cfg: &Config) {
+    let usage = report_memory_usage(scene);
+    if let Some(ref path) = cfg.stats_out {
+        let mut memory = toml::value::Table::new();
+        for (name, bytes) in usage {
+            memory.insert(name.to_string(),
+                          toml::Value::Integer(i64(bytes).unwrap_or(i64::max_value())));
+        }
+        let mut stats = toml::value::Table::new();
+        stats.insert("memory".to_string(), toml::Value::Table(memory));
+        if let Some(coherence) = coherence_stats(scene, cfg) {
+            stats.insert("coherence".to_string(), toml::Value::Table(coherence));
+        }
+        File::create(path)
+            .and_then(|mut f| f.write_all(toml::Value::Table(stats).to_string().as_bytes()))
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path.display(), e)));
+    }
+}
+
+/// Print how much memory the scene and the frame buffers take up, and the peak resident set
+/// size if the OS tells us, and return the same numbers.
+fn report_memory_usage(scene: &Scene) -> Vec<(&'static str, usize)> {
+    let mut usage = scene.memory_usage();
+    usage.push(("frame_buffers_peak", film::peak_frame_bytes()));
+    if let Some(rss) = peak_rss() {
+        usage.push(("peak_rss", rss));
+    }
+    for &(name, bytes) in &usage {
+        println!("memory: {:>10.1} MiB {}", f64(bytes) / (1024.0 * 1024.0), name);
+    }
+    usage
+}
+
+/// The coherence of the primary rays of the first shot, or None if Embree traces them or there
+/// are no shots.
+fn coherence_stats(scene: &Scene, cfg: &Config) -> Option<toml::value::Table> {
+    if cfg.backend != Backend::Native {
+        return None;
+    }
+    let shots = plan_shots(cfg, scene).unwrap_or_else(|e| fail(&e));
+    let (camera, _) = shots.into_iter().next()?;
+    Some(print_timing("measuring ray coherence", || coherence::stats(scene, cfg, &camera)))
+}
+
+/// The peak resident set size in bytes, as reported by Linux.
+fn peak_rss() -> Option<usize> {
+    let mut status = String::new();
+    File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb: usize = line["VmHWM:".len()..].trim().trim_right_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn seconds(t: Duration) -> f64 {
+    f64(t.as_secs()) + f64(t.subsec_nanos()) / 1e9
+}
+
+fn print_ray_stats(rays_tested: usize, t: Duration) {
     let mrays = f64(rays_tested) / 1e6;
-    let time_per_ray = t / u32(rays_tested).unwrap();
-    println!("{:.2}M rays @ {:.3} Mray/s ({:} per ray)",
-             mrays,
-             mrays / seconds,
-             elapsed::ElapsedDuration::new(time_per_ray));
+    print!("{:.2}M rays @ {:.3} Mray/s", mrays, mrays / seconds(t));
+    // Not as a `Duration` divided by the ray count, which is often more than a u32 holds.
+    if rays_tested > 0 {
+        let nanos = seconds(t) / f64(rays_tested) * 1e9;
+        let time_per_ray = Duration::from_nanos(u64(nanos).unwrap());
+        print!(" ({} per ray)", elapsed::ElapsedDuration::new(time_per_ray));
+    }
+    println!();
+}
+
+/// Report an error that's the user's fault and exit.
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1)
+}
+
+/// The views of the left and right eye for `--stereo`, with parallel lines of sight.
+fn stereo_pair(camera: &Camera, separation: f32) -> (Camera, Camera) {
+    (camera.shifted(-separation / 2.0), camera.shifted(separation / 2.0))
+}
+
+/// Turns `out.bmp` into `out_0042.bmp`.
+fn numbered_file_name(path: &Path, i: u32) -> PathBuf {
+    suffixed_file_name(path, &format!("{:04}", i))
+}
+
+/// Turns `out.bmp` into `out_left.bmp`.
+fn suffixed_file_name(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{}_{}", stem, suffix);
+    if let Some(ext) = path.extension() {
+        file_name.push('.');
+        file_name.push_str(&ext.to_string_lossy());
+    }
+    path.with_file_name(file_name)
 }
 
 fn measure_and_print_time<T, F>(description: &str, f: F) -> (T, Duration)
     where F: FnOnce() -> T
 {
-    let (t, result) = elapsed::measure_time(f);
-    println!("[{:^10}] {}", t, description);
-    (result, t.duration())
+    let (result, t) = profile::timed(description, f);
+    println!("[{:^10}] {}", elapsed::ElapsedDuration::new(t), description);
+    (result, t)
 }
 
 fn print_timing<T, F>(description: &str, f: F) -> T
@@ -0,0 +1,245 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use integrator::to_world;
+use sampling::{Rng, cosine_hemisphere};
+use std::f32::consts::PI;
+
+/// The albedo of surfaces that don't have a material assigned.
+pub const DEFAULT_ALBEDO: f32 = 0.8;
+
+/// How a surface scatters light.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Material {
+    /// Lambertian reflection.
+    Diffuse { albedo: Rgb },
+    /// Lambertian reflection plus glossy reflection off a rough surface, modeled with the GGX
+    /// microfacet distribution with roughness `alpha` and Smith shadowing. `specular` is the
+    /// reflectance of the glossy layer at normal incidence.
+    Glossy {
+        diffuse: Rgb,
+        specular: Rgb,
+        alpha: f32,
+    },
+    /// A perfect mirror.
+    Mirror { reflectance: Rgb },
+    /// A smooth boundary between air and a dielectric such as glass or water, with index of
+    /// refraction `ior`. Light is reflected or refracted according to the Fresnel equations.
+    Glass { ior: f32 },
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Material::Diffuse { albedo: Rgb::grey(DEFAULT_ALBEDO) }
+    }
+}
+
+/// A direction sampled from a material.
+pub struct Scatter {
+    pub wi: Vector3<f32>,
+    /// The BSDF times the cosine term divided by the pdf, i.e., what the path throughput gets
+    /// multiplied with.
+    pub weight: Rgb,
+    /// The pdf (w.r.t. solid angle) of sampling `wi`, or zero if the material is specular.
+    pub pdf: f32,
+}
+
+impl Material {
+    /// The color of the material, for simple shading.
+    pub fn albedo(&self) -> Rgb {
+        match *self {
+            Material::Diffuse { albedo } => albedo,
+            Material::Glossy { diffuse, .. } => diffuse,
+            Material::Mirror { reflectance } => reflectance,
+            Material::Glass { .. } => Rgb::grey(1.0),
+        }
+    }
+
+    /// Replace the diffuse color, e.g. with a texture lookup. Materials without a diffuse
+    /// component are unchanged.
+    pub fn with_albedo(&self, albedo: Rgb) -> Material {
+        match *self {
+            Material::Diffuse { .. } => Material::Diffuse { albedo },
+            Material::Glossy { specular, alpha, .. } => {
+                Material::Glossy {
+                    diffuse: albedo,
+                    specular,
+                    alpha,
+                }
+            }
+            Material::Mirror { .. } | Material::Glass { .. } => *self,
+        }
+    }
+
+    /// Whether the material only scatters into a single direction, so sampling lights for it
+    /// is pointless.
+    pub fn is_specular(&self) -> bool {
+        match *self {
+            Material::Diffuse { .. } | Material::Glossy { .. } => false,
+            Material::Mirror { .. } | Material::Glass { .. } => true,
+        }
+    }
+
+    /// The BSDF for light arriving from `wi` and leaving towards `wo`, at a surface with unit
+    /// normal `n` facing `wo`. Zero for specular materials, since the chance of picking their
+    /// one direction is zero.
+    pub fn eval(&self, wo: Vector3<f32>, wi: Vector3<f32>, n: Vector3<f32>) -> Rgb {
+        if wi.dot(n) <= 0.0 {
+            return Rgb::black();
+        }
+        match *self {
+            Material::Diffuse { albedo } => albedo / PI,
+            Material::Glossy { diffuse, specular, alpha } => {
+                diffuse / PI + ggx_brdf(wo, wi, n, specular, alpha)
+            }
+            Material::Mirror { .. } | Material::Glass { .. } => Rgb::black(),
+        }
+    }
+
+    /// The pdf (w.r.t. solid angle) of `sample` picking `wi` for light leaving towards `wo`.
+    /// Zero for specular materials.
+    pub fn pdf(&self, wo: Vector3<f32>, wi: Vector3<f32>, n: Vector3<f32>) -> f32 {
+        let cos_i = wi.dot(n);
+        if cos_i <= 0.0 {
+            return 0.0;
+        }
+        match *self {
+            Material::Diffuse { .. } => cos_i / PI,
+            Material::Glossy { diffuse, specular, alpha } => {
+                let p_spec = glossy_probability(diffuse, specular);
+                (1.0 - p_spec) * cos_i / PI + p_spec * ggx_pdf(wo, wi, n, alpha)
+            }
+            Material::Mirror { .. } | Material::Glass { .. } => 0.0,
+        }
+    }
+
+    /// Sample the direction light continues in, for a ray travelling in direction `d` that hit
+    /// a surface with unit normal `n`, which must face against `d`. `entering` tells whether
+    /// the ray came from outside the object (w.r.t. the winding order of the triangle).
+    pub fn sample(&self, d: Vector3<f32>, n: Vector3<f32>, entering: bool, rng: &mut Rng)
+                  -> Scatter {
+        match *self {
+            Material::Diffuse { albedo } => {
+                // With cosine-weighted sampling, cos / pdf cancels with the 1/pi in the BRDF.
+                let local = cosine_hemisphere(rng.next_f32(), rng.next_f32());
+                Scatter {
+                    wi: to_world(local, n),
+                    weight: albedo,
+                    pdf: local.z / PI,
+                }
+            }
+            Material::Glossy { diffuse, specular, alpha } => {
+                let (u, v) = (rng.next_f32(), rng.next_f32());
+                let wi = if rng.next_f32() < glossy_probability(diffuse, specular) {
+                    let h = to_world(ggx_sample_half_vector(alpha, u, v), n);
+                    reflect(d, h)
+                } else {
+                    to_world(cosine_hemisphere(u, v), n)
+                };
+                let pdf = self.pdf(-d, wi, n);
+                let weight = if pdf > 0.0 {
+                    self.eval(-d, wi, n) * (wi.dot(n) / pdf)
+                } else {
+                    // Sampled a direction below the surface.
+                    Rgb::black()
+                };
+                Scatter { wi, weight, pdf }
+            }
+            Material::Mirror { reflectance } => {
+                Scatter {
+                    wi: reflect(d, n),
+                    weight: reflectance,
+                    pdf: 0.0,
+                }
+            }
+            Material::Glass { ior } => {
+                let eta = if entering { 1.0 / ior } else { ior };
+                let cos_i = -d.dot(n);
+                let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+                let wi = if sin2_t >= 1.0 {
+                    // Total internal reflection
+                    reflect(d, n)
+                } else {
+                    let cos_t = (1.0 - sin2_t).sqrt();
+                    if rng.next_f32() < fresnel_dielectric(cos_i, cos_t, eta) {
+                        reflect(d, n)
+                    } else {
+                        (d * eta + n * (eta * cos_i - cos_t)).normalize()
+                    }
+                };
+                // Choosing between reflection and refraction proportionally to the Fresnel
+                // term cancels it out of the weight.
+                Scatter {
+                    wi,
+                    weight: Rgb::grey(1.0),
+                    pdf: 0.0,
+                }
+            }
+        }
+    }
+}
+
+fn reflect(d: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    d - n * (2.0 * d.dot(n))
+}
+
+/// Fraction of unpolarized light that is reflected at a smooth dielectric boundary.
+/// `eta` is the ratio of the indices of refraction on the incident and transmitted side.
+fn fresnel_dielectric(cos_i: f32, cos_t: f32, eta: f32) -> f32 {
+    let parallel = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let perpendicular = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+/// How often the glossy layer is sampled rather than the diffuse one, based on how much each
+/// of them reflects.
+fn glossy_probability(diffuse: Rgb, specular: Rgb) -> f32 {
+    let (d, s) = (diffuse.luminance(), specular.luminance());
+    if d + s > 0.0 { s / (d + s) } else { 0.5 }
+}
+
+/// The GGX (a.k.a. Trowbridge-Reitz) normal distribution, for a microfacet normal with the
+/// given cosine to the macro surface normal.
+fn ggx_d(cos_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let t = cos_h * cos_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * t * t)
+}
+
+/// The Smith masking function for GGX, for a direction with the given cosine to the normal.
+fn ggx_g1(cos: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt())
+}
+
+/// The specular microfacet BRDF (Walter et al. 2007), with Schlick's Fresnel approximation.
+fn ggx_brdf(wo: Vector3<f32>, wi: Vector3<f32>, n: Vector3<f32>, f0: Rgb, alpha: f32) -> Rgb {
+    let (cos_o, cos_i) = (wo.dot(n), wi.dot(n));
+    if cos_o <= 0.0 || cos_i <= 0.0 {
+        return Rgb::black();
+    }
+    let h = (wo + wi).normalize();
+    let schlick = (1.0 - wi.dot(h).max(0.0)).powi(5);
+    let fresnel = f0 * (1.0 - schlick) + Rgb::grey(schlick);
+    let g = ggx_g1(cos_o, alpha) * ggx_g1(cos_i, alpha);
+    fresnel * (ggx_d(h.dot(n), alpha) * g / (4.0 * cos_o * cos_i))
+}
+
+/// Sample a microfacet normal relative to +Z proportionally to D(h) cos(theta_h).
+fn ggx_sample_half_vector(alpha: f32, u: f32, v: f32) -> Vector3<f32> {
+    let tan2_theta = alpha * alpha * u / (1.0 - u);
+    let cos_theta = 1.0 / (1.0 + tan2_theta).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// The pdf of getting `wi` by reflecting `wo` about a half vector from `ggx_sample_half_vector`.
+fn ggx_pdf(wo: Vector3<f32>, wi: Vector3<f32>, n: Vector3<f32>, alpha: f32) -> f32 {
+    let h = (wo + wi).normalize();
+    let cos_h = h.dot(n);
+    let wo_dot_h = wo.dot(h).abs();
+    if cos_h <= 0.0 || wo_dot_h == 0.0 {
+        return 0.0;
+    }
+    ggx_d(cos_h, alpha) * cos_h / (4.0 * wo_dot_h)
+}
@@ -0,0 +1,254 @@
+//! A loader for PLY files, in ASCII or binary encoding. Only the vertex positions and the
+//! faces' vertex indices are used, plus the vertex colors (`red`, `green` and `blue`) if there
+//! are any; all other elements and properties are skipped.
+
+use cast::{f32, f64, usize};
+use cgmath::vec3;
+use color::Rgb;
+use input::{Geometry, NO_VERTEX_COLOR};
+use std::io::BufRead;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// The scalar types of PLY properties, by size in bytes and interpretation.
+#[derive(Copy, Clone, Debug)]
+enum Type {
+    Int(usize),
+    Uint(usize),
+    Float(usize),
+}
+
+impl Type {
+    /// The value that stands for 1.0 in colors of this type.
+    fn color_scale(self) -> f64 {
+        match self {
+            Type::Int(size) => f64((1u64 << (8 * size - 1)) - 1),
+            Type::Uint(size) => f64((1u64 << (8 * size)) - 1),
+            Type::Float(_) => 1.0,
+        }
+    }
+
+    fn parse(name: &str) -> Result<Type, String> {
+        Ok(match name {
+               "char" | "int8" => Type::Int(1),
+               "uchar" | "uint8" => Type::Uint(1),
+               "short" | "int16" => Type::Int(2),
+               "ushort" | "uint16" => Type::Uint(2),
+               "int" | "int32" => Type::Int(4),
+               "uint" | "uint32" => Type::Uint(4),
+               "float" | "float32" => Type::Float(4),
+               "double" | "float64" => Type::Float(8),
+               _ => return Err(format!("unknown PLY type {}", name)),
+           })
+    }
+}
+
+enum Property {
+    Scalar(String, Type),
+    /// A list with the type of its length and the type of its items.
+    List(String, Type, Type),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Reads the values in the body of the file, one element at a time.
+struct Values<'a> {
+    read: &'a mut BufRead,
+    encoding: Encoding,
+    /// The unread values of the current line, for the ASCII encoding.
+    tokens: Vec<String>,
+}
+
+impl<'a> Values<'a> {
+    /// Start reading the next element.
+    fn next_element(&mut self) -> Result<(), String> {
+        if self.encoding == Encoding::Ascii {
+            let mut line = String::new();
+            self.read.read_line(&mut line).map_err(|e| e.to_string())?;
+            self.tokens = line.split_whitespace().rev().map(str::to_string).collect();
+        }
+        Ok(())
+    }
+
+    fn next(&mut self, ty: Type) -> Result<f64, String> {
+        if self.encoding == Encoding::Ascii {
+            return self.tokens
+                       .pop()
+                       .and_then(|t| t.parse().ok())
+                       .ok_or_else(|| "truncated or malformed PLY element".to_string());
+        }
+        let size = match ty {
+            Type::Int(size) | Type::Uint(size) | Type::Float(size) => size,
+        };
+        let mut bytes = [0; 8];
+        self.read.read_exact(&mut bytes[..size]).map_err(|e| e.to_string())?;
+        if self.encoding == Encoding::BigEndian {
+            bytes[..size].reverse();
+        }
+        let b = bytes;
+        Ok(match ty {
+               Type::Int(1) => f64::from(i8::from_le_bytes([b[0]])),
+               Type::Uint(1) => f64::from(b[0]),
+               Type::Int(2) => f64::from(i16::from_le_bytes([b[0], b[1]])),
+               Type::Uint(2) => f64::from(u16::from_le_bytes([b[0], b[1]])),
+               Type::Int(_) => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+               Type::Uint(_) => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+               Type::Float(4) => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+               Type::Float(_) => f64::from_le_bytes(b),
+           })
+    }
+}
+
+pub fn read(read: &mut BufRead) -> Result<Geometry, String> {
+    let (encoding, elements) = read_header(read)?;
+    let mut values = Values {
+        read,
+        encoding,
+        tokens: Vec::new(),
+    };
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut faces = Vec::new();
+    for element in &elements {
+        for _ in 0..element.count {
+            values.next_element()?;
+            let mut position = [None; 3];
+            let mut color = [None; 3];
+            let mut face = None;
+            for property in &element.properties {
+                match *property {
+                    Property::Scalar(ref name, ty) => {
+                        let value = values.next(ty)?;
+                        let (axis, is_color) = match name.as_str() {
+                            "x" => (0, false),
+                            "y" => (1, false),
+                            "z" => (2, false),
+                            "red" | "diffuse_red" => (0, true),
+                            "green" | "diffuse_green" => (1, true),
+                            "blue" | "diffuse_blue" => (2, true),
+                            _ => continue,
+                        };
+                        let out_of_range = |_| format!("PLY property {} out of range", name);
+                        if is_color {
+                            let value = f32(value / ty.color_scale()).map_err(out_of_range)?;
+                            color[axis] = Some(value);
+                        } else {
+                            position[axis] = Some(f32(value).map_err(out_of_range)?);
+                        }
+                    }
+                    Property::List(ref name, count_ty, item_ty) => {
+                        let count = values.next(count_ty)?;
+                        let count = usize(count).map_err(|_| "invalid PLY list length")?;
+                        let items = (0..count)
+                            .map(|_| values.next(item_ty))
+                            .collect::<Result<Vec<f64>, String>>()?;
+                        if name == "vertex_indices" || name == "vertex_index" {
+                            face = Some(items);
+                        }
+                    }
+                }
+            }
+            match element.name.as_str() {
+                "vertex" => {
+                    match position {
+                        [Some(x), Some(y), Some(z)] => vertices.push(vec3(x, y, z)),
+                        _ => return Err("PLY vertex without x, y and z".to_string()),
+                    }
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        colors.resize(vertices.len() - 1, NO_VERTEX_COLOR);
+                        colors.push(Rgb::new(r, g, b));
+                    }
+                }
+                "face" => faces.extend(face),
+                _ => {}
+            }
+        }
+    }
+
+    if !colors.is_empty() {
+        colors.resize(vertices.len(), NO_VERTEX_COLOR);
+    }
+    let mut tris = Vec::with_capacity(faces.len());
+    for face in faces {
+        let corners = face.iter()
+            .map(|&i| usize(i).ok().filter(|&i| i < vertices.len()))
+            .collect::<Option<Vec<usize>>>()
+            .ok_or_else(|| "invalid vertex index in PLY face".to_string())?;
+        for i in 1..corners.len().saturating_sub(1) {
+            tris.push([corners[0], corners[i], corners[i + 1]]);
+        }
+    }
+    Ok(Geometry {
+           vertices,
+           tris,
+           groups: Vec::new(),
+           tri_groups: Vec::new(),
+           colors,
+       })
+}
+
+fn read_header(read: &mut BufRead) -> Result<(Encoding, Vec<Element>), String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("PLY header without end_header".to_string());
+        }
+        let line = line.trim().to_string();
+        if line == "end_header" {
+            break;
+        }
+        lines.push(line);
+    }
+    if lines.first().map(String::as_str) != Some("ply") {
+        return Err("not a PLY file".to_string());
+    }
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in &lines[1..] {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let malformed = || format!("malformed PLY header line '{}'", line);
+        match fields.first().cloned() {
+            Some("format") => {
+                encoding = Some(match fields.get(1).cloned() {
+                                    Some("ascii") => Encoding::Ascii,
+                                    Some("binary_little_endian") => Encoding::LittleEndian,
+                                    Some("binary_big_endian") => Encoding::BigEndian,
+                                    _ => return Err(malformed()),
+                                });
+            }
+            Some("element") if fields.len() == 3 => {
+                elements.push(Element {
+                                  name: fields[1].to_string(),
+                                  count: fields[2].parse().map_err(|_| malformed())?,
+                                  properties: Vec::new(),
+                              });
+            }
+            Some("property") => {
+                let property = match fields.len() {
+                    3 => Property::Scalar(fields[2].to_string(), Type::parse(fields[1])?),
+                    5 if fields[1] == "list" => {
+                        Property::List(fields[4].to_string(),
+                                       Type::parse(fields[2])?,
+                                       Type::parse(fields[3])?)
+                    }
+                    _ => return Err(malformed()),
+                };
+                elements.last_mut().ok_or_else(&malformed)?.properties.push(property);
+            }
+            Some("comment") | Some("obj_info") | None => {}
+            _ => return Err(malformed()),
+        }
+    }
+    let encoding = encoding.ok_or_else(|| "PLY header without format".to_string())?;
+    Ok((encoding, elements))
+}
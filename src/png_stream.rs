@@ -0,0 +1,110 @@
+//! Writing an 8-bit RGB PNG a few rows at a time, for images too large to hold in memory at
+//! once (`--stream`). The PNG encoder of the image crate wants the whole image in one buffer.
+//!
+//! The rows are compressed as they come in, and the compressed data is written out in IDAT
+//! chunks of at most `CHUNK_SIZE` bytes, so memory use doesn't grow with the image.
+
+use cast::{u32, usize};
+use flate2::{Compression, Crc};
+use flate2::write::ZlibEncoder;
+use std::io::{self, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const CHUNK_SIZE: usize = 1 << 16;
+
+pub struct Writer<W: Write> {
+    idat: ZlibEncoder<Chunks<W>>,
+    row_bytes: usize,
+    rows_left: u32,
+}
+
+impl<W: Write> Writer<W> {
+    /// Write the header of a `width` by `height` image to `out`.
+    pub fn new(mut out: W, width: u32, height: u32) -> io::Result<Self> {
+        out.write_all(&SIGNATURE)?;
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        // 8 bits per sample, RGB, deflate, adaptive filtering, not interlaced.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut out, b"IHDR", &header)?;
+        let chunks = Chunks {
+            out,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        };
+        Ok(Writer {
+               idat: ZlibEncoder::new(chunks, Compression::default()),
+               row_bytes: 3 * usize(width),
+               rows_left: height,
+           })
+    }
+
+    /// Append rows of RGB triples, top to bottom; `rgb` must hold a whole number of rows.
+    pub fn write_rows(&mut self, rgb: &[u8]) -> io::Result<()> {
+        if self.row_bytes == 0 {
+            return Ok(());
+        }
+        assert_eq!(rgb.len() % self.row_bytes, 0, "BUG: partial row");
+        for row in rgb.chunks(self.row_bytes) {
+            assert!(self.rows_left > 0, "BUG: more rows than the image has");
+            // Each row starts with its filter type, and no filter is 0.
+            self.idat.write_all(&[0])?;
+            self.idat.write_all(row)?;
+            self.rows_left -= 1;
+        }
+        Ok(())
+    }
+
+    /// Write the rest of the compressed data and the end of the file, after all rows.
+    pub fn finish(self) -> io::Result<W> {
+        assert!(self.row_bytes == 0 || self.rows_left == 0,
+                "BUG: {} rows missing",
+                self.rows_left);
+        let mut chunks = self.idat.finish()?;
+        chunks.flush_chunk()?;
+        write_chunk(&mut chunks.out, b"IEND", &[])?;
+        chunks.out.flush()?;
+        Ok(chunks.out)
+    }
+}
+
+/// Cuts the compressed data into IDAT chunks.
+struct Chunks<W: Write> {
+    out: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Chunks<W> {
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            write_chunk(&mut self.out, b"IDAT", &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Chunks<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..n]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.flush_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&u32(data.len()).unwrap().to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.sum().to_be_bytes())
+}
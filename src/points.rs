@@ -0,0 +1,130 @@
+//! Point clouds, from `.xyz`/`.pts` text files or PLY files without faces.
+//!
+//! Each point becomes a splat: a small octahedron, so that it lives in the same triangle BVH
+//! as everything else and works with every render kind. Its radius is half the distance to the
+//! nearest other point, so splats close the gaps in dense regions without swelling up in
+//! sparse ones.
+
+use cast::f32;
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use input::Geometry;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Read a text point cloud with one point per line: `x y z` optionally followed by `r g b` in
+/// 0..255 (`.xyz`), or `x y z intensity r g b` (`.pts`, which also starts with a line holding
+/// the number of points).
+pub fn read(read: &mut BufRead) -> Result<Geometry, String> {
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    for (i, line) in read.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        let invalid = || format!("invalid point on line {}", i + 1);
+        let values = line.split_whitespace()
+            .map(|s| s.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| invalid())?;
+        let color_start = match values.len() {
+            // Empty lines and the point count of `.pts` files.
+            0 | 1 => continue,
+            2 => return Err(invalid()),
+            3 | 4 | 5 => None,
+            6 => Some(3),
+            _ => Some(4),
+        };
+        vertices.push(vec3(values[0], values[1], values[2]));
+        if let Some(c) = color_start {
+            colors.push(Rgb::new(values[c], values[c + 1], values[c + 2]) / 255.0);
+        }
+    }
+    if !colors.is_empty() && colors.len() != vertices.len() {
+        return Err("some points have colors and others don't".to_string());
+    }
+    Ok(Geometry {
+           vertices,
+           tris: Vec::new(),
+           groups: Vec::new(),
+           tri_groups: Vec::new(),
+           colors,
+       })
+}
+
+/// The distance from each point to the nearest other point (at a different position), found
+/// in a uniform grid. The cells are sized so that a scanned surface has about one point per
+/// cell; points without a neighbor in the adjacent cells get the size of a cell.
+fn nearest_distances(points: &[Vector3<f32>]) -> Vec<f32> {
+    let (min, max) = points.iter().fold((points[0], points[0]), |(min, max), p| {
+        (vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+         vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)))
+    });
+    let cell_size = (max - min).magnitude() / f32(points.len()).sqrt();
+    if cell_size == 0.0 {
+        return vec![1.0; points.len()];
+    }
+    let cell = |p: Vector3<f32>| {
+        let c = (p - min) / cell_size;
+        (c.x as i64, c.y as i64, c.z as i64)
+    };
+    let mut grid: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+    for (i, &p) in points.iter().enumerate() {
+        grid.entry(cell(p)).or_insert_with(Vec::new).push(i);
+    }
+    points.par_iter()
+        .map(|&p| {
+            let (x, y, z) = cell(p);
+            let mut best = cell_size;
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    for dz in -1..2 {
+                        let neighbors = grid.get(&(x + dx, y + dy, z + dz));
+                        for &j in neighbors.map_or(&[][..], |v| &v[..]) {
+                            let dist = (points[j] - p).magnitude();
+                            if dist > 0.0 && dist < best {
+                                best = dist;
+                            }
+                        }
+                    }
+                }
+            }
+            best
+        })
+        .collect()
+}
+
+/// Turn the points (the vertices) of a point cloud into octahedra, keeping their colors.
+pub fn splat(points: Geometry) -> Geometry {
+    if points.vertices.is_empty() {
+        return points;
+    }
+    let radii = nearest_distances(&points.vertices);
+    let mut vertices = Vec::with_capacity(6 * points.vertices.len());
+    let mut tris = Vec::with_capacity(8 * points.vertices.len());
+    for (i, (&p, &dist)) in points.vertices.iter().zip(&radii).enumerate() {
+        let r = 0.5 * dist;
+        vertices.extend(vec![p + vec3(r, 0.0, 0.0),
+                             p - vec3(r, 0.0, 0.0),
+                             p + vec3(0.0, r, 0.0),
+                             p - vec3(0.0, r, 0.0),
+                             p + vec3(0.0, 0.0, r),
+                             p - vec3(0.0, 0.0, r)]);
+        // One face per octant, wound so that its normal points away from `p`.
+        for octant in 0..8 {
+            let (sx, sy, sz) = (octant & 1, octant >> 1 & 1, octant >> 2 & 1);
+            let (a, b, c) = (6 * i + sx, 6 * i + 2 + sy, 6 * i + 4 + sz);
+            tris.push(if (sx + sy + sz) % 2 == 0 { [a, b, c] } else { [a, c, b] });
+        }
+    }
+    let colors = points.colors
+        .iter()
+        .flat_map(|&c| vec![c; 6])
+        .collect();
+    Geometry {
+        vertices,
+        tris,
+        groups: Vec::new(),
+        tri_groups: Vec::new(),
+        colors,
+    }
+}
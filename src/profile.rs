@@ -0,0 +1,115 @@
+//! Recording where the time goes, as spans of work on each thread, to look at in chrome://tracing
+//! (or another viewer of its JSON format, such as Perfetto or speedscope).
+//!
+//! Everything timed with `print_timing` is a span, and so are the tiles (or chunks of pixels)
+//! that the rayon threads render. Nothing is recorded unless `enable` was called, so the spans
+//! cost next to nothing otherwise.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+struct Span {
+    name: String,
+    thread: usize,
+    /// Relative to `EPOCH`.
+    start: Duration,
+    duration: Duration,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+    static ref SPANS: Mutex<Vec<Span>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    /// Small numbers for the threads, in the order they first finished a span.
+    static THREAD: Cell<Option<usize>> = Cell::new(None);
+}
+
+fn thread_number() -> usize {
+    THREAD.with(|id| {
+        id.get().unwrap_or_else(|| {
+            let next = NEXT_THREAD.fetch_add(1, Ordering::SeqCst);
+            id.set(Some(next));
+            next
+        })
+    })
+}
+
+/// Start recording spans.
+pub fn enable() {
+    ::lazy_static::initialize(&EPOCH);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Run `f`, recording how long it took as a span called `name`.
+pub fn span<T, F>(name: &str, f: F) -> T
+    where F: FnOnce() -> T
+{
+    timed(name, f).0
+}
+
+/// Like `span`, but also return how long `f` took, whether or not spans are recorded.
+pub fn timed<T, F>(name: &str, f: F) -> (T, Duration)
+    where F: FnOnce() -> T
+{
+    let start = Instant::now();
+    let result = f();
+    let duration = start.elapsed();
+    if ENABLED.load(Ordering::Relaxed) {
+        let span = Span {
+            name: name.to_string(),
+            thread: thread_number(),
+            start: start.duration_since(*EPOCH),
+            duration,
+        };
+        SPANS.lock().unwrap().push(span);
+    }
+    (result, duration)
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos() / 1000)
+}
+
+/// Write the spans recorded so far as "complete" events of the Trace Event Format.
+pub fn write_chrome_trace(path: &Path) -> io::Result<()> {
+    let spans = SPANS.lock().unwrap();
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{{\"traceEvents\": [")?;
+    for (i, span) in spans.iter().enumerate() {
+        let separator = if i + 1 < spans.len() { "," } else { "" };
+        writeln!(out,
+                 "{{\"name\": \"{}\", \"ph\": \"X\", \"pid\": 1, \"tid\": {}, \"ts\": {}, \
+                  \"dur\": {}}}{}",
+                 escape(&span.name),
+                 span.thread,
+                 micros(span.start),
+                 micros(span.duration),
+                 separator)?;
+    }
+    writeln!(out, "]}}")?;
+    out.flush()
+}
+
+/// Escape a string for use inside quotes in JSON.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,192 @@
+use cast::{f32, u64, usize};
+use cgmath::{Vector3, vec3};
+use std::f32::consts::PI;
+
+/// The PCG32 generator by Melissa O'Neill (pcg-random.org).
+/// Small, fast, and good enough for Monte Carlo rendering.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+    inc: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Rng {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    /// Create a generator whose output only depends on the pixel coordinates, so that renders
+    /// are reproducible regardless of how pixels are distributed among threads.
+    pub fn for_pixel(x: u32, y: u32) -> Self {
+        Rng::for_pixel_pass(x, y, 0)
+    }
+
+    /// Like `for_pixel`, but each pass of a progressive render gets its own stream.
+    pub fn for_pixel_pass(x: u32, y: u32, pass: u32) -> Self {
+        Rng::new(mix(u64(x) << 32 | u64(y)), u64(pass))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniformly distributed in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        // Use the upper 24 bits, which is exactly the precision of an f32 mantissa.
+        f32(self.next_u32() >> 8) * (1.0 / 16777216.0)
+    }
+}
+
+/// The splitmix64 finalizer, to turn structured inputs (e.g. coordinates) into seeds.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Map a point from the unit square to the unit disk, preserving relative areas
+/// (Shirley and Chiu's concentric mapping).
+pub fn concentric_disk(u: f32, v: f32) -> (f32, f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, (PI / 2.0) - (PI / 4.0) * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Cosine-weighted direction on the hemisphere around +Z. The pdf is cos(theta) / pi.
+pub fn cosine_hemisphere(u: f32, v: f32) -> Vector3<f32> {
+    let (x, y) = concentric_disk(u, v);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    vec3(x, y, z)
+}
+
+/// Uniformly distributed direction on the unit sphere. The pdf is 1 / (4 pi).
+pub fn uniform_sphere(u: f32, v: f32) -> Vector3<f32> {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// A piecewise constant distribution on [0, 1), for sampling proportionally to tabulated values.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Self {
+        let n = f32(func.len());
+        let mut cdf = Vec::with_capacity(func.len() + 1);
+        cdf.push(0.0);
+        for (i, &f) in func.iter().enumerate() {
+            let prev = cdf[i];
+            cdf.push(prev + f / n);
+        }
+        let integral = cdf[func.len()];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            // Fall back to a uniform distribution if the function is zero everywhere.
+            *c = if integral > 0.0 {
+                *c / integral
+            } else {
+                f32(i) / n
+            };
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Returns a sample in [0, 1), its pdf, and the index of the piece it's in.
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        // Find the last cdf entry <= u.
+        let i = match self.cdf.binary_search_by(|c| c.partial_cmp(&u).unwrap()) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let i = i.min(self.func.len() - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let offset = if width > 0.0 {
+            (u - self.cdf[i]) / width
+        } else {
+            0.0
+        };
+        let n = f32(self.func.len());
+        ((f32(i) + offset) / n, self.pdf_of_piece(i), i)
+    }
+
+    /// The pdf of `sample` returning `x`.
+    pub fn pdf(&self, x: f32) -> f32 {
+        self.pdf_of_piece(self.piece(x))
+    }
+
+    fn piece(&self, x: f32) -> usize {
+        usize(x * f32(self.func.len()))
+            .unwrap_or(0)
+            .min(self.func.len() - 1)
+    }
+
+    fn pdf_of_piece(&self, i: usize) -> f32 {
+        if self.integral > 0.0 {
+            self.func[i] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// A piecewise constant distribution on [0, 1)², built from a row-major table of values.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+        let rows: Vec<_> = func.chunks(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        assert_eq!(rows.len(), height);
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral()).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    /// Returns a sample in [0, 1)² and its pdf.
+    pub fn sample(&self, u: f32, v: f32) -> ((f32, f32), f32) {
+        let (y, pdf_y, row) = self.marginal.sample(v);
+        let (x, pdf_x, _) = self.rows[row].sample(u);
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    /// The pdf of `sample` returning (x, y).
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        let row = self.marginal.piece(y);
+        self.marginal.pdf_of_piece(row) * self.rows[row].pdf(x)
+    }
+}
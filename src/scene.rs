@@ -1,69 +1,1444 @@
-use super::{Config, print_timing};
-use bvh::{self, Bvh};
-use cast::usize;
-use cgmath::{Vector3, vec3};
-use geom::{Hit, Ray, Tri, TriSliceExt};
-use obj;
+use super::{Config, fail, print_timing};
+use beebox::Aabb;
+use bvh::{self, Bvh, BvhLayout, EntryNodes, NoStats, StatsRecorder};
+use cast::{f32, f64, usize, u32};
+use cgmath::{InnerSpace, Matrix3, Rad, Vector2, Vector3, vec2, vec3};
+use color::Rgb;
+use decimate;
+#[cfg(feature = "embree")]
+use embree_scene::EmbreeScene;
+use envmap::{self, Environment};
+use fast_obj;
+use film::Frame;
+use geom::{Frustum, Hit, HitFilter, Index, MAX_TRIS, Ray, Tri, TriIsect, TriMesh, accept_hit,
+           index};
+use input::{self, Format, Geometry, NO_VERTEX_COLOR};
+use light::Light;
+use material::{DEFAULT_ALBEDO, Material};
+use obj::raw::{self, Polygon};
+use obj::raw::material::{Material as MtlMaterial, MtlColor, MtlTextureMap};
+use ply;
+use points;
+use rayon::prelude::*;
+use shape::Shape;
+use std::collections::{HashMap, HashSet};
+use std::f32;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::Path;
+use std::io::{BufRead, BufReader};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use stl;
+use texture::Texture;
+use volume::{self, Volume};
+use watertri::Intersection;
+
+/// What answers `Scene::intersect` and the other closest hit queries, for primary rays and
+/// bounces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Our own BVH.
+    Native,
+    /// Embree, which only exists with the `embree` feature. It ignores cutouts and back-face
+    /// culling and doesn't record traversal statistics.
+    Embree,
+}
+
+/// What the integers of ID images (`Scene::id`) identify.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IdKind {
+    /// The input file, or the shape.
+    Object,
+    /// The material, counting each shape's material separately.
+    Material,
+}
 
 pub struct Scene {
-    pub tris: Vec<Tri>,
+    pub mesh: TriMesh,
     bvh: Bvh,
+    bb: Aabb,
+    pub env: Environment,
+    /// The lights given in the configuration, plus the sun if the environment has one.
+    pub lights: Vec<Light>,
+    /// A density grid that primary rays pass through on their way to the surfaces.
+    pub volume: Option<Volume>,
+    /// Analytic primitives besides the mesh, with their materials. Hits on `shapes[i]` have
+    /// the ID `mesh.tris.len() + i` in place of a triangle ID.
+    shapes: Vec<(Shape, Material)>,
+    materials: Vec<SceneMaterial>,
+    /// Index into `materials` for each triangle.
+    tri_materials: Vec<u32>,
+    /// Texture coordinates of each triangle's vertices, zero if the OBJ has none.
+    tri_uvs: Vec<[Vector2<f32>; 3]>,
+    /// The names of the OBJ groups in the scene.
+    groups: Vec<String>,
+    /// Index into `groups` for each triangle.
+    tri_groups: Vec<u32>,
+    /// Index into `objects` for each group, i.e. the input file the group comes from.
+    group_objects: Vec<u32>,
+    /// The names of the input files.
+    objects: Vec<String>,
+    /// The MTL files and textures the OBJ files refer to, whether they could be read or not.
+    material_files: Vec<PathBuf>,
+    /// Whether every surface is diffuse with the color of its group, see `--color-groups`.
+    color_groups: bool,
+    /// The color of each vertex as loaded (usually sRGB), empty if the input has none.
+    vertex_colors: Vec<Rgb>,
+    /// Whether any material has a cutout, so that intersections need to be filtered.
+    has_cutouts: bool,
+    mesh_stats: MeshStats,
     rays_tested: AtomicUsize,
+    /// The sum of the coverage of the images rendered since `take_coverage`, and their number.
+    coverage: Mutex<Option<(Frame<f32>, u32)>>,
+    /// A copy of the mesh in Embree, for `Backend::Embree`.
+    #[cfg(feature = "embree")]
+    embree: Option<EmbreeScene>,
+}
+
+/// Problems found while loading the OBJ file.
+#[derive(Copy, Clone, Debug, Default)]
+struct MeshStats {
+    /// Number of vertex positions that are exact copies of earlier ones.
+    duplicate_positions: usize,
+    /// Number of triangles dropped because they have no area.
+    degenerate_tris: usize,
+    /// Number of triangles dropped because a vertex is NaN or infinite.
+    non_finite_tris: usize,
+    /// Number of vertices moved onto a nearby vertex by welding.
+    welded_vertices: usize,
+    /// Number of triangles removed by welding because they were duplicates or lost their area.
+    welded_tris: usize,
+}
+
+/// Surfaces are cut away where their alpha is below this.
+const ALPHA_THRESHOLD: f32 = 0.5;
+
+/// A material as loaded from an MTL file, whose diffuse color may come from a texture.
+struct SceneMaterial {
+    name: String,
+    material: Material,
+    albedo_map: Option<Texture>,
+    /// A mask (`map_d`) for cutting holes into the surface.
+    alpha_map: Option<Texture>,
+}
+
+impl SceneMaterial {
+    /// The texture whose alpha decides which parts of the surface exist, if any.
+    /// Without a separate mask, the alpha of the diffuse texture is used.
+    fn cutout(&self) -> Option<&Texture> {
+        match (&self.alpha_map, &self.albedo_map) {
+            (&Some(ref mask), _) => Some(mask),
+            (&None, &Some(ref texture)) if texture.is_transparent() => Some(texture),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of an OBJ file, before building the BVH.
+struct Mesh {
+    geometry: TriMesh,
+    materials: Vec<SceneMaterial>,
+    tri_materials: Vec<u32>,
+    tri_uvs: Vec<[Vector2<f32>; 3]>,
+    groups: Vec<String>,
+    tri_groups: Vec<u32>,
+    vertex_colors: Vec<Rgb>,
+    material_files: Vec<PathBuf>,
+    stats: MeshStats,
 }
 
 impl Scene {
     pub fn new(cfg: &Config) -> Self {
-        let desc = format!("loading OBJ: {}", cfg.input_file.display());
-        let mut tris = print_timing(&desc, || read_obj(&cfg.input_file));
-        normalize(&mut tris);
-        let (bvh, tris) = bvh::construct(&tris, cfg);
-        Scene {
-            tris,
+        Scene::load(cfg).unwrap_or_else(|e| fail(&e))
+    }
+
+    /// Like `new`, but problems with the input files are returned rather than fatal, e.g. to
+    /// keep going when a watched file is only partly written.
+    pub fn load(cfg: &Config) -> Result<Self, String> {
+        let mut meshes = Vec::with_capacity(cfg.input_files.len());
+        for (i, path) in cfg.input_files.iter().enumerate() {
+            let format = cfg.input_format.unwrap_or_else(|| Format::from_extension(path));
+            let mut mesh = read_mesh(path, format)?;
+            if let Some(&color) = cfg.part_colors.get(i) {
+                paint(&mut mesh, color);
+            }
+            meshes.push(mesh);
+        }
+        let group_objects = meshes.iter()
+            .enumerate()
+            .flat_map(|(i, mesh)| iter::repeat(u32(i).unwrap()).take(mesh.groups.len()))
+            .collect();
+        let mut mesh = if meshes.len() == 1 {
+            meshes.pop().unwrap()
+        } else {
+            print_timing("merging meshes", || merge(meshes))?
+        };
+        if !cfg.only_groups.is_empty() || !cfg.exclude_groups.is_empty() {
+            filter_groups(&mut mesh, &cfg.only_groups, &cfg.exclude_groups)?;
+        }
+        // Decimation needs to know which triangles share vertices, so it welds exact copies.
+        if let Some(epsilon) = cfg.weld_epsilon.or_else(|| cfg.decimate.map(|_| 0.0)) {
+            print_timing("welding vertices", || weld(&mut mesh, epsilon));
+        }
+        if let Some(ratio) = cfg.decimate {
+            print_timing("decimating", || decimate(&mut mesh, ratio));
+        }
+        if let Some(levels) = cfg.tessellate {
+            print_timing("tessellating", || tessellate(&mut mesh, levels));
+        }
+        let env = envmap::load(cfg).map_err(|e| format!("could not load environment: {}", e))?;
+        let mut lights = cfg.lights.clone();
+        if let Some((dir, irradiance)) = env.sun() {
+            lights.push(Light::Directional { dir, irradiance });
+        }
+        let volume = volume::load(cfg).map_err(|e| format!("could not load volume: {}", e))?;
+        let mut bb = cfg.shapes.iter().fold(mesh.geometry.bbox(), |bb, &(s, _)| bb.union(s.bbox()));
+        if let Some(ref volume) = volume {
+            bb = bb.union(volume.bbox());
+        }
+        let (bvh, tris, order) = bvh::construct(&mesh.geometry, cfg);
+        let tri_materials = order.iter().map(|&i| mesh.tri_materials[i]).collect();
+        let tri_uvs = order.iter().map(|&i| mesh.tri_uvs[i]).collect();
+        let tri_groups = order.iter().map(|&i| mesh.tri_groups[i]).collect();
+        let has_cutouts = mesh.materials.iter().any(|m| m.cutout().is_some());
+        let mut geometry = TriMesh::new(mesh.geometry.vertices, tris);
+        geometry.set_tri_isect(cfg.tri_isect);
+        geometry.set_precision(cfg.precision);
+        geometry.set_cull_backfaces(cfg.cull_backfaces);
+        let mut scene = Scene {
+            mesh: geometry,
             bvh,
+            bb,
+            env,
+            lights,
+            volume,
+            shapes: cfg.shapes.clone(),
+            materials: mesh.materials,
+            tri_materials,
+            tri_uvs,
+            groups: mesh.groups,
+            tri_groups,
+            group_objects,
+            objects: cfg.input_files.iter().map(|path| path.display().to_string()).collect(),
+            material_files: mesh.material_files,
+            color_groups: cfg.color_groups,
+            vertex_colors: mesh.vertex_colors,
+            has_cutouts,
+            mesh_stats: mesh.stats,
             rays_tested: AtomicUsize::new(0),
+            coverage: Mutex::new(None),
+            #[cfg(feature = "embree")]
+            embree: None,
+        };
+        scene.set_backend(cfg.backend);
+        Ok(scene)
+    }
+
+    /// Switch to another backend for closest hit queries.
+    #[cfg(feature = "embree")]
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.embree = match backend {
+            Backend::Native => None,
+            Backend::Embree => {
+                Some(print_timing("building Embree scene", || EmbreeScene::new(&self.mesh)))
+            }
+        };
+    }
+
+    #[cfg(not(feature = "embree"))]
+    pub fn set_backend(&mut self, backend: Backend) {
+        if backend == Backend::Embree {
+            fail("the Embree backend needs suptracer to be built with the `embree` feature");
         }
     }
 
+    /// The hit from Embree if that's the backend, otherwise None.
+    #[cfg(feature = "embree")]
+    fn embree_hit(&self, r: &Ray) -> Option<Hit> {
+        self.embree.as_ref().map(|embree| embree.intersect(r))
+    }
+
+    #[cfg(not(feature = "embree"))]
+    fn embree_hit(&self, _: &Ray) -> Option<Hit> {
+        None
+    }
+
+    /// Like `embree_hit`, but skipping hits that don't pass `filter`.
+    #[cfg(feature = "embree")]
+    fn embree_hit_filtered(&self, r: &Ray, filter: &HitFilter) -> Option<Hit> {
+        self.embree.as_ref().map(|embree| embree.intersect_filtered(r, filter))
+    }
+
+    #[cfg(not(feature = "embree"))]
+    fn embree_hit_filtered(&self, _: &Ray, _: &HitFilter) -> Option<Hit> {
+        None
+    }
+
+    /// Create the Embree scene again after the mesh changed, if Embree is the backend.
+    #[cfg(feature = "embree")]
+    fn update_backend(&mut self) {
+        if self.embree.is_some() {
+            self.set_backend(Backend::Embree);
+        }
+    }
+
+    #[cfg(not(feature = "embree"))]
+    fn update_backend(&mut self) {}
+
+    /// Print statistics about the mesh and the BVH, to get an idea of how expensive the scene
+    /// is and whether there's anything wrong with it.
+    pub fn print_info(&self, cfg: &Config) {
+        let (min, max) = (self.bb.min(), self.bb.max());
+        println!("triangles: {}", self.mesh.tris.len());
+        println!("vertices: {}", self.mesh.vertices.len());
+        println!("dropped triangles: {} degenerate, {} with NaN or infinite vertices",
+                 self.mesh_stats.degenerate_tris,
+                 self.mesh_stats.non_finite_tris);
+        println!("duplicated vertex positions: {}", self.mesh_stats.duplicate_positions);
+        println!("welded: {} vertices, {} triangles removed",
+                 self.mesh_stats.welded_vertices,
+                 self.mesh_stats.welded_tris);
+        println!("materials: {}", self.materials.len() - 1);
+        println!("groups: {}", self.groups.len());
+        println!("shapes: {}", self.shapes.len());
+        println!("bounding box: ({}, {}, {}) to ({}, {}, {})",
+                 min.x,
+                 min.y,
+                 min.z,
+                 max.x,
+                 max.y,
+                 max.z);
+        let stats = self.bvh.stats();
+        println!("BVH nodes: {} ({} leaves)", stats.nodes, stats.leaves);
+        println!("BVH depth: {}", stats.max_depth);
+        println!("triangles per leaf: {} to {}, {:.2} on average",
+                 stats.min_leaf_size,
+                 stats.max_leaf_size,
+                 f64(stats.leaf_tris) / f64(stats.leaves));
+        println!("SAH cost: {:.2}", self.sah_cost(cfg));
+    }
+
+    /// Bytes used by the big parts of the scene, by name.
+    pub fn memory_usage(&self) -> Vec<(&'static str, usize)> {
+        vec![("mesh", self.mesh.memory_usage()),
+             ("triangle_materials", self.tri_materials.capacity() * mem::size_of::<u32>()),
+             ("texture_coordinates",
+              self.tri_uvs.capacity() * mem::size_of::<[Vector2<f32>; 3]>()),
+             ("triangle_groups", self.tri_groups.capacity() * mem::size_of::<u32>()),
+             ("vertex_colors", self.vertex_colors.capacity() * mem::size_of::<Rgb>()),
+             ("bvh_nodes", self.bvh.memory_usage()),
+             ("volume", self.volume.as_ref().map_or(0, |v| v.memory_usage()))]
+    }
+
     pub fn intersect(&self, r: &Ray) -> Hit {
+        self.intersect_recorded(r, &mut NoStats)
+    }
+
+    /// Like `intersect`, but hits must also pass `filter`, e.g. to ignore certain triangles.
+    pub fn intersect_filtered(&self, r: &Ray, filter: &HitFilter) -> Hit {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        let hit = match self.embree_hit_filtered(r, filter) {
+            Some(hit) => hit,
+            None => {
+                self.with_extra_filter(filter, |filter| {
+                    bvh::traverse(&self.mesh, &self.bvh, r, filter, &mut NoStats)
+                })
+            }
+        };
+        self.closer_shape_hit(r, hit, Some(filter))
+    }
+
+    /// Like `intersect`, but tell `stats` how the ray traverses the BVH.
+    pub fn intersect_recorded<S>(&self, r: &Ray, stats: &mut S) -> Hit
+        where S: StatsRecorder
+    {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        let hit = match self.embree_hit(r) {
+            Some(hit) => hit,
+            None => {
+                self.with_hit_filter(|filter| {
+                                         bvh::traverse(&self.mesh,
+                                                       &self.bvh,
+                                                       r,
+                                                       filter,
+                                                       stats)
+                                     })
+            }
+        };
+        self.closer_shape_hit(r, hit, None)
+    }
+
+    /// Like `intersect_recorded`, but traverse the BVH without a stack.
+    pub fn intersect_stackless<S>(&self, r: &Ray, stats: &mut S) -> Hit
+        where S: StatsRecorder
+    {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        let hit = match self.embree_hit(r) {
+            Some(hit) => hit,
+            None => {
+                self.with_hit_filter(|filter| {
+                                         bvh::traverse_stackless(&self.mesh,
+                                                                 &self.bvh,
+                                                                 r,
+                                                                 filter,
+                                                                 stats)
+                                     })
+            }
+        };
+        self.closer_shape_hit(r, hit, None)
+    }
+
+    /// The BVH nodes that rays inside of `frustum` may hit, for `intersect_from`.
+    pub fn visible_nodes(&self, frustum: &Frustum) -> EntryNodes {
+        bvh::frustum_entries(&self.bvh, frustum)
+    }
+
+    /// Like `intersect_recorded`, but only traverse the subtrees below `entries`, which must
+    /// have been collected for a frustum that contains the ray.
+    pub fn intersect_from<S>(&self, entries: &EntryNodes, r: &Ray, stats: &mut S) -> Hit
+        where S: StatsRecorder
+    {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        let hit = match self.embree_hit(r) {
+            Some(hit) => hit,
+            None => {
+                self.with_hit_filter(|filter| {
+                                         bvh::traverse_from(&self.mesh,
+                                                            &self.bvh,
+                                                            entries,
+                                                            r,
+                                                            filter,
+                                                            stats)
+                                     })
+            }
+        };
+        self.closer_shape_hit(r, hit, None)
+    }
+
+    /// Intersect up to `bvh::PACKET_SIZE` coherent rays at once, with one recorder per ray.
+    pub fn intersect_packet<S>(&self, rays: &[Ray], stats: &mut [S]) -> Vec<Hit>
+        where S: StatsRecorder
+    {
+        self.rays_tested.fetch_add(rays.len(), Ordering::SeqCst);
+        let embree_hits: Option<Vec<Hit>> = rays.iter().map(|r| self.embree_hit(r)).collect();
+        let hits = match embree_hits {
+            Some(hits) => hits,
+            None => {
+                self.with_hit_filter(|filter| {
+                                         bvh::traverse_packet(&self.mesh,
+                                                              &self.bvh,
+                                                              rays,
+                                                              filter,
+                                                              stats)
+                                     })
+            }
+        };
+        rays.iter().zip(hits).map(|(r, hit)| self.closer_shape_hit(r, hit, None)).collect()
+    }
+
+    /// Intersect many rays, in parallel. Unlike `intersect_packet`, the rays don't need to be
+    /// coherent and there can be any number of them.
+    pub fn intersect_batch(&self, rays: &[Ray]) -> Vec<Hit> {
+        rays.par_iter().map(|r| self.intersect(r)).collect()
+    }
+
+    /// All hits along the ray, closest first, but at most `capacity` of them.
+    pub fn intersect_all(&self, r: &Ray, capacity: usize) -> Vec<Hit> {
+        self.rays_tested.fetch_add(1, Ordering::SeqCst);
+        let mut hits = self.with_hit_filter(|filter| {
+                                                bvh::traverse_all(&self.mesh,
+                                                                  &self.bvh,
+                                                                  r,
+                                                                  filter,
+                                                                  capacity)
+                                            });
+        if !self.shapes.is_empty() {
+            // Spheres can be hit twice.
+            for (i, &(shape, _)) in self.shapes.iter().enumerate() {
+                let mut r = *r;
+                while let Some((t, uv)) = shape.intersect(&r, r.t_max) {
+                    hits.push(self.shape_hit(i, t, uv));
+                    r.t_min = t;
+                }
+            }
+            hits.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap());
+            hits.truncate(capacity);
+        }
+        hits
+    }
+
+    /// The point of the mesh closest to `p`, the triangle it's on and its distance to `p`.
+    /// Shapes are ignored.
+    pub fn closest_point(&self, p: Vector3<f32>) -> Option<(Vector3<f32>, Index, f32)> {
+        bvh::closest_point(&self.mesh, &self.bvh, p)
+    }
+
+    /// Call `f` with every triangle that may overlap `bb`, and some that don't.
+    pub fn overlapping_tris<F>(&self, bb: &Aabb, f: F)
+        where F: FnMut(Index)
+    {
+        bvh::overlapping(&self.bvh, bb, f)
+    }
+
+    /// Whether anything is hit before `t_max` (or before `r.t_max`, if that's closer).
+    pub fn occluded(&self, r: &Ray, t_max: f32) -> bool {
+        let r = &Ray { t_max: t_max.min(r.t_max), ..*r };
+        self.shapes.iter().any(|&(shape, _)| shape.intersect(r, r.t_max).is_some()) ||
+        self.with_hit_filter(|filter| bvh::occluded(&self.mesh, &self.bvh, r, filter))
+    }
+
+    /// `occluded` for many rays in parallel, with `t_max[i]` as the limit for `rays[i]`.
+    pub fn occluded_batch(&self, rays: &[Ray], t_max: &[f32]) -> Vec<bool> {
+        assert_eq!(rays.len(), t_max.len());
+        rays.par_iter().zip(t_max).map(|(r, &t_max)| self.occluded(r, t_max)).collect()
+    }
+
+    pub fn intersect_verbose<S>(&self, r: &Ray, stats: &mut S) -> Hit
+        where S: StatsRecorder
+    {
         self.rays_tested.fetch_add(1, Ordering::SeqCst);
-        bvh::traverse(&self.tris, &self.bvh, r)
+        let hit = self.with_hit_filter(|filter| {
+                                           bvh::traverse_verbose(&self.mesh,
+                                                                 &self.bvh,
+                                                                 r,
+                                                                 filter,
+                                                                 stats)
+                                       });
+        self.closer_shape_hit(r, hit, None)
+    }
+
+    /// `hit` or the closest hit on a shape that passes `filter`, whichever is closer.
+    fn closer_shape_hit(&self, r: &Ray, mut hit: Hit, filter: Option<&HitFilter>) -> Hit {
+        let mut t_max = if hit.is_valid() { hit.t } else { r.t_max };
+        for (i, &(shape, _)) in self.shapes.iter().enumerate() {
+            if let Some((t, uv)) = shape.intersect(r, t_max) {
+                let shape_hit = self.shape_hit(i, t, uv);
+                let intersection = Intersection {
+                    t,
+                    u: shape_hit.u,
+                    v: shape_hit.v,
+                    w: shape_hit.w,
+                };
+                if accept_hit(filter, shape_hit.tri_id, &intersection) {
+                    t_max = t;
+                    hit = shape_hit;
+                }
+            }
+        }
+        hit
+    }
+
+    /// The hit at `t` on `shapes[i]`, at the point with texture coordinates `uv`, which are
+    /// stored in place of the barycentric coordinates.
+    fn shape_hit(&self, i: usize, t: f32, uv: Vector2<f32>) -> Hit {
+        Hit {
+            tri_id: index(self.mesh.tris.len() + i),
+            t,
+            u: uv.x,
+            v: uv.y,
+            w: 0.0,
+        }
+    }
+
+    /// The shape that a hit ID refers to, if it isn't a triangle.
+    fn shape(&self, id: Index) -> Option<&(Shape, Material)> {
+        usize(id).checked_sub(self.mesh.tris.len()).map(|i| &self.shapes[i])
+    }
+
+    /// The unit normal at the (valid) hit of `r`, in no particular orientation for triangles.
+    pub fn normal(&self, r: &Ray, hit: &Hit) -> Vector3<f32> {
+        match self.shape(hit.tri_id) {
+            Some(&(shape, _)) => shape.normal(r.o + r.d * hit.t),
+            None => self.mesh.normal(hit.tri_id),
+        }
+    }
+
+    /// Whether the surface with this hit ID is flat, so that a ray leaving it can't hit it
+    /// again.
+    pub fn is_flat(&self, id: Index) -> bool {
+        self.shape(id).map_or(true, |&(shape, _)| shape.is_flat())
+    }
+
+    /// Call `f` with the filter that makes rays pass through cutouts, or with `None` if there
+    /// are no cutouts, to avoid the overhead of checking every intersection.
+    fn with_hit_filter<R, F>(&self, f: F) -> R
+        where F: FnOnce(Option<&HitFilter>) -> R
+    {
+        if self.has_cutouts {
+            f(Some(&|tri_id, i: &Intersection| self.alpha_test(tri_id, i)))
+        } else {
+            f(None)
+        }
+    }
+
+    /// Like `with_hit_filter`, but hits must pass `extra` as well.
+    fn with_extra_filter<R, F>(&self, extra: &HitFilter, f: F) -> R
+        where F: FnOnce(Option<&HitFilter>) -> R
+    {
+        self.with_hit_filter(|cutouts| match cutouts {
+                                 Some(cutouts) => {
+                                     f(Some(&|tri_id, i: &Intersection| {
+                                                 extra(tri_id, i) && cutouts(tri_id, i)
+                                             }))
+                                 }
+                                 None => f(Some(extra)),
+                             })
+    }
+
+    /// Whether the intersection is with a part of the triangle that wasn't cut away.
+    fn alpha_test(&self, tri_id: Index, i: &Intersection) -> bool {
+        if self.shape(tri_id).is_some() {
+            return true;
+        }
+        let m = &self.materials[usize(self.tri_materials[usize(tri_id)])];
+        match m.cutout() {
+            Some(texture) => texture.alpha(self.uv_at(tri_id, i.u, i.v, i.w)) >= ALPHA_THRESHOLD,
+            None => true,
+        }
+    }
+
+    /// The material at the (valid) hit point, with textures already looked up.
+    pub fn material(&self, hit: &Hit) -> Material {
+        if let Some(&(_, material)) = self.shape(hit.tri_id) {
+            return material;
+        }
+        if self.color_groups {
+            return Material::Diffuse { albedo: Rgb::distinct(self.tri_groups[usize(hit.tri_id)]) };
+        }
+        let m = &self.materials[usize(self.tri_materials[usize(hit.tri_id)])];
+        match m.albedo_map {
+            Some(ref texture) => m.material.with_albedo(texture.sample(self.uv(hit))),
+            None => m.material,
+        }
+    }
+
+    /// The integer identifying the object or material at the (valid) hit point. IDs start at 1
+    /// and are the same in every image of the scene, so that 0 can be used for the background.
+    /// Shapes come after the input files and after the materials, respectively.
+    pub fn id(&self, hit: &Hit, kind: IdKind) -> u32 {
+        let tri_id = usize(hit.tri_id);
+        let id = if tri_id < self.mesh.tris.len() {
+            match kind {
+                IdKind::Object => self.group_objects[usize(self.tri_groups[tri_id])],
+                IdKind::Material => self.tri_materials[tri_id],
+            }
+        } else {
+            let before = match kind {
+                IdKind::Object => self.objects.len(),
+                IdKind::Material => self.materials.len(),
+            };
+            u32(before + tri_id - self.mesh.tris.len()).unwrap()
+        };
+        id + 1
+    }
+
+    /// The names of what the IDs from `Scene::id` identify, starting with ID 1.
+    pub fn id_names(&self, kind: IdKind) -> Vec<String> {
+        let names = match kind {
+            IdKind::Object => self.objects.clone(),
+            IdKind::Material => self.materials.iter().map(|m| m.name.clone()).collect(),
+        };
+        let shapes = (0..self.shapes.len()).map(|i| format!("shape {}", i + 1));
+        names.into_iter().chain(shapes).collect()
+    }
+
+    /// The interpolated vertex color at the (valid) hit point, white if the input has no vertex
+    /// colors.
+    pub fn vertex_color(&self, hit: &Hit) -> Rgb {
+        if self.vertex_colors.is_empty() || self.shape(hit.tri_id).is_some() {
+            return NO_VERTEX_COLOR;
+        }
+        let tri = &self.mesh.tris[usize(hit.tri_id)];
+        let color = |i: Index| self.vertex_colors[usize(i)];
+        color(tri.a) * hit.u + color(tri.b) * hit.v + color(tri.c) * hit.w
+    }
+
+    /// The interpolated texture coordinates at the (valid) hit point.
+    pub fn uv(&self, hit: &Hit) -> Vector2<f32> {
+        if self.shape(hit.tri_id).is_some() {
+            return vec2(hit.u, hit.v);
+        }
+        self.uv_at(hit.tri_id, hit.u, hit.v, hit.w)
+    }
+
+    /// The texture coordinates of the corners of a triangle.
+    pub fn tri_uvs(&self, tri_id: Index) -> [Vector2<f32>; 3] {
+        self.tri_uvs[usize(tri_id)]
+    }
+
+    /// The texture coordinates at the point with barycentric coordinates (u, v, w) in a triangle.
+    fn uv_at(&self, tri_id: Index, u: f32, v: f32, w: f32) -> Vector2<f32> {
+        let uvs = &self.tri_uvs[usize(tri_id)];
+        uvs[0] * u + uvs[1] * v + uvs[2] * w
     }
 
     pub fn rays_tested(&self) -> usize {
         self.rays_tested.load(Ordering::SeqCst)
     }
+
+    /// Record which fraction of the primary rays of each pixel hit something, for another pass
+    /// of the image that is being rendered.
+    pub fn add_coverage(&self, coverage: Frame<f32>) {
+        let mut sum = self.coverage.lock().unwrap();
+        *sum = Some(match sum.take() {
+                        Some((mut total, passes)) => {
+                            for (y, row) in total.rows_mut() {
+                                for (x, c) in (0..).zip(row) {
+                                    *c += coverage.get(x, y);
+                                }
+                            }
+                            (total, passes + 1)
+                        }
+                        None => (coverage, 1),
+                    });
+    }
+
+    /// The coverage recorded since the last call, averaged over the passes, e.g. for the alpha
+    /// channel of `--transparent` images.
+    pub fn take_coverage(&self) -> Option<Frame<f32>> {
+        let sum = self.coverage.lock().unwrap().take();
+        sum.map(|(total, passes)| total.map(|c| c / f32(passes)))
+    }
+
+    /// Replace the vertices with `rest_pose` rotated by `angle` radians around the vertical
+    /// axis through `center`. The BVH is not updated, call `refit` afterwards.
+    pub fn spin(&mut self, rest_pose: &[Vector3<f32>], center: Vector3<f32>, angle: f32) {
+        let rot = Matrix3::from_angle_y(Rad(angle));
+        for (v, &rest) in self.mesh.vertices.iter_mut().zip(rest_pose) {
+            *v = center + rot * (rest - center);
+        }
+    }
+
+    pub fn set_bvh_layout(&mut self, layout: BvhLayout) {
+        self.bvh.set_layout(layout);
+    }
+
+    /// Switch to another triangle intersection algorithm.
+    pub fn set_tri_isect(&mut self, isect: TriIsect) {
+        self.mesh.set_tri_isect(isect);
+    }
+
+    /// Build a new BVH with the settings in `cfg`, e.g. to compare builders and parameters.
+    pub fn rebuild_bvh(&mut self, cfg: &Config) {
+        let (bvh, tris, order) = bvh::construct(&self.mesh, cfg);
+        self.tri_materials = order.iter().map(|&i| self.tri_materials[i]).collect();
+        self.tri_uvs = order.iter().map(|&i| self.tri_uvs[i]).collect();
+        self.tri_groups = order.iter().map(|&i| self.tri_groups[i]).collect();
+        self.mesh.tris = tris;
+        // The Woop transforms are stored in triangle order.
+        let isect = self.mesh.tri_isect();
+        self.mesh.set_tri_isect(isect);
+        self.bvh = bvh;
+        self.update_backend();
+    }
+
+    pub fn refit(&mut self) {
+        // The Woop transforms depend on the vertex positions.
+        let isect = self.mesh.tri_isect();
+        self.mesh.set_tri_isect(isect);
+        self.bvh.refit(&self.mesh);
+        self.bb = self.mesh.bbox();
+        self.update_backend();
+    }
+
+    pub fn sah_cost(&self, cfg: &Config) -> f32 {
+        self.bvh.sah_cost(cfg.sah_traversal_cost)
+    }
+
+    /// The boxes of the BVH nodes at most `max_depth` levels below the root.
+    pub fn bvh_boxes(&self, max_depth: usize) -> Vec<Aabb> {
+        self.bvh.boxes(max_depth)
+    }
+
+    pub fn bbox(&self) -> &Aabb {
+        &self.bb
+    }
+
+    /// The MTL files and textures the materials were loaded from, or would have been if the
+    /// files could be read.
+    pub fn material_files(&self) -> &[PathBuf] {
+        &self.material_files
+    }
 }
 
-fn normalize(tris: &mut [Tri]) {
-    let bb = tris.bbox();
-    let (min, max) = (bb.min(), bb.max());
-    let center = (min + max) / 2.0;
-    // This heuristically moves the model such that it's probably within view.
-    let displace = center + vec3(0.0, 0.0, (min.z - max.z).abs());
-    for tri in tris {
-        tri.a -= displace;
-        tri.b -= displace;
-        tri.c -= displace;
+/// Read the mesh file at `path`, which is in the given format.
+fn read_mesh(path: &Path, format: Format) -> Result<Mesh, String> {
+    match format {
+        Format::Obj => {
+            print_timing(&format!("loading OBJ: {}", path.display()), || read_obj(path))
+        }
+        Format::Ply => {
+            // PLY files without faces are point clouds.
+            let read = |read: &mut BufRead| {
+                ply::read(read).map(|g| if g.tris.is_empty() { points::splat(g) } else { g })
+            };
+            print_timing(&format!("loading PLY: {}", path.display()),
+                         || read_geometry(path, read))
+        }
+        Format::Stl => {
+            print_timing(&format!("loading STL: {}", path.display()),
+                         || read_geometry(path, stl::read))
+        }
+        Format::Xyz => {
+            let read = |read: &mut BufRead| points::read(read).map(points::splat);
+            print_timing(&format!("loading point cloud: {}", path.display()),
+                         || read_geometry(path, read))
+        }
     }
 }
 
-fn read_obj(path: &Path) -> Vec<Tri> {
-    let read = BufReader::new(File::open(path).unwrap());
-    let o = obj::load_obj::<obj::Position, _>(read).unwrap();
-    o.indices
-        .chunks(3)
-        .map(|chunk| {
-            assert!(chunk.len() == 3);
-            let (i, j, k) = (usize(chunk[0]), usize(chunk[1]), usize(chunk[2]));
+/// Read the triangles of an OBJ file along with their materials and texture coordinates.
+/// Polygons with more than three vertices are triangulated as fans. Triangles without area or
+/// with NaN or infinite vertices are dropped, since they would mess up the BVH.
+/// Uncompressed files with only geometry are loaded in parallel by `fast_obj`.
+fn read_obj(path: &Path) -> Result<Mesh, String> {
+    if !input::is_compressed(path) && !input::is_stdin(path) {
+        match fast_obj::read(path)? {
+            Some(geometry) => return mesh_from_geometry(path, geometry),
+            None => println!("note: using the sequential OBJ parser for {}", path.display()),
+        }
+    }
+    let read = input::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let o = raw::parse_obj(read).map_err(|e| format!("{}: {:?}", path.display(), e))?;
+    let mut material_files = Vec::new();
+    let mtls = read_mtls(path, &o.material_libraries, &mut material_files);
+    // Faces without a material get the default material at index 0.
+    let mut materials = vec![default_material()];
+    let mut polygon_materials = vec![0; o.polygons.len()];
+    for (name, group) in &o.meshes {
+        let id = match mtls.get(name) {
+            Some(&(ref mtl, ref mtl_path)) => {
+                materials.push(SceneMaterial {
+                                   name: name.clone(),
+                                   material: material_from_mtl(mtl),
+                                   albedo_map: read_map(&mtl.diffuse_map,
+                                                        mtl_path,
+                                                        Texture::open,
+                                                        &mut material_files),
+                                   alpha_map: read_map(&mtl.dissolve_map,
+                                                       mtl_path,
+                                                       Texture::open_mask,
+                                                       &mut material_files),
+                               });
+                u32(materials.len() - 1).unwrap()
+            }
+            None => {
+                if !name.is_empty() {
+                    println!("warning: material {} is not defined", name);
+                }
+                0
+            }
+        };
+        for range in &group.polygons {
+            for m in &mut polygon_materials[range.start..range.end] {
+                *m = id;
+            }
+        }
+    }
+
+    // Faces outside of any group are in the group `default`. The other groups are numbered in
+    // the order in which they start, and faces in several groups go to the last of them.
+    let mut group_starts: Vec<(usize, &String)> = o.groups
+        .iter()
+        .filter(|&(name, _)| name != "default")
+        .map(|(name, group)| (group.polygons.iter().map(|r| r.start).min().unwrap_or(0), name))
+        .collect();
+    group_starts.sort();
+    let mut groups = vec!["default".to_string()];
+    let mut polygon_groups = vec![0; o.polygons.len()];
+    for (_, name) in group_starts {
+        let id = u32(groups.len()).unwrap();
+        groups.push(name.clone());
+        for range in &o.groups[name].polygons {
+            for g in &mut polygon_groups[range.start..range.end] {
+                *g = id;
+            }
+        }
+    }
+
+    if o.positions.len() > MAX_TRIS {
+        return Err(too_large(path, o.positions.len(), "vertices"));
+    }
+    let vertices: Vec<Vector3<f32>> =
+        o.positions.iter().map(|&(x, y, z, _)| vec3(x, y, z)).collect();
+    let uv = |i: Option<usize>| match i {
+        Some(i) => {
+            let (u, v, _) = o.tex_coords[i];
+            vec2(u, v)
+        }
+        None => vec2(0.0, 0.0),
+    };
+    let mut stats = MeshStats::default();
+    let mut tris = Vec::new();
+    let mut tri_materials = Vec::new();
+    let mut tri_uvs = Vec::new();
+    let mut tri_groups = Vec::new();
+    let polygon_info = polygon_materials.iter().zip(&polygon_groups);
+    for (polygon, (&material, &group)) in o.polygons.iter().zip(polygon_info) {
+        let corners: Vec<(usize, Option<usize>)> = match *polygon {
+            Polygon::P(ref vs) => vs.iter().map(|&p| (p, None)).collect(),
+            Polygon::PT(ref vs) => vs.iter().map(|&(p, t)| (p, Some(t))).collect(),
+            Polygon::PN(ref vs) => vs.iter().map(|&(p, _)| (p, None)).collect(),
+            Polygon::PTN(ref vs) => vs.iter().map(|&(p, t, _)| (p, Some(t))).collect(),
+        };
+        for i in 1..corners.len().saturating_sub(1) {
+            let (a, b, c) = (corners[0], corners[i], corners[i + 1]);
+            if !check_tri(&mut stats, vertices[a.0], vertices[b.0], vertices[c.0]) {
+                continue;
+            }
+            tris.push(Tri {
+                          a: index(a.0),
+                          b: index(b.0),
+                          c: index(c.0),
+                      });
+            tri_materials.push(material);
+            tri_uvs.push([uv(a.1), uv(b.1), uv(c.1)]);
+            tri_groups.push(group);
+        }
+    }
+    finish_mesh(path,
+                Mesh {
+                    geometry: TriMesh::new(vertices, tris),
+                    materials,
+                    tri_materials,
+                    tri_uvs,
+                    groups,
+                    tri_groups,
+                    vertex_colors: Vec::new(),
+                    material_files,
+                    stats,
+                })
+}
+
+/// Read a PLY or STL file with `read`, which is one of the loaders that only produce geometry.
+fn read_geometry<F>(path: &Path, read: F) -> Result<Mesh, String>
+    where F: FnOnce(&mut BufRead) -> Result<Geometry, String>
+{
+    let mut input = input::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let geometry = read(&mut *input).map_err(|e| format!("{}: {}", path.display(), e))?;
+    mesh_from_geometry(path, geometry)
+}
+
+/// The mesh for bare geometry, from `fast_obj` or a PLY or STL file, with the default material
+/// everywhere.
+fn mesh_from_geometry(path: &Path, geometry: Geometry) -> Result<Mesh, String> {
+    let vertices = geometry.vertices;
+    if vertices.len() > MAX_TRIS {
+        return Err(too_large(path, vertices.len(), "vertices"));
+    }
+    let (groups, tri_groups) = if geometry.groups.is_empty() {
+        (vec!["default".to_string()], vec![0; geometry.tris.len()])
+    } else {
+        (geometry.groups, geometry.tri_groups)
+    };
+    let mut stats = MeshStats::default();
+    let (tris, tri_groups): (Vec<Tri>, Vec<u32>) = geometry.tris
+        .into_iter()
+        .zip(tri_groups)
+        .filter(|&([a, b, c], _)| check_tri(&mut stats, vertices[a], vertices[b], vertices[c]))
+        .map(|([a, b, c], group)| {
+                 (Tri {
+                      a: index(a),
+                      b: index(b),
+                      c: index(c),
+                  },
+                  group)
+             })
+        .unzip();
+    let tri_count = tris.len();
+    finish_mesh(path,
+                Mesh {
+                    geometry: TriMesh::new(vertices, tris),
+                    materials: vec![default_material()],
+                    tri_materials: vec![0; tri_count],
+                    tri_uvs: vec![[vec2(0.0, 0.0); 3]; tri_count],
+                    groups,
+                    tri_groups,
+                    vertex_colors: geometry.colors,
+                    material_files: Vec::new(),
+                    stats,
+                })
+}
+
+/// Faces without a material get this one.
+fn default_material() -> SceneMaterial {
+    SceneMaterial {
+        name: "default".to_string(),
+        material: Material::default(),
+        albedo_map: None,
+        alpha_map: None,
+    }
+}
+
+/// Whether a triangle with these corners should be kept, counting it in `stats` if not.
+fn check_tri(stats: &mut MeshStats, a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> bool {
+    if !is_finite(a) || !is_finite(b) || !is_finite(c) {
+        stats.non_finite_tris += 1;
+        false
+    } else if (b - a).cross(c - a).magnitude2() == 0.0 {
+        stats.degenerate_tris += 1;
+        false
+    } else {
+        true
+    }
+}
+
+/// The checks and statistics shared by both OBJ loaders.
+fn finish_mesh(path: &Path, mut mesh: Mesh) -> Result<Mesh, String> {
+    if mesh.geometry.tris.len() > MAX_TRIS {
+        return Err(too_large(path, mesh.geometry.tris.len(), "triangles"));
+    }
+    let stats = &mut mesh.stats;
+    if stats.degenerate_tris + stats.non_finite_tris > 0 {
+        println!("warning: dropped {} degenerate triangles and {} with NaN or infinite vertices",
+                 stats.degenerate_tris,
+                 stats.non_finite_tris);
+    }
+    let mut seen = HashSet::new();
+    stats.duplicate_positions = mesh.geometry
+        .vertices
+        .iter()
+        .filter(|v| !seen.insert((v.x.to_bits(), v.y.to_bits(), v.z.to_bits())))
+        .count();
+    Ok(mesh)
+}
+
+/// Replace all materials of `mesh` with a diffuse one of the given albedo. The default material
+/// stays at index 0 (unused), like in every other mesh, so that `merge` can share it.
+fn paint(mesh: &mut Mesh, albedo: Rgb) {
+    mesh.materials = vec![default_material(),
+                          SceneMaterial {
+                              name: format!("part color {},{},{}", albedo.r, albedo.g, albedo.b),
+                              material: Material::Diffuse { albedo },
+                              albedo_map: None,
+                              alpha_map: None,
+                          }];
+    for m in &mut mesh.tri_materials {
+        *m = 1;
+    }
+}
+
+/// Put the meshes of several files into one. The default material (index 0) of every mesh
+/// becomes the one of the merged mesh; all other materials are kept separately.
+fn merge(meshes: Vec<Mesh>) -> Result<Mesh, String> {
+    let mut vertices = Vec::new();
+    let mut tris = Vec::new();
+    let mut materials = vec![default_material()];
+    let mut tri_materials = Vec::new();
+    let mut tri_uvs = Vec::new();
+    let mut groups = Vec::new();
+    let mut tri_groups = Vec::new();
+    let has_colors = meshes.iter().any(|mesh| !mesh.vertex_colors.is_empty());
+    let mut vertex_colors = Vec::new();
+    let mut material_files = Vec::new();
+    let mut stats = MeshStats::default();
+    for mesh in meshes {
+        let group_offset = u32(groups.len()).unwrap();
+        let vertex_offset = vertices.len();
+        let material_offset = u32(materials.len() - 1).unwrap();
+        if vertex_offset + mesh.geometry.vertices.len() > MAX_TRIS {
+            return Err(too_large(Path::new("all input files"),
+                                 vertex_offset + mesh.geometry.vertices.len(),
+                                 "vertices"));
+        }
+        if tris.len() + mesh.geometry.tris.len() > MAX_TRIS {
+            return Err(too_large(Path::new("all input files"),
+                                 tris.len() + mesh.geometry.tris.len(),
+                                 "triangles"));
+        }
+        vertices.extend(mesh.geometry.vertices);
+        if has_colors {
+            vertex_colors.extend(mesh.vertex_colors);
+            vertex_colors.resize(vertices.len(), NO_VERTEX_COLOR);
+        }
+        tris.extend(mesh.geometry.tris.iter().map(|tri| {
             Tri {
-                a: Vector3::from(o.vertices[i].position),
-                b: Vector3::from(o.vertices[j].position),
-                c: Vector3::from(o.vertices[k].position),
+                a: index(usize(tri.a) + vertex_offset),
+                b: index(usize(tri.b) + vertex_offset),
+                c: index(usize(tri.c) + vertex_offset),
+            }
+        }));
+        materials.extend(mesh.materials.into_iter().skip(1));
+        tri_materials.extend(mesh.tri_materials
+                                 .iter()
+                                 .map(|&m| if m == 0 { 0 } else { m + material_offset }));
+        tri_uvs.extend(mesh.tri_uvs);
+        groups.extend(mesh.groups);
+        tri_groups.extend(mesh.tri_groups.iter().map(|&g| g + group_offset));
+        material_files.extend(mesh.material_files);
+        stats.duplicate_positions += mesh.stats.duplicate_positions;
+        stats.degenerate_tris += mesh.stats.degenerate_tris;
+        stats.non_finite_tris += mesh.stats.non_finite_tris;
+    }
+    Ok(Mesh {
+           geometry: TriMesh::new(vertices, tris),
+           materials,
+           tri_materials,
+           tri_uvs,
+           groups,
+           tri_groups,
+           vertex_colors,
+           material_files,
+           stats,
+       })
+}
+
+/// Remove the triangles that aren't in one of the groups named in `only` (unless it's empty)
+/// or that are in one of the groups named in `exclude`. Groups from different files that have
+/// the same name are treated alike.
+fn filter_groups(mesh: &mut Mesh, only: &[String], exclude: &[String]) -> Result<(), String> {
+    for name in only.iter().chain(exclude) {
+        if !mesh.groups.contains(name) {
+            println!("warning: there is no group {}", name);
+        }
+    }
+    let keep_group: Vec<bool> = mesh.groups
+        .iter()
+        .map(|name| (only.is_empty() || only.contains(name)) && !exclude.contains(name))
+        .collect();
+    let keep: Vec<bool> = mesh.tri_groups.iter().map(|&g| keep_group[usize(g)]).collect();
+    retain_kept(&mut mesh.geometry.tris, &keep);
+    retain_kept(&mut mesh.tri_materials, &keep);
+    retain_kept(&mut mesh.tri_uvs, &keep);
+    retain_kept(&mut mesh.tri_groups, &keep);
+    if mesh.geometry.tris.is_empty() {
+        return Err("no triangles are left after filtering by group".to_string());
+    }
+    Ok(())
+}
+
+fn too_large(path: &Path, count: usize, what: &str) -> String {
+    let hint = if cfg!(feature = "large-scenes") {
+        ""
+    } else {
+        " (rebuild with the large-scenes feature for more)"
+    };
+    format!("{}: {} {}, but at most {} are supported{}",
+            path.display(),
+            count,
+            what,
+            MAX_TRIS,
+            hint)
+}
+
+/// Merge vertices that are within `epsilon` of each other (along every axis), then remove the
+/// triangles that became degenerate and all but the first copy of duplicated ones, regardless
+/// of their winding order. Vertices that no triangle uses anymore are removed as well.
+fn weld(mesh: &mut Mesh, epsilon: f32) {
+    // Vertices are merged into the first vertex found nearby. To find those, the vertices are
+    // bucketed in a grid with cells of size epsilon, so only the 27 surrounding cells need to
+    // be checked. With epsilon = 0 the cells are the exact positions. Far from the origin the
+    // cell coordinates are clamped, so that the neighbors' coordinates can't overflow; vertices
+    // in the same cell are still only merged if they're close.
+    let coord = |x: f32| (f64(x) / f64(epsilon)).floor().max(-9e18).min(9e18) as i64;
+    let cell = |v: Vector3<f32>| if epsilon > 0.0 {
+        (coord(v.x), coord(v.y), coord(v.z))
+    } else {
+        (i64::from(v.x.to_bits()), i64::from(v.y.to_bits()), i64::from(v.z.to_bits()))
+    };
+    let reach = if epsilon > 0.0 { 1 } else { 0 };
+    let geometry = &mut mesh.geometry;
+    let mut merged: Vec<Vector3<f32>> = Vec::new();
+    let mut grid: HashMap<(i64, i64, i64), Vec<Index>> = HashMap::new();
+    // Only look at vertices that are used, so that unused ones are dropped.
+    let old_vertices = mem::replace(&mut geometry.vertices, Vec::new());
+    // Merged vertices keep the color of the first one.
+    let old_colors = mem::replace(&mut mesh.vertex_colors, Vec::new());
+    let mut merged_colors = Vec::with_capacity(old_colors.len());
+    let mut remap: Vec<Option<Index>> = vec![None; old_vertices.len()];
+    let mut weld_vertex = |i: Index| {
+        if let Some(id) = remap[usize(i)] {
+            return (id, merged[usize(id)]);
+        }
+        let v = old_vertices[usize(i)];
+        let (x, y, z) = cell(v);
+        let mut found = None;
+        'search: for dx in -reach..reach + 1 {
+            for dy in -reach..reach + 1 {
+                for dz in -reach..reach + 1 {
+                    let candidates = match grid.get(&(x + dx, y + dy, z + dz)) {
+                        Some(candidates) => candidates,
+                        None => continue,
+                    };
+                    for &id in candidates {
+                        let d = merged[usize(id)] - v;
+                        if d.x.abs() <= epsilon && d.y.abs() <= epsilon && d.z.abs() <= epsilon {
+                            found = Some(id);
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+        let id = found.unwrap_or_else(|| {
+            let id = index(merged.len());
+            merged.push(v);
+            if !old_colors.is_empty() {
+                merged_colors.push(old_colors[usize(i)]);
+            }
+            grid.entry((x, y, z)).or_insert_with(Vec::new).push(id);
+            id
+        });
+        remap[usize(i)] = Some(id);
+        (id, merged[usize(id)])
+    };
+
+    let mut seen = HashSet::new();
+    let mut keep = Vec::with_capacity(geometry.tris.len());
+    for tri in &mut geometry.tris {
+        let (a, b, c) = (weld_vertex(tri.a), weld_vertex(tri.b), weld_vertex(tri.c));
+        tri.a = a.0;
+        tri.b = b.0;
+        tri.c = c.0;
+        let mut ids = [a.0, b.0, c.0];
+        ids.sort();
+        let degenerate = ids[0] == ids[1] || ids[1] == ids[2] ||
+                         (b.1 - a.1).cross(c.1 - a.1).magnitude2() == 0.0;
+        keep.push(!degenerate && seen.insert(ids));
+    }
+    let used_vertices = remap.iter().filter(|id| id.is_some()).count();
+    mesh.stats.welded_vertices = used_vertices - merged.len();
+    geometry.vertices = merged;
+    mesh.vertex_colors = merged_colors;
+    let before = geometry.tris.len();
+    retain_kept(&mut geometry.tris, &keep);
+    retain_kept(&mut mesh.tri_materials, &keep);
+    retain_kept(&mut mesh.tri_uvs, &keep);
+    retain_kept(&mut mesh.tri_groups, &keep);
+    mesh.stats.welded_tris = before - geometry.tris.len();
+    println!("welding merged {} vertices and removed {} triangles",
+             mesh.stats.welded_vertices,
+             mesh.stats.welded_tris);
+}
+
+/// Simplify the mesh to about `ratio` of its triangles, keeping the attributes of the triangles
+/// that are left.
+fn decimate(mesh: &mut Mesh, ratio: f32) {
+    let (keep, report) = decimate::decimate(&mut mesh.geometry, ratio);
+    retain_kept(&mut mesh.geometry.tris, &keep);
+    retain_kept(&mut mesh.tri_materials, &keep);
+    retain_kept(&mut mesh.tri_uvs, &keep);
+    retain_kept(&mut mesh.tri_groups, &keep);
+    println!("decimation left {} of {} triangles, error {} max, {} mean",
+             report.tris_after,
+             report.tris_before,
+             report.max_error,
+             report.mean_error);
+}
+
+/// Split every triangle into four at the midpoints of its edges, `levels` times, to get a mesh
+/// with 4^levels as many triangles but the same surface. Neighbors share their midpoints, so
+/// the result is as watertight as the input.
+fn tessellate(mesh: &mut Mesh, levels: u32) {
+    let before = mesh.geometry.tris.len();
+    let after = (0..levels).fold(before, |n, _| n.saturating_mul(4));
+    if after > MAX_TRIS {
+        fail(&too_large(Path::new("the tessellated mesh"), after, "triangles"));
+    }
+    for _ in 0..levels {
+        let vertices = &mut mesh.geometry.vertices;
+        let colors = &mut mesh.vertex_colors;
+        let mut midpoints: HashMap<(Index, Index), Index> = HashMap::new();
+        let mut midpoint = |a: Index, b: Index| {
+            let key = if a < b { (a, b) } else { (b, a) };
+            *midpoints.entry(key).or_insert_with(|| {
+                let (a, b) = (usize(a), usize(b));
+                vertices.push((vertices[a] + vertices[b]) * 0.5);
+                if !colors.is_empty() {
+                    let (ca, cb) = (colors[a], colors[b]);
+                    let uncolored = ca == NO_VERTEX_COLOR || cb == NO_VERTEX_COLOR;
+                    colors.push(if uncolored { NO_VERTEX_COLOR } else { (ca + cb) * 0.5 });
+                }
+                index(vertices.len() - 1)
+            })
+        };
+        let old_tris = mem::replace(&mut mesh.geometry.tris, Vec::new());
+        let mut tris = Vec::with_capacity(4 * old_tris.len());
+        let mut tri_uvs = Vec::with_capacity(4 * old_tris.len());
+        for (tri, uv) in old_tris.iter().zip(&mesh.tri_uvs) {
+            let (ab, bc, ca) =
+                (midpoint(tri.a, tri.b), midpoint(tri.b, tri.c), midpoint(tri.c, tri.a));
+            let (uv_ab, uv_bc, uv_ca) =
+                ((uv[0] + uv[1]) * 0.5, (uv[1] + uv[2]) * 0.5, (uv[2] + uv[0]) * 0.5);
+            tris.extend(vec![Tri { a: tri.a, b: ab, c: ca },
+                             Tri { a: ab, b: tri.b, c: bc },
+                             Tri { a: ca, b: bc, c: tri.c },
+                             Tri { a: ab, b: bc, c: ca }]);
+            tri_uvs.extend(vec![[uv[0], uv_ab, uv_ca],
+                                [uv_ab, uv[1], uv_bc],
+                                [uv_ca, uv_bc, uv[2]],
+                                [uv_ab, uv_bc, uv_ca]]);
+        }
+        mesh.geometry.tris = tris;
+        mesh.tri_uvs = tri_uvs;
+        let repeat = |v: &[u32]| v.iter().flat_map(|&x| iter::repeat(x).take(4)).collect();
+        mesh.tri_materials = repeat(&mesh.tri_materials);
+        mesh.tri_groups = repeat(&mesh.tri_groups);
+        if mesh.geometry.vertices.len() > MAX_TRIS {
+            fail(&too_large(Path::new("the tessellated mesh"),
+                            mesh.geometry.vertices.len(),
+                            "vertices"));
+        }
+    }
+    println!("tessellation turned {} triangles into {}", before, after);
+}
+
+/// Remove the elements of `v` whose entry in `keep` is false.
+fn retain_kept<T: Clone>(v: &mut Vec<T>, keep: &[bool]) {
+    *v = v.iter().zip(keep).filter(|&(_, &k)| k).map(|(x, _)| x.clone()).collect();
+}
+
+fn is_finite(v: Vector3<f32>) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+/// Read the materials from all the MTL files an OBJ file references, along with the path of
+/// the file each is from. Missing or broken files only cause a warning, since the geometry can
+/// still be rendered with default materials. The paths of the files are added to `files`.
+fn read_mtls(obj_path: &Path,
+             libs: &[String],
+             files: &mut Vec<PathBuf>)
+             -> HashMap<String, (MtlMaterial, PathBuf)> {
+    let mut materials = HashMap::new();
+    for lib in libs {
+        let path = obj_path.with_file_name(lib);
+        files.push(path.clone());
+        let mtl = File::open(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| raw::parse_mtl(BufReader::new(f)).map_err(|e| format!("{:?}", e)));
+        match mtl {
+            Ok(mtl) => {
+                for (name, m) in mtl.materials {
+                    materials.insert(name, (m, path.clone()));
+                }
+            }
+            Err(e) => println!("warning: could not read {}: {}", path.display(), e),
+        }
+    }
+    materials
+}
+
+/// Load a texture referenced by an MTL file, if there is one. A diffuse texture (`map_Kd`)
+/// replaces the diffuse color (`Kd`) rather than being multiplied with it, since exporters tend
+/// to write some arbitrary grey for `Kd` when there's a texture. The path is added to `files`.
+fn read_map<F>(map: &Option<MtlTextureMap>,
+               mtl_path: &Path,
+               open: F,
+               files: &mut Vec<PathBuf>)
+               -> Option<Texture>
+    where F: Fn(&Path) -> Result<Texture, String>
+{
+    map.as_ref().and_then(|map| {
+        let path = mtl_path.with_file_name(&map.file);
+        files.push(path.clone());
+        match open(&path) {
+            Ok(texture) => Some(texture),
+            Err(e) => {
+                println!("warning: could not load texture {}", e);
+                None
             }
-        })
-        .collect()
+        }
+    })
+}
+
+/// Materials with a specular exponent (`Ns`) at least this large are treated as mirrors.
+const MIRROR_EXPONENT: f32 = 1000.0;
+
+/// Convert a Phong specular exponent to a GGX roughness with a similar highlight
+/// (Walter et al. 2007, section 5.2).
+fn exponent_to_roughness(ns: f32) -> f32 {
+    (2.0 / (ns.max(0.0) + 2.0)).sqrt()
+}
+
+/// Pick the material that best matches an MTL material, based on its illumination model.
+/// Materials with highlights (`illum 2`) that have a specular color (`Ks`) become glossy, with
+/// a roughness derived from the specular exponent (`Ns`).
+fn material_from_mtl(mtl: &MtlMaterial) -> Material {
+    let color = |c: &Option<MtlColor>| match *c {
+        Some(MtlColor::Rgb(r, g, b)) => Some(Rgb::new(r, g, b)),
+        _ => None,
+    };
+    let diffuse = color(&mtl.diffuse).unwrap_or(Rgb::grey(DEFAULT_ALBEDO));
+    let mirror = Material::Mirror { reflectance: color(&mtl.specular).unwrap_or(Rgb::grey(1.0)) };
+    match mtl.illumination_model {
+        // Refraction and ray traced reflection
+        Some(4) | Some(6) | Some(7) => Material::Glass { ior: mtl.optical_density.unwrap_or(1.5) },
+        // Ray traced reflection
+        Some(3) | Some(5) => mirror,
+        _ if mtl.specular_exponent.map_or(false, |ns| ns >= MIRROR_EXPONENT) => mirror,
+        Some(2) if color(&mtl.specular).map_or(false, |ks| !ks.is_black()) => {
+            Material::Glossy {
+                diffuse,
+                specular: color(&mtl.specular).unwrap(),
+                alpha: exponent_to_roughness(mtl.specular_exponent.unwrap_or(0.0)),
+            }
+        }
+        _ => Material::Diffuse { albedo: diffuse },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cli::config_from_args;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    /// An ASCII STL file with one small triangle at each of the given `x`, all at height `y`.
+    fn write_stl(path: &Path, xs: &[f32], y: f32) {
+        let mut f = File::create(path).unwrap();
+        writeln!(f, "solid test").unwrap();
+        for &x in xs {
+            writeln!(f, "facet normal 0 0 1\nouter loop").unwrap();
+            for &(dx, dy) in &[(0.0, 0.0), (0.5, 0.0), (0.0, 0.5)] {
+                writeln!(f, "vertex {} {} 0", x + dx, y + dy).unwrap();
+            }
+            writeln!(f, "endloop\nendfacet").unwrap();
+        }
+        writeln!(f, "endsolid test").unwrap();
+    }
+
+    #[test]
+    fn rebuild_keeps_groups_with_their_triangles() {
+        let dir = env::temp_dir().join(format!("suptracer-rebuild-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // The two files (groups 0 and 1) cover the two halves of the x range, at different heights.
+        let (low, high) = (dir.join("low.stl"), dir.join("high.stl"));
+        write_stl(&low, &[0.0, 1.0, 2.0, 3.0], 0.0);
+        write_stl(&high, &[4.0, 5.0, 6.0, 7.0], 3.0);
+        let args = |builder| {
+            config_from_args(vec!["suptracer",
+                                  low.to_str().unwrap(),
+                                  high.to_str().unwrap(),
+                                  "--bvh-builder",
+                                  builder,
+                                  "--max-leaf-tris",
+                                  "1"])
+        };
+        let mut scene = Scene::new(&args("median"));
+        // Turning the scene around reverses the order along x, so the rebuild must reorder.
+        let rest_pose = scene.mesh.vertices.clone();
+        scene.spin(&rest_pose, vec3(3.5, 0.0, 0.0), f32::consts::PI);
+        scene.rebuild_bvh(&args("middle"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(scene.tri_groups.len(), 8);
+        for (tri_id, &group) in scene.tri_groups.iter().enumerate() {
+            let (a, _, _) = scene.mesh.corners(index(tri_id));
+            assert_eq!(group, if a.y < 1.0 { 0 } else { 1 }, "triangle {} at {:?}", tri_id, a);
+        }
+    }
+
+    #[test]
+    fn load_reports_partly_written_files() {
+        let path = env::temp_dir().join(format!("suptracer-partial-{}.stl", process::id()));
+        fs::write(&path, "solid test\nfacet normal 0 0 1\nouter loop\nvertex 0 0").unwrap();
+        let result = Scene::load(&config_from_args(vec!["suptracer", path.to_str().unwrap()]));
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
 }
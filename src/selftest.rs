@@ -0,0 +1,120 @@
+//! Regression tests against reference images, for `suptracer selftest`.
+//!
+//! A few tiny meshes are rendered with fixed settings and compared to the reference images next
+//! to them. Each kind of image is rendered with every traversal, renderer and BVH builder, and
+//! all of them must match the same reference, since none of these should change what's seen.
+//! `--bless` writes new references, rendered with the default traversal and builder.
+
+use super::{Config, SelfTest, fail, renderer};
+use bmp;
+use camera::Camera;
+use cast::f32;
+use cli;
+use film;
+use scene::Scene;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process;
+
+/// The meshes rendered, in the test directory.
+const MESHES: &[&str] = &["tetrahedron.stl", "cube.ply", "octahedron.obj"];
+/// The kinds of images compared. They don't depend on the BVH, unlike heatmaps.
+const KINDS: &[&str] = &["depth", "normal", "shaded"];
+/// Settings that must not change the image, each a list of extra arguments. The first is what
+/// references are rendered with.
+const VARIANTS: &[&[&str]] = &[&[],
+                               &["--traversal", "packet"],
+                               &["--traversal", "frustum"],
+                               &["--traversal", "stackless"],
+                               &["--renderer", "wavefront"],
+                               &["--bvh-builder", "median"],
+                               &["--bvh-builder", "middle"],
+                               &["--bvh-layout", "compressed"],
+                               &["--precision", "f64"]];
+const SIZE: &str = "48x48";
+
+/// How much a color channel may differ from the reference before the pixel counts as different.
+const TOLERANCE: u8 = 2;
+/// The fraction of the pixels that may differ, to allow for rays that graze an edge.
+const MAX_DIFFERENT: f32 = 0.005;
+
+pub fn run(test: &SelfTest) {
+    let mut failures = 0;
+    let mut total = 0;
+    for mesh in MESHES {
+        for kind in KINDS {
+            let stem = Path::new(mesh).file_stem().unwrap().to_string_lossy().into_owned();
+            let reference_file = test.dir.join(format!("{}_{}.bmp", stem, kind));
+            let variants = if test.bless { &VARIANTS[..1] } else { VARIANTS };
+            for variant in variants {
+                let mut args: Vec<OsString> = vec!["suptracer".into(),
+                                                   test.dir.join(mesh).into_os_string(),
+                                                   "--dim".into(),
+                                                   SIZE.into(),
+                                                   "--kind".into(),
+                                                   kind.into()];
+                args.extend(variant.iter().map(OsString::from));
+                let cfg = cli::config_from_args(args);
+                let image = render(&cfg);
+                let name = format!("{} {} {}", mesh, kind, variant.join(" "));
+                total += 1;
+                if test.bless {
+                    film::save(&*image, &reference_file).unwrap_or_else(|e| fail(&e));
+                    println!("wrote {}", reference_file.display());
+                    continue;
+                }
+                match compare(&image.to_bmp(), &reference_file) {
+                    Ok(()) => println!("ok      {}", name),
+                    Err(msg) => {
+                        println!("FAILED  {}: {}", name, msg);
+                        failures += 1;
+                    }
+                }
+            }
+        }
+    }
+    if test.bless {
+        return;
+    }
+    println!("{} of {} images match their reference", total - failures, total);
+    if failures > 0 {
+        process::exit(1);
+    }
+}
+
+fn render(cfg: &Config) -> Box<film::ToBmp> {
+    let scene = Scene::new(cfg);
+    let camera = Camera::new(cfg, scene.bbox());
+    renderer(cfg.render_kind)(&scene, cfg, &camera)
+}
+
+fn compare(image: &bmp::Image, reference_file: &Path) -> Result<(), String> {
+    let reference = bmp::open(reference_file).map_err(|e| {
+        format!("could not read {} ({}), run `suptracer selftest --bless` to create it",
+                reference_file.display(),
+                e)
+    })?;
+    let (w, h) = (image.get_width(), image.get_height());
+    if (reference.get_width(), reference.get_height()) != (w, h) {
+        return Err(format!("the reference is {}x{}, not {}x{}",
+                           reference.get_width(),
+                           reference.get_height(),
+                           w,
+                           h));
+    }
+    let mut different = 0u32;
+    for y in 0..h {
+        for x in 0..w {
+            let (a, b) = (image.get_pixel(x, y), reference.get_pixel(x, y));
+            let differs = |p: u8, q: u8| p.max(q) - p.min(q) > TOLERANCE;
+            if differs(a.r, b.r) || differs(a.g, b.g) || differs(a.b, b.b) {
+                different += 1;
+            }
+        }
+    }
+    if f32(different) > MAX_DIFFERENT * f32(w * h) {
+        Err(format!("{} pixels differ", different))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! A tiny HTTP server for watching renders from a browser, e.g. on a remote machine without a
+//! display.
+//!
+//! Every image the renderer publishes (one per pass with `--passes`) is encoded once as JPEG.
+//! `/` streams them as MJPEG (`multipart/x-mixed-replace`), which browsers show as a live image,
+//! and `/frame.png` answers with a PNG snapshot of the latest one, for clients that would rather
+//! poll. Each connection gets its own thread, so slow clients don't hold up the renderer.
+//! Only the local machine can connect unless another address is given, e.g. 0.0.0.0.
+
+use cast::usize;
+use film::ToBmp;
+use image::ColorType;
+use image::jpeg::JPEGEncoder;
+use image::png::PNGEncoder;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+const JPEG_QUALITY: u8 = 90;
+
+/// The latest published image.
+#[derive(Clone)]
+struct Snapshot {
+    /// Counts the published images, so that streams can tell when there's a new one.
+    generation: u64,
+    width: u32,
+    height: u32,
+    rgb: Arc<Vec<u8>>,
+    jpeg: Arc<Vec<u8>>,
+}
+
+type Latest = Arc<(Mutex<Option<Snapshot>>, Condvar)>;
+
+/// The rendering side of the server.
+pub struct Preview {
+    latest: Latest,
+    /// The thread accepting connections, which runs until the process ends.
+    server: JoinHandle<()>,
+}
+
+/// Listen on `port` at `address`, in the background.
+pub fn start(address: IpAddr, port: u16) -> io::Result<Preview> {
+    let listener = TcpListener::bind((address, port))?;
+    let latest: Latest = Arc::new((Mutex::new(None), Condvar::new()));
+    let server_latest = latest.clone();
+    let server = thread::spawn(move || for stream in listener.incoming() {
+                                   if let Ok(stream) = stream {
+                                       let latest = server_latest.clone();
+                                       // Errors only mean that the client went away.
+                                       thread::spawn(move || handle(stream, &latest).ok());
+                                   }
+                               });
+    let host = if address.is_loopback() {
+        "localhost".to_string()
+    } else {
+        address.to_string()
+    };
+    println!("serving the render at http://{}:{}/", host, port);
+    Ok(Preview { latest, server })
+}
+
+impl Preview {
+    /// Keep serving the latest image until the process is interrupted.
+    pub fn wait(self) {
+        println!("done rendering, still serving until interrupted");
+        self.server.join().expect("BUG: the server thread panicked");
+    }
+
+    /// Show `image` to all clients from now on.
+    pub fn publish(&self, image: &ToBmp) {
+        let img = image.to_bmp();
+        let (width, height) = (img.get_width(), img.get_height());
+        let mut rgb = Vec::with_capacity(3 * usize(width) * usize(height));
+        for y in 0..height {
+            for x in 0..width {
+                let px = img.get_pixel(x, y);
+                rgb.extend_from_slice(&[px.r, px.g, px.b]);
+            }
+        }
+        let mut jpeg = Vec::new();
+        JPEGEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+            .encode(&rgb, width, height, ColorType::RGB(8))
+            .expect("BUG: encoding JPEG into memory failed");
+        let (ref lock, ref published) = *self.latest;
+        let mut latest = lock.lock().unwrap();
+        let generation = latest.as_ref().map_or(0, |s| s.generation + 1);
+        *latest = Some(Snapshot {
+                           generation,
+                           width,
+                           height,
+                           rgb: Arc::new(rgb),
+                           jpeg: Arc::new(jpeg),
+                       });
+        published.notify_all();
+    }
+}
+
+/// Wait until there's an image newer than generation `seen` (any image if it's None).
+fn wait_for_image(latest: &Latest, seen: Option<u64>) -> Snapshot {
+    let (ref lock, ref published) = **latest;
+    let mut guard = lock.lock().unwrap();
+    loop {
+        if let Some(ref s) = *guard {
+            if seen.map_or(true, |seen| s.generation > seen) {
+                return s.clone();
+            }
+        }
+        guard = published.wait(guard).unwrap();
+    }
+}
+
+fn handle(stream: TcpStream, latest: &Latest) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, none of them matter.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut out = stream;
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    match path {
+        "/" => {
+            write!(out,
+                   "HTTP/1.0 200 OK\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\r\n")?;
+            let mut seen = None;
+            loop {
+                let snapshot = wait_for_image(latest, seen);
+                write!(out,
+                       "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                       snapshot.jpeg.len())?;
+                out.write_all(&snapshot.jpeg)?;
+                write!(out, "\r\n")?;
+                out.flush()?;
+                seen = Some(snapshot.generation);
+            }
+        }
+        "/frame.png" => {
+            let snapshot = wait_for_image(latest, None);
+            let mut png = Vec::new();
+            PNGEncoder::new(&mut png)
+                .encode(&snapshot.rgb, snapshot.width, snapshot.height, ColorType::RGB(8))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            write!(out,
+                   "HTTP/1.0 200 OK\r\n\
+                    Cache-Control: no-cache\r\n\
+                    Content-Type: image/png\r\n\
+                    Content-Length: {}\r\n\r\n",
+                   png.len())?;
+            out.write_all(&png)
+        }
+        _ => write!(out, "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"),
+    }
+}
@@ -0,0 +1,99 @@
+use beebox::Aabb;
+use cgmath::{InnerSpace, Vector2, Vector3, vec2};
+use geom::Ray;
+use std::f32::consts::PI;
+
+/// An analytic primitive that is rendered next to the triangles of the mesh, e.g. for test
+/// scenes like a Cornell box with spheres that would look faceted if tessellated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shape {
+    Sphere { center: Vector3<f32>, radius: f32 },
+    /// The parallelogram spanned by `edge_u` and `edge_v` starting at `corner`. Unlike
+    /// rectangular lights, it's visible from both sides.
+    Quad {
+        corner: Vector3<f32>,
+        edge_u: Vector3<f32>,
+        edge_v: Vector3<f32>,
+    },
+}
+
+impl Shape {
+    /// The closest intersection with `r` between `r.t_min` and `t_max`, and the texture
+    /// coordinates of the point that was hit.
+    pub fn intersect(&self, r: &Ray, t_max: f32) -> Option<(f32, Vector2<f32>)> {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                // Solve |o + t * d - center| = radius for t, with the numerically stable
+                // formulation from Ray Tracing Gems, chapter 7.
+                let f = r.o - center;
+                let a = r.d.magnitude2();
+                let b = -f.dot(r.d);
+                let perp = f + r.d * (b / a);
+                let discriminant = radius * radius - perp.magnitude2();
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let c = f.magnitude2() - radius * radius;
+                let q = b + b.signum() * (a * discriminant).sqrt();
+                let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (c / q, q / a) };
+                let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+                let t = if t0 > r.t_min { t0 } else { t1 };
+                if t <= r.t_min || t >= t_max {
+                    return None;
+                }
+                let n = (r.o + r.d * t - center) / radius;
+                let u = 0.5 + n.z.atan2(n.x) / (2.0 * PI);
+                let v = 0.5 + n.y.max(-1.0).min(1.0).asin() / PI;
+                Some((t, vec2(u, v)))
+            }
+            Shape::Quad { corner, edge_u, edge_v } => {
+                let n = edge_u.cross(edge_v);
+                let cos = r.d.dot(n);
+                if cos == 0.0 {
+                    return None;
+                }
+                let t = (corner - r.o).dot(n) / cos;
+                if t <= r.t_min || t >= t_max {
+                    return None;
+                }
+                // Solve q = a * edge_u + b * edge_v, like `Light::intersect`.
+                let q = r.o + r.d * t - corner;
+                let n2 = n.magnitude2();
+                let a = q.cross(edge_v).dot(n) / n2;
+                let b = edge_u.cross(q).dot(n) / n2;
+                if a < 0.0 || a > 1.0 || b < 0.0 || b > 1.0 {
+                    return None;
+                }
+                Some((t, vec2(a, b)))
+            }
+        }
+    }
+
+    /// The unit normal at the point `p` on the surface, pointing outwards for spheres.
+    pub fn normal(&self, p: Vector3<f32>) -> Vector3<f32> {
+        match *self {
+            Shape::Sphere { center, .. } => (p - center).normalize(),
+            Shape::Quad { edge_u, edge_v, .. } => edge_u.cross(edge_v).normalize(),
+        }
+    }
+
+    /// Whether the surface is flat, so that a ray leaving it can't hit it again.
+    pub fn is_flat(&self) -> bool {
+        match *self {
+            Shape::Sphere { .. } => false,
+            Shape::Quad { .. } => true,
+        }
+    }
+
+    pub fn bbox(&self) -> Aabb {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                let r = Vector3::new(radius, radius, radius);
+                Aabb::new(vec![center - r, center + r])
+            }
+            Shape::Quad { corner, edge_u, edge_v } => {
+                Aabb::new(vec![corner, corner + edge_u, corner + edge_v, corner + edge_u + edge_v])
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use std::f32::consts::FRAC_PI_2;
+
+/// The sun's irradiance relative to the sky's zenith radiance.
+const SUN_IRRADIANCE: f32 = 5.0;
+/// What rays below the horizon see.
+const GROUND: f32 = 0.1;
+
+/// The analytic daylight model of Preetham, Shirley, and Smits,
+/// "A Practical Analytic Model for Daylight" (SIGGRAPH 1999).
+/// Radiance is normalized such that the zenith has luminance 1.
+pub struct Sky {
+    sun_dir: Vector3<f32>,
+    /// Perez coefficients A through E for the channels Y, x, y.
+    perez: [[f32; 5]; 3],
+    /// Value of Y, x, y at the zenith.
+    zenith: [f32; 3],
+}
+
+impl Sky {
+    /// Angles are in radians. Azimuth 0 is the -Z direction, increasing towards +X.
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32) -> Self {
+        let t = turbidity;
+        let (sin_el, cos_el) = sun_elevation.sin_cos();
+        let (sin_az, cos_az) = sun_azimuth.sin_cos();
+        let sun_dir = vec3(cos_el * sin_az, sin_el, -cos_el * cos_az);
+        let perez = [[0.1787 * t - 1.4630,
+                      -0.3554 * t + 0.4275,
+                      -0.0227 * t + 5.3251,
+                      0.1206 * t - 2.5771,
+                      -0.0670 * t + 0.3703],
+                     [-0.0193 * t - 0.2592,
+                      -0.0665 * t + 0.0008,
+                      -0.0004 * t + 0.2125,
+                      -0.0641 * t - 0.8989,
+                      -0.0033 * t + 0.0452],
+                     [-0.0167 * t - 0.2608,
+                      -0.0950 * t + 0.0092,
+                      -0.0079 * t + 0.2102,
+                      -0.0441 * t - 1.6537,
+                      -0.0109 * t + 0.0529]];
+        let theta_s = FRAC_PI_2 - sun_elevation;
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let x_z = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) +
+                  t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) +
+                  (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let y_z = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) +
+                  t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) +
+                  (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+        Sky {
+            sun_dir,
+            perez,
+            zenith: [1.0, x_z, y_z],
+        }
+    }
+
+    pub fn radiance(&self, d: Vector3<f32>) -> Rgb {
+        let d = d.normalize();
+        if d.y <= 0.0 {
+            return Rgb::grey(GROUND);
+        }
+        let cos_theta = d.y.max(0.01);
+        let cos_gamma = d.dot(self.sun_dir).max(-1.0).min(1.0);
+        let cos_theta_s = self.sun_dir.y;
+        let mut yxy = [0.0; 3];
+        for (i, coeffs) in self.perez.iter().enumerate() {
+            yxy[i] = self.zenith[i] * perez(coeffs, cos_theta, cos_gamma) /
+                     perez(coeffs, 1.0, cos_theta_s);
+        }
+        xyy_to_rgb(yxy[1], yxy[2], yxy[0])
+    }
+
+    /// Direction towards the sun and its irradiance, for use as a directional light.
+    pub fn sun(&self) -> (Vector3<f32>, Rgb) {
+        let color = self.radiance(self.sun_dir + vec3(0.0, 1e-3, 0.0));
+        let normalized = color / color.luminance().max(1e-6);
+        (self.sun_dir, normalized * SUN_IRRADIANCE)
+    }
+}
+
+/// The Perez sky luminance distribution function.
+fn perez(c: &[f32; 5], cos_theta: f32, cos_gamma: f32) -> f32 {
+    let gamma = cos_gamma.acos();
+    (1.0 + c[0] * (c[1] / cos_theta).exp()) *
+    (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+/// Convert CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Rgb {
+    let big_x = x / y * lum;
+    let big_z = (1.0 - x - y) / y * lum;
+    Rgb::new((3.2406 * big_x - 1.5372 * lum - 0.4986 * big_z).max(0.0),
+             (-0.9689 * big_x + 1.8758 * lum + 0.0415 * big_z).max(0.0),
+             (0.0557 * big_x - 0.2040 * lum + 1.0570 * big_z).max(0.0))
+}
@@ -0,0 +1,73 @@
+//! A loader for STL files, binary or ASCII. STL stores every triangle with its own three
+//! vertices, so nothing is shared between triangles (see `--weld-epsilon`).
+
+use cast::usize;
+use cgmath::{Vector3, vec3};
+use input::Geometry;
+use std::io::BufRead;
+use std::str;
+
+/// Size of the header of binary STL files, including the triangle count.
+const HEADER_BYTES: usize = 84;
+/// Size of a triangle in binary STL files: normal, corners and an attribute.
+const TRI_BYTES: usize = 50;
+
+pub fn read(read: &mut BufRead) -> Result<Geometry, String> {
+    let mut bytes = Vec::new();
+    read.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    // ASCII files start with "solid", but so do some binary ones, so the size decides.
+    if bytes.len() >= HEADER_BYTES {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]);
+        if bytes.len() == HEADER_BYTES + usize(count) * TRI_BYTES {
+            return Ok(read_binary(&bytes[HEADER_BYTES..]));
+        }
+    }
+    if bytes.starts_with(b"solid") {
+        let text = str::from_utf8(&bytes).map_err(|_| "ASCII STL that isn't UTF-8".to_string())?;
+        read_ascii(text)
+    } else {
+        Err("neither ASCII STL nor binary STL of the size its header says".to_string())
+    }
+}
+
+fn read_binary(bytes: &[u8]) -> Geometry {
+    let f32_at = |i: usize| {
+        f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+    };
+    let vertices: Vec<Vector3<f32>> = (0..bytes.len() / TRI_BYTES)
+        .flat_map(|tri| (0..3).map(move |corner| tri * TRI_BYTES + 12 * (corner + 1)))
+        .map(|i| vec3(f32_at(i), f32_at(i + 4), f32_at(i + 8)))
+        .collect();
+    unshared_tris(vertices)
+}
+
+fn read_ascii(text: &str) -> Result<Geometry, String> {
+    let mut vertices = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first() != Some(&"vertex") {
+            continue;
+        }
+        let coords: Vec<f32> = fields[1..].iter().filter_map(|s| s.parse().ok()).collect();
+        if coords.len() != 3 {
+            return Err(format!("malformed STL vertex '{}'", line.trim()));
+        }
+        vertices.push(vec3(coords[0], coords[1], coords[2]));
+    }
+    if vertices.len() % 3 != 0 {
+        return Err("STL facet without three vertices".to_string());
+    }
+    Ok(unshared_tris(vertices))
+}
+
+/// The triangles made of every three consecutive vertices.
+fn unshared_tris(vertices: Vec<Vector3<f32>>) -> Geometry {
+    let tris = (0..vertices.len() / 3).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+    Geometry {
+        vertices,
+        tris,
+        groups: Vec::new(),
+        tri_groups: Vec::new(),
+        colors: Vec::new(),
+    }
+}
@@ -0,0 +1,102 @@
+use cast::f32;
+use cgmath::Vector2;
+use color::Rgb;
+use image;
+use std::path::Path;
+
+/// An image that is mapped onto surfaces, storing linear RGB and alpha.
+pub struct Texture {
+    width: usize,
+    height: usize,
+    texels: Vec<Rgb>,
+    alpha: Vec<f32>,
+    /// Whether there are any (partially) transparent texels.
+    transparent: bool,
+}
+
+impl Texture {
+    /// Load an 8-bit image in any format the `image` crate understands, assuming it's sRGB.
+    pub fn open(path: &Path) -> Result<Texture, String> {
+        let img = image::open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .to_rgba();
+        let (width, height) = img.dimensions();
+        let texels = img.pixels()
+            .map(|px| {
+                     Rgb::new(srgb_to_linear(px.data[0]),
+                              srgb_to_linear(px.data[1]),
+                              srgb_to_linear(px.data[2]))
+                 })
+            .collect();
+        let alpha: Vec<f32> = img.pixels().map(|px| f32(px.data[3]) / 255.0).collect();
+        Ok(Texture {
+               width: width as usize,
+               height: height as usize,
+               texels,
+               transparent: alpha.iter().any(|&a| a < 1.0),
+               alpha,
+           })
+    }
+
+    /// Load a greyscale image as a white texture whose alpha is the brightness of the image,
+    /// like the `map_d` masks in MTL files.
+    pub fn open_mask(path: &Path) -> Result<Texture, String> {
+        let img = image::open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?
+            .to_luma();
+        let (width, height) = img.dimensions();
+        let alpha: Vec<f32> = img.pixels().map(|px| f32(px.data[0]) / 255.0).collect();
+        Ok(Texture {
+               width: width as usize,
+               height: height as usize,
+               texels: vec![Rgb::grey(1.0); alpha.len()],
+               transparent: alpha.iter().any(|&a| a < 1.0),
+               alpha,
+           })
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.transparent
+    }
+
+    /// Alpha of the texel containing `uv`. Not filtered, since it's only used for cutouts.
+    pub fn alpha(&self, uv: Vector2<f32>) -> f32 {
+        let x = (uv.x * f32(self.width)).floor() as i64;
+        let y = ((1.0 - uv.y) * f32(self.height)).floor() as i64;
+        self.alpha[self.index(x, y)]
+    }
+
+    /// Bilinearly filtered lookup. Texture coordinates outside [0, 1] wrap around, and v = 0 is
+    /// the bottom of the image.
+    pub fn sample(&self, uv: Vector2<f32>) -> Rgb {
+        let x = uv.x * f32(self.width) - 0.5;
+        let y = (1.0 - uv.y) * f32(self.height) - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Rgb {
+        self.texels[self.index(x, y)]
+    }
+
+    /// The index of a texel, wrapping around coordinates outside the texture.
+    fn index(&self, x: i64, y: i64) -> usize {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let x = ((x % w) + w) % w;
+        let y = ((y % h) + h) % h;
+        (y * w + x) as usize
+    }
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = f32(c) / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,148 @@
+//! A density grid rendered next to the mesh, e.g. the output of a simulation around a part.
+//!
+//! Grids are read from the binary `.vol` format of Mitsuba 0.x with one float channel. Primary
+//! rays march through the grid up to the closest surface with an emission/absorption model:
+//! each unit of density emits `emission` and absorbs a fraction `absorption` of the light per
+//! unit of length. The volume doesn't scatter light, cast shadows or show up in reflections.
+
+use super::Config;
+use beebox::Aabb;
+use cast::{f32, usize};
+use cgmath::{InnerSpace, Vector3, vec3};
+use color::Rgb;
+use geom::Ray;
+use std::f32;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub struct Volume {
+    bb: Aabb,
+    /// The number of voxels along each axis.
+    res: [usize; 3],
+    /// Densities at the voxel centers, x varying fastest, then y, then z.
+    density: Vec<f32>,
+    absorption: f32,
+    emission: Rgb,
+}
+
+/// Load the volume given in the configuration, if any.
+pub fn load(cfg: &Config) -> io::Result<Option<Volume>> {
+    let path = match cfg.volume {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let (bb, res, density) = read_vol(path)?;
+    Ok(Some(Volume {
+                bb,
+                res,
+                density,
+                absorption: cfg.volume_absorption,
+                emission: cfg.volume_emission,
+            }))
+}
+
+/// Read a Mitsuba grid volume: "VOL", version 3, encoding 1 (float32), the resolution in x, y
+/// and z, the number of channels and the bounding box, then the data, all little-endian.
+fn read_vol(path: &Path) -> io::Result<(Aabb, [usize; 3], Vec<f32>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    if bytes.len() < 48 || &bytes[..3] != b"VOL" || bytes[3] != 3 {
+        return Err(invalid("not a version 3 .vol file"));
+    }
+    let word = |i: usize| {
+        let mut w = [0; 4];
+        w.copy_from_slice(&bytes[4 + 4 * i..8 + 4 * i]);
+        u32::from_le_bytes(w)
+    };
+    if word(0) != 1 {
+        return Err(invalid("only float32 grids are supported"));
+    }
+    if word(4) != 1 {
+        return Err(invalid("only grids with one channel are supported"));
+    }
+    let res = [usize(word(1)), usize(word(2)), usize(word(3))];
+    let corner = |i: usize| {
+        vec3(f32::from_bits(word(i)), f32::from_bits(word(i + 1)), f32::from_bits(word(i + 2)))
+    };
+    let bb = Aabb::new(vec![corner(5), corner(8)]);
+    let voxels = res[0].checked_mul(res[1])
+        .and_then(|n| n.checked_mul(res[2]))
+        .ok_or_else(|| invalid("resolution too large"))?;
+    if voxels == 0 || Some(bytes.len()) != voxels.checked_mul(4).and_then(|n| n.checked_add(48)) {
+        return Err(invalid("resolution doesn't match the amount of data"));
+    }
+    let density = (0..voxels).map(|i| f32::from_bits(word(11 + i))).collect();
+    Ok((bb, res, density))
+}
+
+impl Volume {
+    pub fn bbox(&self) -> Aabb {
+        Aabb::new(vec![self.bb.min(), self.bb.max()])
+    }
+
+    /// Bytes used by the density grid.
+    pub fn memory_usage(&self) -> usize {
+        self.density.capacity() * ::std::mem::size_of::<f32>()
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.density[(z * self.res[1] + y) * self.res[0] + x]
+    }
+
+    /// The density at `p`, interpolated trilinearly between the voxel centers.
+    fn density(&self, p: Vector3<f32>) -> f32 {
+        let (min, max) = (self.bb.min(), self.bb.max());
+        let mut cells = [(0, 0, 0.0); 3];
+        for axis in 0..3 {
+            let n = self.res[axis];
+            let x = (p[axis] - min[axis]) / (max[axis] - min[axis]) * f32(n) - 0.5;
+            let x = x.max(0.0).min(f32(n - 1));
+            let lo = x.floor() as usize;
+            cells[axis] = (lo, (lo + 1).min(n - 1), x - x.floor());
+        }
+        let ((x0, x1, fx), (y0, y1, fy), (z0, z1, fz)) = (cells[0], cells[1], cells[2]);
+        let lerp = |a: f32, b: f32, f: f32| a + (b - a) * f;
+        let plane = |z: usize| {
+            lerp(lerp(self.voxel(x0, y0, z), self.voxel(x1, y0, z), fx),
+                 lerp(self.voxel(x0, y1, z), self.voxel(x1, y1, z), fx),
+                 fy)
+        };
+        lerp(plane(z0), plane(z1), fz)
+    }
+
+    /// The light arriving along `r` when `behind` is what's seen at distance `t_max` (infinity
+    /// for the background), after passing through the volume. Marches in steps of half the
+    /// smallest voxel size, sampling the density in the middle of each step.
+    pub fn composite(&self, r: &Ray, t_max: f32, behind: Rgb) -> Rgb {
+        let (min, max) = (self.bb.min(), self.bb.max());
+        let (mut t_enter, mut t_exit) = (r.t_min, t_max);
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.d[axis];
+            let t0 = (min[axis] - r.o[axis]) * inv_d;
+            let t1 = (max[axis] - r.o[axis]) * inv_d;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter >= t_exit || t_enter.is_nan() || t_exit.is_nan() {
+            return behind;
+        }
+        let step = (0..3)
+            .map(|axis| (max[axis] - min[axis]) / f32(self.res[axis]))
+            .fold(f32::INFINITY, f32::min) * 0.5;
+        // The direction isn't necessarily normalized, but the absorption is per unit length.
+        let step_t = step / r.d.magnitude();
+        let (mut radiance, mut transmittance) = (Rgb::black(), 1.0);
+        let mut t = t_enter;
+        while t < t_exit && transmittance > 1e-4 {
+            let dt = step_t.min(t_exit - t);
+            let density = self.density(r.o + r.d * (t + 0.5 * dt));
+            let length = dt / step_t * step;
+            radiance += self.emission * (transmittance * density * length);
+            transmittance *= (-self.absorption * density * length).exp();
+            t += dt;
+        }
+        radiance + behind * transmittance
+    }
+}
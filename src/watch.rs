@@ -0,0 +1,44 @@
+use super::fail;
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+/// Call `f` whenever one of the files is written, replaced, or created. Never returns.
+///
+/// The directories containing the files are watched rather than the files themselves,
+/// because many programs save by writing a new file and renaming it over the old one.
+pub fn on_change<F: FnMut()>(files: &[PathBuf], mut f: F) -> ! {
+    let files: HashSet<PathBuf> = files.iter().map(|p| absolute(p)).collect();
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, Duration::from_millis(200))
+        .unwrap_or_else(|e| fail(&format!("could not watch files: {}", e)));
+    let dirs: HashSet<PathBuf> = files.iter()
+        .filter_map(|p| p.parent().map(Path::to_owned))
+        .collect();
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)
+            .unwrap_or_else(|e| fail(&format!("could not watch {}: {}", dir.display(), e)));
+    }
+    println!("watching for changes, press Ctrl+C to stop");
+    loop {
+        let changed = match rx.recv() {
+            Ok(DebouncedEvent::Write(path)) |
+            Ok(DebouncedEvent::Create(path)) |
+            Ok(DebouncedEvent::Rename(_, path)) => files.contains(&absolute(&path)),
+            Ok(DebouncedEvent::Error(e, _)) => {
+                fail(&format!("error while watching files: {}", e))
+            }
+            Ok(_) => false,
+            Err(_) => fail("file watcher stopped unexpectedly"),
+        };
+        if changed {
+            f();
+        }
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
@@ -0,0 +1,29 @@
+//! Runs `suptracer selftest` on the meshes and reference images in tests/golden, so that
+//! `cargo test` catches traversal and BVH builder changes that alter what's rendered.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The binary, which cargo builds before the integration tests. They end up in
+/// target/<profile>/deps, the binary in target/<profile>.
+fn suptracer() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("suptracer{}", env::consts::EXE_SUFFIX))
+}
+
+#[test]
+fn golden_images() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+    let status = Command::new(suptracer())
+        .arg("selftest")
+        .arg("--dir")
+        .arg(&dir)
+        .status()
+        .expect("could not run suptracer");
+    assert!(status.success(), "renders differ from the reference images, see above");
+}